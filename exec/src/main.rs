@@ -34,8 +34,12 @@ fn main() {
     let contents = fs::read_to_string(fakefs).expect("Should have been able to read the file");
     let root_dir: memoryfs::Directory = serde_yaml::from_str(&contents).unwrap();
 
-    let root = create_memory_fs(root_dir);
+    let (root, metadata) = create_memory_fs(root_dir);
 
-    let server = ServerBuilder::new(root).bind("127.0.0.1:11112").build();
+    let server = ServerBuilder::new(root)
+        .bind("127.0.0.1:11112")
+        .file_metadata(metadata)
+        .build()
+        .expect("Should have been able to build the server");
     server.start();
 }
@@ -1,16 +1,36 @@
-use bold::vfs;
+use std::{
+    collections::HashMap,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use bold::{server::filemanager::FileMetadata, vfs};
 use serde::Deserialize;
 
 #[derive(Deserialize, PartialEq, Debug)]
 pub struct Directory {
     name: String,
     contents: Vec<Node>,
+    #[serde(flatten)]
+    attrs: Attributes,
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
 pub struct File {
     name: String,
     contents: String,
+    #[serde(flatten)]
+    attrs: Attributes,
+}
+
+/// Optional attributes of a node, unset ones fall back to the server defaults
+#[derive(Deserialize, PartialEq, Debug, Default)]
+pub struct Attributes {
+    /// permission bits, e.g. 0o600
+    mode: Option<u32>,
+    owner: Option<String>,
+    group: Option<String>,
+    /// modification time in seconds since the epoch
+    mtime: Option<u64>,
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -19,13 +39,43 @@ pub enum Node {
     File(File),
 }
 
-pub fn create_memory_fs(fs_root: Directory) -> vfs::VfsPath {
-    fn create_dir(fs: &vfs::VfsPath, dir: &Directory) {
+pub fn create_memory_fs(fs_root: Directory) -> (vfs::VfsPath, HashMap<String, FileMetadata>) {
+    fn set_attrs(
+        path: &vfs::VfsPath,
+        attrs: &Attributes,
+        metadata: &mut HashMap<String, FileMetadata>,
+    ) {
+        if let Some(mtime) = attrs.mtime {
+            path.set_modification_time(UNIX_EPOCH + Duration::from_secs(mtime))
+                .unwrap();
+        }
+        if attrs.mode.is_some() || attrs.owner.is_some() || attrs.group.is_some() {
+            let mut key = path.as_str().to_string();
+            if key.is_empty() {
+                key = "/".to_string();
+            }
+            metadata.insert(
+                key,
+                FileMetadata {
+                    mode: attrs.mode,
+                    owner: attrs.owner.clone(),
+                    owner_group: attrs.group.clone(),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn create_dir(
+        fs: &vfs::VfsPath,
+        dir: &Directory,
+        metadata: &mut HashMap<String, FileMetadata>,
+    ) {
         let dir_path = fs.join(&dir.name).unwrap();
         dir_path.create_dir_all().unwrap();
         for node in &dir.contents {
             match node {
-                Node::Dir(dir) => create_dir(&dir_path, dir),
+                Node::Dir(dir) => create_dir(&dir_path, dir, metadata),
                 Node::File(file) => {
                     let file_path = dir_path.join(&file.name).unwrap();
                     file_path
@@ -33,12 +83,84 @@ pub fn create_memory_fs(fs_root: Directory) -> vfs::VfsPath {
                         .unwrap()
                         .write_all(file.contents.as_bytes())
                         .unwrap();
+                    set_attrs(&file_path, &file.attrs, metadata);
                 }
             }
         }
+        set_attrs(&dir_path, &dir.attrs, metadata);
     }
 
     let root: vfs::VfsPath = vfs::MemoryFS::new().into();
-    create_dir(&root, &fs_root);
-    root
+    let mut metadata = HashMap::new();
+    create_dir(&root, &fs_root, &mut metadata);
+    (root, metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use bold::server::filemanager::{FileManagerConfig, FileManagerHandle};
+    use bold_proto::nfs4_proto::{FileAttr, FileAttrValue, Nfstime4};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_file_attributes() {
+        let yaml = r#"
+name: ""
+contents:
+  - !File
+    name: secret
+    contents: "top secret"
+    mode: 0o600
+    owner: "0"
+    group: "0"
+    mtime: 1700000000
+  - !File
+    name: public
+    contents: "hello"
+"#;
+        let root_dir: Directory = serde_yaml::from_str(yaml).unwrap();
+        let (root, metadata) = create_memory_fs(root_dir);
+        let mut fmanager = FileManagerHandle::with_config(
+            root,
+            None,
+            FileManagerConfig {
+                metadata,
+                ..Default::default()
+            },
+        );
+
+        let attr_request = vec![
+            FileAttr::Mode,
+            FileAttr::Owner,
+            FileAttr::OwnerGroup,
+            FileAttr::TimeModify,
+        ];
+        let fh = fmanager
+            .get_filehandle_for_path("secret".to_string())
+            .await
+            .unwrap();
+        let (_, attrs) = fmanager.filehandle_attrs(&attr_request, &fh).unwrap();
+        assert_eq!(
+            attrs.0,
+            vec![
+                FileAttrValue::Mode(0o600),
+                FileAttrValue::Owner("0".to_string()),
+                FileAttrValue::OwnerGroup("0".to_string()),
+                FileAttrValue::TimeModify(Nfstime4 {
+                    seconds: 1700000000,
+                    nseconds: 0
+                }),
+            ]
+        );
+
+        let fh = fmanager
+            .get_filehandle_for_path("public".to_string())
+            .await
+            .unwrap();
+        let (_, attrs) = fmanager
+            .filehandle_attrs(&vec![FileAttr::Mode], &fh)
+            .unwrap();
+        assert_eq!(attrs.0, vec![FileAttrValue::Mode(0o444)]);
+    }
 }
@@ -3,31 +3,58 @@
 pub mod server;
 
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 
-use bold_proto::rpc_proto::{AcceptBody, AcceptedReply, OpaqueAuth, ReplyBody};
-use bold_proto::XDRProtoCodec;
+use bold_proto::{GarbageArgs, XDRProtoCodec};
 use futures::SinkExt;
+use server::access::{AccessList, Cidr, CidrParseError};
+pub use server::authorizer::Authorizer;
 use server::clientmanager::ClientManagerHandle;
-use server::filemanager::FileManagerHandle;
+use server::clock::SharedClock;
+pub use server::clock::{Clock, MockClock, SystemClock};
+pub use server::exports::Exports;
+use server::filemanager::{
+    FileManagerConfig, FileManagerHandle, FileMetadata, Filehandle, FilehandleStrategy,
+};
+pub use server::security::SecurityFlavor;
+pub use server::special::{LocalSpecialFiles, SpecialFiles};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time;
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
-use tracing::{error, info, span, trace, Level};
+use tracing::{error, info, span, trace, Instrument, Level};
 pub use vfs;
+use vfs::OverlayFS;
 pub use vfs::VfsPath;
 
-use crate::server::request::NfsRequest;
-use crate::server::{NFSService, NfsProtoImpl};
+use crate::server::request::{boot_verifier, NfsRequest};
+use crate::server::{garbage_args_reply, NFSService, NfsProtoImpl};
 
+#[derive(Clone)]
 pub struct NFSServer {
     /// The listining address of the server
-    bind: String,
+    bind: SocketAddr,
     /// The root of this NFS file system
     root: VfsPath,
     /// NFSv4.0 service
     service_0: Option<server::nfs40::NFS40Server>,
-    /// The time the server was started
-    boot_time: u64,
+    /// Identifies this server instance, changes on every restart
+    boot_verifier: [u8; 8],
+    /// Configuration of the file manager
+    filemanager_config: FileManagerConfig,
+    /// Custom access policy
+    authorizer: Option<Arc<dyn Authorizer>>,
+    /// Client networks that may connect
+    access: AccessList,
+    /// How long a connection may go on after shutdown was requested
+    drain_timeout: Duration,
     // ToDo: add more minor version support
 }
 
@@ -40,92 +67,222 @@ impl NFSServer {
     /// Start the NFS server, serve forever
     /// This starts a tokio runtime and serves the NFS requests
     pub fn start(&self) {
+        self.start_until(std::future::pending());
+    }
+
+    /// Start the NFS server, serve until `shutdown` completes. Connections
+    /// in flight by then get the drain timeout to finish before they're
+    /// closed.
+    pub fn start_until<F>(&self, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async {
-                let listener = TcpListener::bind(self.bind.clone()).await.unwrap();
+                let listener = TcpListener::bind(self.bind).await.unwrap();
                 info!(%self.bind, "Server listening");
+                self.serve(listener, shutdown).await;
+            });
+    }
 
-                // start the client manager and file manager
-                // configs go here
-                let client_manager_handle = ClientManagerHandle::new();
-                let file_manager_handle = FileManagerHandle::new(self.root.clone(), None);
-
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, addr)) => {
-                            let _ = stream.set_nodelay(true);
-                            info!(%addr, "Client connected");
-                            let span = span!(Level::TRACE, "client", %addr);
-                            let _enter = span.enter();
-                            // Reading NFS RPC messages over record marking codec
-                            let mut nfs_transport = Framed::new(stream, XDRProtoCodec::new());
-                            // clone NFS server to move into the pipeline and actor connects with shared state
-                            // a per-client based filehandle cache
-                            let mut filehandle_cache = HashMap::new();
-
-                            loop {
-                                let msg = nfs_transport.next().await;
-                                match msg {
-                                    Some(Ok(msg)) => {
-                                        // create a NFS request
-                                        let request = NfsRequest::new(
-                                            addr.to_string(),
-                                            client_manager_handle.clone(),
-                                            file_manager_handle.clone(),
-                                            self.boot_time,
-                                            Some(&mut filehandle_cache),
-                                        );
-                                        // ToDo implement and select correct version of NFS protocol, this services all with minor version 0
-                                        let nfs_protocol = self.service_0.as_ref().unwrap();
-                                        let service = NFSService::new(nfs_protocol.clone());
-
-                                        let resp = service.call(msg, request).await;
-                                        match nfs_transport.send(resp).await {
-                                            Ok(_) => {
-                                                trace!("response sent");
-                                            }
-                                            Err(e) => {
-                                                error!("couldn't send response: {:?}", e);
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    Some(Err(e)) => {
-                                        error!("couldn't get message: {:?}", e);
-                                        let resp = Box::new(bold_proto::rpc_proto::RpcReplyMsg {
-                                            xid: 0,
-                                            body: bold_proto::rpc_proto::MsgType::Reply(
-                                                ReplyBody::MsgAccepted(AcceptedReply {
-                                                    verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
-                                                    reply_data: AcceptBody::GarbageArgs,
-                                                }),
-                                            ),
-                                        });
-                                        match nfs_transport.send(resp).await {
-                                            Ok(_) => {
-                                                trace!("response sent");
-                                            }
-                                            Err(e) => {
-                                                error!("couldn't send response: {:?}", e);
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        // client closed connection
-                                        info!(%addr, "Client disconnected");
-                                        break;
-                                    }
-                                }
-                            }
+    async fn serve<F>(&self, listener: TcpListener, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        // start the client manager and file manager
+        // configs go here
+        let client_manager_handle = ClientManagerHandle::with_config(
+            self.boot_verifier,
+            self.filemanager_config.request_timeout,
+            self.filemanager_config.clock.clone(),
+        );
+        let file_manager_handle = FileManagerHandle::with_config(
+            self.root.clone(),
+            None,
+            self.filemanager_config.clone(),
+        );
+
+        // every connection is served by a task of its own, they share the
+        // managers and with them the filehandle cache
+        let server = Arc::new(self.clone());
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                // forget the connections that are done
+                Some(_) = connections.join_next() => continue,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                    let _ = stream.set_nodelay(true);
+                    let server = server.clone();
+                    let client_manager_handle = client_manager_handle.clone();
+                    let file_manager_handle = file_manager_handle.clone();
+                    connections.spawn(async move {
+                        server
+                            .serve_connection(
+                                stream,
+                                addr,
+                                &client_manager_handle,
+                                &file_manager_handle,
+                            )
+                            .await
+                    });
+                }
+                Err(e) => error!("couldn't get client: {:?}", e),
+            }
+        }
+        // the clients get the drain timeout to finish what they're doing,
+        // dropping the tasks closes their connections
+        if !connections.is_empty() {
+            info!(open = connections.len(), "Draining connections");
+            let drained = async { while connections.join_next().await.is_some() {} };
+            if time::timeout(self.drain_timeout, drained).await.is_err() {
+                error!(
+                    open = connections.len(),
+                    "Connection force-closed after {:?}", self.drain_timeout
+                );
+                connections.shutdown().await;
+            }
+        }
+        info!("Server shut down");
+    }
+
+    /// Serve the NFS requests of a client connected from `addr` until it
+    /// disconnects. Connections from sources that aren't admitted are
+    /// closed right away.
+    async fn serve_connection<S>(
+        &self,
+        stream: S,
+        addr: SocketAddr,
+        client_manager_handle: &ClientManagerHandle,
+        file_manager_handle: &FileManagerHandle,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if !self.access.admits(&addr.ip()) {
+            info!(%addr, "Client refused");
+            return;
+        }
+        info!(%addr, "Client connected");
+        let span = span!(Level::TRACE, "client", %addr);
+        self.serve_calls(stream, addr, client_manager_handle, file_manager_handle)
+            .instrument(span)
+            .await
+    }
+
+    async fn serve_calls<S>(
+        &self,
+        stream: S,
+        addr: SocketAddr,
+        client_manager_handle: &ClientManagerHandle,
+        file_manager_handle: &FileManagerHandle,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Reading NFS RPC messages over record marking codec
+        let mut nfs_transport = Framed::new(stream, XDRProtoCodec::new());
+        // clone NFS server to move into the pipeline and actor connects with shared state
+
+        loop {
+            let msg = nfs_transport.next().await;
+            match msg {
+                Some(Ok(msg)) => {
+                    // create a NFS request
+                    let mut builder = NfsRequest::builder(
+                        client_manager_handle.clone(),
+                        file_manager_handle.clone(),
+                    );
+                    builder.client_addr(addr).boot_verifier(self.boot_verifier);
+                    if let Some(authorizer) = &self.authorizer {
+                        builder.authorizer(authorizer.clone());
+                    }
+                    let request = builder.build();
+                    // ToDo implement and select correct version of NFS protocol, this services all with minor version 0
+                    let nfs_protocol = self.service_0.as_ref().unwrap();
+                    let service = NFSService::new(nfs_protocol.clone());
+
+                    let resp = service.call(msg, request).await;
+                    match nfs_transport.send(resp).await {
+                        Ok(_) => {
+                            trace!("response sent");
+                        }
+                        Err(e) => {
+                            error!("couldn't send response: {:?}", e);
+                            break;
                         }
-                        Err(e) => error!("couldn't get client: {:?}", e),
                     }
                 }
-            });
+                Some(Err(e)) => {
+                    error!("couldn't get message: {:?}", e);
+                    let xid = GarbageArgs::from_io_error(&e)
+                        .and_then(|garbage| garbage.xid)
+                        .unwrap_or(0);
+                    let resp = garbage_args_reply(xid);
+                    match nfs_transport.send(resp).await {
+                        Ok(_) => {
+                            trace!("response sent");
+                        }
+                        Err(e) => {
+                            error!("couldn't send response: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    // client closed connection
+                    info!(%addr, "Client disconnected");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Errors returned by [`ServerBuilder::build`]
+#[derive(Debug)]
+pub enum ServerBuilderError {
+    /// The bind string doesn't resolve to a socket address, e.g.
+    /// "127.0.0.1:2049" or "localhost:2049"
+    InvalidBindAddress { bind: String, source: io::Error },
+    /// An allowed or denied network is not in CIDR notation, e.g. "10.0.0.0/8"
+    InvalidNetwork {
+        network: String,
+        source: CidrParseError,
+    },
+    /// The filehandle strategy promises filehandles that outlive the ids
+    /// the server issues, e.g. persistent ones from a volatile id generator
+    UnstableFilehandles { strategy: FilehandleStrategy },
+}
+
+impl fmt::Display for ServerBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerBuilderError::InvalidBindAddress { bind, source } => {
+                write!(f, "invalid bind address {:?}: {}", bind, source)
+            }
+            ServerBuilderError::InvalidNetwork { network, source } => {
+                write!(f, "invalid network {:?}: {}", network, source)
+            }
+            ServerBuilderError::UnstableFilehandles { strategy } => {
+                write!(f, "filehandle ids aren't stable enough for {:?}", strategy)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerBuilderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerBuilderError::InvalidBindAddress { source, .. } => Some(source),
+            ServerBuilderError::InvalidNetwork { source, .. } => Some(source),
+            ServerBuilderError::UnstableFilehandles { .. } => None,
+        }
     }
 }
 
@@ -134,6 +291,20 @@ pub struct ServerBuilder {
     bind: String,
     /// The root of this NFS file system
     root: VfsPath,
+    /// Configuration of the file manager
+    filemanager_config: FileManagerConfig,
+    /// Fixed boot verifier, derived from the boot time if not set
+    boot_verifier: Option<[u8; 8]>,
+    /// Custom access policy
+    authorizer: Option<Arc<dyn Authorizer>>,
+    /// Client networks that may connect, all if empty
+    allow: Vec<String>,
+    /// Client networks that may not connect
+    deny: Vec<String>,
+    /// Writable layer over the root, which is then only read from
+    overlay: Option<VfsPath>,
+    /// How long a connection may go on after shutdown was requested
+    drain_timeout: Duration,
 }
 
 impl ServerBuilder {
@@ -141,33 +312,1006 @@ impl ServerBuilder {
         ServerBuilder {
             bind: "127.0.0.1:11112".to_string(),
             root,
+            filemanager_config: FileManagerConfig {
+                filehandle_cache_ttl: Some(Duration::from_secs(10)),
+                readdir_max_dircount: Some(256 * 1024),
+                readdir_max_maxcount: Some(1024 * 1024),
+                ..Default::default()
+            },
+            boot_verifier: None,
+            authorizer: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            overlay: None,
+            drain_timeout: Duration::from_secs(30),
         }
     }
 
+    /// Set the listening address, an ip or host name with a port. It is
+    /// resolved by [`ServerBuilder::build`]
     pub fn bind(&mut self, bind: &str) -> &mut Self {
         self.bind = bind.to_string();
         self
     }
 
-    pub fn build(&self) -> NFSServer {
+    /// Set how long filehandles stay valid, this is advertised to clients
+    /// as fh_expire_type
+    pub fn filehandle_strategy(&mut self, strategy: FilehandleStrategy) -> &mut Self {
+        self.filemanager_config.filehandle_strategy = strategy;
+        self
+    }
+
+    /// Reply NFS4ERR_DELAY if the backend doesn't answer a request in time,
+    /// by default requests wait forever
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.filemanager_config.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Limit the number of files open at the same time, further opens fail
+    /// with NFS4ERR_RESOURCE
+    pub fn max_opens(&mut self, max_opens: usize) -> &mut Self {
+        self.filemanager_config.max_opens = Some(max_opens);
+        self
+    }
+
+    /// Limit the number of files a single client may have open at the
+    /// same time
+    pub fn max_opens_per_client(&mut self, max_opens: usize) -> &mut Self {
+        self.filemanager_config.max_opens_per_client = Some(max_opens);
+        self
+    }
+
+    /// Limit the number of open and lock owners a single client may hold
+    /// state for, opens by further owners fail with NFS4ERR_RESOURCE
+    pub fn max_owners_per_client(&mut self, max_owners: usize) -> &mut Self {
+        self.filemanager_config.max_owners_per_client = Some(max_owners);
+        self
+    }
+
+    /// Apply writes to the file system right away and report them as
+    /// FILE_SYNC4, instead of caching unstable writes until COMMIT
+    pub fn write_through(&mut self, write_through: bool) -> &mut Self {
+        self.filemanager_config.write_through = write_through;
+        self
+    }
+
+    /// Write unstable data to the file system at the latest `interval`
+    /// after it was cached, without waiting for a COMMIT
+    pub fn write_cache_flush_interval(&mut self, interval: Duration) -> &mut Self {
+        self.filemanager_config.write_cache_flush_interval = Some(interval);
+        self
+    }
+
+    /// Write unstable data to the file system once `size` bytes of it are
+    /// cached for a file, without waiting for a COMMIT
+    pub fn write_cache_flush_size(&mut self, size: u64) -> &mut Self {
+        self.filemanager_config.write_cache_flush_size = Some(size);
+        self
+    }
+
+    /// Report a quota of `bytes` for the export in the quota attributes.
+    /// It has to be enforced by the file system, writes it refuses for
+    /// exceeding a quota fail with NFS4ERR_DQUOT.
+    pub fn quota(&mut self, bytes: u64) -> &mut Self {
+        self.filemanager_config.quota = Some(bytes);
+        self
+    }
+
+    /// Report room for `files` files and directories in the export in the
+    /// files attributes, those that exist take up their share of it
+    pub fn files_total(&mut self, files: u64) -> &mut Self {
+        self.filemanager_config.files_total = Some(files);
+        self
+    }
+
+    /// Announce `flavors` in SECINFO, strongest first whatever order they
+    /// are given in. By default that's AUTH_SYS and AUTH_NONE.
+    pub fn security_flavors(&mut self, flavors: &[SecurityFlavor]) -> &mut Self {
+        self.filemanager_config.security_flavors = flavors.to_vec();
+        self
+    }
+
+    /// Let clients create and read symlinks, devices and hard links, kept by
+    /// `special_files` in the backend. Only root may create devices. Without,
+    /// the export has none of them and creating them fails with
+    /// NFS4ERR_NOTSUPP.
+    pub fn special_files(&mut self, special_files: impl SpecialFiles + 'static) -> &mut Self {
+        self.filemanager_config.special_files = Some(Arc::new(special_files));
+        self
+    }
+
+    /// Refuse LINK with NFS4ERR_MLINK once a file has `max_link` names,
+    /// announced as maxlink. Hard links need [`special_files`](Self::special_files).
+    pub fn max_link(&mut self, max_link: u32) -> &mut Self {
+        self.filemanager_config.max_link = Some(max_link);
+        self
+    }
+
+    /// Give a connection in flight `timeout` to finish once shutdown was
+    /// requested, it's closed after that. Defaults to 30 seconds.
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Resolve filehandles of removed files and directories to whatever was
+    /// recreated at their paths, instead of replying NFS4ERR_STALE.
+    pub fn refresh_stale_filehandles(&mut self, refresh: bool) -> &mut Self {
+        self.filemanager_config.refresh_stale_filehandles = refresh;
+        self
+    }
+
+    /// Mode of files that have none in their metadata and weren't given
+    /// one by the client that created them, by default read-only for all
+    pub fn file_mode(&mut self, mode: u32) -> &mut Self {
+        self.filemanager_config.file_mode = Some(mode);
+        self
+    }
+
+    /// Mode of directories that have none in their metadata and weren't
+    /// given one by the client that created them
+    pub fn dir_mode(&mut self, mode: u32) -> &mut Self {
+        self.filemanager_config.dir_mode = Some(mode);
+        self
+    }
+
+    /// Prefetch `window` bytes after every sequential READ, so the next
+    /// one is served from memory. Off by default.
+    pub fn readahead(&mut self, window: u64) -> &mut Self {
+        self.filemanager_config.readahead = Some(window);
+        self
+    }
+
+    /// How long resolved filehandles are cached for all connections, 10
+    /// seconds by default. `None` turns the cache off.
+    pub fn filehandle_cache(&mut self, ttl: Option<Duration>) -> &mut Self {
+        self.filemanager_config.filehandle_cache_ttl = ttl;
+        self
+    }
+
+    /// Cap the dircount and maxcount of READDIR requests, larger requests
+    /// are answered with a partial listing the client continues. 256 KiB
+    /// and 1 MiB by default.
+    pub fn readdir_max_count(&mut self, dircount: u32, maxcount: u32) -> &mut Self {
+        self.filemanager_config.readdir_max_dircount = Some(dircount);
+        self.filemanager_config.readdir_max_maxcount = Some(maxcount);
+        self
+    }
+
+    /// Refuse paths nested deeper than `depth` components below the root
+    /// with NFS4ERR_NAMETOOLONG
+    pub fn max_path_depth(&mut self, depth: usize) -> &mut Self {
+        self.filemanager_config.max_path_depth = Some(depth);
+        self
+    }
+
+    /// Use a fixed boot verifier instead of one derived from the boot time.
+    /// Clients take a changed verifier as a server restart, so this is
+    /// meant for tests that simulate reboots.
+    pub fn boot_verifier(&mut self, verifier: [u8; 8]) -> &mut Self {
+        self.boot_verifier = Some(verifier);
+        self
+    }
+
+    /// Set attributes like mode and owner per path, for backends that don't
+    /// store them
+    pub fn file_metadata(&mut self, metadata: HashMap<String, FileMetadata>) -> &mut Self {
+        self.filemanager_config.metadata = metadata;
+        self
+    }
+
+    /// Take the time from `clock` instead of the system clock, e.g. to
+    /// let leases expire in tests without waiting
+    pub fn clock(&mut self, clock: impl Clock + 'static) -> &mut Self {
+        self.filemanager_config.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Consult `authorizer` before every operation, see [`Authorizer`]
+    pub fn authorizer(&mut self, authorizer: impl Authorizer + 'static) -> &mut Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Only accept connections from `network`, in CIDR notation like
+    /// "10.0.0.0/8". Can be called multiple times, all networks are allowed
+    /// if it's never called.
+    pub fn allow(&mut self, network: &str) -> &mut Self {
+        self.allow.push(network.to_string());
+        self
+    }
+
+    /// Close connections from `network` right away, in CIDR notation like
+    /// "10.0.0.0/8". Denied networks take precedence over allowed ones.
+    pub fn deny(&mut self, network: &str) -> &mut Self {
+        self.deny.push(network.to_string());
+        self
+    }
+
+    /// Serve the root as a read-only base with `upper` layered over it,
+    /// e.g. a `MemoryFS`. Writes, new files and removals go to `upper`,
+    /// reads see its content where it has any and the base otherwise.
+    /// Removed base files are recorded in a `.whiteout` directory of
+    /// `upper`.
+    pub fn overlay(&mut self, upper: VfsPath) -> &mut Self {
+        self.overlay = Some(upper);
+        self
+    }
+
+    /// Serve `exports` below a pseudo root, instead of the root the builder
+    /// was created with. The exports can be changed with
+    /// [`Exports::reload`] while the server is running.
+    pub fn exports(&mut self, exports: Exports) -> &mut Self {
+        self.root = exports.root();
+        self.filemanager_config.exports = Some(exports);
+        self
+    }
+
+    pub fn build(&self) -> Result<NFSServer, ServerBuilderError> {
+        // host names are resolved once, the server listens on the first
+        // address they resolve to
+        let bind = self
+            .bind
+            .to_socket_addrs()
+            .and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to bind to"))
+            })
+            .map_err(|source| ServerBuilderError::InvalidBindAddress {
+                bind: self.bind.clone(),
+                source,
+            })?;
+        let parse_networks = |networks: &[String]| {
+            networks
+                .iter()
+                .map(|network| {
+                    network
+                        .parse::<Cidr>()
+                        .map_err(|source| ServerBuilderError::InvalidNetwork {
+                            network: network.clone(),
+                            source,
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let access = AccessList::new(parse_networks(&self.allow)?, parse_networks(&self.deny)?);
+        // clients would hold on to filehandles the restarted server doesn't
+        // know anymore
+        let strategy = self.filemanager_config.filehandle_strategy;
+        if strategy.needs_persistent_ids() && !Filehandle::PERSISTENT_IDS {
+            return Err(ServerBuilderError::UnstableFilehandles { strategy });
+        }
         // set the boot time to now
-        let boot_time = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
-        NFSServer {
-            bind: self.bind.clone(),
-            root: self.root.clone(),
+        let boot_time = self.filemanager_config.clock.secs();
+        let root = match &self.overlay {
+            Some(upper) => OverlayFS::new(&[upper.clone(), self.root.clone()]).into(),
+            None => self.root.clone(),
+        };
+        Ok(NFSServer {
+            bind,
+            root,
             service_0: Some(server::nfs40::NFS40Server::new()),
-            boot_time,
+            boot_verifier: self.boot_verifier.unwrap_or(boot_verifier(boot_time)),
+            filemanager_config: self.filemanager_config.clone(),
+            authorizer: self.authorizer.clone(),
+            access,
+            drain_timeout: self.drain_timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+    use tracing_test::traced_test;
+
+    use bold_proto::{
+        nfs4_proto::{FileAttr, NfsStat4, StableHow4, Stateid4, Write4args},
+        MAX_RECORD_SIZE,
+    };
+    use num_traits::FromPrimitive;
+    use vfs::{MemoryFS, VfsPath};
+
+    use crate::server::{
+        clientmanager::{ClientManagerHandle, LEASE_TIME},
+        filemanager::{FileManagerConfig, FileManagerHandle, MAX_FILE_SIZE, MAX_READ, MAX_WRITE},
+        operation::NfsOperation,
+    };
+    use crate::test_utils::{
+        create_dummyfs, create_fake_fs, create_nfs40_server_with_config, FaultFS,
+    };
+    use crate::{Exports, FilehandleStrategy, NFSServer, ServerBuilderError};
+
+    #[test]
+    fn test_build_bind_address() {
+        let server = NFSServer::builder(create_dummyfs())
+            .bind("0.0.0.0:2049")
+            .build()
+            .unwrap();
+        assert_eq!(server.bind, "0.0.0.0:2049".parse().unwrap());
+
+        let server = NFSServer::builder(create_dummyfs())
+            .bind("[::1]:2049")
+            .build()
+            .unwrap();
+        assert_eq!(server.bind, "[::1]:2049".parse().unwrap());
+
+        // host names are resolved
+        let server = NFSServer::builder(create_dummyfs())
+            .bind("localhost:2049")
+            .build()
+            .unwrap();
+        assert!(server.bind.ip().is_loopback());
+        assert_eq!(server.bind.port(), 2049);
+
+        let err = NFSServer::builder(create_dummyfs())
+            .bind("not-an-addr")
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ServerBuilderError::InvalidBindAddress { ref bind, .. } if bind == "not-an-addr"
+        ));
+
+        // a port is required
+        assert!(NFSServer::builder(create_dummyfs())
+            .bind("127.0.0.1")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_build_boot_verifier() {
+        let server = NFSServer::builder(create_dummyfs()).build().unwrap();
+        assert_ne!(server.boot_verifier, [0; 8]);
+
+        let server = NFSServer::builder(create_dummyfs())
+            .boot_verifier([7; 8])
+            .build()
+            .unwrap();
+        assert_eq!(server.boot_verifier, [7; 8]);
+    }
+
+    #[test]
+    fn test_build_persistent_filehandles() {
+        // the ids are issued from a counter, they don't survive a restart
+        let err = NFSServer::builder(create_dummyfs())
+            .filehandle_strategy(FilehandleStrategy::Persistent)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ServerBuilderError::UnstableFilehandles {
+                strategy: FilehandleStrategy::Persistent
+            }
+        ));
+
+        let server = NFSServer::builder(create_dummyfs())
+            .filehandle_strategy(FilehandleStrategy::VolatileOnMigrationRename)
+            .build()
+            .unwrap();
+        assert_eq!(
+            server.filemanager_config.filehandle_strategy,
+            FilehandleStrategy::VolatileOnMigrationRename
+        );
+    }
+
+    #[test]
+    fn test_build_networks() {
+        let err = NFSServer::builder(create_dummyfs())
+            .allow("10.0.0.0/8")
+            .deny("10.0.0.0/40")
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ServerBuilderError::InvalidNetwork { ref network, .. } if network == "10.0.0.0/40"
+        ));
+    }
+
+    // an RPC call of the NFS program, in a record of its own. The
+    // credential is AUTH_SYS for `uid` if there is one, AUTH_NULL otherwise.
+    fn rpc_call(xid: u32, procedure: u32, uid: Option<u32>, args: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for word in [xid, 0, 2, 100003, 4, procedure] {
+            message.extend(word.to_be_bytes());
+        }
+        match uid {
+            Some(uid) => {
+                let mut cred = 0u32.to_be_bytes().to_vec();
+                xdr_opaque(&mut cred, b"laptop");
+                for word in [uid, uid, 0] {
+                    cred.extend(word.to_be_bytes());
+                }
+                message.extend(1u32.to_be_bytes());
+                xdr_opaque(&mut message, &cred);
+            }
+            None => message.extend([0u8; 8]),
+        }
+        // AUTH_NULL verifier
+        message.extend([0u8; 8]);
+        message.extend(args);
+        let mut record = ((message.len() as u32) | (1 << 31)).to_be_bytes().to_vec();
+        record.extend(message);
+        record
+    }
+
+    fn xdr_opaque(buf: &mut Vec<u8>, data: &[u8]) {
+        buf.extend((data.len() as u32).to_be_bytes());
+        buf.extend(data);
+        buf.resize(buf.len() + (4 - data.len() % 4) % 4, 0);
+    }
+
+    fn xdr_bitmap(buf: &mut Vec<u8>, attrs: &[u32]) {
+        let mut words = vec![0u32; 2];
+        for attr in attrs {
+            words[*attr as usize / 32] |= 1 << (attr % 32);
+        }
+        buf.extend((words.len() as u32).to_be_bytes());
+        for word in words {
+            buf.extend(word.to_be_bytes());
+        }
+    }
+
+    // reads the XDR of a reply front to back
+    struct XdrReader(Vec<u8>, usize);
+
+    impl XdrReader {
+        fn u32(&mut self) -> u32 {
+            let word = u32::from_be_bytes(self.0[self.1..self.1 + 4].try_into().unwrap());
+            self.1 += 4;
+            word
+        }
+
+        fn u64(&mut self) -> u64 {
+            ((self.u32() as u64) << 32) | self.u32() as u64
+        }
+
+        fn fixed(&mut self, len: usize) -> Vec<u8> {
+            let data = self.0[self.1..self.1 + len].to_vec();
+            self.1 += len.div_ceil(4) * 4;
+            data
         }
+
+        fn opaque(&mut self) -> Vec<u8> {
+            let len = self.u32() as usize;
+            self.fixed(len)
+        }
+
+        fn bitmap(&mut self) -> Vec<u32> {
+            let len = self.u32() as usize;
+            (0..len).map(|_| self.u32()).collect()
+        }
+    }
+
+    // sends a COMPOUND and returns its status with a reader positioned at
+    // the first result
+    async fn compound<S>(client: &mut S, xid: u32, ops: &[u8], count: u32) -> (u32, XdrReader)
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        compound_as(client, xid, None, ops, count).await
+    }
+
+    // sends a COMPOUND on behalf of `uid`
+    async fn compound_as<S>(
+        client: &mut S,
+        xid: u32,
+        uid: Option<u32>,
+        ops: &[u8],
+        count: u32,
+    ) -> (u32, XdrReader)
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let mut args = Vec::new();
+        // empty tag, minor version 0
+        args.extend([0u8; 8]);
+        args.extend(count.to_be_bytes());
+        args.extend(ops);
+        client
+            .write_all(&rpc_call(xid, 1, uid, &args))
+            .await
+            .unwrap();
+
+        let mut mark = [0u8; 4];
+        client.read_exact(&mut mark).await.unwrap();
+        let mut reply = vec![0u8; (u32::from_be_bytes(mark) & !(1 << 31)) as usize];
+        client.read_exact(&mut reply).await.unwrap();
+        let mut reader = XdrReader(reply, 0);
+        // xid, REPLY, MSG_ACCEPTED
+        assert_eq!([reader.u32(), reader.u32(), reader.u32()], [xid, 1, 0]);
+        // verifier flavor and body
+        reader.u32();
+        reader.opaque();
+        // SUCCESS
+        assert_eq!(reader.u32(), 0);
+        let status = reader.u32();
+        assert!(reader.opaque().is_empty());
+        assert_eq!(reader.u32(), count);
+        (status, reader)
+    }
+
+    // the result header of an operation, returns its status
+    fn op_status(reader: &mut XdrReader, op: u32) -> u32 {
+        assert_eq!(reader.u32(), op);
+        reader.u32()
+    }
+
+    #[tokio::test]
+    async fn test_build_overlay() {
+        let base = create_fake_fs();
+        let upper: VfsPath = MemoryFS::new().into();
+        let server = NFSServer::builder(base.clone())
+            .overlay(upper.clone())
+            .build()
+            .unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(server.root.clone()),
+            FileManagerConfig {
+                write_through: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"Howdy".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // reads see the overlaid content, the base is left alone
+        let read = |root: &VfsPath| root.join("file1.txt").unwrap().read_to_string().unwrap();
+        assert_eq!(read(&server.root), "Howdy, loooooooong world!");
+        assert_eq!(read(&upper), "Howdy, loooooooong world!");
+        assert_eq!(read(&base), "Hello, loooooooong world!");
+        // files that weren't written to are only in the base
+        assert_eq!(
+            server
+                .root
+                .join("dir1/file2.txt")
+                .unwrap()
+                .read_to_string()
+                .unwrap(),
+            "Hello, file2!"
+        );
+        assert!(!upper.join("dir1/file2.txt").unwrap().exists().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_linux_mount_sequence() {
+        let server = NFSServer::builder(create_dummyfs()).build().unwrap();
+        let client_manager_handle = ClientManagerHandle::new();
+        let file_manager_handle = FileManagerHandle::new(create_dummyfs(), None);
+        let root = file_manager_handle.get_root_filehandle().await.unwrap();
+        let supported: Vec<u32> = file_manager_handle
+            .attr_supported_attrs()
+            .iter()
+            .map(|attr| attr.clone() as u32)
+            .collect();
+
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let addr = "192.168.1.2:700".parse().unwrap();
+        let serve =
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle);
+        let mount = async {
+            // SETCLIENTID
+            let mut ops = 35u32.to_be_bytes().to_vec();
+            ops.extend([1u8; 8]);
+            xdr_opaque(&mut ops, b"Linux NFSv4.0 client/192.168.1.2");
+            ops.extend(0x40000000u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"tcp");
+            xdr_opaque(&mut ops, b"192.168.1.2.3.7");
+            ops.extend(1u32.to_be_bytes());
+            let (status, mut reader) = compound(&mut client, 1, &ops, 1).await;
+            assert_eq!(status, 0);
+            assert_eq!(op_status(&mut reader, 35), 0);
+            let clientid = reader.u64();
+            let confirm = reader.fixed(8);
+
+            // SETCLIENTID_CONFIRM
+            let mut ops = 36u32.to_be_bytes().to_vec();
+            ops.extend(clientid.to_be_bytes());
+            ops.extend(&confirm);
+            let (status, mut reader) = compound(&mut client, 2, &ops, 1).await;
+            assert_eq!(status, 0);
+            assert_eq!(op_status(&mut reader, 36), 0);
+
+            // PUTROOTFH, GETATTR with the fsinfo bitmap, GETFH
+            let fsinfo = [
+                FileAttr::LeaseTime as u32,
+                FileAttr::Maxfilesize as u32,
+                FileAttr::Maxread as u32,
+                FileAttr::Maxwrite as u32,
+                FileAttr::TimeDelta as u32,
+            ];
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(9u32.to_be_bytes());
+            xdr_bitmap(&mut ops, &fsinfo);
+            ops.extend(10u32.to_be_bytes());
+            let (status, mut reader) = compound(&mut client, 3, &ops, 3).await;
+            assert_eq!(status, 0);
+            assert_eq!(op_status(&mut reader, 24), 0);
+            assert_eq!(op_status(&mut reader, 9), 0);
+
+            // every requested attribute the server claims to support is
+            // there, in the order of the bitmap
+            let returned = reader.bitmap();
+            let expected: Vec<u32> = fsinfo
+                .into_iter()
+                .filter(|attr| supported.contains(attr))
+                .collect();
+            let mut returned_attrs = Vec::new();
+            for (i, word) in returned.iter().enumerate() {
+                for bit in 0..32 {
+                    if word & (1 << bit) != 0 {
+                        returned_attrs.push(i as u32 * 32 + bit);
+                    }
+                }
+            }
+            assert_eq!(returned_attrs, expected);
+            let mut values = XdrReader(reader.opaque(), 0);
+            for attr in returned_attrs {
+                match FileAttr::from_u32(attr).unwrap() {
+                    FileAttr::LeaseTime => assert_eq!(values.u32(), LEASE_TIME),
+                    FileAttr::Maxread => assert_eq!(values.u64(), MAX_READ),
+                    FileAttr::Maxwrite => assert_eq!(values.u64(), MAX_WRITE),
+                    FileAttr::Maxfilesize => assert_eq!(values.u64(), MAX_FILE_SIZE),
+                    FileAttr::TimeDelta => {
+                        values.u64();
+                        values.u32();
+                    }
+                    attr => panic!("Unexpected attribute {:?}", attr),
+                }
+            }
+            assert_eq!(values.1, values.0.len());
+
+            assert_eq!(op_status(&mut reader, 10), 0);
+            assert_eq!(reader.opaque(), root.id.to_vec());
+            drop(client);
+        };
+        tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(serve, mount) })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_filehandles_per_compound() {
+        let server = NFSServer::builder(create_fake_fs()).build().unwrap();
+        let client_manager_handle = ClientManagerHandle::new();
+        let file_manager_handle = FileManagerHandle::new(create_fake_fs(), None);
+
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let addr = "192.168.1.2:700".parse().unwrap();
+        let serve =
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle);
+        let calls = async {
+            // uid 1000 sets and saves the filehandle of dir1: PUTROOTFH,
+            // LOOKUP, SAVEFH
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"dir1");
+            ops.extend(32u32.to_be_bytes());
+            let (status, _) = compound_as(&mut client, 1, Some(1000), &ops, 3).await;
+            assert_eq!(status, NfsStat4::Nfs4Ok as u32);
+
+            // neither is left for the next compound on the connection, no
+            // matter the principal: GETFH, RESTOREFH
+            let mut xid = 2;
+            for uid in [1000, 1001] {
+                let ops = 10u32.to_be_bytes();
+                let (status, mut reader) = compound_as(&mut client, xid, Some(uid), &ops, 1).await;
+                assert_eq!(status, NfsStat4::Nfs4errNofilehandle as u32);
+                assert_eq!(
+                    op_status(&mut reader, 10),
+                    NfsStat4::Nfs4errNofilehandle as u32
+                );
+                let ops = 31u32.to_be_bytes();
+                let (status, mut reader) =
+                    compound_as(&mut client, xid + 1, Some(uid), &ops, 1).await;
+                assert_eq!(status, NfsStat4::Nfs4errRestorefh as u32);
+                assert_eq!(
+                    op_status(&mut reader, 31),
+                    NfsStat4::Nfs4errRestorefh as u32
+                );
+                xid += 2;
+            }
+            drop(client);
+        };
+        tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(serve, calls) })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_reload_exports() {
+        let data = create_fake_fs();
+        let exports = Exports::new([("data", data.clone())]);
+        let server = NFSServer::builder(create_dummyfs())
+            .exports(exports.clone())
+            .build()
+            .unwrap();
+        let client_manager_handle = ClientManagerHandle::new();
+        let file_manager_handle = FileManagerHandle::with_config(
+            server.root.clone(),
+            None,
+            server.filemanager_config.clone(),
+        );
+
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let addr = "192.168.1.2:700".parse().unwrap();
+        let serve =
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle);
+        let calls = async {
+            // PUTROOTFH, LOOKUP of an export that isn't there yet
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"more");
+            let (status, _) = compound(&mut client, 1, &ops, 2).await;
+            assert_eq!(status, NfsStat4::Nfs4errNoent as u32);
+
+            // once it's added the same connection can go into it: PUTROOTFH,
+            // LOOKUP, LOOKUP, GETFH
+            exports.reload([("data", data.clone()), ("more", create_fake_fs())]);
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"more");
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"dir1");
+            ops.extend(10u32.to_be_bytes());
+            let (status, mut reader) = compound(&mut client, 2, &ops, 4).await;
+            assert_eq!(status, 0);
+            for op in [24, 15, 15] {
+                assert_eq!(op_status(&mut reader, op), 0);
+            }
+            assert_eq!(op_status(&mut reader, 10), 0);
+            let dir1 = reader.opaque();
+            let mut putfh = 22u32.to_be_bytes().to_vec();
+            xdr_opaque(&mut putfh, &dir1);
+            let (status, _) = compound(&mut client, 3, &putfh, 1).await;
+            assert_eq!(status, 0);
+
+            // the filehandles of a removed export are stale, also the ones
+            // cached for all connections
+            exports.reload([("data", data.clone())]);
+            let (status, _) = compound(&mut client, 4, &putfh, 1).await;
+            assert_eq!(status, NfsStat4::Nfs4errStale as u32);
+
+            // the other export is still there
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"data");
+            let (status, _) = compound(&mut client, 5, &ops, 2).await;
+            assert_eq!(status, 0);
+            drop(client);
+        };
+        tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(serve, calls) })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_record_too_large() {
+        let server = NFSServer::builder(create_dummyfs()).build().unwrap();
+        let client_manager_handle = ClientManagerHandle::new();
+        let file_manager_handle = FileManagerHandle::new(create_dummyfs(), None);
+
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let addr = "192.168.1.2:700".parse().unwrap();
+        let serve =
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle);
+        let stream_fragments = async {
+            // non-final fragments, each well below the record size limit
+            let mut fragment = 16384u32.to_be_bytes().to_vec();
+            fragment.resize(4 + 16384, 0);
+            let mut sent = 0;
+            while sent < 2 * MAX_RECORD_SIZE {
+                if client.write_all(&fragment).await.is_err() {
+                    break;
+                }
+                sent += fragment.len();
+            }
+            // the server hung up once the record outgrew the limit
+            assert!(sent < MAX_RECORD_SIZE + 128 * 1024);
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(serve, stream_fragments)
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_denied_connection_closed() {
+        let server = NFSServer::builder(create_dummyfs())
+            .deny("10.0.0.0/8")
+            .build()
+            .unwrap();
+        let client_manager_handle = ClientManagerHandle::new();
+        let file_manager_handle = FileManagerHandle::new(create_dummyfs(), None);
+
+        // a NULL call, denied clients never get to see a reply
+        let call: [u8; 44] = [
+            0x80, 0, 0, 40, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0x86, 0xa3, 0, 0, 0, 4, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let (mut client, stream) = tokio::io::duplex(1024);
+        client.write_all(&call).await.unwrap();
+        let addr = "10.1.2.3:700".parse().unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle),
+        )
+        .await
+        .unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+
+        // the same call from an admitted address is answered
+        let (mut client, stream) = tokio::io::duplex(1024);
+        client.write_all(&call).await.unwrap();
+        let addr = "192.168.1.2:700".parse().unwrap();
+        let serve =
+            server.serve_connection(stream, addr, &client_manager_handle, &file_manager_handle);
+        let read = async {
+            let mut header = [0u8; 8];
+            client.read_exact(&mut header).await.unwrap();
+            drop(client);
+            header
+        };
+        let (_, header) = tokio::join!(serve, read);
+        // the reply carries the xid of the call
+        assert_eq!(header[4..8], [0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_concurrent_connections() {
+        let fs = FaultFS::default();
+        let lookups = fs.lookups.clone();
+        let root: VfsPath = fs.into();
+        root.join("file1.txt").unwrap().create_file().unwrap();
+        let server = NFSServer::builder(root).build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serve = server.serve(listener, async {
+            let _ = shutdown_rx.await;
+        });
+
+        let clients = async {
+            // the first client resolves file1.txt: PUTROOTFH, LOOKUP, GETFH,
+            // then PUTFH
+            let mut first = TcpStream::connect(addr).await.unwrap();
+            let mut ops = 24u32.to_be_bytes().to_vec();
+            ops.extend(15u32.to_be_bytes());
+            xdr_opaque(&mut ops, b"file1.txt");
+            ops.extend(10u32.to_be_bytes());
+            let (status, mut reader) = compound(&mut first, 1, &ops, 3).await;
+            assert_eq!(status, 0);
+            assert_eq!(op_status(&mut reader, 24), 0);
+            assert_eq!(op_status(&mut reader, 15), 0);
+            assert_eq!(op_status(&mut reader, 10), 0);
+            let mut putfh = 22u32.to_be_bytes().to_vec();
+            xdr_opaque(&mut putfh, &reader.opaque());
+            let (status, _) = compound(&mut first, 2, &putfh, 1).await;
+            assert_eq!(status, 0);
+            assert!(lookups.lock().unwrap().contains("/file1.txt"));
+            lookups.lock().unwrap().clear();
+
+            // a second client is served while the first one is still
+            // connected, and its PUTFH is answered from the cache the first
+            // one filled, without asking the backend
+            let mut second = TcpStream::connect(addr).await.unwrap();
+            let (status, _) = compound(&mut second, 1, &putfh, 1).await;
+            assert_eq!(status, 0);
+            assert!(!lookups.lock().unwrap().contains("/file1.txt"));
+
+            drop(first);
+            drop(second);
+            shutdown_tx.send(()).unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(serve, clients)
+        })
+        .await
+        .unwrap();
+        assert!(!logs_contain("Connection force-closed"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_shutdown_drain_timeout() {
+        let server = NFSServer::builder(create_dummyfs())
+            .drain_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serve = server.serve(listener, async {
+            let _ = shutdown_rx.await;
+        });
+
+        let client = async {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            // a NULL call, then the client stalls without hanging up
+            client.write_all(&rpc_call(1, 0, None, &[])).await.unwrap();
+            let mut mark = [0u8; 4];
+            client.read_exact(&mut mark).await.unwrap();
+            let mut reply = vec![0u8; (u32::from_be_bytes(mark) & !(1 << 31)) as usize];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[0..4], [0, 0, 0, 1]);
+
+            let requested = Instant::now();
+            shutdown_tx.send(()).unwrap();
+            // the server closes the connection once the drain timeout is up
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            requested.elapsed()
+        };
+        let (_, elapsed) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(serve, client)
+        })
+        .await
+        .unwrap();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(logs_contain("Connection force-closed"));
     }
 }
 
 #[cfg(test)]
 mod test_utils {
     use crate::server::{
-        clientmanager::ClientManagerHandle, filemanager::FileManagerHandle, request::NfsRequest,
+        clientmanager::ClientManagerHandle,
+        filemanager::{FileManagerConfig, FileManagerHandle},
+        request::NfsRequest,
     };
     use bold_proto::nfs4_proto::{CbClient4, ClientAddr4, NfsClientId4, SetClientId4args};
-    use vfs::{MemoryFS, VfsPath};
+    use std::{
+        collections::HashSet,
+        io,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use vfs::{
+        FileSystem, MemoryFS, PhysicalFS, SeekAndRead, SeekAndWrite, VfsMetadata, VfsPath,
+        VfsResult,
+    };
 
     pub fn create_dummyfs() -> VfsPath {
         let root: VfsPath = MemoryFS::new().into();
@@ -177,6 +1321,11 @@ mod test_utils {
 
     pub fn create_fake_fs() -> VfsPath {
         let root: VfsPath = MemoryFS::new().into();
+        fill_fake_fs(&root);
+        root
+    }
+
+    fn fill_fake_fs(root: &VfsPath) {
         let file1 = root.join("file1.txt").unwrap();
         file1
             .create_file()
@@ -200,7 +1349,194 @@ mod test_utils {
             .unwrap()
             .write_all(b"Hello, file2!")
             .unwrap();
+    }
+
+    /// The backends the operation tests run against
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TestFs {
+        Memory,
+        // a temporary directory on the local disk
+        Physical,
+    }
+
+    /// A temporary directory, removed with everything in it when dropped
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl TestFs {
+        pub const ALL: [TestFs; 2] = [TestFs::Memory, TestFs::Physical];
+
+        /// An empty root on this backend, a physical one lives as long as
+        /// the returned directory
+        pub fn root(self) -> (VfsPath, Option<TempDir>) {
+            match self {
+                TestFs::Memory => (MemoryFS::new().into(), None),
+                TestFs::Physical => {
+                    static NEXT: AtomicUsize = AtomicUsize::new(0);
+                    let path = std::env::temp_dir().join(format!(
+                        "bold-test-{}-{}",
+                        std::process::id(),
+                        NEXT.fetch_add(1, Ordering::SeqCst)
+                    ));
+                    std::fs::create_dir_all(&path).unwrap();
+                    let root = PhysicalFS::new(&path).into();
+                    (root, Some(TempDir(path)))
+                }
+            }
+        }
+
+        /// The files and directories of [`create_fake_fs`] on this backend
+        pub fn create_fake_fs(self) -> (VfsPath, Option<TempDir>) {
+            let (root, dir) = self.root();
+            fill_fake_fs(&root);
+            (root, dir)
+        }
+    }
+
+    /// Backend calls a [`FaultFS`] can be told to misbehave on
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FsCall {
+        // metadata and exists, resolving a filehandle looks its path up
+        Lookup,
+        Open,
+        Append,
+    }
+
+    #[derive(Debug, Clone)]
+    enum Fault {
+        Fail(io::ErrorKind),
+        Stall(Duration),
+    }
 
+    /// A MemoryFS that fails or stalls the calls a test configures it for,
+    /// on paths ending in a given suffix, and records the paths looked up
+    /// and the files opened
+    #[derive(Debug, Default)]
+    pub struct FaultFS {
+        inner: MemoryFS,
+        faults: Vec<(FsCall, &'static str, Fault)>,
+        pub lookups: Arc<Mutex<HashSet<String>>>,
+        pub opens: Arc<AtomicUsize>,
+    }
+
+    impl FaultFS {
+        /// `call` fails with `kind` on paths ending in `suffix`, an empty
+        /// suffix matches every path
+        pub fn fail(mut self, call: FsCall, suffix: &'static str, kind: io::ErrorKind) -> Self {
+            self.faults.push((call, suffix, Fault::Fail(kind)));
+            self
+        }
+
+        /// `call` blocks for `delay` on paths ending in `suffix`
+        pub fn stall(mut self, call: FsCall, suffix: &'static str, delay: Duration) -> Self {
+            self.faults.push((call, suffix, Fault::Stall(delay)));
+            self
+        }
+
+        fn inject(&self, call: FsCall, path: &str) -> VfsResult<()> {
+            let faults = self
+                .faults
+                .iter()
+                .filter(|(faulty_call, suffix, _)| *faulty_call == call && path.ends_with(suffix));
+            for (_, _, fault) in faults {
+                match fault {
+                    Fault::Fail(kind) => return Err(io::Error::from(*kind).into()),
+                    Fault::Stall(delay) => std::thread::sleep(*delay),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl FileSystem for FaultFS {
+        fn read_dir(&self, path: &str) -> VfsResult<Box<dyn Iterator<Item = String> + Send>> {
+            self.inner.read_dir(path)
+        }
+        fn create_dir(&self, path: &str) -> VfsResult<()> {
+            self.inner.create_dir(path)
+        }
+        fn open_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndRead + Send>> {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+            self.inject(FsCall::Open, path)?;
+            self.inner.open_file(path)
+        }
+        fn create_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+            self.inner.create_file(path)
+        }
+        fn append_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+            self.inject(FsCall::Append, path)?;
+            self.inner.append_file(path)
+        }
+        fn metadata(&self, path: &str) -> VfsResult<VfsMetadata> {
+            self.inject(FsCall::Lookup, path)?;
+            self.inner.metadata(path)
+        }
+        fn exists(&self, path: &str) -> VfsResult<bool> {
+            self.lookups.lock().unwrap().insert(path.to_string());
+            self.inject(FsCall::Lookup, path)?;
+            self.inner.exists(path)
+        }
+        fn remove_file(&self, path: &str) -> VfsResult<()> {
+            self.inner.remove_file(path)
+        }
+        fn remove_dir(&self, path: &str) -> VfsResult<()> {
+            self.inner.remove_dir(path)
+        }
+    }
+
+    /// Shape of a tree built by [`create_tree_fs`]
+    #[derive(Debug, Clone, Default)]
+    pub struct TreeSpec {
+        // levels of directories, the root included
+        pub depth: usize,
+        // subdirectories of every directory above the last level
+        pub breadth: usize,
+        // files in every directory
+        pub files: usize,
+        // bytes of every file, see tree_file_content
+        pub file_size: usize,
+    }
+
+    /// Content of the files of a generated tree, a repeating byte pattern
+    /// so reads at any offset can be checked
+    pub fn tree_file_content(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A tree of `dir<n>` directories holding `file<n>.bin` files, for
+    /// tests that need more than create_fake_fs
+    pub fn create_tree_fs(spec: &TreeSpec) -> VfsPath {
+        fn fill(dir: &VfsPath, spec: &TreeSpec, level: usize, content: &[u8]) {
+            dir.create_dir_all().unwrap();
+            for i in 0..spec.files {
+                dir.join(format!("file{}.bin", i))
+                    .unwrap()
+                    .create_file()
+                    .unwrap()
+                    .write_all(content)
+                    .unwrap();
+            }
+            if level + 1 < spec.depth {
+                for i in 0..spec.breadth {
+                    let sub = dir.join(format!("dir{}", i)).unwrap();
+                    fill(&sub, spec, level + 1, content);
+                }
+            }
+        }
+
+        let root: VfsPath = MemoryFS::new().into();
+        fill(&root, spec, 0, &tree_file_content(spec.file_size));
         root
     }
 
@@ -218,22 +1554,21 @@ mod test_utils {
         }
     }
 
-    pub async fn create_nfs40_server(root: Option<VfsPath>) -> NfsRequest<'static> {
-        let root = if root.is_none() {
-            create_dummyfs()
-        } else {
-            root.unwrap()
-        };
+    pub async fn create_nfs40_server(root: Option<VfsPath>) -> NfsRequest {
+        create_nfs40_server_with_config(root, FileManagerConfig::default()).await
+    }
+
+    pub async fn create_nfs40_server_with_config(
+        root: Option<VfsPath>,
+        config: FileManagerConfig,
+    ) -> NfsRequest {
+        let root = root.unwrap_or_else(create_dummyfs);
 
         let client_mananger_handle = ClientManagerHandle::new();
-        let file_mananger_handle = FileManagerHandle::new(root, None);
+        let file_mananger_handle = FileManagerHandle::with_config(root, None, config);
 
-        NfsRequest::new(
-            "127.0.0.1:12345".to_owned(),
-            client_mananger_handle,
-            file_mananger_handle,
-            0_u64,
-            None,
-        )
+        NfsRequest::builder(client_mananger_handle, file_mananger_handle)
+            .client_addr("127.0.0.1:12345".parse().unwrap())
+            .build()
     }
 }
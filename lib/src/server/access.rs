@@ -0,0 +1,152 @@
+use std::{
+    fmt,
+    net::{AddrParseError, IpAddr},
+    str::FromStr,
+};
+
+/// A network in CIDR notation, e.g. "10.0.0.0/8" or "fd00::/8". A bare
+/// address is a network with a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Errors returned when parsing a [`Cidr`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CidrParseError {
+    /// The part before the slash is not an IP address
+    InvalidAddress(AddrParseError),
+    /// The prefix length is not a number or too long for the address
+    InvalidPrefix(String),
+}
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CidrParseError::InvalidAddress(e) => write!(f, "invalid address: {}", e),
+            CidrParseError::InvalidPrefix(prefix) => {
+                write!(f, "invalid prefix length {:?}", prefix)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CidrParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CidrParseError::InvalidAddress(e) => Some(e),
+            CidrParseError::InvalidPrefix(_) => None,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(CidrParseError::InvalidAddress)?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| CidrParseError::InvalidPrefix(prefix.to_string()))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    /// Whether `addr` is part of this network. IPv4 clients connecting to
+    /// a dual-stack listener show up as IPv4-mapped IPv6 addresses, they
+    /// match the IPv4 networks.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Decides which client addresses may connect at all. A denied network
+/// always wins, and once a network is allowed explicitly, all others are
+/// denied.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        AccessList { allow, deny }
+    }
+
+    pub fn admits(&self, addr: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessList, Cidr, CidrParseError};
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+
+        let host: Cidr = "fd00::1".parse().unwrap();
+        assert!(host.contains(&"fd00::1".parse().unwrap()));
+        assert!(!host.contains(&"fd00::2".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"192.168.1.1".parse().unwrap()));
+
+        assert!(matches!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(CidrParseError::InvalidPrefix(_))
+        ));
+        assert!(matches!(
+            "10.0.0/8".parse::<Cidr>(),
+            Err(CidrParseError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn test_access_list() {
+        let everyone = AccessList::default();
+        assert!(everyone.admits(&"192.168.1.1".parse().unwrap()));
+
+        let access = AccessList::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.0.1.0/24".parse().unwrap()],
+        );
+        assert!(access.admits(&"10.0.0.1".parse().unwrap()));
+        assert!(!access.admits(&"10.0.1.1".parse().unwrap()));
+        assert!(!access.admits(&"192.168.1.1".parse().unwrap()));
+    }
+}
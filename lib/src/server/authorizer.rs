@@ -0,0 +1,107 @@
+use std::fmt::Debug;
+
+use bold_proto::nfs4_proto::{NfsArgOp, NfsOpNum4, NfsStat4, OpenClaim4};
+
+/// Custom access policy, consulted before every operation of a compound.
+///
+/// This comes on top of the checks the protocol does itself, e.g. an
+/// embedder can deny writes to certain paths or enforce per principal
+/// limits.
+pub trait Authorizer: Debug + Send + Sync {
+    /// Decide whether `principal` may run `op` on `path`.
+    ///
+    /// `principal` is derived from the RPC credential, see
+    /// [`principal`](super::request::principal). `path` is the object the
+    /// operation targets: the entry for operations that take a name, like
+    /// LOOKUP or REMOVE, otherwise the current filehandle. It's empty if
+    /// there is no such filehandle. RENAME and LINK are asked about twice,
+    /// for the source and for the new entry, see [`target_paths`]. An error
+    /// fails the operation with that status, typically NFS4ERR_ACCESS or
+    /// NFS4ERR_PERM.
+    fn authorize(&self, principal: Option<&str>, op: NfsOpNum4, path: &str)
+        -> Result<(), NfsStat4>;
+}
+
+/// The operation number of an operation's arguments
+pub fn opnum(arg: &NfsArgOp) -> NfsOpNum4 {
+    match arg {
+        NfsArgOp::OpUndef0 | NfsArgOp::OpUndef1 | NfsArgOp::OpUndef2 => NfsOpNum4::OpIllegal,
+        NfsArgOp::OpAccess(_) => NfsOpNum4::OpAccess,
+        NfsArgOp::Opclose(_) => NfsOpNum4::OpClose,
+        NfsArgOp::Opcommit(_) => NfsOpNum4::OpCommit,
+        NfsArgOp::Opcreate(_) => NfsOpNum4::OpCreate,
+        NfsArgOp::Opdelegpurge(_) => NfsOpNum4::OpDelegPurge,
+        NfsArgOp::Opdelegreturn(_) => NfsOpNum4::OpDelegReturn,
+        NfsArgOp::Opgetattr(_) => NfsOpNum4::OpGetattr,
+        NfsArgOp::Opgetfh(_) => NfsOpNum4::OpGetfh,
+        NfsArgOp::Oplink(_) => NfsOpNum4::OpLink,
+        NfsArgOp::Oplock(_) => NfsOpNum4::OpLock,
+        NfsArgOp::Oplockt(_) => NfsOpNum4::OpLockt,
+        NfsArgOp::Oplocku(_) => NfsOpNum4::OpLocku,
+        NfsArgOp::Oplookup(_) => NfsOpNum4::OpLookup,
+        NfsArgOp::Oplookupp(_) => NfsOpNum4::OpLookupP,
+        NfsArgOp::Opnverify(_) => NfsOpNum4::OpNverify,
+        NfsArgOp::Opopen(_) => NfsOpNum4::OpOpen,
+        NfsArgOp::Opopenattr(_) => NfsOpNum4::OpOpenattr,
+        NfsArgOp::OpopenConfirm(_) => NfsOpNum4::OpOpenConfirm,
+        NfsArgOp::OpopenDowngrade(_) => NfsOpNum4::OpOpenDowngrade,
+        NfsArgOp::Opputfh(_) => NfsOpNum4::OpPutfh,
+        NfsArgOp::Opputpubfh(_) => NfsOpNum4::OpPutpubfh,
+        NfsArgOp::Opputrootfh(_) => NfsOpNum4::OpPutrootfh,
+        NfsArgOp::Opread(_) => NfsOpNum4::OpRead,
+        NfsArgOp::Opreaddir(_) => NfsOpNum4::OpReaddir,
+        NfsArgOp::Opreadlink(_) => NfsOpNum4::OpReadlink,
+        NfsArgOp::Opremove(_) => NfsOpNum4::OpRemove,
+        NfsArgOp::Oprename(_) => NfsOpNum4::OpRename,
+        NfsArgOp::Oprenew(_) => NfsOpNum4::OpRenew,
+        NfsArgOp::Oprestorefh(_) => NfsOpNum4::OpRestorefh,
+        NfsArgOp::Opsavefh(_) => NfsOpNum4::OpSavefh,
+        NfsArgOp::OpSecinfo(_) => NfsOpNum4::OpSecinfo,
+        NfsArgOp::Opsetattr(_) => NfsOpNum4::OpSetattr,
+        NfsArgOp::Opsetclientid(_) => NfsOpNum4::OpSetclientid,
+        NfsArgOp::OpsetclientidConfirm(_) => NfsOpNum4::OpSetclientidConfirm,
+        NfsArgOp::Opverify(_) => NfsOpNum4::OpVerify,
+        NfsArgOp::Opwrite(_) => NfsOpNum4::OpWrite,
+        NfsArgOp::OpreleaseLockOwner(_) => NfsOpNum4::OpReleaseLockowner,
+    }
+}
+
+/// The paths an operation targets, given the paths of the current and the
+/// saved filehandle, see [`Authorizer::authorize`]. RENAME targets the old
+/// entry in the saved directory and the new one in the current directory,
+/// LINK the saved file and the new entry. Everything else targets a single
+/// path.
+pub fn target_paths(arg: &NfsArgOp, current: &str, saved: &str) -> Vec<String> {
+    let name = match arg {
+        NfsArgOp::Oplookup(args) => &args.objname,
+        NfsArgOp::Opremove(args) => &args.target,
+        NfsArgOp::Opcreate(args) => &args.objname,
+        NfsArgOp::OpSecinfo(args) => &args.name,
+        NfsArgOp::Opopen(args) => match &args.claim {
+            OpenClaim4::ClaimNull(name) => name,
+            _ => return vec![current.to_string()],
+        },
+        NfsArgOp::Oprename(args) => {
+            return vec![
+                entry_path(saved, &args.oldname),
+                entry_path(current, &args.newname),
+            ]
+        }
+        NfsArgOp::Oplink(args) => {
+            return vec![saved.to_string(), entry_path(current, &args.newname)]
+        }
+        _ => return vec![current.to_string()],
+    };
+    vec![entry_path(current, name)]
+}
+
+// the path of `name` in the directory at `dir`, empty without a directory
+fn entry_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        String::new()
+    } else if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
@@ -4,19 +4,31 @@ use rand::Rng;
 use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{mpsc, oneshot};
 use tracing::error;
 
 use bold_proto::nfs4_proto::NfsStat4;
 
+use super::{clock::SharedClock, request::boot_verifier};
+
 type ClientDb = MultiIndexClientEntryMap;
 
+/// Seconds a client's lease lasts without being renewed, reported as the
+/// lease_time attribute
+pub const LEASE_TIME: u32 = 60;
+
 #[derive(Debug)]
 pub struct ClientManager {
     receiver: mpsc::Receiver<ClientManagerMessage>,
     db: Arc<ClientDb>,
-    client_id_seq: u64,
+    // the upper 32 bits of every clientid handed out by this server instance
+    boot_id: u32,
+    client_id_seq: u32,
     filehandles: HashMap<String, Vec<u8>>,
+    // when the lease of each confirmed client was last renewed
+    leases: HashMap<u64, SystemTime>,
+    clock: SharedClock,
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -58,7 +70,16 @@ struct ConfirmClientRequest {
     pub client_id: u64,
     pub setclientid_confirm: [u8; 8],
     pub principal: Option<String>,
-    pub respond_to: oneshot::Sender<Result<ClientEntry, ClientManagerError>>,
+    pub respond_to: oneshot::Sender<Result<ConfirmedClient, ClientManagerError>>,
+}
+
+/// A confirmed client record, along with the clientid of the earlier
+/// instance of the client it replaces, if any. The state of that instance
+/// has to be released.
+#[derive(Debug, Clone)]
+pub struct ConfirmedClient {
+    pub client: ClientEntry,
+    pub replaced: Option<u64>,
 }
 
 struct RenewLeasesRequest {
@@ -79,12 +100,21 @@ pub struct SetCurrentFilehandleRequest {
 }
 
 impl ClientManager {
-    fn new(receiver: mpsc::Receiver<ClientManagerMessage>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<ClientManagerMessage>,
+        boot_verifier: [u8; 8],
+        clock: SharedClock,
+    ) -> Self {
+        let boot_id = u32::from_be_bytes(boot_verifier[..4].try_into().unwrap())
+            ^ u32::from_be_bytes(boot_verifier[4..].try_into().unwrap());
         ClientManager {
             receiver,
             db: ClientDb::default().into(),
+            boot_id,
             client_id_seq: 0,
             filehandles: HashMap::new(),
+            leases: HashMap::new(),
+            clock,
         }
     }
 
@@ -118,9 +148,15 @@ impl ClientManager {
         }
     }
 
+    // clientids embed the boot verifier, so ids of an earlier server instance
+    // are never handed out again and can be told apart
     fn get_next_client_id(&mut self) -> u64 {
         self.client_id_seq += 1;
-        self.client_id_seq
+        ((self.boot_id as u64) << 32) | self.client_id_seq as u64
+    }
+
+    fn is_stale_client_id(&self, client_id: u64) -> bool {
+        (client_id >> 32) as u32 != self.boot_id
     }
 
     fn set_current_fh(&mut self, client_addr: String, filehandle: Vec<u8>) {
@@ -152,7 +188,11 @@ impl ClientManager {
                 if !entry.confirmed {
                     entries_to_remove.push(entry.clone());
                 }
-                existing_clientid = Some(entry.clientid);
+                // a new verifier is a rebooted client, a new instance that
+                // gets a clientid of its own
+                if entry.verifier == verifier {
+                    existing_clientid = Some(entry.clientid);
+                }
             }
 
             entries_to_remove.iter().for_each(|entry| {
@@ -197,7 +237,12 @@ impl ClientManager {
         client_id: u64,
         setclientid_confirm: [u8; 8],
         principal: Option<String>,
-    ) -> Result<ClientEntry, ClientManagerError> {
+    ) -> Result<ConfirmedClient, ClientManagerError> {
+        if self.is_stale_client_id(client_id) {
+            return Err(ClientManagerError {
+                nfs_error: NfsStat4::Nfs4errStaleClientid,
+            });
+        }
         let db = Arc::get_mut(&mut self.db).unwrap();
 
         let entries = db.get_by_clientid(&client_id);
@@ -238,7 +283,24 @@ impl ClientManager {
                 db.modify_by_setclientid_confirm(&new_confirmed.setclientid_confirm, |c| {
                     c.confirmed = true;
                 });
-                Ok(new_confirmed)
+                // https://datatracker.ietf.org/doc/html/rfc7530#section-16.34.5
+                // confirming a rebooted client removes the record of its
+                // earlier instance
+                let replaced = db
+                    .get_by_id(&new_confirmed.id)
+                    .into_iter()
+                    .find(|entry| entry.confirmed && entry.clientid != client_id)
+                    .cloned();
+                let replaced = replaced.map(|entry| {
+                    db.remove_by_setclientid_confirm(&entry.setclientid_confirm);
+                    self.leases.remove(&entry.clientid);
+                    entry.clientid
+                });
+                self.leases.insert(client_id, self.clock.now());
+                Ok(ConfirmedClient {
+                    client: new_confirmed,
+                    replaced,
+                })
             }
             None => Err(ClientManagerError {
                 nfs_error: NfsStat4::Nfs4errStaleClientid,
@@ -247,6 +309,11 @@ impl ClientManager {
     }
 
     fn renew_leases(&mut self, client_id: u64) -> Result<(), ClientManagerError> {
+        if self.is_stale_client_id(client_id) {
+            return Err(ClientManagerError {
+                nfs_error: NfsStat4::Nfs4errStaleClientid,
+            });
+        }
         let db = Arc::get_mut(&mut self.db).unwrap();
         let entries = db.get_by_clientid(&client_id);
         if entries.is_empty() {
@@ -254,7 +321,17 @@ impl ClientManager {
                 nfs_error: NfsStat4::Nfs4errStaleClientid,
             });
         }
-        // todo: implement lease renewal
+        let now = self.clock.now();
+        if let Some(renewed) = self.leases.get(&client_id) {
+            let elapsed = now.duration_since(*renewed).unwrap_or_default();
+            if elapsed > Duration::from_secs(LEASE_TIME as u64) {
+                error!("Lease of client {} expired {:?} ago", client_id, elapsed);
+                return Err(ClientManagerError {
+                    nfs_error: NfsStat4::Nfs4errExpired,
+                });
+            }
+        }
+        self.leases.insert(client_id, now);
         Ok(())
     }
 
@@ -266,6 +343,7 @@ impl ClientManager {
     pub fn remove_client(&mut self, client_id: u64) {
         let db = Arc::get_mut(&mut self.db).unwrap();
         db.remove_by_clientid(&client_id);
+        self.leases.remove(&client_id);
     }
 
     pub fn get_client_confirmed(&mut self, clientid: u64) -> Option<&ClientEntry> {
@@ -293,6 +371,7 @@ impl fmt::Display for ClientManagerError {
 #[derive(Debug, Clone)]
 pub struct ClientManagerHandle {
     sender: mpsc::Sender<ClientManagerMessage>,
+    request_timeout: Option<Duration>,
 }
 
 impl Default for ClientManagerHandle {
@@ -303,12 +382,56 @@ impl Default for ClientManagerHandle {
 
 impl ClientManagerHandle {
     pub fn new() -> Self {
+        let clock = SharedClock::default();
+        Self::with_config(boot_verifier(clock.secs()), None, clock)
+    }
+
+    /// Clientids are derived from `boot_verifier`, requests the client manager
+    /// doesn't answer within `request_timeout` fail with NFS4ERR_DELAY. Leases
+    /// expire according to `clock`.
+    pub fn with_config(
+        boot_verifier: [u8; 8],
+        request_timeout: Option<Duration>,
+        clock: SharedClock,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(16);
-        let cmanager = ClientManager::new(receiver);
+        let cmanager = ClientManager::new(receiver, boot_verifier, clock);
         // start the client manager actor
         tokio::spawn(run_client_manager(cmanager));
 
-        Self { sender }
+        Self {
+            sender,
+            request_timeout,
+        }
+    }
+
+    async fn request<T>(
+        &self,
+        msg: ClientManagerMessage,
+        rx: oneshot::Receiver<Result<T, ClientManagerError>>,
+    ) -> Result<T, ClientManagerError> {
+        let request = async {
+            if let Err(e) = self.sender.send(msg).await {
+                error!("Couldn't send request: {:?}", e);
+                return Err(ClientManagerError {
+                    nfs_error: NfsStat4::Nfs4errServerfault,
+                });
+            }
+            rx.await.unwrap_or(Err(ClientManagerError {
+                nfs_error: NfsStat4::Nfs4errServerfault,
+            }))
+        };
+        match self.request_timeout {
+            Some(duration) => tokio::time::timeout(duration, request)
+                .await
+                .unwrap_or_else(|_| {
+                    error!("ClientManager didn't respond within {:?}", duration);
+                    Err(ClientManagerError {
+                        nfs_error: NfsStat4::Nfs4errDelay,
+                    })
+                }),
+            None => request.await,
+        }
     }
 
     pub async fn set_current_filehandle(&self, client_addr: String, filehandle_id: Vec<u8>) {
@@ -337,25 +460,15 @@ impl ClientManagerHandle {
         principal: Option<String>,
     ) -> Result<ClientEntry, ClientManagerError> {
         let (tx, rx) = oneshot::channel();
-        let resp = self
-            .sender
-            .send(ClientManagerMessage::UpsertClient(UpsertClientRequest {
-                verifier,
-                id,
-                callback,
-                principal,
-                respond_to: tx,
-            }))
-            .await;
-        match resp {
-            Ok(_) => rx.await.unwrap(),
-            Err(e) => {
-                error!("Couldn't upsert client: {:?}", e);
-                Err(ClientManagerError {
-                    nfs_error: NfsStat4::Nfs4errServerfault,
-                })
-            }
-        }
+        let req = UpsertClientRequest {
+            verifier,
+            id,
+            callback,
+            principal,
+            respond_to: tx,
+        };
+        self.request(ClientManagerMessage::UpsertClient(req), rx)
+            .await
     }
 
     pub async fn confirm_client(
@@ -363,46 +476,26 @@ impl ClientManagerHandle {
         client_id: u64,
         setclientid_confirm: [u8; 8],
         principal: Option<String>,
-    ) -> Result<ClientEntry, ClientManagerError> {
+    ) -> Result<ConfirmedClient, ClientManagerError> {
         let (tx, rx) = oneshot::channel();
-        let resp = self
-            .sender
-            .send(ClientManagerMessage::ConfirmClient(ConfirmClientRequest {
-                client_id,
-                setclientid_confirm,
-                principal,
-                respond_to: tx,
-            }))
-            .await;
-        match resp {
-            Ok(_) => rx.await.unwrap(),
-            Err(e) => {
-                error!("Couldn't confirm client: {:?}", e);
-                Err(ClientManagerError {
-                    nfs_error: NfsStat4::Nfs4errServerfault,
-                })
-            }
-        }
+        let req = ConfirmClientRequest {
+            client_id,
+            setclientid_confirm,
+            principal,
+            respond_to: tx,
+        };
+        self.request(ClientManagerMessage::ConfirmClient(req), rx)
+            .await
     }
 
     pub async fn renew_leases(&self, client_id: u64) -> Result<(), ClientManagerError> {
         let (tx, rx) = oneshot::channel();
-        let resp = self
-            .sender
-            .send(ClientManagerMessage::RenewLeases(RenewLeasesRequest {
-                client_id,
-                respond_to: tx,
-            }))
-            .await;
-        match resp {
-            Ok(_) => rx.await.unwrap(),
-            Err(e) => {
-                error!("Couldn't renew leases: {:?}", e);
-                Err(ClientManagerError {
-                    nfs_error: NfsStat4::Nfs4errServerfault,
-                })
-            }
-        }
+        let req = RenewLeasesRequest {
+            client_id,
+            respond_to: tx,
+        };
+        self.request(ClientManagerMessage::RenewLeases(req), rx)
+            .await
     }
 }
 
@@ -417,15 +510,21 @@ async fn run_client_manager(mut actor: ClientManager) {
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, SystemTime};
 
     use tokio::sync::mpsc;
 
     use bold_proto::nfs4_proto::NfsStat4;
 
+    use crate::server::{
+        clock::{MockClock, SharedClock},
+        request::boot_verifier,
+    };
+
     #[test]
     fn test_upsert_clients_no_principals() {
         let (_, receiver) = mpsc::channel(16);
-        let mut manager = super::ClientManager::new(receiver);
+        let mut manager = super::ClientManager::new(receiver, [0; 8], SharedClock::default());
 
         let verifier = [0; 8];
         let id = "test".to_string();
@@ -467,7 +566,8 @@ mod tests {
 
         let confirmed_client = manager
             .confirm_client(client.clientid, same_client.setclientid_confirm, None)
-            .unwrap();
+            .unwrap()
+            .client;
         assert!(confirmed_client.confirmed);
         assert_eq!(confirmed_client.clientid, client.clientid);
 
@@ -508,7 +608,7 @@ mod tests {
     #[test]
     fn test_upsert_clients_double_confirm() {
         let (_, receiver) = mpsc::channel(16);
-        let mut manager = super::ClientManager::new(receiver);
+        let mut manager = super::ClientManager::new(receiver, [0; 8], SharedClock::default());
 
         let verifier = [0; 8];
         let id = "test".to_string();
@@ -525,12 +625,14 @@ mod tests {
 
         let confirmed_client = manager
             .confirm_client(client.clientid, client.setclientid_confirm, None)
-            .unwrap();
+            .unwrap()
+            .client;
         assert!(confirmed_client.confirmed);
         assert_eq!(confirmed_client.clientid, client.clientid);
         let confirmed_client = manager
             .confirm_client(client.clientid, client.setclientid_confirm, None)
-            .unwrap();
+            .unwrap()
+            .client;
         assert!(confirmed_client.confirmed);
         assert_eq!(confirmed_client.clientid, client.clientid);
     }
@@ -538,7 +640,7 @@ mod tests {
     #[test]
     fn test_upsert_clients_principals() {
         let (_, receiver) = mpsc::channel(16);
-        let mut manager = super::ClientManager::new(receiver);
+        let mut manager = super::ClientManager::new(receiver, [0; 8], SharedClock::default());
 
         let verifier = [0; 8];
         let id = "test".to_string();
@@ -564,7 +666,8 @@ mod tests {
                 client.setclientid_confirm,
                 Some("Linux".to_string()),
             )
-            .unwrap();
+            .unwrap()
+            .client;
 
         assert_eq!(same_client.id, id);
         assert_eq!(same_client.verifier, verifier);
@@ -573,4 +676,73 @@ mod tests {
         assert_eq!(same_client.principal, Some("Linux".to_string()));
         assert!(same_client.confirmed);
     }
+
+    #[test]
+    fn test_clientids_across_restarts() {
+        let callback = super::ClientCallback {
+            program: 0,
+            rnetid: "tcp".to_string(),
+            raddr: "".to_string(),
+            callback_ident: 0,
+        };
+
+        let (_, receiver) = mpsc::channel(16);
+        let mut first_boot = super::ClientManager::new(
+            receiver,
+            boot_verifier(1_700_000_000),
+            SharedClock::default(),
+        );
+        let old_client = first_boot
+            .upsert_client([0; 8], "test".to_string(), callback.clone(), None)
+            .unwrap();
+
+        // the server restarts and forgets about all clients
+        let (_, receiver) = mpsc::channel(16);
+        let mut second_boot = super::ClientManager::new(
+            receiver,
+            boot_verifier(1_700_000_100),
+            SharedClock::default(),
+        );
+        let new_client = second_boot
+            .upsert_client([0; 8], "other".to_string(), callback, None)
+            .unwrap();
+        assert_ne!(old_client.clientid, new_client.clientid);
+
+        let stale = second_boot.renew_leases(old_client.clientid);
+        assert_eq!(stale.unwrap_err().nfs_error, NfsStat4::Nfs4errStaleClientid);
+        let stale =
+            second_boot.confirm_client(old_client.clientid, old_client.setclientid_confirm, None);
+        assert_eq!(stale.unwrap_err().nfs_error, NfsStat4::Nfs4errStaleClientid);
+        assert!(second_boot.renew_leases(new_client.clientid).is_ok());
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let (_, receiver) = mpsc::channel(16);
+        let mut manager =
+            super::ClientManager::new(receiver, [0; 8], SharedClock::new(clock.clone()));
+        let callback = super::ClientCallback {
+            program: 0,
+            rnetid: "tcp".to_string(),
+            raddr: "".to_string(),
+            callback_ident: 0,
+        };
+        let client = manager
+            .upsert_client([0; 8], "test".to_string(), callback, None)
+            .unwrap();
+        manager
+            .confirm_client(client.clientid, client.setclientid_confirm, None)
+            .unwrap();
+
+        // every renewal starts a new lease
+        clock.advance(Duration::from_secs(super::LEASE_TIME as u64 - 1));
+        assert!(manager.renew_leases(client.clientid).is_ok());
+        clock.advance(Duration::from_secs(super::LEASE_TIME as u64));
+        assert!(manager.renew_leases(client.clientid).is_ok());
+
+        clock.advance(Duration::from_secs(super::LEASE_TIME as u64 + 1));
+        let expired = manager.renew_leases(client.clientid);
+        assert_eq!(expired.unwrap_err().nfs_error, NfsStat4::Nfs4errExpired);
+    }
 }
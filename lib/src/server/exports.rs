@@ -0,0 +1,280 @@
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use vfs::{
+    error::VfsErrorKind, FileSystem, SeekAndRead, SeekAndWrite, VfsFileType, VfsMetadata, VfsPath,
+    VfsResult,
+};
+
+// told the path of every export a reload took away or replaced, returns
+// false once it's no longer interested
+type ReloadListener = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+struct ExportTable {
+    exports: BTreeMap<String, VfsPath>,
+    // when the table was last swapped, the modify time of the pseudo root
+    modified: SystemTime,
+}
+
+/// The exports of a server, each one a directory of the pseudo root.
+///
+/// The table is shared by all clones of the handle and can be swapped
+/// with [`Exports::reload`] while the server is running. Clients see a new
+/// export as soon as the reload returns, filehandles of a removed one are
+/// stale from then on.
+#[derive(Clone)]
+pub struct Exports {
+    table: Arc<RwLock<ExportTable>>,
+    listeners: Arc<Mutex<Vec<ReloadListener>>>,
+}
+
+impl fmt::Debug for Exports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = self.table.read().unwrap();
+        f.debug_struct("Exports")
+            .field("exports", &table.exports.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Exports {
+    /// Exports from names to the roots served below them, e.g.
+    /// `[("data", root)]` is served as "/data"
+    pub fn new<I, N>(exports: I) -> Self
+    where
+        I: IntoIterator<Item = (N, VfsPath)>,
+        N: Into<String>,
+    {
+        Exports {
+            table: Arc::new(RwLock::new(ExportTable {
+                exports: Self::export_table(exports),
+                modified: SystemTime::now(),
+            })),
+            listeners: Arc::default(),
+        }
+    }
+
+    /// The pseudo root to build the server with
+    pub fn root(&self) -> VfsPath {
+        VfsPath::new(self.clone())
+    }
+
+    /// Names of the current exports, in order
+    pub fn names(&self) -> Vec<String> {
+        self.table.read().unwrap().exports.keys().cloned().collect()
+    }
+
+    /// Replace all exports at once. Exports that are gone or now serve
+    /// another root are dropped from the filehandle cache, operations on
+    /// their filehandles fail with NFS4ERR_STALE.
+    pub fn reload<I, N>(&self, exports: I)
+    where
+        I: IntoIterator<Item = (N, VfsPath)>,
+        N: Into<String>,
+    {
+        let exports = Self::export_table(exports);
+        let affected: Vec<String> = {
+            let mut table = self.table.write().unwrap();
+            let affected = table
+                .exports
+                .iter()
+                .filter(|(name, root)| exports.get(*name) != Some(root))
+                .map(|(name, _)| format!("/{}", name))
+                .collect();
+            table.exports = exports;
+            table.modified = SystemTime::now();
+            affected
+        };
+        let mut listeners = self.listeners.lock().unwrap();
+        for path in affected {
+            listeners.retain(|listener| listener(&path));
+        }
+    }
+
+    /// Call `listener` with the path of every export a reload takes away
+    /// or replaces
+    pub(crate) fn on_reload(&self, listener: impl Fn(&str) -> bool + Send + Sync + 'static) {
+        self.listeners.lock().unwrap().push(Box::new(listener));
+    }
+
+    fn export_table<I, N>(exports: I) -> BTreeMap<String, VfsPath>
+    where
+        I: IntoIterator<Item = (N, VfsPath)>,
+        N: Into<String>,
+    {
+        exports
+            .into_iter()
+            .map(|(name, root)| (name.into(), root))
+            .collect()
+    }
+
+    // the export `path` is in and the path below its root, None for the
+    // pseudo root itself
+    fn resolve(&self, path: &str) -> VfsResult<Option<VfsPath>> {
+        let path = path.trim_start_matches('/');
+        if path.is_empty() {
+            return Ok(None);
+        }
+        let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+        let table = self.table.read().unwrap();
+        match table.exports.get(name) {
+            Some(root) if rest.is_empty() => Ok(Some(root.clone())),
+            Some(root) => Ok(Some(root.join(rest)?)),
+            None => Err(VfsErrorKind::FileNotFound.into()),
+        }
+    }
+
+    // like resolve, for changes the pseudo root doesn't take
+    fn resolve_export(&self, path: &str) -> VfsResult<VfsPath> {
+        self.resolve(path)?
+            .ok_or_else(|| VfsErrorKind::NotSupported.into())
+    }
+
+    // both paths, if they are below the same export
+    fn resolve_pair(&self, src: &str, dest: &str) -> VfsResult<(VfsPath, VfsPath)> {
+        fn export_name(path: &str) -> &str {
+            path.trim_start_matches('/').split('/').next().unwrap_or("")
+        }
+        if export_name(src) != export_name(dest) {
+            return Err(VfsErrorKind::NotSupported.into());
+        }
+        Ok((self.resolve_export(src)?, self.resolve_export(dest)?))
+    }
+
+    // the exports themselves only change with a reload
+    fn refuse_export_root(path: &str) -> VfsResult<()> {
+        if !path.trim_start_matches('/').contains('/') {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied).into());
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for Exports {
+    fn read_dir(&self, path: &str) -> VfsResult<Box<dyn Iterator<Item = String> + Send>> {
+        match self.resolve(path)? {
+            Some(dir) => Ok(Box::new(dir.read_dir()?.map(|entry| entry.filename()))),
+            None => Ok(Box::new(self.names().into_iter())),
+        }
+    }
+
+    fn create_dir(&self, path: &str) -> VfsResult<()> {
+        Self::refuse_export_root(path)?;
+        self.resolve_export(path)?.create_dir()
+    }
+
+    fn open_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndRead + Send>> {
+        self.resolve_export(path)?.open_file()
+    }
+
+    fn create_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+        Self::refuse_export_root(path)?;
+        self.resolve_export(path)?.create_file()
+    }
+
+    fn append_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+        self.resolve_export(path)?.append_file()
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<VfsMetadata> {
+        match self.resolve(path)? {
+            Some(path) => path.metadata(),
+            None => Ok(VfsMetadata {
+                file_type: VfsFileType::Directory,
+                len: 0,
+                created: None,
+                modified: Some(self.table.read().unwrap().modified),
+                accessed: None,
+            }),
+        }
+    }
+
+    fn set_creation_time(&self, path: &str, time: SystemTime) -> VfsResult<()> {
+        self.resolve_export(path)?.set_creation_time(time)
+    }
+
+    fn set_modification_time(&self, path: &str, time: SystemTime) -> VfsResult<()> {
+        self.resolve_export(path)?.set_modification_time(time)
+    }
+
+    fn set_access_time(&self, path: &str, time: SystemTime) -> VfsResult<()> {
+        self.resolve_export(path)?.set_access_time(time)
+    }
+
+    fn exists(&self, path: &str) -> VfsResult<bool> {
+        match self.resolve(path) {
+            Ok(Some(path)) => path.exists(),
+            Ok(None) => Ok(true),
+            Err(e) if matches!(e.kind(), VfsErrorKind::FileNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_file(&self, path: &str) -> VfsResult<()> {
+        Self::refuse_export_root(path)?;
+        self.resolve_export(path)?.remove_file()
+    }
+
+    fn remove_dir(&self, path: &str) -> VfsResult<()> {
+        Self::refuse_export_root(path)?;
+        self.resolve_export(path)?.remove_dir()
+    }
+
+    fn copy_file(&self, src: &str, dest: &str) -> VfsResult<()> {
+        Self::refuse_export_root(dest)?;
+        let (src, dest) = self.resolve_pair(src, dest)?;
+        src.copy_file(&dest)
+    }
+
+    fn move_file(&self, src: &str, dest: &str) -> VfsResult<()> {
+        Self::refuse_export_root(src)?;
+        Self::refuse_export_root(dest)?;
+        let (src, dest) = self.resolve_pair(src, dest)?;
+        src.move_file(&dest)
+    }
+
+    fn move_dir(&self, src: &str, dest: &str) -> VfsResult<()> {
+        Self::refuse_export_root(src)?;
+        Self::refuse_export_root(dest)?;
+        let (src, dest) = self.resolve_pair(src, dest)?;
+        src.move_dir(&dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vfs::VfsPath;
+
+    use super::Exports;
+    use crate::test_utils::{create_dummyfs, create_fake_fs};
+
+    #[test]
+    fn test_pseudo_root() {
+        let exports = Exports::new([("data", create_fake_fs())]);
+        let root = exports.root();
+        let names = |root: &VfsPath| {
+            let mut names: Vec<String> = root.read_dir().unwrap().map(|p| p.filename()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&root), vec!["data"]);
+        assert!(root.join("data/dir1/file2.txt").unwrap().exists().unwrap());
+        assert!(!root.join("more").unwrap().exists().unwrap());
+
+        // a reload is seen through paths resolved before it
+        let more = root.join("more").unwrap();
+        exports.reload([("more", create_dummyfs())]);
+        assert_eq!(names(&root), vec!["more"]);
+        assert!(more.is_dir().unwrap());
+        assert!(!root.join("data").unwrap().exists().unwrap());
+
+        // exports come and go with reloads only
+        assert!(more.remove_dir().is_err());
+        assert!(root.join("new").unwrap().create_dir().is_err());
+        assert!(more.exists().unwrap());
+    }
+}
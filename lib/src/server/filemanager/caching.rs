@@ -1,8 +1,16 @@
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::{
+    io::{Cursor, Seek, SeekFrom, Write},
+    time::Duration,
+};
 
-use tokio::sync::mpsc;
+use bold_proto::nfs4_proto::NfsStat4;
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{debug, error};
 
-use super::{handle::WriteCacheMessage, FileManagerHandle, Filehandle};
+use super::{
+    handle::WriteCacheMessage, io_error_status, respond, vfs_error_status, FileManagerHandle,
+    Filehandle,
+};
 
 #[derive(Debug)]
 pub struct WriteCache {
@@ -11,6 +19,14 @@ pub struct WriteCache {
     pub filehandle: Filehandle,
     pub receiver: mpsc::Receiver<WriteCacheMessage>,
     pub filemanager: FileManagerHandle,
+    // flush without a COMMIT this long after the first unflushed write, or
+    // once this many bytes are unflushed
+    pub flush_interval: Option<Duration>,
+    pub flush_size: Option<u64>,
+    // when the oldest unflushed write arrived and how many bytes were
+    // written since the last flush
+    pub dirty_since: Option<Instant>,
+    pub unflushed: u64,
 }
 
 impl WriteCache {
@@ -18,16 +34,22 @@ impl WriteCache {
         receiver: mpsc::Receiver<WriteCacheMessage>,
         filehandle: Filehandle,
         filemanager: FileManagerHandle,
+        flush_interval: Option<Duration>,
+        flush_size: Option<u64>,
     ) -> Self {
         let mut filelike = Cursor::new(Vec::new());
         let mut file = filehandle.file.open_file().unwrap();
-        file.read_to_end(&mut filelike.get_mut()).unwrap();
+        file.read_to_end(filelike.get_mut()).unwrap();
         WriteCache {
             filelike,
             changed: false,
             filehandle,
             receiver,
             filemanager,
+            flush_interval,
+            flush_size,
+            dirty_since: None,
+            unflushed: 0,
         }
     }
 
@@ -38,6 +60,13 @@ impl WriteCache {
                 self.filelike.seek(SeekFrom::Start(req.offset)).unwrap();
                 self.filelike.write_all(req.data.as_slice()).unwrap();
                 self.changed = true;
+                self.dirty_since.get_or_insert_with(Instant::now);
+                self.unflushed += req.data.len() as u64;
+                if self.flush_size.is_some_and(|size| self.unflushed >= size) {
+                    debug!("Flushing {} cached bytes", self.unflushed);
+                    // a failure is reported by the next COMMIT
+                    let _ = self.flush().await;
+                }
                 // update filehandle size (probably not needed here)
                 // let new_size = self.filelike.get_ref().len() as u64;
                 // self.filehandle.attr_size = new_size;
@@ -50,33 +79,79 @@ impl WriteCache {
                 //     .update_filehandle(self.filehandle.clone())
                 //     .await;
             }
-            WriteCacheMessage::Commit => {
-                // commit cache
-                if self.changed {
-                    let mut file = self.filehandle.file.append_file().unwrap();
-                    let _ = file.seek(SeekFrom::Start(0));
-                    let content = self.filelike.get_ref();
-                    let count = file.write(content.as_slice()).unwrap() as u32;
-
-                    if count > 0 {
-                        file.flush().unwrap();
-                        self.filemanager
-                            .touch_file(self.filehandle.id.clone())
-                            .await;
-                    }
+            WriteCacheMessage::Commit(req) => {
+                // commit cache, it's kept while it holds data that couldn't
+                // be written
+                let res = self.flush().await;
+                if res.is_ok() {
+                    self.filemanager
+                        .drop_write_cache_handle(self.filehandle.id)
+                        .await;
                 }
-                self.filemanager
-                    .drop_write_cache_handle(self.filehandle.id.clone())
-                    .await;
+                respond(req.respond_to, res);
+            }
+        }
+    }
+
+    // write the cache to the file, it stays in use for further writes. If
+    // the backend fails the data stays dirty and is written again by the
+    // next flush, a timed flush retries after another interval.
+    async fn flush(&mut self) -> Result<(), NfsStat4> {
+        if self.changed {
+            if let Err(status) = self.write_out() {
+                error!(
+                    "Couldn't flush cached writes of {:?}: {:?}",
+                    self.filehandle.path, status
+                );
+                self.dirty_since = Some(Instant::now());
+                return Err(status);
             }
+            self.filemanager.touch_file(self.filehandle.id).await;
+            self.changed = false;
         }
+        self.dirty_since = None;
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    fn write_out(&self) -> Result<(), NfsStat4> {
+        let mut file = self
+            .filehandle
+            .file
+            .append_file()
+            .map_err(|e| vfs_error_status(&e))?;
+        let _ = file.seek(SeekFrom::Start(0));
+        file.write_all(self.filelike.get_ref())
+            .and_then(|_| file.flush())
+            .map_err(|e| io_error_status(&e))
+    }
+
+    // when the unflushed writes are due, if they ever are without a COMMIT
+    fn flush_deadline(&self) -> Option<Instant> {
+        Some(self.dirty_since? + self.flush_interval?)
     }
 }
 
 // WriteCache is run as with the actor pattern
 // learn more: https://ryhl.io/blog/actors-with-tokio/
 pub async fn run_file_write_cache(mut actor: WriteCache) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
+    loop {
+        let msg = match actor.flush_deadline() {
+            Some(deadline) => {
+                tokio::select! {
+                    msg = actor.receiver.recv() => msg,
+                    _ = tokio::time::sleep_until(deadline) => {
+                        debug!("Flushing cached writes of {:?}", actor.filehandle.path);
+                        let _ = actor.flush().await;
+                        continue;
+                    }
+                }
+            }
+            None => actor.receiver.recv().await,
+        };
+        match msg {
+            Some(msg) => actor.handle_message(msg).await,
+            None => break,
+        }
     }
 }
@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use tracing::debug;
+
+use super::{Filehandle, NfsFh4};
+use crate::server::clock::SharedClock;
+
+/// Filehandles resolved recently, shared by all connections so a lookup
+/// done for one client spares the others the round trip to the actor
+#[derive(Debug)]
+pub struct FilehandleCache {
+    ttl: Duration,
+    clock: SharedClock,
+    entries: Mutex<HashMap<NfsFh4, (SystemTime, Filehandle)>>,
+}
+
+impl FilehandleCache {
+    pub fn new(ttl: Duration, clock: SharedClock) -> Self {
+        FilehandleCache {
+            ttl,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, filehandle: Filehandle) {
+        let now = self.clock.now();
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(filehandle.id, (now, filehandle));
+    }
+
+    /// The cached filehandle, unless it's older than the ttl
+    pub fn get(&self, id: &NfsFh4) -> Option<Filehandle> {
+        let mut entries = self.entries.lock().unwrap();
+        let (time, filehandle) = entries.get(id)?;
+        let age = self
+            .clock
+            .now()
+            .duration_since(*time)
+            .unwrap_or(Duration::ZERO);
+        if age > self.ttl {
+            debug!("Cached filehandle {:?} expired", id);
+            entries.remove(id);
+            return None;
+        }
+        Some(filehandle.clone())
+    }
+
+    pub fn remove(&self, id: &NfsFh4) {
+        self.entries.lock().unwrap().remove(id);
+    }
+
+    /// Forget `path` and everything below it
+    pub fn remove_path(&self, path: &str) {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, (_, fh)| fh.path != path && !fh.path.starts_with(&prefix));
+    }
+}
@@ -8,7 +8,8 @@ use tracing::debug;
 use vfs::VfsPath;
 
 use bold_proto::nfs4_proto::{
-    Fsid4, NfsFh4, NfsFtype4, Nfstime4, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
+    FsLocations4, Fsid4, NfsFh4, NfsFtype4, NfsStat4, Nfstime4, Specdata4, FH4_PERSISTENT,
+    FH4_VOLATILE_ANY, FH4_VOL_MIGRATION, FH4_VOL_RENAME, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
 };
 
 use super::{handle::WriteCacheHandle, locking::LockingState};
@@ -50,6 +51,9 @@ pub struct Filehandle {
     // mode:
     // The NFSv4.0 mode attribute is based on the UNIX mode bits.
     pub attr_mode: u32,
+    // numlinks:
+    // Number of hard links to this object.
+    pub attr_numlinks: u32,
     // owner:
     // The string name of the owner of this object.
     pub attr_owner: String,
@@ -59,6 +63,10 @@ pub struct Filehandle {
     // space_used:
     // Number of file system bytes allocated to this object.
     pub attr_space_used: u64,
+    // rawdev:
+    // Raw device number of file of type NF4BLK or NF4CHR.  The device
+    // number is split into major and minor numbers.
+    pub attr_rawdev: Specdata4,
     // time_access:
     // Represents the time of last access to the object by a READ operation
     // sent to the server.
@@ -69,6 +77,22 @@ pub struct Filehandle {
     // time_modified:
     // The time of last modification to the object.
     pub attr_time_modify: Nfstime4,
+    // mounted_on_fileid:
+    // Like fileid, but if the target filehandle is the root of a file
+    // system, this attribute represents the fileid of the underlying
+    // directory. There is a single file system without a pseudo-fs above
+    // it, so this is the fileid for every object.
+    pub attr_mounted_on_fileid: u64,
+    // fs_locations:
+    // Locations where this file system may be found. Only set for
+    // referrals, the object itself is absent on this server.
+    pub attr_fs_locations: Option<FsLocations4>,
+    // the archive bit Windows clients use for their backups
+    pub attr_archive: bool,
+    // time_backup:
+    // The time of the last backup of the object, as set by backup tools.
+    // Objects never backed up report the epoch.
+    pub attr_time_backup: Nfstime4,
     // this filehandle has exclusive open
     pub verifier: Option<[u8; 8]>,
     // attached locks, see LockingState
@@ -81,8 +105,17 @@ pub struct Filehandle {
 }
 
 impl Filehandle {
-    pub fn new(file: VfsPath, id: NfsFh4, major: u64, minor: u64, version: u64) -> Self {
-        let init_time = Self::attr_time_access();
+    pub fn new(
+        file: VfsPath,
+        id: NfsFh4,
+        major: u64,
+        minor: u64,
+        version: u64,
+        metadata: Option<&FileMetadata>,
+        now: SystemTime,
+    ) -> Self {
+        let init_time = Self::nfstime(now);
+        let metadata = metadata.cloned().unwrap_or_default();
         let mut path = file.as_str().to_string();
         if path.is_empty() {
             path = "/".to_string();
@@ -91,26 +124,65 @@ impl Filehandle {
         Filehandle {
             id,
             path,
-            attr_type: Self::attr_type(&file),
+            attr_type: metadata.ftype.unwrap_or_else(|| Self::attr_type(&file)),
             attr_change: Self::attr_change(&file, version),
             attr_size: Self::attr_size(&file),
             attr_fileid: Self::attr_fileid(&file),
+            attr_mounted_on_fileid: Self::attr_fileid(&file),
             attr_fsid: Self::attr_fsid(major, minor),
-            attr_mode: Self::attr_mode(&file),
-            attr_owner: Self::attr_owner(&file),
-            attr_owner_group: Self::attr_owner_group(&file),
+            attr_mode: metadata.mode.unwrap_or_else(|| Self::attr_mode(&file)),
+            attr_numlinks: 1,
+            attr_owner: metadata.owner.unwrap_or_else(|| Self::attr_owner(&file)),
+            attr_owner_group: metadata
+                .owner_group
+                .unwrap_or_else(|| Self::attr_owner_group(&file)),
             attr_space_used: Self::attr_space_used(&file),
+            attr_rawdev: metadata.rawdev.unwrap_or_default(),
+            attr_fs_locations: metadata.fs_locations,
+            attr_archive: metadata.archive.unwrap_or(false),
+            attr_time_backup: metadata.time_backup.unwrap_or(Nfstime4 {
+                seconds: 0,
+                nseconds: 0,
+            }),
             attr_time_access: init_time,
             attr_time_metadata: init_time,
-            attr_time_modify: init_time,
+            attr_time_modify: Self::attr_time_modify(&file).unwrap_or(init_time),
             file,
-            verifier: None,
+            verifier: metadata.verifier,
             locks: Vec::new(),
             write_cache: None,
             version,
         }
     }
 
+    /// Refresh the attributes that describe the file's data, the other
+    /// metadata keeps its value. `now` is the time of the change.
+    pub fn update_data_attrs(&mut self, now: SystemTime) {
+        self.attr_size = Self::attr_size(&self.file);
+        self.attr_space_used = Self::attr_space_used(&self.file);
+        self.version += 1;
+        self.attr_change = Self::attr_change(&self.file, self.version);
+        self.attr_time_modify = Self::nfstime(now);
+    }
+
+    /// Whether the ids this server hands out stay the same across restarts.
+    /// They don't, they're made up of the boot time and a counter.
+    pub const PERSISTENT_IDS: bool = false;
+
+    /// Whether `id` has the layout of the ids this server hands out: the
+    /// volatile marker 128, the boot time, a counter and a trailing 1.
+    pub fn is_well_formed(id: &NfsFh4) -> bool {
+        id[0] == 128 && id[id.len() - 1] == 1
+    }
+
+    /// The boot time of the server instance that issued the filehandle id.
+    pub fn boot_time(id: &NfsFh4) -> Option<u64> {
+        if !Self::is_well_formed(id) {
+            return None;
+        }
+        Some(u64::from_be_bytes(id[1..9].try_into().unwrap()))
+    }
+
     fn attr_type(file: &VfsPath) -> NfsFtype4 {
         if file.is_dir().unwrap() {
             return NfsFtype4::Nf4dir;
@@ -121,22 +193,24 @@ impl Filehandle {
         NfsFtype4::Nf4Undef
     }
 
-    pub fn attr_change(file: &VfsPath, default: u64) -> u64 {
+    // the modification time only has a resolution of seconds, the version
+    // in the low bits tells apart changes made within the same second
+    pub fn attr_change(file: &VfsPath, version: u64) -> u64 {
         let v = file.metadata();
         debug!("### attr_change ### {:?}", v);
-        if v.is_ok() {
-            if let Some(v) = v.unwrap().modified {
-                return v.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if let Ok(v) = v {
+            if let Some(v) = v.modified {
+                return (v.duration_since(UNIX_EPOCH).unwrap().as_secs() << 16) + version;
             }
         }
-        default
+        version
     }
 
     fn attr_fileid(file: &VfsPath) -> u64 {
         let mut hasher = DefaultHasher::new();
         file.as_str().hash(&mut hasher);
 
-        u64::try_from(hasher.finish()).unwrap()
+        hasher.finish()
     }
 
     fn attr_fsid(major: u64, minor: u64) -> Fsid4 {
@@ -155,21 +229,114 @@ impl Filehandle {
         "1000".to_string()
     }
 
+    // a dangling symlink has no metadata of its own in the backend
     pub fn attr_size(file: &VfsPath) -> u64 {
-        file.metadata().unwrap().len
+        file.metadata().map_or(0, |metadata| metadata.len)
     }
 
     fn attr_space_used(file: &VfsPath) -> u64 {
-        file.metadata().unwrap().len
+        file.metadata().map_or(0, |metadata| metadata.len)
     }
 
-    pub fn attr_time_access() -> Nfstime4 {
-        let since_epoch = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
+    fn attr_time_modify(file: &VfsPath) -> Option<Nfstime4> {
+        let modified = file.metadata().ok()?.modified?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+        Some(Nfstime4 {
+            seconds: since_epoch.as_secs() as i64,
+            nseconds: since_epoch.subsec_nanos(),
+        })
+    }
+
+    fn nfstime(time: SystemTime) -> Nfstime4 {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
         Nfstime4 {
             seconds: since_epoch.as_secs() as i64,
             nseconds: since_epoch.subsec_nanos(),
         }
     }
 }
+
+/// Attributes of a file the VFS backend can't express, they take precedence
+/// over the defaults when a filehandle is created
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    // e.g. for symlinks that are stored as regular files
+    pub ftype: Option<NfsFtype4>,
+    // device numbers of block and character devices
+    pub rawdev: Option<Specdata4>,
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub owner_group: Option<String>,
+    // makes the directory a referral to a file system on other servers,
+    // clients are sent there with NFS4ERR_MOVED
+    pub fs_locations: Option<FsLocations4>,
+    pub archive: Option<bool>,
+    pub time_backup: Option<Nfstime4>,
+    // the verifier of the EXCLUSIVE4 OPEN that created the file
+    pub verifier: Option<[u8; 8]>,
+}
+
+impl FileMetadata {
+    /// Take over the attributes set in `other`, keeping the rest
+    pub fn update(&mut self, other: FileMetadata) {
+        self.ftype = other.ftype.or(self.ftype.take());
+        self.rawdev = other.rawdev.or(self.rawdev.take());
+        self.mode = other.mode.or(self.mode);
+        self.owner = other.owner.or(self.owner.take());
+        self.owner_group = other.owner_group.or(self.owner_group.take());
+        self.fs_locations = other.fs_locations.or(self.fs_locations.take());
+        self.archive = other.archive.or(self.archive);
+        self.time_backup = other.time_backup.or(self.time_backup);
+        self.verifier = other.verifier.or(self.verifier);
+    }
+}
+
+/// How long filehandles handed out by this server stay valid, see
+/// <https://datatracker.ietf.org/doc/html/rfc7530#section-4.2.3>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilehandleStrategy {
+    /// Filehandles may expire at any time, e.g. after a server restart.
+    #[default]
+    Volatile,
+    /// Filehandles only expire when the object is renamed or the file system
+    /// is migrated. Suitable for backends where handles survive a restart.
+    VolatileOnMigrationRename,
+    /// Filehandles never expire, clients don't need to look objects up
+    /// again after a restart or migration. This needs filehandle ids that
+    /// stay the same across restarts.
+    Persistent,
+}
+
+impl FilehandleStrategy {
+    /// The value advertised in the fh_expire_type attribute.
+    pub fn expire_type(&self) -> u32 {
+        match self {
+            FilehandleStrategy::Volatile => FH4_VOLATILE_ANY,
+            FilehandleStrategy::VolatileOnMigrationRename => FH4_VOL_MIGRATION | FH4_VOL_RENAME,
+            FilehandleStrategy::Persistent => FH4_PERSISTENT,
+        }
+    }
+
+    /// Whether advertising this strategy is only honest if filehandle ids
+    /// survive a restart of the server
+    pub fn needs_persistent_ids(&self) -> bool {
+        matches!(self, FilehandleStrategy::Persistent)
+    }
+
+    /// The error returned for a filehandle the server no longer knows about.
+    ///
+    /// Ids this server can't have issued are bad handles. Volatile
+    /// filehandles that were issued by an earlier server instance have
+    /// expired, all others are stale.
+    pub fn unknown_filehandle_error(&self, id: &NfsFh4, boot_time: u64) -> NfsStat4 {
+        if !Filehandle::is_well_formed(id) {
+            return NfsStat4::Nfs4errBadhandle;
+        }
+        match self {
+            FilehandleStrategy::Volatile if Filehandle::boot_time(id) != Some(boot_time) => {
+                NfsStat4::Nfs4errFhexpired
+            }
+            _ => NfsStat4::Nfs4errStale,
+        }
+    }
+}
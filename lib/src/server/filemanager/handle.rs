@@ -1,29 +1,56 @@
-use tokio::sync::{mpsc, oneshot};
-use tracing::debug;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::{debug, error};
 use vfs::VfsPath;
 
 use bold_proto::nfs4_proto::{
-    Attrlist4, FileAttr, FileAttrValue, NfsLease4, NfsStat4, ACL4_SUPPORT_ALLOW_ACL,
-    FH4_VOLATILE_ANY, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
+    Attrlist4, FileAttr, FileAttrValue, NfsFtype4, NfsLease4, NfsStat4, Nfsace4, Nfstime4,
+    SecInfo4resok, Specdata4, Stateid4, ACL4_SUPPORT_ALLOW_ACL, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
 };
 
 use super::{
-    caching::run_file_write_cache, caching::WriteCache, filehandle::Filehandle, run_file_manager,
-    FileManager,
+    acl_from_mode,
+    caching::run_file_write_cache,
+    caching::WriteCache,
+    fhcache::FilehandleCache,
+    filehandle::{FileMetadata, Filehandle, FilehandleStrategy},
+    io_error_status, objects_below,
+    readahead::Readahead,
+    run_file_manager, space_used_below, vfs_error_status, FileManager, FileManagerConfig,
+    LockingState, SpecialFiles, MAX_FILE_SIZE, MAX_READ, MAX_WRITE,
 };
-use crate::server::filemanager::NfsFh4;
+use crate::server::{clientmanager::LEASE_TIME, clock::SharedClock, filemanager::NfsFh4, security};
 
 pub enum FileManagerMessage {
     GetRootFilehandle(GetRootFilehandleRequest),
     GetFilehandle(GetFilehandleRequest),
     GetFilehandleAttrs(GetFilehandleAttrsRequest),
+    GetFilehandleAttrsMany(GetFilehandleAttrsManyRequest),
     CreateFile(CreateFileRequest),
+    OpenFile(OpenFileRequest),
+    CreateSymlink(CreateSymlinkRequest),
+    CreateDevice(CreateDeviceRequest),
     RemoveFile(RemoveFileRequest),
+    RenameFile(RenameFileRequest),
+    LinkFile(LinkFileRequest),
     TouchFile(TouchFileRequest),
     UpdateFilehandle(Filehandle),
+    SetMetadata(SetMetadataRequest),
     LockFile(),
-    CloseFile(),
-    GetWriteCacheHandle(WriteCacheHandleRequest),
+    CloseFile(CloseFileRequest),
+    ReleaseLockowner(ReleaseLockownerRequest),
+    ReleaseClient(ReleaseClientRequest),
+    UpdateStateid(UpdateStateidRequest),
+    ConfirmOpen(ConfirmOpenRequest),
+    DowngradeOpen(DowngradeOpenRequest),
+    CheckStateidAccess(CheckStateidAccessRequest),
+    DumpLocks(DumpLocksRequest),
+    GetWriteCacheHandle(Box<WriteCacheHandleRequest>),
     DropWriteCacheHandle(DropCacheHandleRequest),
 }
 
@@ -31,10 +58,14 @@ pub struct GetRootFilehandleRequest {
     pub respond_to: oneshot::Sender<Filehandle>,
 }
 
+pub struct DumpLocksRequest {
+    pub respond_to: oneshot::Sender<Vec<LockingState>>,
+}
+
 pub struct GetFilehandleRequest {
     pub path: Option<String>,
     pub filehandle: Option<NfsFh4>,
-    pub respond_to: oneshot::Sender<Option<Filehandle>>,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
 }
 
 pub struct GetFilehandleAttrsRequest {
@@ -43,6 +74,14 @@ pub struct GetFilehandleAttrsRequest {
     pub respond_to: oneshot::Sender<Option<(Vec<FileAttr>, Vec<FileAttrValue>)>>,
 }
 
+pub type FilehandleAttrs = (Filehandle, Vec<FileAttr>, Vec<FileAttrValue>);
+
+pub struct GetFilehandleAttrsManyRequest {
+    pub paths: Vec<String>,
+    pub attrs_request: Vec<FileAttr>,
+    pub respond_to: oneshot::Sender<Vec<Result<FilehandleAttrs, NfsStat4>>>,
+}
+
 pub struct CreateFileRequest {
     pub path: VfsPath,
     pub client_id: u64,
@@ -50,7 +89,77 @@ pub struct CreateFileRequest {
     pub share_access: u32,
     pub share_deny: u32,
     pub verifier: Option<[u8; 8]>,
-    pub respond_to: oneshot::Sender<Option<Filehandle>>,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
+}
+
+pub struct OpenFileRequest {
+    pub path: VfsPath,
+    pub client_id: u64,
+    pub owner: Vec<u8>,
+    pub share_access: u32,
+    pub share_deny: u32,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
+}
+
+pub struct CreateSymlinkRequest {
+    pub path: VfsPath,
+    pub target: String,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
+}
+
+pub struct CreateDeviceRequest {
+    pub path: VfsPath,
+    pub ftype: NfsFtype4,
+    pub rawdev: Specdata4,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
+}
+
+pub struct ReleaseLockownerRequest {
+    pub client_id: u64,
+    pub owner: Vec<u8>,
+    pub respond_to: oneshot::Sender<Result<(), NfsStat4>>,
+}
+
+pub struct ReleaseClientRequest {
+    pub client_id: u64,
+    pub respond_to: oneshot::Sender<()>,
+}
+
+pub struct CloseFileRequest {
+    pub stateid: Stateid4,
+    pub respond_to: oneshot::Sender<Result<Stateid4, NfsStat4>>,
+}
+
+pub struct UpdateStateidRequest {
+    pub stateid: Stateid4,
+    pub respond_to: oneshot::Sender<Result<Stateid4, NfsStat4>>,
+}
+
+pub struct ConfirmOpenRequest {
+    pub stateid: Stateid4,
+    pub seqid: u32,
+    pub respond_to: oneshot::Sender<Result<Stateid4, NfsStat4>>,
+}
+
+pub struct DowngradeOpenRequest {
+    pub stateid: Stateid4,
+    pub seqid: u32,
+    pub share_access: u32,
+    pub share_deny: u32,
+    pub respond_to: oneshot::Sender<Result<Stateid4, NfsStat4>>,
+}
+
+pub struct CheckStateidAccessRequest {
+    pub stateid: Stateid4,
+    pub filehandle_id: NfsFh4,
+    pub access: u32,
+    pub respond_to: oneshot::Sender<Result<(), NfsStat4>>,
+}
+
+pub struct SetMetadataRequest {
+    pub filehandle_id: NfsFh4,
+    pub metadata: FileMetadata,
+    pub respond_to: oneshot::Sender<Result<Filehandle, NfsStat4>>,
 }
 
 pub struct RemoveFileRequest {
@@ -58,6 +167,18 @@ pub struct RemoveFileRequest {
     pub respond_to: oneshot::Sender<()>,
 }
 
+pub struct RenameFileRequest {
+    pub from: VfsPath,
+    pub to: VfsPath,
+    pub respond_to: oneshot::Sender<Result<(), NfsStat4>>,
+}
+
+pub struct LinkFileRequest {
+    pub existing: VfsPath,
+    pub path: VfsPath,
+    pub respond_to: oneshot::Sender<Result<(), NfsStat4>>,
+}
+
 pub struct TouchFileRequest {
     pub id: NfsFh4,
 }
@@ -77,28 +198,176 @@ pub struct FileManagerError {
     pub nfs_error: NfsStat4,
 }
 
+/// Held while a file's content changes, see
+/// [`FileManagerHandle::lock_file`]. Data prefetched for the file is
+/// dropped once the change is done.
+pub struct FileLock {
+    _guard: OwnedMutexGuard<()>,
+    id: NfsFh4,
+    readahead: Option<Arc<Readahead>>,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Some(readahead) = &self.readahead {
+            readahead.invalidate(&self.id);
+        }
+    }
+}
+
+// an async lock per key, created on demand
+type KeyedLocks<K> = Arc<Mutex<HashMap<K, Arc<AsyncMutex<()>>>>>;
+
 #[derive(Debug, Clone)]
 pub struct FileManagerHandle {
     sender: mpsc::Sender<FileManagerMessage>,
     lease_time: u32,
     hard_link_support: bool,
-    symlink_support: bool,
+    // symlinks of the backend, if it has any
+    special_files: Option<Arc<dyn SpecialFiles>>,
+    max_link: Option<u32>,
     unique_handles: bool,
+    filehandle_strategy: FilehandleStrategy,
+    request_timeout: Option<Duration>,
+    write_through: bool,
+    quota: Option<u64>,
+    files_total: Option<u64>,
+    write_cache_flush_interval: Option<Duration>,
+    write_cache_flush_size: Option<u64>,
+    clock: SharedClock,
+    // serializes the I/O of operations changing a file's content, shared by
+    // all clones of the handle
+    file_locks: KeyedLocks<NfsFh4>,
+    // hands out the turns of WRITEs with the same stateid to a file in the
+    // order they arrived, shared by all clones of the handle
+    write_order: KeyedLocks<([u8; 12], NfsFh4)>,
+    readahead: Option<Arc<Readahead>>,
+    filehandle_cache: Option<Arc<FilehandleCache>>,
+    readdir_max_dircount: Option<u32>,
+    readdir_max_maxcount: Option<u32>,
+    max_path_depth: Option<usize>,
+    // the SECINFO reply, the same for every directory of the export
+    secinfo: SecInfo4resok,
 }
 
 impl FileManagerHandle {
     pub fn new(root: VfsPath, fsid: Option<u64>) -> Self {
+        Self::with_config(root, fsid, FileManagerConfig::default())
+    }
+
+    pub fn with_config(root: VfsPath, fsid: Option<u64>, config: FileManagerConfig) -> Self {
         let (sender, receiver) = mpsc::channel(16);
-        let fmanager = FileManager::new(receiver, root, fsid);
+        let filehandle_strategy = config.filehandle_strategy;
+        let request_timeout = config.request_timeout;
+        let write_through = config.write_through;
+        let quota = config.quota;
+        let files_total = config.files_total;
+        let write_cache_flush_interval = config.write_cache_flush_interval;
+        let write_cache_flush_size = config.write_cache_flush_size;
+        let clock = config.clock.clone();
+        let readahead = config
+            .readahead
+            .map(|window| Arc::new(Readahead::new(window)));
+        let filehandle_cache = config
+            .filehandle_cache_ttl
+            .map(|ttl| Arc::new(FilehandleCache::new(ttl, clock.clone())));
+        if let (Some(exports), Some(cache)) = (&config.exports, &filehandle_cache) {
+            let cache = Arc::downgrade(cache);
+            exports.on_reload(move |path| match cache.upgrade() {
+                Some(cache) => {
+                    cache.remove_path(path);
+                    true
+                }
+                None => false,
+            });
+        }
+        let special_files = config.special_files.clone();
+        let max_link = config.max_link;
+        let readdir_max_dircount = config.readdir_max_dircount;
+        let readdir_max_maxcount = config.readdir_max_maxcount;
+        let max_path_depth = config.max_path_depth;
+        let secinfo = security::secinfo(&config.security_flavors);
+        let fmanager = FileManager::new(receiver, root, fsid, config);
         // start the filemanager actor
         tokio::spawn(run_file_manager(fmanager));
 
         Self {
             sender,
-            lease_time: 60,
-            hard_link_support: false,
-            symlink_support: false,
+            lease_time: LEASE_TIME,
+            hard_link_support: special_files.is_some(),
+            special_files,
+            max_link,
             unique_handles: false,
+            filehandle_strategy,
+            request_timeout,
+            write_through,
+            quota,
+            files_total,
+            write_cache_flush_interval,
+            write_cache_flush_size,
+            clock,
+            file_locks: Arc::default(),
+            write_order: Arc::default(),
+            readahead,
+            filehandle_cache,
+            readdir_max_dircount,
+            readdir_max_maxcount,
+            max_path_depth,
+            secinfo,
+        }
+    }
+
+    // send a message to the filemanager actor, waiting at most the
+    // configured request timeout for a free slot in the queue
+    async fn send(&self, msg: FileManagerMessage) -> Result<(), FileManagerError> {
+        let send = self.sender.send(msg);
+        let res = match self.request_timeout {
+            Some(duration) => match tokio::time::timeout(duration, send).await {
+                Ok(res) => res,
+                Err(_) => {
+                    error!("FileManager didn't accept request within {:?}", duration);
+                    return Err(FileManagerError {
+                        nfs_error: NfsStat4::Nfs4errDelay,
+                    });
+                }
+            },
+            None => send.await,
+        };
+        res.map_err(|_| {
+            error!("FileManager is gone, couldn't send request");
+            FileManagerError {
+                nfs_error: NfsStat4::Nfs4errServerfault,
+            }
+        })
+    }
+
+    // send a message to the filemanager actor and wait for its response, a
+    // backend that doesn't respond in time fails with NFS4ERR_DELAY
+    async fn request<T>(
+        &self,
+        msg: FileManagerMessage,
+        rx: oneshot::Receiver<T>,
+    ) -> Result<T, FileManagerError> {
+        let request = async {
+            self.send(msg).await?;
+            rx.await.map_err(|_| {
+                error!("FileManager dropped request without responding");
+                FileManagerError {
+                    nfs_error: NfsStat4::Nfs4errServerfault,
+                }
+            })
+        };
+        match self.request_timeout {
+            Some(duration) => match tokio::time::timeout(duration, request).await {
+                Ok(res) => res,
+                Err(_) => {
+                    error!("FileManager didn't respond within {:?}", duration);
+                    Err(FileManagerError {
+                        nfs_error: NfsStat4::Nfs4errDelay,
+                    })
+                }
+            },
+            None => request.await,
         }
     }
 
@@ -107,49 +376,19 @@ impl FileManagerHandle {
         path: Option<String>,
         filehandle: Option<NfsFh4>,
     ) -> Result<Filehandle, FileManagerError> {
-        let filehandle_set = filehandle.is_some();
         let (tx, rx) = oneshot::channel();
         let req = GetFilehandleRequest {
             path: path.clone(),
             filehandle,
             respond_to: tx,
         };
-        self.sender
-            .send(FileManagerMessage::GetFilehandle(req))
-            .await
-            .unwrap();
-        match rx.await {
-            Ok(fh) => {
-                if let Some(fh) = fh {
-                    return Ok(fh);
-                }
-                if let Some(path) = path {
-                    debug!("File not found: {:?}", path);
-                    if !filehandle_set {
-                        Err(FileManagerError {
-                            nfs_error: NfsStat4::Nfs4errNoent,
-                        })
-                    } else {
-                        Err(FileManagerError {
-                            nfs_error: NfsStat4::Nfs4errStale,
-                        })
-                    }
-                } else {
-                    debug!("Filehandle not found");
-                    // https://datatracker.ietf.org/doc/html/rfc7530#section-4.2.3
-                    // If the server can definitively determine that a
-                    // volatile filehandle refers to an object that has been removed, the
-                    // server should return NFS4ERR_STALE to the client (as is the case for
-                    // persistent filehandles)
-                    Err(FileManagerError {
-                        nfs_error: NfsStat4::Nfs4errStale,
-                    })
-                }
-            }
-            Err(_) => Err(FileManagerError {
-                nfs_error: NfsStat4::Nfs4errServerfault,
-            }),
-        }
+        let fh = self
+            .request(FileManagerMessage::GetFilehandle(req), rx)
+            .await?;
+        fh.map_err(|nfs_error| {
+            debug!("Filehandle not found: {:?} {:?}", path, nfs_error);
+            FileManagerError { nfs_error }
+        })
     }
 
     pub async fn get_root_filehandle(&self) -> Result<Filehandle, FileManagerError> {
@@ -178,23 +417,30 @@ impl FileManagerHandle {
             attrs_request,
             respond_to: tx,
         };
-        self.sender
-            .send(FileManagerMessage::GetFilehandleAttrs(req))
+        let attrs = self
+            .request(FileManagerMessage::GetFilehandleAttrs(req), rx)
+            .await?;
+        attrs.ok_or(FileManagerError {
+            nfs_error: NfsStat4::Nfs4errBadhandle,
+        })
+    }
+
+    /// Resolves the filehandles of `paths` along with their attributes in a
+    /// single round trip to the actor, results are in the order of `paths`
+    pub async fn get_filehandle_attrs_many(
+        &self,
+        paths: Vec<String>,
+        attrs_request: Vec<FileAttr>,
+    ) -> Result<Vec<Result<FilehandleAttrs, NfsStat4>>, FileManagerError> {
+        debug!("Batched attributes request for {} entries", paths.len());
+        let (tx, rx) = oneshot::channel();
+        let req = GetFilehandleAttrsManyRequest {
+            paths,
+            attrs_request,
+            respond_to: tx,
+        };
+        self.request(FileManagerMessage::GetFilehandleAttrsMany(req), rx)
             .await
-            .unwrap();
-        match rx.await {
-            Ok(attrs) => {
-                if let Some(attrs) = attrs {
-                    return Ok(attrs);
-                }
-                Err(FileManagerError {
-                    nfs_error: NfsStat4::Nfs4errBadhandle,
-                })
-            }
-            Err(_) => Err(FileManagerError {
-                nfs_error: NfsStat4::Nfs4errServerfault,
-            }),
-        }
     }
 
     pub async fn create_file(
@@ -216,55 +462,318 @@ impl FileManagerHandle {
             verifier,
             respond_to: tx,
         };
-        self.sender
-            .send(FileManagerMessage::CreateFile(req))
+        let fh = self
+            .request(FileManagerMessage::CreateFile(req), rx)
+            .await?;
+        fh.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Open the existing file at `path` with a share reservation, fails
+    /// with NFS4ERR_NOENT if there is none
+    pub async fn open_file(
+        &self,
+        path: VfsPath,
+        client_id: u64,
+        owner: Vec<u8>,
+        access: u32,
+        deny: u32,
+    ) -> Result<Filehandle, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = OpenFileRequest {
+            path,
+            client_id,
+            owner,
+            share_access: access,
+            share_deny: deny,
+            respond_to: tx,
+        };
+        let fh = self.request(FileManagerMessage::OpenFile(req), rx).await?;
+        fh.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Create a symlink at `path` pointing to `target`, fails with
+    /// NFS4ERR_NOTSUPP unless symlinks are enabled
+    pub async fn create_symlink(
+        &self,
+        path: VfsPath,
+        target: String,
+    ) -> Result<Filehandle, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = CreateSymlinkRequest {
+            path,
+            target,
+            respond_to: tx,
+        };
+        let fh = self
+            .request(FileManagerMessage::CreateSymlink(req), rx)
+            .await?;
+        fh.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Create a block or character device at `path`, fails with
+    /// NFS4ERR_NOTSUPP unless devices are enabled
+    pub async fn create_device(
+        &self,
+        path: VfsPath,
+        ftype: NfsFtype4,
+        rawdev: Specdata4,
+    ) -> Result<Filehandle, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = CreateDeviceRequest {
+            path,
+            ftype,
+            rawdev,
+            respond_to: tx,
+        };
+        let fh = self
+            .request(FileManagerMessage::CreateDevice(req), rx)
+            .await?;
+        fh.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Release the open state identified by `stateid`, returns the stateid
+    /// with its final seqid
+    pub async fn close_file(&self, stateid: Stateid4) -> Result<Stateid4, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = CloseFileRequest {
+            stateid,
+            respond_to: tx,
+        };
+        let res = self.request(FileManagerMessage::CloseFile(req), rx).await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Release a lock owner, fails with NFS4ERR_LOCKS_HELD while it still
+    /// holds locks
+    pub async fn release_lockowner(
+        &self,
+        client_id: u64,
+        owner: Vec<u8>,
+    ) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = ReleaseLockownerRequest {
+            client_id,
+            owner,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::ReleaseLockowner(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Drop all opens and locks of a client, e.g. of an instance that was
+    /// replaced after the client rebooted
+    pub async fn release_client(&self, client_id: u64) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = ReleaseClientRequest {
+            client_id,
+            respond_to: tx,
+        };
+        self.request(FileManagerMessage::ReleaseClient(req), rx)
             .await
-            .unwrap();
-        match rx.await {
-            Ok(fh) => {
-                if let Some(fh) = fh {
-                    return Ok(fh);
-                }
-                Err(FileManagerError {
-                    // TODO: check if this is the correct error
-                    nfs_error: NfsStat4::Nfs4errBadhandle,
-                })
-            }
-            Err(_) => Err(FileManagerError {
-                nfs_error: NfsStat4::Nfs4errServerfault,
-            }),
-        }
+    }
+
+    /// Set the mode of a file, returns the filehandle with the new mode
+    pub async fn set_mode(
+        &self,
+        filehandle_id: NfsFh4,
+        mode: u32,
+    ) -> Result<Filehandle, FileManagerError> {
+        let metadata = FileMetadata {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        self.set_metadata(filehandle_id, metadata).await
+    }
+
+    /// Set the archive bit of a file, returns the updated filehandle
+    pub async fn set_archive(
+        &self,
+        filehandle_id: NfsFh4,
+        archive: bool,
+    ) -> Result<Filehandle, FileManagerError> {
+        let metadata = FileMetadata {
+            archive: Some(archive),
+            ..Default::default()
+        };
+        self.set_metadata(filehandle_id, metadata).await
+    }
+
+    /// Set the time of the last backup of a file, returns the updated
+    /// filehandle
+    pub async fn set_time_backup(
+        &self,
+        filehandle_id: NfsFh4,
+        time_backup: Nfstime4,
+    ) -> Result<Filehandle, FileManagerError> {
+        let metadata = FileMetadata {
+            time_backup: Some(time_backup),
+            ..Default::default()
+        };
+        self.set_metadata(filehandle_id, metadata).await
+    }
+
+    async fn set_metadata(
+        &self,
+        filehandle_id: NfsFh4,
+        metadata: FileMetadata,
+    ) -> Result<Filehandle, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = SetMetadataRequest {
+            filehandle_id,
+            metadata,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::SetMetadata(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Check that `stateid` may be used for I/O on the file, `access` is
+    /// the share access the operation needs, e.g. OPEN4_SHARE_ACCESS_WRITE
+    pub async fn check_stateid_access(
+        &self,
+        stateid: Stateid4,
+        filehandle_id: NfsFh4,
+        access: u32,
+    ) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = CheckStateidAccessRequest {
+            stateid,
+            filehandle_id,
+            access,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::CheckStateidAccess(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Record a change of the state behind `stateid`, returns the stateid
+    /// with its seqid bumped. The seqid sent by the client has to be the
+    /// current one.
+    pub async fn update_stateid(&self, stateid: Stateid4) -> Result<Stateid4, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = UpdateStateidRequest {
+            stateid,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::UpdateStateid(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Confirm the open behind `stateid`, like update_stateid. A
+    /// retransmission of the open owner's last confirm, same `seqid` and
+    /// stateid, is answered as the first time.
+    pub async fn confirm_open(
+        &self,
+        stateid: Stateid4,
+        seqid: u32,
+    ) -> Result<Stateid4, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = ConfirmOpenRequest {
+            stateid,
+            seqid,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::ConfirmOpen(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Give up some of the access and deny modes of an open, returns the
+    /// bumped open stateid
+    pub async fn downgrade_open(
+        &self,
+        stateid: Stateid4,
+        seqid: u32,
+        share_access: u32,
+        share_deny: u32,
+    ) -> Result<Stateid4, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = DowngradeOpenRequest {
+            stateid,
+            seqid,
+            share_access,
+            share_deny,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::DowngradeOpen(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// A snapshot of all opens and byte-range locks, to debug stuck locks.
+    /// Every entry is logged as well.
+    pub async fn dump_locks(&self) -> Result<Vec<LockingState>, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = DumpLocksRequest { respond_to: tx };
+        self.request(FileManagerMessage::DumpLocks(req), rx).await
     }
 
     pub async fn remove_file(&self, path: VfsPath) -> Result<(), FileManagerError> {
         let (tx, rx) = oneshot::channel();
-        self.sender
-            .send(FileManagerMessage::RemoveFile(RemoveFileRequest {
-                path,
-                respond_to: tx,
-            }))
-            .await
-            .unwrap();
-        match rx.await {
-            Ok(_) => Ok(()),
-            Err(_) => Err(FileManagerError {
-                nfs_error: NfsStat4::Nfs4errServerfault,
-            }),
-        }
+        let req = RemoveFileRequest {
+            path,
+            respond_to: tx,
+        };
+        self.request(FileManagerMessage::RemoveFile(req), rx).await
+    }
+
+    /// Move a file or directory from `from` to `to`, replacing a target of
+    /// the same kind. Filehandles of the moved objects keep their ids.
+    pub async fn rename_file(&self, from: VfsPath, to: VfsPath) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = RenameFileRequest {
+            from,
+            to,
+            respond_to: tx,
+        };
+        let res = self
+            .request(FileManagerMessage::RenameFile(req), rx)
+            .await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+
+    /// Create `path` as another name of the file at `existing`
+    pub async fn link_file(
+        &self,
+        existing: VfsPath,
+        path: VfsPath,
+    ) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        let req = LinkFileRequest {
+            existing,
+            path,
+            respond_to: tx,
+        };
+        let res = self.request(FileManagerMessage::LinkFile(req), rx).await?;
+        res.map_err(|nfs_error| FileManagerError { nfs_error })
     }
 
     pub async fn touch_file(&self, id: NfsFh4) {
-        self.sender
+        if let Err(e) = self
             .send(FileManagerMessage::TouchFile(TouchFileRequest { id }))
             .await
-            .unwrap();
+        {
+            error!("Couldn't touch file: {:?}", e);
+        }
     }
 
     pub async fn update_filehandle(&self, filehandle: Filehandle) {
-        self.sender
+        if let Err(e) = self
             .send(FileManagerMessage::UpdateFilehandle(filehandle))
             .await
-            .unwrap();
+        {
+            error!("Couldn't update filehandle: {:?}", e);
+        }
     }
 
     pub async fn get_write_cache_handle(
@@ -272,31 +781,51 @@ impl FileManagerHandle {
         filehandle: Filehandle,
     ) -> Result<WriteCacheHandle, FileManagerError> {
         let (tx, rx) = oneshot::channel();
-        self.sender
-            .send(FileManagerMessage::GetWriteCacheHandle(
-                WriteCacheHandleRequest {
-                    filemanager: self.clone(),
-                    filehandle,
-                    respond_to: tx,
-                },
-            ))
+        let req = WriteCacheHandleRequest {
+            filemanager: self.clone(),
+            filehandle,
+            respond_to: tx,
+        };
+        self.request(FileManagerMessage::GetWriteCacheHandle(Box::new(req)), rx)
             .await
-            .unwrap();
-        match rx.await {
-            Ok(handle) => Ok(handle),
-            Err(_) => Err(FileManagerError {
-                nfs_error: NfsStat4::Nfs4errServerfault,
-            }),
+    }
+
+    /// Write the cached data of a file to the backend, so it can be read
+    /// from there. Returns whether there was a write cache to flush.
+    pub async fn flush_write_cache(&self, filehandle_id: NfsFh4) -> Result<bool, FileManagerError> {
+        let filehandle = self.get_filehandle_for_id(filehandle_id).await?;
+        if filehandle.write_cache.is_none() {
+            return Ok(false);
+        }
+        let _lock = self.lock_file(filehandle_id).await;
+        self.flush_locked_write_cache(filehandle_id).await
+    }
+
+    /// [`Self::flush_write_cache`] for a caller that already holds the
+    /// file's lock
+    pub async fn flush_locked_write_cache(
+        &self,
+        filehandle_id: NfsFh4,
+    ) -> Result<bool, FileManagerError> {
+        let filehandle = self.get_filehandle_for_id(filehandle_id).await?;
+        match filehandle.write_cache {
+            Some(write_cache) => {
+                write_cache.commit().await?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
     }
 
     pub async fn drop_write_cache_handle(&self, filehandle_id: NfsFh4) {
-        self.sender
+        if let Err(e) = self
             .send(FileManagerMessage::DropWriteCacheHandle(
                 DropCacheHandleRequest { filehandle_id },
             ))
             .await
-            .unwrap();
+        {
+            error!("Couldn't drop write cache handle: {:?}", e);
+        }
     }
 
     pub fn filehandle_attrs(
@@ -341,6 +870,10 @@ impl FileManagerHandle {
                     attrs.push(FileAttrValue::NamedAttr(self.attr_named_attr()));
                     answer_attrs.push(FileAttr::NamedAttr);
                 }
+                FileAttr::Acl => {
+                    attrs.push(FileAttrValue::Acl(self.attr_acl(filehandle.attr_mode)));
+                    answer_attrs.push(FileAttr::Acl);
+                }
                 FileAttr::AclSupport => {
                     attrs.push(FileAttrValue::AclSupport(self.attr_acl_support()));
                     answer_attrs.push(FileAttr::AclSupport);
@@ -353,6 +886,33 @@ impl FileManagerHandle {
                     attrs.push(FileAttrValue::UniqueHandles(self.attr_unique_handles()));
                     answer_attrs.push(FileAttr::UniqueHandles);
                 }
+                FileAttr::FsLocations => {
+                    // only referrals have locations
+                    if let Some(locations) = &filehandle.attr_fs_locations {
+                        attrs.push(FileAttrValue::FsLocations(locations.clone()));
+                        answer_attrs.push(FileAttr::FsLocations);
+                    }
+                }
+                FileAttr::Homogeneous => {
+                    attrs.push(FileAttrValue::Homogeneous(self.attr_homogeneous()));
+                    answer_attrs.push(FileAttr::Homogeneous);
+                }
+                FileAttr::Maxfilesize => {
+                    attrs.push(FileAttrValue::Maxfilesize(self.attr_maxfilesize()));
+                    answer_attrs.push(FileAttr::Maxfilesize);
+                }
+                FileAttr::Maxlink => {
+                    attrs.push(FileAttrValue::Maxlink(self.attr_maxlink()));
+                    answer_attrs.push(FileAttr::Maxlink);
+                }
+                FileAttr::Maxread => {
+                    attrs.push(FileAttrValue::Maxread(self.attr_maxread()));
+                    answer_attrs.push(FileAttr::Maxread);
+                }
+                FileAttr::Maxwrite => {
+                    attrs.push(FileAttrValue::Maxwrite(self.attr_maxwrite()));
+                    answer_attrs.push(FileAttr::Maxwrite);
+                }
                 FileAttr::FhExpireType => {
                     attrs.push(FileAttrValue::FhExpireType(self.attr_expire_type()));
                     answer_attrs.push(FileAttr::FhExpireType);
@@ -365,12 +925,20 @@ impl FileManagerHandle {
                     attrs.push(FileAttrValue::Fileid(filehandle.attr_fileid));
                     answer_attrs.push(FileAttr::Fileid);
                 }
+                FileAttr::Archive => {
+                    attrs.push(FileAttrValue::Archive(filehandle.attr_archive));
+                    answer_attrs.push(FileAttr::Archive);
+                }
+                FileAttr::TimeBackup => {
+                    attrs.push(FileAttrValue::TimeBackup(filehandle.attr_time_backup));
+                    answer_attrs.push(FileAttr::TimeBackup);
+                }
                 FileAttr::Mode => {
-                    attrs.push(FileAttrValue::Mode(self.attr_mode()));
+                    attrs.push(FileAttrValue::Mode(filehandle.attr_mode));
                     answer_attrs.push(FileAttr::Mode);
                 }
                 FileAttr::Numlinks => {
-                    attrs.push(FileAttrValue::Numlinks(self.attr_numlinks()));
+                    attrs.push(FileAttrValue::Numlinks(filehandle.attr_numlinks));
                     answer_attrs.push(FileAttr::Numlinks);
                 }
                 FileAttr::Owner => {
@@ -383,6 +951,32 @@ impl FileManagerHandle {
                     ));
                     answer_attrs.push(FileAttr::OwnerGroup);
                 }
+                FileAttr::Rawdev => {
+                    attrs.push(FileAttrValue::Rawdev(filehandle.attr_rawdev));
+                    answer_attrs.push(FileAttr::Rawdev);
+                }
+                FileAttr::QuotaAvailHard | FileAttr::QuotaAvailSoft | FileAttr::QuotaUsed
+                    if self.quota.is_some() =>
+                {
+                    let (avail, used) = self.attr_quota(&filehandle.file.root());
+                    attrs.push(match fileattr {
+                        FileAttr::QuotaAvailHard => FileAttrValue::QuotaAvailHard(avail),
+                        FileAttr::QuotaAvailSoft => FileAttrValue::QuotaAvailSoft(avail),
+                        _ => FileAttrValue::QuotaUsed(used),
+                    });
+                    answer_attrs.push(fileattr.clone());
+                }
+                FileAttr::FilesAvail | FileAttr::FilesFree | FileAttr::FilesTotal
+                    if self.files_total.is_some() =>
+                {
+                    let (free, total) = self.attr_files(&filehandle.file.root());
+                    attrs.push(match fileattr {
+                        FileAttr::FilesAvail => FileAttrValue::FilesAvail(free),
+                        FileAttr::FilesFree => FileAttrValue::FilesFree(free),
+                        _ => FileAttrValue::FilesTotal(total),
+                    });
+                    answer_attrs.push(fileattr.clone());
+                }
                 FileAttr::SpaceUsed => {
                     attrs.push(FileAttrValue::SpaceUsed(filehandle.attr_space_used));
                     answer_attrs.push(FileAttr::SpaceUsed);
@@ -399,12 +993,12 @@ impl FileManagerHandle {
                     attrs.push(FileAttrValue::TimeModify(filehandle.attr_time_modify));
                     answer_attrs.push(FileAttr::TimeModify);
                 }
-                // FileAttr::MountedOnFileid => {
-                //     attrs.push(FileAttrValue::MountedOnFileid(
-                //         filehandle.attr_mounted_on_fileid,
-                //     ));
-                //     answer_attrs.push(FileAttr::MountedOnFileid);
-                // }
+                FileAttr::MountedOnFileid => {
+                    attrs.push(FileAttrValue::MountedOnFileid(
+                        filehandle.attr_mounted_on_fileid,
+                    ));
+                    answer_attrs.push(FileAttr::MountedOnFileid);
+                }
                 _ => {}
             }
         }
@@ -415,29 +1009,145 @@ impl FileManagerHandle {
         &self,
         filehandle: &Filehandle,
         attr_vals: &Attrlist4<FileAttrValue>,
-    ) -> Attrlist4<FileAttr> {
+    ) -> Result<Attrlist4<FileAttr>, NfsStat4> {
+        // only regular files have a size to change, nothing is set if the
+        // request includes it for another type
+        let size = attr_vals.iter().find_map(|attr| match attr {
+            FileAttrValue::Size(size) => Some(*size),
+            _ => None,
+        });
+        if let Some(size) = size {
+            match filehandle.attr_type {
+                NfsFtype4::Nf4reg => {}
+                NfsFtype4::Nf4dir => return Err(NfsStat4::Nfs4errIsdir),
+                _ => return Err(NfsStat4::Nfs4errInval),
+            }
+            if size > MAX_FILE_SIZE {
+                return Err(NfsStat4::Nfs4errFbig);
+            }
+        }
         let mut attrsset = Attrlist4::<FileAttr>::new(None);
         for attr in attr_vals.iter() {
             match attr {
                 FileAttrValue::Size(args) => {
                     debug!("Set size to: {:?}", args);
-                    let mut buf = vec![0_u8; *args as usize];
-                    let mut file = filehandle.file.open_file().unwrap();
-                    let _ = file.rewind();
-                    file.read_exact(&mut buf).unwrap();
-
-                    let mut file = filehandle.file.append_file().unwrap();
-                    let _ = file.rewind();
-                    file.write_all(&buf).unwrap();
-                    file.flush().unwrap();
+                    // writing over the old content doesn't shrink the file,
+                    // so the file is recreated with the content cut or padded
+                    let mut buf = Vec::new();
+                    let mut file = filehandle
+                        .file
+                        .open_file()
+                        .map_err(|e| vfs_error_status(&e))?;
+                    file.read_to_end(&mut buf)
+                        .map_err(|e| io_error_status(&e))?;
+                    buf.resize(*args as usize, 0);
+
+                    let mut file = filehandle
+                        .file
+                        .create_file()
+                        .map_err(|e| vfs_error_status(&e))?;
+                    file.write_all(&buf)
+                        .and_then(|_| file.flush())
+                        .map_err(|e| io_error_status(&e))?;
                     attrsset.push(FileAttr::Size);
                 }
+                // kept in the file's metadata, SETATTR stores it with the
+                // file manager
+                FileAttrValue::Archive(_) | FileAttrValue::TimeBackup(_) => {}
                 _ => {
                     debug!("Not supported set attr requested for: {:?}", attr);
                 }
             }
         }
-        attrsset
+        Ok(attrsset)
+    }
+
+    /// Lock a file against concurrent changes to its content, e.g. a WRITE
+    /// racing a SETATTR of the size. The lock is held until the guard is
+    /// dropped.
+    pub async fn lock_file(&self, filehandle_id: NfsFh4) -> FileLock {
+        let lock = {
+            let mut file_locks = self.file_locks.lock().unwrap();
+            // forget the locks no one holds or waits for
+            file_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            file_locks.entry(filehandle_id).or_default().clone()
+        };
+        FileLock {
+            _guard: lock.lock_owned().await,
+            id: filehandle_id,
+            readahead: self.readahead.clone(),
+        }
+    }
+
+    /// Wait for the turn of a WRITE with `stateid` to a file. WRITEs of the
+    /// same stateid get their turns in the order they asked for them, so
+    /// this has to be called before the WRITE awaits anything else. The turn
+    /// lasts until the guard is dropped.
+    pub async fn order_write(
+        &self,
+        stateid: &Stateid4,
+        filehandle_id: NfsFh4,
+    ) -> OwnedMutexGuard<()> {
+        let turn = {
+            let mut write_order = self.write_order.lock().unwrap();
+            // forget the stateids no WRITE is in flight for
+            write_order.retain(|_, turn| Arc::strong_count(turn) > 1);
+            write_order
+                .entry((stateid.other, filehandle_id))
+                .or_default()
+                .clone()
+        };
+        // tokio's mutex is fair, waiters get it first come first served
+        turn.lock_owned().await
+    }
+
+    /// The readahead of sequential READs, if it's enabled
+    pub fn readahead(&self) -> Option<&Arc<Readahead>> {
+        self.readahead.as_ref()
+    }
+
+    /// The current time of the server's clock
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Whether WRITE goes straight to the backend instead of the write cache
+    pub fn write_through(&self) -> bool {
+        self.write_through
+    }
+
+    /// The dircount and maxcount READDIR caps the client's values to
+    pub fn readdir_limits(&self) -> (Option<u32>, Option<u32>) {
+        (self.readdir_max_dircount, self.readdir_max_maxcount)
+    }
+
+    /// The deepest path, in components below the root, filehandles are
+    /// handed out for
+    pub fn max_path_depth(&self) -> Option<usize> {
+        self.max_path_depth
+    }
+
+    /// The target of a symlink, read from the backend
+    pub fn read_link(&self, filehandle: &Filehandle) -> Result<String, FileManagerError> {
+        let special_files = self.special_files.as_ref().ok_or(FileManagerError {
+            nfs_error: NfsStat4::Nfs4errNotsupp,
+        })?;
+        special_files.read_link(&filehandle.path).map_err(|e| {
+            error!("Couldn't read symlink {:?}: {:?}", filehandle.path, e);
+            FileManagerError {
+                nfs_error: io_error_status(&e),
+            }
+        })
+    }
+
+    /// The security flavors of the export, in order of preference
+    pub fn secinfo(&self) -> &SecInfo4resok {
+        &self.secinfo
+    }
+
+    /// The filehandle cache shared by all connections, if caching is on
+    pub fn filehandle_cache(&self) -> Option<&FilehandleCache> {
+        self.filehandle_cache.as_deref()
     }
 
     pub fn attr_lease_time(&self) -> NfsLease4 {
@@ -456,7 +1166,7 @@ impl FileManagerHandle {
         // The bit vector that would retrieve all REQUIRED and RECOMMENDED
         // attributes that are supported for this object.  The scope of this
         //attribute applies to all objects with a matching fsid.
-        Attrlist4::<FileAttr>::new(Some(vec![
+        let mut attrs = Attrlist4::<FileAttr>::new(Some(vec![
             FileAttr::SupportedAttrs,
             FileAttr::Type,
             FileAttr::FhExpireType,
@@ -475,23 +1185,64 @@ impl FileManagerHandle {
             // FileAttr::Cansettime,
             FileAttr::Filehandle,
             FileAttr::Fileid,
+            FileAttr::Homogeneous,
+            FileAttr::Maxfilesize,
+            FileAttr::Maxlink,
+            FileAttr::Maxread,
+            FileAttr::Maxwrite,
             FileAttr::Mode,
             FileAttr::Numlinks,
             FileAttr::Owner,
             FileAttr::OwnerGroup,
+            FileAttr::Rawdev,
             FileAttr::SpaceUsed,
             FileAttr::TimeAccess,
+            FileAttr::TimeBackup,
             FileAttr::TimeMetadata,
             FileAttr::TimeModify,
-            // FileAttr::MountedOnFileid,
-        ]))
+            FileAttr::MountedOnFileid,
+        ]));
+        if self.quota.is_some() {
+            attrs.extend([
+                FileAttr::QuotaAvailHard,
+                FileAttr::QuotaAvailSoft,
+                FileAttr::QuotaUsed,
+            ]);
+        }
+        if self.files_total.is_some() {
+            attrs.extend([
+                FileAttr::FilesAvail,
+                FileAttr::FilesFree,
+                FileAttr::FilesTotal,
+            ]);
+        }
+        attrs
+    }
+
+    pub fn attr_quota(&self, root: &VfsPath) -> (u64, u64) {
+        // quota_avail_hard, quota_avail_soft, quota_used:
+        // The space left before the quota refuses further allocations, and
+        // the space counted against it. The quota is enforced by the
+        // backend, there's a single one for the whole export and no soft
+        // limit below the hard one.
+        let used = space_used_below(root);
+        (self.quota.unwrap_or(0).saturating_sub(used), used)
+    }
+
+    pub fn attr_files(&self, root: &VfsPath) -> (u64, u64) {
+        // files_avail, files_free, files_total:
+        // The file slots left for the user and in total, and all slots of
+        // the file system. Every file and directory of the export, the root
+        // included, takes one and nothing is reserved.
+        let total = self.files_total.unwrap_or(0);
+        (total.saturating_sub(objects_below(root)), total)
     }
 
     pub fn attr_expire_type(&self) -> u32 {
         // fh_expire_type:
         // The server uses this to specify filehandle expiration behavior to the
         // client.  See Section 4 for additional description.
-        FH4_VOLATILE_ANY
+        self.filehandle_strategy.expire_type()
     }
 
     pub fn attr_link_support(&self) -> bool {
@@ -503,7 +1254,7 @@ impl FileManagerHandle {
     pub fn attr_symlink_support(&self) -> bool {
         // symlink_support:
         // TRUE, if the object's file system supports symbolic links.
-        self.symlink_support
+        self.special_files.is_some()
     }
 
     pub fn attr_named_attr(&self) -> bool {
@@ -520,7 +1271,43 @@ impl FileManagerHandle {
         self.unique_handles
     }
 
-    pub fn attr_acl(&self) -> bool {
+    pub fn attr_homogeneous(&self) -> bool {
+        // homogeneous:
+        // TRUE, if this object's file system is homogeneous, i.e., all
+        // objects in the file system (all objects on the server with the same
+        // fsid) have common values for all per-file system attributes.
+        // There is a single backend per export and the per-file system
+        // attributes don't depend on the object.
+        true
+    }
+
+    pub fn attr_maxfilesize(&self) -> u64 {
+        // maxfilesize:
+        // Maximum supported file size for the file system of this object.
+        MAX_FILE_SIZE
+    }
+
+    pub fn attr_maxlink(&self) -> u32 {
+        // maxlink:
+        // Maximum number of links for this object.
+        self.max_link.unwrap_or(u32::MAX)
+    }
+
+    pub fn attr_maxread(&self) -> u64 {
+        // maxread:
+        // Maximum amount of data the READ operation will return for this
+        // object.
+        MAX_READ
+    }
+
+    pub fn attr_maxwrite(&self) -> u64 {
+        // maxwrite:
+        // Maximum amount of data the WRITE operation will accept for this
+        // object.
+        MAX_WRITE
+    }
+
+    pub fn attr_acl(&self, mode: u32) -> Vec<Nfsace4> {
         // acl:
         // The NFSv4.0 ACL attribute contains an array of ACEs that are
         // associated with the file system object.  Although the client can read
@@ -528,7 +1315,8 @@ impl FileManagerHandle {
         // ACL to perform access control.  The client can use the OPEN or ACCESS
         // operations to check access without modifying or reading data or
         // metadata.
-        false
+        // There's no ACL storage, the ACEs are derived from the mode bits.
+        acl_from_mode(mode)
     }
 
     pub fn attr_acl_support(&self) -> u32 {
@@ -537,28 +1325,16 @@ impl FileManagerHandle {
         ACL4_SUPPORT_ALLOW_ACL
     }
 
-    pub fn attr_archive(&self) -> bool {
-        // archive:
-        // TRUE, if the object's file system supports the archive attribute.
-        false
-    }
-
     pub fn attr_mode(&self) -> u32 {
         // mode:
         // The NFSv4.0 mode attribute is based on the UNIX mode bits.
         MODE4_RUSR + MODE4_RGRP + MODE4_ROTH
     }
-
-    pub fn attr_numlinks(&self) -> u32 {
-        // numlinks:
-        // Number of hard links to this object.
-        1
-    }
 }
 
 pub enum WriteCacheMessage {
     Write(WriteBytesRequest),
-    Commit,
+    Commit(CommitRequest),
 }
 
 pub struct WriteBytesRequest {
@@ -568,6 +1344,12 @@ pub struct WriteBytesRequest {
     pub data: Vec<u8>,
 }
 
+pub struct CommitRequest {
+    // answered once the cache is written out, or with the status of the
+    // failed write
+    pub respond_to: oneshot::Sender<Result<(), NfsStat4>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct WriteCacheHandle {
     sender: mpsc::Sender<WriteCacheMessage>,
@@ -576,21 +1358,208 @@ pub struct WriteCacheHandle {
 impl WriteCacheHandle {
     pub fn new(filehandle: Filehandle, filemanager: FileManagerHandle) -> Self {
         let (sender, receiver) = mpsc::channel(16);
-        let write_cache = WriteCache::new(receiver, filehandle, filemanager);
+        let flush_interval = filemanager.write_cache_flush_interval;
+        let flush_size = filemanager.write_cache_flush_size;
+        let write_cache = WriteCache::new(
+            receiver,
+            filehandle,
+            filemanager,
+            flush_interval,
+            flush_size,
+        );
         // start the writecache actor
         tokio::spawn(run_file_write_cache(write_cache));
 
         Self { sender }
     }
 
-    pub async fn write_bytes(&self, offset: u64, data: Vec<u8>) {
-        self.sender
-            .send(WriteCacheMessage::Write(WriteBytesRequest { offset, data }))
+    async fn send(&self, msg: WriteCacheMessage) -> Result<(), FileManagerError> {
+        self.sender.send(msg).await.map_err(|_| {
+            error!("WriteCache is gone, couldn't send request");
+            FileManagerError {
+                nfs_error: NfsStat4::Nfs4errServerfault,
+            }
+        })
+    }
+
+    pub async fn write_bytes(&self, offset: u64, data: Vec<u8>) -> Result<(), FileManagerError> {
+        self.send(WriteCacheMessage::Write(WriteBytesRequest { offset, data }))
+            .await
+    }
+
+    /// Write the cache out, the writes sent before are applied first as the
+    /// cache handles its messages in order. If the backend fails, the data
+    /// stays cached and the next commit tries again.
+    pub async fn commit(&self) -> Result<(), FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(WriteCacheMessage::Commit(CommitRequest { respond_to: tx }))
+            .await?;
+        rx.await
+            .map_err(|_| {
+                error!("WriteCache is gone, couldn't commit");
+                NfsStat4::Nfs4errServerfault
+            })
+            .and_then(|res| res)
+            .map_err(|nfs_error| FileManagerError { nfs_error })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use tracing_test::traced_test;
+
+    use bold_proto::nfs4_proto::{
+        NfsStat4, Stateid4, OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_ACCESS_READ,
+        OPEN4_SHARE_DENY_NONE, OPEN4_SHARE_DENY_WRITE,
+    };
+
+    use super::{FileManagerHandle, WriteCacheHandle};
+    use crate::{
+        server::filemanager::{FilehandleStrategy, LockType},
+        test_utils::create_dummyfs,
+    };
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_dump_locks() {
+        let root = create_dummyfs();
+        let fmanager = FileManagerHandle::new(root.clone(), None);
+        assert!(fmanager.dump_locks().await.unwrap().is_empty());
+
+        let first = fmanager
+            .create_file(
+                root.join("first").unwrap(),
+                2,
+                b"writer".to_vec(),
+                OPEN4_SHARE_ACCESS_BOTH,
+                OPEN4_SHARE_DENY_WRITE,
+                None,
+            )
             .await
             .unwrap();
+        let second = fmanager
+            .create_file(
+                root.join("second").unwrap(),
+                1,
+                b"reader".to_vec(),
+                OPEN4_SHARE_ACCESS_READ,
+                OPEN4_SHARE_DENY_NONE,
+                None,
+            )
+            .await
+            .unwrap();
+
+        // ordered by client
+        let locks = fmanager.dump_locks().await.unwrap();
+        let entries: Vec<_> = locks
+            .iter()
+            .map(|lock| {
+                (
+                    lock.lock_type.clone(),
+                    lock.client_id,
+                    lock.owner.as_slice(),
+                    lock.filehandle_id,
+                    lock.share_access,
+                    lock.share_deny,
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    LockType::Open,
+                    1,
+                    b"reader".as_slice(),
+                    second.id,
+                    Some(OPEN4_SHARE_ACCESS_READ),
+                    Some(OPEN4_SHARE_DENY_NONE)
+                ),
+                (
+                    LockType::Open,
+                    2,
+                    b"writer".as_slice(),
+                    first.id,
+                    Some(OPEN4_SHARE_ACCESS_BOTH),
+                    Some(OPEN4_SHARE_DENY_WRITE)
+                ),
+            ]
+        );
+        assert_eq!(locks[1].stateid, first.locks[0].stateid);
+        assert!(logs_contain("Locking state dump, 2 entries"));
+
+        // closed opens are gone from the dump
+        let stateid = Stateid4 {
+            seqid: locks[1].seqid,
+            other: locks[1].stateid,
+        };
+        fmanager.close_file(stateid).await.unwrap();
+        let locks = fmanager.dump_locks().await.unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].filehandle_id, second.id);
     }
 
-    pub async fn commit(&self) {
-        self.sender.send(WriteCacheMessage::Commit).await.unwrap();
+    #[tokio::test]
+    #[traced_test]
+    async fn test_file_manager_gone() {
+        let (sender, receiver) = mpsc::channel(16);
+        // the actor is gone
+        drop(receiver);
+        let fmanager = FileManagerHandle {
+            sender,
+            lease_time: 60,
+            hard_link_support: false,
+            special_files: None,
+            max_link: None,
+            unique_handles: false,
+            filehandle_strategy: FilehandleStrategy::default(),
+            request_timeout: None,
+            write_through: false,
+            quota: None,
+            files_total: None,
+            write_cache_flush_interval: None,
+            write_cache_flush_size: None,
+            clock: Default::default(),
+            file_locks: Default::default(),
+            write_order: Default::default(),
+            readahead: None,
+            filehandle_cache: None,
+            readdir_max_dircount: None,
+            readdir_max_maxcount: None,
+            max_path_depth: None,
+            secinfo: Vec::new(),
+        };
+
+        let err = fmanager.get_root_filehandle().await.unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
+        let err = fmanager
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
+        let path = create_dummyfs().join("file1.txt").unwrap();
+        let err = fmanager
+            .create_file(path, 1, vec![1], 1, 0, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
+        let stateid = Stateid4 {
+            seqid: 1,
+            other: [1; 12],
+        };
+        let err = fmanager.close_file(stateid).await.unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
+        // fire and forget messages just log
+        fmanager.touch_file([0; 26]).await;
+        assert!(logs_contain("FileManager is gone"));
+
+        let (sender, receiver) = mpsc::channel(16);
+        drop(receiver);
+        let write_cache = WriteCacheHandle { sender };
+        let err = write_cache.write_bytes(0, vec![1]).await.unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
+        let err = write_cache.commit().await.unwrap_err();
+        assert_eq!(err.nfs_error, NfsStat4::Nfs4errServerfault);
     }
 }
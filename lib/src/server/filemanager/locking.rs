@@ -1,9 +1,9 @@
-use bold_proto::nfs4_proto::NfsFh4;
+use bold_proto::nfs4_proto::{NfsFh4, NfsStat4, Stateid4};
 use multi_index_map::MultiIndexMap;
 
 pub type LockingStateDb = MultiIndexLockingStateMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum LockType {
     Open,
     ByteRange,
@@ -18,6 +18,7 @@ pub struct LockingState {
     // that represents a set of locks (often a single lock) for the same
     // file, of the same type, and sharing the same ownership
     // characteristics.
+    #[multi_index(hashed_unique)]
     pub stateid: [u8; 12],
     pub seqid: u32,
     // clientid:
@@ -55,6 +56,15 @@ pub struct LockingState {
     pub share_deny: Option<u32>,
 }
 
+/// The last seqid-ordered request of an open owner and what it was
+/// answered, a retransmission with the same seqid gets the same answer
+#[derive(Debug, Clone)]
+pub struct OwnerReplay {
+    pub seqid: u32,
+    pub stateid: Stateid4,
+    pub result: Result<Stateid4, NfsStat4>,
+}
+
 impl LockingState {
     pub fn new_shared_reservation(
         filehandle_id: NfsFh4,
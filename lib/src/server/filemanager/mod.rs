@@ -1,23 +1,110 @@
-use std::collections::HashMap;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    sync::Arc,
+    time::Duration,
+};
 
 use bold_proto::nfs4_proto::{
-    Attrlist4, FileAttr, FileAttrValue, NfsFh4, NfsLease4, NfsStat4, ACL4_SUPPORT_ALLOW_ACL,
-    FH4_VOLATILE_ANY, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
+    Attrlist4, FileAttr, FileAttrValue, NfsFh4, NfsFtype4, NfsLease4, NfsStat4, Nfsace4, Specdata4,
+    Stateid4, ACE4_ACCESS_ALLOWED_ACE_TYPE, ACE4_APPEND_DATA, ACE4_EXECUTE, ACE4_IDENTIFIER_GROUP,
+    ACE4_READ_DATA, ACE4_WRITE_DATA, ACL4_SUPPORT_ALLOW_ACL, MODE4_RGRP, MODE4_ROTH, MODE4_RUSR,
+    OPEN4_SHARE_ACCESS_READ,
 };
 
 mod filehandle;
-pub use filehandle::Filehandle;
+pub use filehandle::{FileMetadata, Filehandle, FilehandleStrategy};
 pub use handle::FileManagerHandle;
+pub use locking::{LockType, LockingState};
 mod caching;
+mod fhcache;
 mod handle;
 mod locking;
+mod readahead;
 
+use super::{
+    clientmanager::LEASE_TIME, clock::SharedClock, exports::Exports, security::SecurityFlavor,
+    special::SpecialFiles,
+};
 use filehandle::FilehandleDb;
 use handle::{FileManagerMessage, WriteCacheHandle};
-use locking::{LockingState, LockingStateDb};
-use tokio::sync::mpsc;
+use locking::{LockingStateDb, OwnerReplay};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error};
-use vfs::VfsPath;
+use vfs::{error::VfsErrorKind, AltrootFS, VfsError, VfsFileType, VfsPath};
+
+/// Amount of data clients should ask for in a single READ, advertised as
+/// maxread. It's the same for all files of the export.
+pub const MAX_READ: u64 = 1024 * 1024;
+/// Amount of data clients should send in a single WRITE, advertised as
+/// maxwrite. It's the same for all files of the export.
+pub const MAX_WRITE: u64 = 1024 * 1024;
+/// Largest size SETATTR sets a file to, advertised as maxfilesize. The file
+/// is resized in memory, so this bounds what a single SETATTR allocates.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+/// How many filehandles of removed objects are kept to refresh them when
+/// stale filehandles are refreshed, the oldest are forgotten first.
+pub const MAX_RETIRED_FILEHANDLES: usize = 1024;
+
+/// Tunables of the FileManager, set up once when the server starts
+#[derive(Debug, Clone, Default)]
+pub struct FileManagerConfig {
+    pub filehandle_strategy: FilehandleStrategy,
+    // per path attributes, keyed by the full path of the file
+    pub metadata: HashMap<String, FileMetadata>,
+    // how long to wait for the actor before replying NFS4ERR_DELAY
+    pub request_timeout: Option<Duration>,
+    // maximum number of concurrent opens, in total and per client
+    pub max_opens: Option<usize>,
+    pub max_opens_per_client: Option<usize>,
+    // maximum number of open and lock owners a client holds state for
+    pub max_owners_per_client: Option<usize>,
+    // deepest path, in components below the root, a filehandle is handed
+    // out for
+    pub max_path_depth: Option<usize>,
+    // apply all writes to the backend right away, bypassing the write cache
+    pub write_through: bool,
+    // write cached unstable data to the backend this long after the first
+    // write, or once this many bytes were written, without a COMMIT
+    pub write_cache_flush_interval: Option<Duration>,
+    pub write_cache_flush_size: Option<u64>,
+    // symlinks, devices and hard links of the backend, creating them fails
+    // with NFS4ERR_NOTSUPP without
+    pub special_files: Option<Arc<dyn SpecialFiles>>,
+    // the most names a file may have, advertised as maxlink, only the
+    // backend's limit applies if None
+    pub max_link: Option<u32>,
+    // mode of files and directories without metadata, unless the client
+    // sets one when creating them
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    // the limit a quota of the backend puts on the space of the export,
+    // reported in the quota attributes
+    pub quota: Option<u64>,
+    // how many files and directories the export can hold, reported in the
+    // files attributes
+    pub files_total: Option<u64>,
+    // bytes prefetched after a sequential READ, no readahead if None
+    pub readahead: Option<u64>,
+    // how long filehandles stay in the cache shared by all connections, no
+    // caching if None
+    pub filehandle_cache_ttl: Option<Duration>,
+    // the export table the root was built from, what's cached of exports a
+    // reload takes away is dropped
+    pub exports: Option<Exports>,
+    // upper bounds for the dircount and maxcount of READDIR, whatever the
+    // client asks for
+    pub readdir_max_dircount: Option<u32>,
+    pub readdir_max_maxcount: Option<u32>,
+    // resolve a stale filehandle to whatever was recreated at its path,
+    // instead of failing with NFS4ERR_STALE
+    pub refresh_stale_filehandles: bool,
+    // the flavors SECINFO announces, AUTH_SYS and AUTH_NONE if empty
+    pub security_flavors: Vec<SecurityFlavor>,
+    // the time source for the boot time and the time attributes of files
+    pub clock: SharedClock,
+}
 
 #[derive(Debug)]
 pub struct FileManager {
@@ -25,10 +112,26 @@ pub struct FileManager {
     pub lease_time: u32,
     pub hard_link_support: bool,
     pub symlink_support: bool,
+    pub special_files: Option<Arc<dyn SpecialFiles>>,
+    pub max_link: Option<u32>,
     pub unique_handles: bool,
     pub fsid: u64,
+    pub filehandle_strategy: FilehandleStrategy,
+    pub metadata: HashMap<String, FileMetadata>,
+    pub max_opens: Option<usize>,
+    pub max_opens_per_client: Option<usize>,
+    pub max_owners_per_client: Option<usize>,
+    pub max_path_depth: Option<usize>,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub quota: Option<u64>,
+    pub files_total: Option<u64>,
+    pub refresh_stale_filehandles: bool,
     // database for all managed filehandles
     pub fhdb: FilehandleDb,
+    // filehandles whose objects were removed, kept to refresh them if
+    // their paths come back, oldest first
+    pub retired: VecDeque<Filehandle>,
     // this field trackes a sequence number for filehandles
     pub next_fh_id: u128,
     // database for all managed locking states
@@ -36,9 +139,12 @@ pub struct FileManager {
     // this field trackes a sequence number for stateids
     pub next_stateid_id: u64,
     pub boot_time: u64,
+    pub clock: SharedClock,
     // endpoint for incoming messages
     pub receiver: mpsc::Receiver<FileManagerMessage>,
     pub cachedb: HashMap<NfsFh4, WriteCacheHandle>,
+    // last OPEN_CONFIRM per open owner, keyed by clientid and owner
+    pub owner_replays: HashMap<(u64, Vec<u8>), OwnerReplay>,
 }
 
 impl FileManager {
@@ -46,24 +152,47 @@ impl FileManager {
         receiver: mpsc::Receiver<FileManagerMessage>,
         root: VfsPath,
         fsid: Option<u64>,
+        config: FileManagerConfig,
     ) -> Self {
         let fsid = fsid.unwrap_or(152);
-        let boot_time = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let boot_time = config.clock.secs();
+        // clients see the export root as "/", a directory below the root of
+        // the backend becomes the root of a file system of its own
+        let root = if root.as_str().is_empty() {
+            root
+        } else {
+            VfsPath::new(AltrootFS::new(root))
+        };
         let mut fmanager = FileManager {
             receiver,
             root: root.clone(),
-            // lease time in seconds
-            lease_time: 60,
-            hard_link_support: false,
-            symlink_support: false,
+            lease_time: LEASE_TIME,
+            hard_link_support: config.special_files.is_some(),
+            symlink_support: config.special_files.is_some(),
+            special_files: config.special_files,
+            max_link: config.max_link,
             unique_handles: false,
             boot_time,
+            clock: config.clock,
             fsid,
+            filehandle_strategy: config.filehandle_strategy,
+            metadata: config.metadata,
+            max_opens: config.max_opens,
+            max_opens_per_client: config.max_opens_per_client,
+            max_owners_per_client: config.max_owners_per_client,
+            max_path_depth: config.max_path_depth,
+            file_mode: config.file_mode,
+            dir_mode: config.dir_mode,
+            quota: config.quota,
+            files_total: config.files_total,
+            refresh_stale_filehandles: config.refresh_stale_filehandles,
             next_fh_id: 100,
             next_stateid_id: 100,
             fhdb: FilehandleDb::default(),
+            retired: VecDeque::new(),
             lockdb: LockingStateDb::default(),
             cachedb: HashMap::new(),
+            owner_replays: HashMap::new(),
         };
         // always have a root filehandle upon start
         fmanager.root_fh();
@@ -76,65 +205,162 @@ impl FileManager {
             FileManagerMessage::GetRootFilehandle(req) => {
                 let fh_wo_locks = self.root_fh();
                 let fh = self.attach_locks(fh_wo_locks);
-                req.respond_to.send(fh).unwrap();
+                respond(req.respond_to, fh);
             }
             FileManagerMessage::GetFilehandle(req) => {
-                if req.filehandle.is_some() {
-                    let fh = self.get_filehandle_by_id(&req.filehandle.unwrap());
+                if let Some(filehandle) = req.filehandle {
+                    let fh = self
+                        .get_filehandle_by_id(&filehandle)
+                        .or_else(|| self.refresh_filehandle(&filehandle));
                     match fh {
                         Some(fh_wo_locks) => {
                             let fh = self.attach_locks(fh_wo_locks);
-                            req.respond_to.send(Some(fh)).unwrap();
+                            respond(req.respond_to, Ok(fh));
                         }
                         None => {
                             debug!("Filehandle not found");
-                            req.respond_to.send(None).unwrap();
+                            // https://datatracker.ietf.org/doc/html/rfc7530#section-4.2.3
+                            // If the server can definitively determine that a
+                            // volatile filehandle refers to an object that has been removed, the
+                            // server should return NFS4ERR_STALE to the client (as is the case for
+                            // persistent filehandles)
+                            let err = self
+                                .filehandle_strategy
+                                .unknown_filehandle_error(&filehandle, self.boot_time);
+                            respond(req.respond_to, Err(err));
                         }
                     }
-                } else if req.path.is_some() {
-                    let path = self.root.join(req.path.unwrap()).unwrap();
+                } else if let Some(path) = req.path {
+                    if let Err(e) = self.check_path_depth(&path) {
+                        respond(req.respond_to, Err(e));
+                        return;
+                    }
+                    let path = self.root.join(path).unwrap();
                     // check if file exists
-                    if path.exists().unwrap() {
+                    if self.path_exists(&path) {
                         let fh_wo_locks = self.get_filehandle(&path);
                         let fh = self.attach_locks(fh_wo_locks);
-                        req.respond_to.send(Some(fh)).unwrap();
+                        respond(req.respond_to, Ok(fh));
                     } else {
                         debug!("File not found {:?}", path);
-                        req.respond_to.send(None).unwrap();
+                        respond(req.respond_to, Err(NfsStat4::Nfs4errNoent));
                     }
                 } else {
                     let fh_wo_locks = self.root_fh();
                     let fh = self.attach_locks(fh_wo_locks);
-                    req.respond_to.send(Some(fh)).unwrap();
+                    respond(req.respond_to, Ok(fh));
                 }
             }
             FileManagerMessage::GetFilehandleAttrs(req) => {
-                req.respond_to
-                    .send(self.filehandle_attrs(&req.attrs_request, &req.filehandle_id))
-                    .unwrap();
+                respond(
+                    req.respond_to,
+                    self.filehandle_attrs(&req.attrs_request, &req.filehandle_id),
+                );
+            }
+            FileManagerMessage::GetFilehandleAttrsMany(req) => {
+                let results = req
+                    .paths
+                    .iter()
+                    .map(|path| {
+                        self.check_path_depth(path)?;
+                        let path = self.root.join(path).map_err(|e| vfs_error_status(&e))?;
+                        if !self.path_exists(&path) {
+                            debug!("File not found {:?}", path);
+                            return Err(NfsStat4::Nfs4errNoent);
+                        }
+                        let fh = self.get_filehandle(&path);
+                        let (answer_attrs, attrs) = self
+                            .filehandle_attrs(&req.attrs_request, &fh.id)
+                            .ok_or(NfsStat4::Nfs4errServerfault)?;
+                        Ok((self.attach_locks(fh), answer_attrs, attrs))
+                    })
+                    .collect();
+                respond(req.respond_to, results);
             }
             FileManagerMessage::CreateFile(req) => {
-                let fh = self.create_file(&req.path);
-                if let Some(mut fh) = fh {
-                    let stateid = self.get_new_lockingstate_id();
-                    let lock = LockingState::new_shared_reservation(
-                        fh.id.clone(),
-                        stateid,
-                        req.client_id,
-                        req.owner,
-                        req.share_access,
-                        req.share_deny,
-                    );
-                    // add this new locking state to the db
-                    self.lockdb.insert(lock.clone());
-                    fh.locks = vec![lock];
-                    req.respond_to.send(Some(fh)).unwrap();
-                } else {
-                    req.respond_to.send(None).unwrap();
-                }
+                let res = self
+                    .check_path_depth(req.path.as_str())
+                    .and_then(|_| self.check_open_limits(req.client_id, &req.owner))
+                    .and_then(|_| self.create_or_open_file(&req.path, req.verifier))
+                    .map(|fh| {
+                        self.add_open(
+                            fh,
+                            req.client_id,
+                            req.owner,
+                            req.share_access,
+                            req.share_deny,
+                        )
+                    });
+                respond(req.respond_to, res);
+            }
+            FileManagerMessage::OpenFile(req) => {
+                let res = self
+                    .check_path_depth(req.path.as_str())
+                    .and_then(|_| self.existing_file(&req.path))
+                    .and_then(|fh| {
+                        self.check_open_limits(req.client_id, &req.owner)?;
+                        Ok(self.add_open(
+                            fh,
+                            req.client_id,
+                            req.owner,
+                            req.share_access,
+                            req.share_deny,
+                        ))
+                    });
+                respond(req.respond_to, res);
+            }
+            FileManagerMessage::CreateSymlink(req) => {
+                respond(req.respond_to, self.create_symlink(&req.path, &req.target));
+            }
+            FileManagerMessage::CreateDevice(req) => {
+                respond(
+                    req.respond_to,
+                    self.create_device(&req.path, req.ftype, req.rawdev),
+                );
             }
             FileManagerMessage::LockFile() => todo!(),
-            FileManagerMessage::CloseFile() => todo!(),
+            FileManagerMessage::ReleaseLockowner(req) => {
+                respond(
+                    req.respond_to,
+                    self.release_lockowner(req.client_id, &req.owner),
+                );
+            }
+            FileManagerMessage::ReleaseClient(req) => {
+                self.release_client(req.client_id);
+                respond(req.respond_to, ());
+            }
+            FileManagerMessage::CloseFile(req) => {
+                let res = self.bump_stateid(&req.stateid).inspect(|stateid| {
+                    self.lockdb.remove_by_stateid(&stateid.other);
+                });
+                respond(req.respond_to, res);
+            }
+            FileManagerMessage::CheckStateidAccess(req) => {
+                respond(
+                    req.respond_to,
+                    self.check_stateid_access(&req.stateid, &req.filehandle_id, req.access),
+                );
+            }
+            FileManagerMessage::SetMetadata(req) => {
+                respond(
+                    req.respond_to,
+                    self.set_metadata(&req.filehandle_id, req.metadata),
+                );
+            }
+            FileManagerMessage::UpdateStateid(req) => {
+                respond(req.respond_to, self.bump_stateid(&req.stateid));
+            }
+            FileManagerMessage::ConfirmOpen(req) => {
+                respond(req.respond_to, self.confirm_open(&req.stateid, req.seqid));
+            }
+            FileManagerMessage::DowngradeOpen(req) => {
+                let res =
+                    self.downgrade_open(&req.stateid, req.seqid, req.share_access, req.share_deny);
+                respond(req.respond_to, res);
+            }
+            FileManagerMessage::DumpLocks(req) => {
+                respond(req.respond_to, self.dump_locks());
+            }
             FileManagerMessage::RemoveFile(req) => {
                 let filehandle = self.get_filehandle_by_path(&req.path.as_str().to_string());
                 let mut parent_path = req.path.parent().as_str().to_string();
@@ -146,7 +372,7 @@ impl FileManager {
                         } else {
                             let _ = req.path.remove_file();
                         }
-                        self.fhdb.remove_by_id(&filehandle.id);
+                        self.retire_filehandle(filehandle);
                     }
                     None => {
                         if req.path.is_dir().unwrap() {
@@ -156,6 +382,8 @@ impl FileManager {
                         }
                     }
                 }
+                // whatever is created at the path next starts out fresh
+                self.forget_metadata(req.path.as_str());
 
                 if parent_path.is_empty() {
                     // this is root
@@ -165,7 +393,13 @@ impl FileManager {
                 let parent_filehandle = self.get_filehandle_by_path(&parent_path).unwrap();
                 // TODO: check locks
                 self.touch_filehandle(parent_filehandle);
-                req.respond_to.send(()).unwrap()
+                respond(req.respond_to, ())
+            }
+            FileManagerMessage::RenameFile(req) => {
+                respond(req.respond_to, self.rename_file(&req.from, &req.to));
+            }
+            FileManagerMessage::LinkFile(req) => {
+                respond(req.respond_to, self.link_file(&req.existing, &req.path));
             }
             FileManagerMessage::TouchFile(req) => {
                 let filehandle = self.get_filehandle_by_id(&req.id);
@@ -181,7 +415,7 @@ impl FileManager {
             }
             FileManagerMessage::GetWriteCacheHandle(req) => {
                 let handle = self.get_cache_handle(req.filehandle, req.filemanager);
-                req.respond_to.send(handle).unwrap();
+                respond(req.respond_to, handle);
             }
             FileManagerMessage::DropWriteCacheHandle(req) => {
                 self.drop_cache_handle(&req.filehandle_id);
@@ -194,13 +428,7 @@ impl FileManager {
 
     fn touch_filehandle(&mut self, filehandle: Filehandle) {
         // create a new filehandle with refreshed attributes
-        let fh = Filehandle::new(
-            filehandle.file.clone(),
-            filehandle.id.clone(),
-            self.fsid,
-            self.fsid,
-            filehandle.version,
-        );
+        let fh = self.new_filehandle(&filehandle.file, filehandle.id, filehandle.version);
         self.fhdb.remove_by_id(&filehandle.id);
         debug!("Touching filehandle: {:?}", fh);
         // and replace the old one
@@ -214,6 +442,74 @@ impl FileManager {
         self.fhdb.insert(filehandle);
     }
 
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-16.16.5
+    // UNCHECKED4 opens an existing file as it is. EXCLUSIVE4 only does so
+    // for a retransmission of the OPEN that created it, i.e. if the file
+    // was created with the same verifier, it's NFS4ERR_EXIST otherwise.
+    fn create_or_open_file(
+        &mut self,
+        path: &VfsPath,
+        verifier: Option<[u8; 8]>,
+    ) -> Result<Filehandle, NfsStat4> {
+        if path.is_file().unwrap_or(false) {
+            let created_with = self.metadata.get(path.as_str()).and_then(|m| m.verifier);
+            if verifier.is_some() && created_with != verifier {
+                debug!("File {:?} exists, created with another verifier", path);
+                return Err(NfsStat4::Nfs4errExist);
+            }
+            return Ok(self.get_filehandle(path));
+        }
+        if verifier.is_some() {
+            // the filehandle of the new file picks it up
+            self.metadata
+                .entry(path.as_str().to_string())
+                .or_default()
+                .verifier = verifier;
+        }
+        // TODO: check if this is the correct error
+        self.create_file(path).ok_or_else(|| {
+            self.forget_metadata(path.as_str());
+            NfsStat4::Nfs4errBadhandle
+        })
+    }
+
+    // the file an OPEN without OPEN4_CREATE refers to
+    fn existing_file(&mut self, path: &VfsPath) -> Result<Filehandle, NfsStat4> {
+        if !self.path_exists(path) {
+            debug!("File not found {:?}", path);
+            return Err(NfsStat4::Nfs4errNoent);
+        }
+        if path.is_dir().unwrap_or(false) {
+            return Err(NfsStat4::Nfs4errIsdir);
+        }
+        Ok(self.get_filehandle(path))
+    }
+
+    // record the share reservation of an open of `fh`, it's the only lock
+    // of the returned filehandle
+    fn add_open(
+        &mut self,
+        mut fh: Filehandle,
+        client_id: u64,
+        owner: Vec<u8>,
+        share_access: u32,
+        share_deny: u32,
+    ) -> Filehandle {
+        let stateid = self.get_new_lockingstate_id();
+        let lock = LockingState::new_shared_reservation(
+            fh.id,
+            stateid,
+            client_id,
+            owner,
+            share_access,
+            share_deny,
+        );
+        // add this new locking state to the db
+        self.lockdb.insert(lock.clone());
+        fh.locks = vec![lock];
+        fh
+    }
+
     fn create_file(&mut self, request_file: &VfsPath) -> Option<Filehandle> {
         let newfile = match request_file.create_file() {
             Ok(_) => {
@@ -240,6 +536,530 @@ impl FileManager {
         Some(fh)
     }
 
+    // move a file or directory, the filehandles of it and of everything
+    // below it keep their ids and follow it to the new path
+    fn rename_file(&mut self, from: &VfsPath, to: &VfsPath) -> Result<(), NfsStat4> {
+        if !self.path_exists(from) {
+            return Err(NfsStat4::Nfs4errNoent);
+        }
+        let old_path = from.as_str().to_string();
+        let new_path = to.as_str().to_string();
+        if old_path == new_path {
+            return Ok(());
+        }
+        let prefix = format!("{}/", old_path);
+        let is_dir = from.is_dir().unwrap_or(false);
+        // a directory can't be moved below itself
+        if new_path.starts_with(&prefix) {
+            return Err(NfsStat4::Nfs4errInval);
+        }
+
+        // an existing target is replaced by an object of the same kind, a
+        // directory only if it's empty
+        if self.path_exists(to) {
+            if to.is_dir().unwrap_or(false) != is_dir {
+                return Err(NfsStat4::Nfs4errExist);
+            }
+            let removed = if is_dir {
+                if to
+                    .read_dir()
+                    .map_or(true, |mut entries| entries.next().is_some())
+                {
+                    return Err(NfsStat4::Nfs4errExist);
+                }
+                to.remove_dir()
+            } else {
+                to.remove_file()
+            };
+            if let Err(e) = removed {
+                error!("Couldn't replace {:?}: {:?}", new_path, e);
+                return Err(vfs_error_status(&e));
+            }
+            if let Some(replaced) = self.get_filehandle_by_path(&new_path) {
+                self.retire_filehandle(replaced);
+            }
+            self.forget_metadata(&new_path);
+        }
+
+        let moved = if is_dir {
+            from.move_dir(to)
+        } else {
+            from.move_file(to)
+        };
+        if let Err(e) = moved {
+            error!("Couldn't move {:?} to {:?}: {:?}", old_path, new_path, e);
+            return Err(vfs_error_status(&e));
+        }
+
+        let moved: Vec<Filehandle> = self
+            .fhdb
+            .iter()
+            .filter(|(_, fh)| fh.path == old_path || fh.path.starts_with(&prefix))
+            .map(|(_, fh)| fh.clone())
+            .collect();
+        for mut fh in moved {
+            self.fhdb.remove_by_id(&fh.id);
+            if fh.path != old_path {
+                let below = fh.path[prefix.len()..].to_string();
+                fh.file = to.join(&below).unwrap();
+                fh.path = format!("{}/{}", new_path, below);
+            } else {
+                fh.file = to.clone();
+                fh.path = new_path.clone();
+            }
+            debug!("Moved filehandle: {:?}", fh);
+            self.fhdb.insert(fh);
+        }
+        // the metadata follows the objects as well
+        let moved: Vec<String> = self
+            .metadata
+            .keys()
+            .filter(|path| **path == old_path || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in moved {
+            let metadata = self.metadata.remove(&path).unwrap();
+            let path = format!("{}{}", new_path, &path[old_path.len()..]);
+            self.metadata.insert(path, metadata);
+        }
+
+        // both directories changed
+        for parent in [from.parent(), to.parent()] {
+            let mut path = parent.as_str().to_string();
+            if path.is_empty() {
+                path = "/".to_string();
+            }
+            if let Some(parent) = self.get_filehandle_by_path(&path) {
+                self.touch_filehandle(parent);
+            }
+        }
+        Ok(())
+    }
+
+    // symlinks and devices are created in the backend, if it can hold them
+    fn create_symlink(&mut self, path: &VfsPath, target: &str) -> Result<Filehandle, NfsStat4> {
+        self.create_special_file(path, |special_files| {
+            special_files.symlink(path.as_str(), target)
+        })
+    }
+
+    fn create_device(
+        &mut self,
+        path: &VfsPath,
+        ftype: NfsFtype4,
+        rawdev: Specdata4,
+    ) -> Result<Filehandle, NfsStat4> {
+        self.create_special_file(path, |special_files| {
+            special_files.mknod(path.as_str(), ftype, rawdev)
+        })
+    }
+
+    fn create_special_file(
+        &mut self,
+        path: &VfsPath,
+        create: impl FnOnce(&dyn SpecialFiles) -> io::Result<()>,
+    ) -> Result<Filehandle, NfsStat4> {
+        let special_files = match &self.special_files {
+            Some(special_files) => special_files.clone(),
+            None => return Err(NfsStat4::Nfs4errNotsupp),
+        };
+        self.check_path_depth(path.as_str())?;
+        if self.path_exists(path) {
+            return Err(NfsStat4::Nfs4errExist);
+        }
+        if let Err(e) = create(special_files.as_ref()) {
+            error!("Error creating {:?}: {:?}", path.as_str(), e);
+            return Err(io_error_status(&e));
+        }
+
+        let fh = self.get_filehandle(path);
+        self.touch_parent(path);
+        Ok(fh)
+    }
+
+    // another name for an existing file, as long as the file has fewer
+    // than maxlink names
+    fn link_file(&mut self, existing: &VfsPath, path: &VfsPath) -> Result<(), NfsStat4> {
+        let special_files = match &self.special_files {
+            Some(special_files) => special_files.clone(),
+            None => return Err(NfsStat4::Nfs4errNotsupp),
+        };
+        self.check_path_depth(path.as_str())?;
+        if existing.is_dir().unwrap_or(false) {
+            return Err(NfsStat4::Nfs4errIsdir);
+        }
+        if self.path_exists(path) {
+            return Err(NfsStat4::Nfs4errExist);
+        }
+        if let Some(max_link) = self.max_link {
+            let links = special_files
+                .link_count(existing.as_str())
+                .map_err(|e| io_error_status(&e))?;
+            if links >= max_link {
+                error!("{:?} already has {} links", existing.as_str(), links);
+                return Err(NfsStat4::Nfs4errMlink);
+            }
+        }
+        if let Err(e) = special_files.hard_link(existing.as_str(), path.as_str()) {
+            error!("Error linking {:?}: {:?}", path.as_str(), e);
+            return Err(io_error_status(&e));
+        }
+
+        // the file has one more link now
+        if let Some(filehandle) = self.get_filehandle_by_path(&existing.as_str().to_string()) {
+            self.touch_filehandle(filehandle);
+        }
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    fn touch_parent(&mut self, path: &VfsPath) {
+        let mut parent_path = path.parent().as_str().to_string();
+        if parent_path.is_empty() {
+            parent_path = "/".to_string();
+        }
+        if let Some(parent_filehandle) = self.get_filehandle_by_path(&parent_path) {
+            self.touch_filehandle(parent_filehandle);
+        }
+    }
+
+    // the type of a symlink or device in the backend, the vfs file systems
+    // only see regular files and directories
+    fn special_type(&self, path: &VfsPath) -> Option<(NfsFtype4, Specdata4)> {
+        let special_files = self.special_files.as_ref()?;
+        special_files
+            .special_type(path.as_str())
+            .inspect_err(|e| error!("Couldn't get the type of {:?}: {:?}", path.as_str(), e))
+            .ok()
+            .flatten()
+    }
+
+    fn read_link(&self, path: &VfsPath) -> Option<String> {
+        let special_files = self.special_files.as_ref()?;
+        special_files
+            .read_link(path.as_str())
+            .inspect_err(|e| error!("Couldn't read symlink {:?}: {:?}", path.as_str(), e))
+            .ok()
+    }
+
+    // a symlink exists even if its target doesn't
+    fn path_exists(&self, path: &VfsPath) -> bool {
+        path.exists().unwrap_or(false) || self.special_type(path).is_some()
+    }
+
+    // drop the metadata of a removed object and of everything below it
+    fn forget_metadata(&mut self, path: &str) {
+        let prefix = format!("{}/", path);
+        self.metadata
+            .retain(|key, _| key != path && !key.starts_with(&prefix));
+    }
+
+    // paths deeper than the configured limit are refused with
+    // NFS4ERR_NAMETOOLONG, before they are resolved in the backend
+    fn check_path_depth(&self, path: &str) -> Result<(), NfsStat4> {
+        if let Some(max_path_depth) = self.max_path_depth {
+            let depth = path.split('/').filter(|c| !c.is_empty()).count();
+            if depth > max_path_depth {
+                error!(
+                    "Path {:?} exceeds the maximum depth {}",
+                    path, max_path_depth
+                );
+                return Err(NfsStat4::Nfs4errNametoolong);
+            }
+        }
+        Ok(())
+    }
+
+    // opens are tracked as share reservations in the locking db, refuse new
+    // ones if the server or the client already holds the maximum, or if
+    // they'd add an owner beyond the client's limit
+    fn check_open_limits(&self, client_id: u64, owner: &[u8]) -> Result<(), NfsStat4> {
+        if let Some(max_opens) = self.max_opens {
+            let opens = self
+                .lockdb
+                .iter()
+                .filter(|(_, lock)| lock.lock_type == LockType::Open)
+                .count();
+            if opens >= max_opens {
+                error!("Maximum number of opens reached: {}", max_opens);
+                return Err(NfsStat4::Nfs4errResource);
+            }
+        }
+        if let Some(max_opens) = self.max_opens_per_client {
+            let opens = self
+                .lockdb
+                .get_by_client_id(&client_id)
+                .into_iter()
+                .filter(|lock| lock.lock_type == LockType::Open)
+                .count();
+            if opens >= max_opens {
+                error!(
+                    "Maximum number of opens reached for client {}: {}",
+                    client_id, max_opens
+                );
+                return Err(NfsStat4::Nfs4errResource);
+            }
+        }
+        if let Some(max_owners) = self.max_owners_per_client {
+            // an owner takes up a slot as long as it holds any state
+            let owners: HashSet<&[u8]> = self
+                .lockdb
+                .get_by_client_id(&client_id)
+                .into_iter()
+                .map(|lock| lock.owner.as_slice())
+                .collect();
+            if !owners.contains(owner) && owners.len() >= max_owners {
+                error!(
+                    "Maximum number of owners reached for client {}: {}",
+                    client_id, max_owners
+                );
+                return Err(NfsStat4::Nfs4errResource);
+            }
+        }
+        Ok(())
+    }
+
+    // forget everything a client holds, its opens and locks as well as the
+    // seqid history of its owners
+    fn release_client(&mut self, client_id: u64) {
+        let locks = self.lockdb.remove_by_client_id(&client_id);
+        debug!("Released {} locks of client {}", locks.len(), client_id);
+        self.owner_replays
+            .retain(|(owner_client_id, _), _| *owner_client_id != client_id);
+    }
+
+    // all locking states, ordered by client and owner so the ones that
+    // belong together are next to each other
+    fn dump_locks(&self) -> Vec<LockingState> {
+        let mut locks: Vec<LockingState> =
+            self.lockdb.iter().map(|(_, lock)| lock.clone()).collect();
+        locks.sort_by(|a, b| {
+            (a.client_id, &a.owner, a.stateid).cmp(&(b.client_id, &b.owner, b.stateid))
+        });
+        debug!("Locking state dump, {} entries", locks.len());
+        for lock in &locks {
+            debug!(
+                "{:?} stateid {:?} seqid {} client {} owner {:?} file {:?} access {:?} deny {:?} range {:?}+{:?}",
+                lock.lock_type,
+                lock.stateid,
+                lock.seqid,
+                lock.client_id,
+                lock.owner,
+                lock.filehandle_id,
+                lock.share_access,
+                lock.share_deny,
+                lock.start,
+                lock.length
+            );
+        }
+        locks
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-16.37
+    // a lock owner can only be released once it holds no more locks. Byte
+    // range state is dropped together with the last lock, so there is
+    // nothing left to free after that.
+    fn release_lockowner(&self, client_id: u64, owner: &[u8]) -> Result<(), NfsStat4> {
+        let locks_held = self
+            .lockdb
+            .get_by_client_id(&client_id)
+            .into_iter()
+            .any(|lock| lock.owner == owner && lock.lock_type == LockType::ByteRange);
+        if locks_held {
+            error!("Lock owner {:?} still holds locks", owner);
+            return Err(NfsStat4::Nfs4errLocksHeld);
+        }
+        Ok(())
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-9.1.4.2
+    // a seqid older than the current one is NFS4ERR_OLD_STATEID, one the
+    // server never handed out is NFS4ERR_BAD_STATEID
+    fn check_stateid(&self, stateid: &Stateid4) -> Result<&LockingState, NfsStat4> {
+        let lock = match self.lockdb.get_by_stateid(&stateid.other) {
+            Some(lock) => lock,
+            None => {
+                debug!("Unknown stateid {:?}", stateid);
+                return Err(NfsStat4::Nfs4errBadStateid);
+            }
+        };
+        match stateid.seqid.cmp(&lock.seqid) {
+            Ordering::Equal => Ok(lock),
+            Ordering::Less => {
+                debug!("Old stateid {:?}, current seqid {}", stateid, lock.seqid);
+                Err(NfsStat4::Nfs4errOldStateid)
+            }
+            Ordering::Greater => {
+                debug!("Future stateid {:?}, current seqid {}", stateid, lock.seqid);
+                Err(NfsStat4::Nfs4errBadStateid)
+            }
+        }
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-9.1.4.3
+    // READ and WRITE have to present a stateid of the file that was opened
+    // with the access they need, otherwise it's NFS4ERR_OPENMODE. The
+    // special stateids aren't bound to an open.
+    fn check_stateid_access(
+        &self,
+        stateid: &Stateid4,
+        filehandle_id: &NfsFh4,
+        access: u32,
+    ) -> Result<(), NfsStat4> {
+        if stateid.other == [0; 12] || stateid.other == [0xff; 12] {
+            return self.check_special_stateid_access(stateid, filehandle_id, access);
+        }
+        // an old stateid of an open that was downgraded since must not
+        // grant the access it had before, so the current state is checked
+        // first
+        let (lock, checked) = match self.check_stateid(stateid) {
+            Ok(lock) => (lock, Ok(())),
+            Err(NfsStat4::Nfs4errOldStateid) => (
+                self.lockdb.get_by_stateid(&stateid.other).unwrap(),
+                Err(NfsStat4::Nfs4errOldStateid),
+            ),
+            Err(e) => return Err(e),
+        };
+        if lock.filehandle_id != *filehandle_id {
+            debug!("Stateid {:?} belongs to another file", stateid);
+            return Err(NfsStat4::Nfs4errBadStateid);
+        }
+        if lock.lock_type == LockType::Open && lock.share_access.unwrap_or(0) & access == 0 {
+            debug!(
+                "Stateid {:?} is open with access {:?}, {} required",
+                stateid, lock.share_access, access
+            );
+            return Err(NfsStat4::Nfs4errOpenmode);
+        }
+        checked
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-9.1.4.3
+    // The anonymous stateid (all zeros) stands for I/O without an open, it
+    // must not conflict with the deny modes of the opens of the file. The
+    // READ bypass stateid (all ones) lets READ ignore them, any other
+    // operation treats it like the anonymous one. The seqid of either must
+    // match the other field.
+    fn check_special_stateid_access(
+        &self,
+        stateid: &Stateid4,
+        filehandle_id: &NfsFh4,
+        access: u32,
+    ) -> Result<(), NfsStat4> {
+        let bypass = stateid.other == [0xff; 12];
+        let seqid = if bypass { u32::MAX } else { 0 };
+        if stateid.seqid != seqid {
+            debug!("Special stateid {:?} with a bad seqid", stateid);
+            return Err(NfsStat4::Nfs4errBadStateid);
+        }
+        if bypass && access == OPEN4_SHARE_ACCESS_READ {
+            return Ok(());
+        }
+        let denied = self
+            .lockdb
+            .get_by_filehandle_id(filehandle_id)
+            .iter()
+            .any(|lock| lock.share_deny.unwrap_or(0) & access != 0);
+        if denied {
+            debug!("Access {} denied by an open of {:?}", access, filehandle_id);
+            return Err(NfsStat4::Nfs4errLocked);
+        }
+        Ok(())
+    }
+
+    // every operation that changes the state behind a stateid increments
+    // its seqid
+    fn bump_stateid(&mut self, stateid: &Stateid4) -> Result<Stateid4, NfsStat4> {
+        let mut lock = self.check_stateid(stateid)?.clone();
+        lock.seqid = lock.seqid.wrapping_add(1);
+        let stateid = Stateid4 {
+            seqid: lock.seqid,
+            other: lock.stateid,
+        };
+        self.lockdb.remove_by_stateid(&lock.stateid);
+        self.lockdb.insert(lock);
+        Ok(stateid)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-9.1.7
+    // The requests of an open owner that change its state carry a seqid
+    // that increases by one with each of them. The server keeps the last
+    // reply of an open owner, a retransmission gets it again instead of
+    // failing on the bumped stateid, and a seqid out of order is
+    // NFS4ERR_BAD_SEQID.
+    fn sequence_owner_request(
+        &mut self,
+        stateid: &Stateid4,
+        seqid: u32,
+        request: impl FnOnce(&mut Self) -> Result<Stateid4, NfsStat4>,
+    ) -> Result<Stateid4, NfsStat4> {
+        let owner = match self.lockdb.get_by_stateid(&stateid.other) {
+            Some(lock) => (lock.client_id, lock.owner.clone()),
+            None => {
+                debug!("Unknown stateid {:?}", stateid);
+                return Err(NfsStat4::Nfs4errBadStateid);
+            }
+        };
+        if let Some(replay) = self.owner_replays.get(&owner) {
+            if replay.seqid == seqid && replay.stateid == *stateid {
+                debug!("Replaying seqid {} of {:?}", seqid, owner);
+                return replay.result.clone();
+            }
+            if seqid != replay.seqid.wrapping_add(1) {
+                debug!("Seqid {} after {} of {:?}", seqid, replay.seqid, owner);
+                return Err(NfsStat4::Nfs4errBadSeqid);
+            }
+        }
+        let result = request(self);
+        self.owner_replays.insert(
+            owner,
+            OwnerReplay {
+                seqid,
+                stateid: stateid.clone(),
+                result: result.clone(),
+            },
+        );
+        result
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-9.1.8
+    fn confirm_open(&mut self, stateid: &Stateid4, seqid: u32) -> Result<Stateid4, NfsStat4> {
+        self.sequence_owner_request(stateid, seqid, |fm| fm.bump_stateid(stateid))
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7530#section-16.19.4
+    // An open can only give up some of its access and deny modes, asking
+    // for any it doesn't already have is NFS4ERR_INVAL.
+    fn downgrade_open(
+        &mut self,
+        stateid: &Stateid4,
+        seqid: u32,
+        share_access: u32,
+        share_deny: u32,
+    ) -> Result<Stateid4, NfsStat4> {
+        self.sequence_owner_request(stateid, seqid, |fm| {
+            let mut lock = fm.check_stateid(stateid)?.clone();
+            if lock.lock_type != LockType::Open {
+                debug!("Stateid {:?} isn't an open", stateid);
+                return Err(NfsStat4::Nfs4errBadStateid);
+            }
+            let access = lock.share_access.unwrap_or(0);
+            let deny = lock.share_deny.unwrap_or(0);
+            if share_access == 0 || share_access & !access != 0 || share_deny & !deny != 0 {
+                debug!(
+                    "Can't downgrade {}/{} to {}/{}",
+                    access, deny, share_access, share_deny
+                );
+                return Err(NfsStat4::Nfs4errInval);
+            }
+            lock.share_access = Some(share_access);
+            lock.share_deny = Some(share_deny);
+            fm.lockdb.remove_by_stateid(&lock.stateid);
+            fm.lockdb.insert(lock);
+            fm.bump_stateid(stateid)
+        })
+    }
+
     fn get_new_lockingstate_id(&mut self) -> [u8; 12] {
         // create a new unique lockingstate id
         let mut id = vec![0_u8, 0_u8, 0_u8, 0_u8];
@@ -275,18 +1095,54 @@ impl FileManager {
     fn get_filehandle_by_id(&mut self, id: &NfsFh4) -> Option<Filehandle> {
         let fh = self.fhdb.get_by_id(id);
         if let Some(fh) = fh {
-            if fh.file.exists().unwrap() {
+            if self.path_exists(&fh.file) {
                 debug!("Found filehandle: {:?}", fh);
                 return Some(fh.clone());
             } else {
                 // this filehandle is stale, remove it
                 debug!("Removing stale filehandle: {:?}", fh);
-                self.fhdb.remove_by_id(id);
+                let fh = fh.clone();
+                self.retire_filehandle(fh);
             }
         }
         None
     }
 
+    // forget the filehandle of a removed object, unless it may be refreshed
+    // once its path is back
+    fn retire_filehandle(&mut self, filehandle: Filehandle) {
+        self.fhdb.remove_by_id(&filehandle.id);
+        if self.refresh_stale_filehandles {
+            // a refreshed filehandle may be retired again
+            self.retired.retain(|retired| retired.id != filehandle.id);
+            if self.retired.len() >= MAX_RETIRED_FILEHANDLES {
+                self.retired.pop_front();
+            }
+            self.retired.push_back(filehandle);
+        }
+    }
+
+    // the filehandle of an object of the same type that took the place of
+    // the one a retired filehandle referred to
+    fn refresh_filehandle(&mut self, id: &NfsFh4) -> Option<Filehandle> {
+        let idx = self.retired.iter().position(|retired| retired.id == *id)?;
+        let retired = &self.retired[idx];
+        let is_dir = retired.attr_type == NfsFtype4::Nf4dir;
+        if !self.path_exists(&retired.file) || retired.file.is_dir().unwrap_or(false) != is_dir {
+            return None;
+        }
+        debug!("Refreshing stale filehandle of {:?}", retired.path);
+        if let Some(fh) = self.get_filehandle_by_path(&retired.path) {
+            // the new object already has a filehandle of its own
+            return Some(fh);
+        }
+        // nobody asked for the new object yet, it inherits the old id
+        let retired = self.retired.remove(idx)?;
+        let fh = self.new_filehandle(&retired.file, *id, retired.version + 1);
+        self.fhdb.insert(fh.clone());
+        Some(fh)
+    }
+
     pub fn get_filehandle_by_path(&self, path: &String) -> Option<Filehandle> {
         debug!("get_filehandle_by_path: {}", path);
         self.fhdb.get_by_path(path).cloned()
@@ -297,7 +1153,7 @@ impl FileManager {
         match self.get_filehandle_by_id(&id) {
             Some(fh) => fh.clone(),
             None => {
-                let fh = Filehandle::new(file.clone(), id, self.fsid, self.fsid, 0);
+                let fh = self.new_filehandle(file, id, 0);
                 debug!("Storing new filehandle: {:?}", fh);
                 self.fhdb.insert(fh.clone());
                 fh
@@ -305,6 +1161,73 @@ impl FileManager {
         }
     }
 
+    fn new_filehandle(&self, file: &VfsPath, id: NfsFh4, version: u64) -> Filehandle {
+        let mut path = file.as_str();
+        if path.is_empty() {
+            path = "/";
+        }
+        let mut metadata = self.metadata.get(path).cloned().unwrap_or_default();
+        // the backend knows best what the object is, this survives restarts
+        let special_type = self.special_type(file);
+        if let Some((ftype, rawdev)) = special_type {
+            metadata.ftype = Some(ftype);
+            metadata.rawdev = Some(rawdev);
+        }
+        if metadata.mode.is_none() {
+            metadata.mode = if file.is_dir().unwrap_or(false) {
+                self.dir_mode
+            } else {
+                self.file_mode
+            };
+        }
+        let mut fh = Filehandle::new(
+            file.clone(),
+            id,
+            self.fsid,
+            self.fsid,
+            version,
+            Some(&metadata),
+            self.clock.now(),
+        );
+        if let Some(special_files) = &self.special_files {
+            if let Ok(links) = special_files.link_count(fh.path.as_str()) {
+                fh.attr_numlinks = links;
+            }
+        }
+        // the size of a symlink is that of the link text, not of its target
+        if fh.attr_type == NfsFtype4::Nf4lnk {
+            if let Some(target) = self.read_link(file) {
+                fh.attr_size = target.len() as u64;
+                fh.attr_space_used = fh.attr_size;
+            }
+        }
+        fh
+    }
+
+    // attributes set by clients are kept in the metadata, so they survive
+    // refreshing the filehandle's attributes
+    fn set_metadata(
+        &mut self,
+        id: &NfsFh4,
+        metadata: FileMetadata,
+    ) -> Result<Filehandle, NfsStat4> {
+        let filehandle = match self.get_filehandle_by_id(id) {
+            Some(filehandle) => filehandle,
+            None => {
+                return Err(self
+                    .filehandle_strategy
+                    .unknown_filehandle_error(id, self.boot_time))
+            }
+        };
+        self.metadata
+            .entry(filehandle.path.clone())
+            .or_default()
+            .update(metadata);
+        self.touch_filehandle(filehandle);
+        let filehandle = self.get_filehandle_by_id(id).unwrap();
+        Ok(self.attach_locks(filehandle))
+    }
+
     pub fn root_fh(&mut self) -> Filehandle {
         self.get_filehandle(&self.root.clone())
     }
@@ -320,12 +1243,12 @@ impl FileManager {
         mut filehandle: Filehandle,
         filemanager: FileManagerHandle,
     ) -> WriteCacheHandle {
-        if self.cachedb.contains_key(&filehandle.id) {
-            self.cachedb.get(&filehandle.id).unwrap().clone()
+        if let Some(handle) = self.cachedb.get(&filehandle.id) {
+            handle.clone()
         } else {
             let handle = WriteCacheHandle::new(filehandle.clone(), filemanager);
             filehandle.write_cache = Some(handle.clone());
-            self.cachedb.insert(filehandle.id.clone(), handle.clone());
+            self.cachedb.insert(filehandle.id, handle.clone());
             self.update_filehandle(filehandle);
             handle
         }
@@ -389,6 +1312,10 @@ impl FileManager {
                             attrs.push(FileAttrValue::NamedAttr(self.attr_named_attr()));
                             answer_attrs.push(FileAttr::NamedAttr);
                         }
+                        FileAttr::Acl => {
+                            attrs.push(FileAttrValue::Acl(self.attr_acl(filehandle.attr_mode)));
+                            answer_attrs.push(FileAttr::Acl);
+                        }
                         FileAttr::AclSupport => {
                             attrs.push(FileAttrValue::AclSupport(self.attr_acl_support()));
                             answer_attrs.push(FileAttr::AclSupport);
@@ -401,6 +1328,33 @@ impl FileManager {
                             attrs.push(FileAttrValue::UniqueHandles(self.attr_unique_handles()));
                             answer_attrs.push(FileAttr::UniqueHandles);
                         }
+                        FileAttr::FsLocations => {
+                            // only referrals have locations
+                            if let Some(locations) = &filehandle.attr_fs_locations {
+                                attrs.push(FileAttrValue::FsLocations(locations.clone()));
+                                answer_attrs.push(FileAttr::FsLocations);
+                            }
+                        }
+                        FileAttr::Homogeneous => {
+                            attrs.push(FileAttrValue::Homogeneous(self.attr_homogeneous()));
+                            answer_attrs.push(FileAttr::Homogeneous);
+                        }
+                        FileAttr::Maxfilesize => {
+                            attrs.push(FileAttrValue::Maxfilesize(self.attr_maxfilesize()));
+                            answer_attrs.push(FileAttr::Maxfilesize);
+                        }
+                        FileAttr::Maxlink => {
+                            attrs.push(FileAttrValue::Maxlink(self.attr_maxlink()));
+                            answer_attrs.push(FileAttr::Maxlink);
+                        }
+                        FileAttr::Maxread => {
+                            attrs.push(FileAttrValue::Maxread(self.attr_maxread()));
+                            answer_attrs.push(FileAttr::Maxread);
+                        }
+                        FileAttr::Maxwrite => {
+                            attrs.push(FileAttrValue::Maxwrite(self.attr_maxwrite()));
+                            answer_attrs.push(FileAttr::Maxwrite);
+                        }
                         FileAttr::FhExpireType => {
                             attrs.push(FileAttrValue::FhExpireType(self.attr_expire_type()));
                             answer_attrs.push(FileAttr::FhExpireType);
@@ -413,12 +1367,20 @@ impl FileManager {
                             attrs.push(FileAttrValue::Fileid(filehandle.attr_fileid));
                             answer_attrs.push(FileAttr::Fileid);
                         }
+                        FileAttr::Archive => {
+                            attrs.push(FileAttrValue::Archive(filehandle.attr_archive));
+                            answer_attrs.push(FileAttr::Archive);
+                        }
+                        FileAttr::TimeBackup => {
+                            attrs.push(FileAttrValue::TimeBackup(filehandle.attr_time_backup));
+                            answer_attrs.push(FileAttr::TimeBackup);
+                        }
                         FileAttr::Mode => {
-                            attrs.push(FileAttrValue::Mode(self.attr_mode()));
+                            attrs.push(FileAttrValue::Mode(filehandle.attr_mode));
                             answer_attrs.push(FileAttr::Mode);
                         }
                         FileAttr::Numlinks => {
-                            attrs.push(FileAttrValue::Numlinks(self.attr_numlinks()));
+                            attrs.push(FileAttrValue::Numlinks(filehandle.attr_numlinks));
                             answer_attrs.push(FileAttr::Numlinks);
                         }
                         FileAttr::Owner => {
@@ -431,6 +1393,34 @@ impl FileManager {
                             ));
                             answer_attrs.push(FileAttr::OwnerGroup);
                         }
+                        FileAttr::Rawdev => {
+                            attrs.push(FileAttrValue::Rawdev(filehandle.attr_rawdev));
+                            answer_attrs.push(FileAttr::Rawdev);
+                        }
+                        FileAttr::QuotaAvailHard
+                        | FileAttr::QuotaAvailSoft
+                        | FileAttr::QuotaUsed
+                            if self.quota.is_some() =>
+                        {
+                            let (avail, used) = self.attr_quota(&self.root);
+                            attrs.push(match fileattr {
+                                FileAttr::QuotaAvailHard => FileAttrValue::QuotaAvailHard(avail),
+                                FileAttr::QuotaAvailSoft => FileAttrValue::QuotaAvailSoft(avail),
+                                _ => FileAttrValue::QuotaUsed(used),
+                            });
+                            answer_attrs.push(fileattr.clone());
+                        }
+                        FileAttr::FilesAvail | FileAttr::FilesFree | FileAttr::FilesTotal
+                            if self.files_total.is_some() =>
+                        {
+                            let (free, total) = self.attr_files(&self.root);
+                            attrs.push(match fileattr {
+                                FileAttr::FilesAvail => FileAttrValue::FilesAvail(free),
+                                FileAttr::FilesFree => FileAttrValue::FilesFree(free),
+                                _ => FileAttrValue::FilesTotal(total),
+                            });
+                            answer_attrs.push(fileattr.clone());
+                        }
                         FileAttr::SpaceUsed => {
                             attrs.push(FileAttrValue::SpaceUsed(filehandle.attr_space_used));
                             answer_attrs.push(FileAttr::SpaceUsed);
@@ -447,12 +1437,12 @@ impl FileManager {
                             attrs.push(FileAttrValue::TimeModify(filehandle.attr_time_modify));
                             answer_attrs.push(FileAttr::TimeModify);
                         }
-                        // FileAttr::MountedOnFileid => {
-                        //     attrs.push(FileAttrValue::MountedOnFileid(
-                        //         filehandle.attr_mounted_on_fileid,
-                        //     ));
-                        //     answer_attrs.push(FileAttr::MountedOnFileid);
-                        // }
+                        FileAttr::MountedOnFileid => {
+                            attrs.push(FileAttrValue::MountedOnFileid(
+                                filehandle.attr_mounted_on_fileid,
+                            ));
+                            answer_attrs.push(FileAttr::MountedOnFileid);
+                        }
                         _ => {}
                     }
                 }
@@ -477,7 +1467,7 @@ impl FileManager {
         // The bit vector that would retrieve all REQUIRED and RECOMMENDED
         // attributes that are supported for this object.  The scope of this
         //attribute applies to all objects with a matching fsid.
-        Attrlist4::<FileAttr>::new(Some(vec![
+        let mut attrs = Attrlist4::<FileAttr>::new(Some(vec![
             FileAttr::SupportedAttrs,
             FileAttr::Type,
             FileAttr::FhExpireType,
@@ -496,23 +1486,64 @@ impl FileManager {
             // FileAttr::Cansettime,
             FileAttr::Filehandle,
             FileAttr::Fileid,
+            FileAttr::Homogeneous,
+            FileAttr::Maxfilesize,
+            FileAttr::Maxlink,
+            FileAttr::Maxread,
+            FileAttr::Maxwrite,
             FileAttr::Mode,
             FileAttr::Numlinks,
             FileAttr::Owner,
             FileAttr::OwnerGroup,
+            FileAttr::Rawdev,
             FileAttr::SpaceUsed,
             FileAttr::TimeAccess,
+            FileAttr::TimeBackup,
             FileAttr::TimeMetadata,
             FileAttr::TimeModify,
-            // FileAttr::MountedOnFileid,
-        ]))
+            FileAttr::MountedOnFileid,
+        ]));
+        if self.quota.is_some() {
+            attrs.extend([
+                FileAttr::QuotaAvailHard,
+                FileAttr::QuotaAvailSoft,
+                FileAttr::QuotaUsed,
+            ]);
+        }
+        if self.files_total.is_some() {
+            attrs.extend([
+                FileAttr::FilesAvail,
+                FileAttr::FilesFree,
+                FileAttr::FilesTotal,
+            ]);
+        }
+        attrs
+    }
+
+    pub fn attr_quota(&self, root: &VfsPath) -> (u64, u64) {
+        // quota_avail_hard, quota_avail_soft, quota_used:
+        // The space left before the quota refuses further allocations, and
+        // the space counted against it. The quota is enforced by the
+        // backend, there's a single one for the whole export and no soft
+        // limit below the hard one.
+        let used = space_used_below(root);
+        (self.quota.unwrap_or(0).saturating_sub(used), used)
+    }
+
+    pub fn attr_files(&self, root: &VfsPath) -> (u64, u64) {
+        // files_avail, files_free, files_total:
+        // The file slots left for the user and in total, and all slots of
+        // the file system. Every file and directory of the export, the root
+        // included, takes one and nothing is reserved.
+        let total = self.files_total.unwrap_or(0);
+        (total.saturating_sub(objects_below(root)), total)
     }
 
     pub fn attr_expire_type(&self) -> u32 {
         // fh_expire_type:
         // The server uses this to specify filehandle expiration behavior to the
         // client.  See Section 4 for additional description.
-        FH4_VOLATILE_ANY
+        self.filehandle_strategy.expire_type()
     }
 
     pub fn attr_link_support(&self) -> bool {
@@ -541,7 +1572,43 @@ impl FileManager {
         self.unique_handles
     }
 
-    pub fn attr_acl(&self) -> bool {
+    pub fn attr_homogeneous(&self) -> bool {
+        // homogeneous:
+        // TRUE, if this object's file system is homogeneous, i.e., all
+        // objects in the file system (all objects on the server with the same
+        // fsid) have common values for all per-file system attributes.
+        // There is a single backend per export and the per-file system
+        // attributes don't depend on the object.
+        true
+    }
+
+    pub fn attr_maxfilesize(&self) -> u64 {
+        // maxfilesize:
+        // Maximum supported file size for the file system of this object.
+        MAX_FILE_SIZE
+    }
+
+    pub fn attr_maxlink(&self) -> u32 {
+        // maxlink:
+        // Maximum number of links for this object.
+        self.max_link.unwrap_or(u32::MAX)
+    }
+
+    pub fn attr_maxread(&self) -> u64 {
+        // maxread:
+        // Maximum amount of data the READ operation will return for this
+        // object.
+        MAX_READ
+    }
+
+    pub fn attr_maxwrite(&self) -> u64 {
+        // maxwrite:
+        // Maximum amount of data the WRITE operation will accept for this
+        // object.
+        MAX_WRITE
+    }
+
+    pub fn attr_acl(&self, mode: u32) -> Vec<Nfsace4> {
         // acl:
         // The NFSv4.0 ACL attribute contains an array of ACEs that are
         // associated with the file system object.  Although the client can read
@@ -549,7 +1616,8 @@ impl FileManager {
         // ACL to perform access control.  The client can use the OPEN or ACCESS
         // operations to check access without modifying or reading data or
         // metadata.
-        false
+        // There's no ACL storage, the ACEs are derived from the mode bits.
+        acl_from_mode(mode)
     }
 
     pub fn attr_acl_support(&self) -> u32 {
@@ -558,22 +1626,98 @@ impl FileManager {
         ACL4_SUPPORT_ALLOW_ACL
     }
 
-    pub fn attr_archive(&self) -> bool {
-        // archive:
-        // TRUE, if the object's file system supports the archive attribute.
-        false
-    }
-
     pub fn attr_mode(&self) -> u32 {
         // mode:
         // The NFSv4.0 mode attribute is based on the UNIX mode bits.
         MODE4_RUSR + MODE4_RGRP + MODE4_ROTH
     }
+}
+
+/// Bytes taken by the files below `root`, what counts against a quota
+pub fn space_used_below(root: &VfsPath) -> u64 {
+    root.walk_dir()
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .filter(|metadata| metadata.file_type == VfsFileType::File)
+                .map(|metadata| metadata.len)
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Files and directories below `root` and `root` itself
+pub fn objects_below(root: &VfsPath) -> u64 {
+    1 + root
+        .walk_dir()
+        .map(|entries| entries.filter(|entry| entry.is_ok()).count() as u64)
+        .unwrap_or(0)
+}
+
+/// The status a failed backend call is answered with. Conditions that are
+/// likely to clear up, like a busy or timed out backend, are reported as
+/// NFS4ERR_DELAY so clients retry instead of failing the call. Anything the
+/// backend can't tell apart is NFS4ERR_IO.
+pub fn vfs_error_status(e: &VfsError) -> NfsStat4 {
+    match e.kind() {
+        VfsErrorKind::IoError(e) => io_error_status(e),
+        _ => NfsStat4::Nfs4errIo,
+    }
+}
+
+/// Same as [`vfs_error_status`], for the errors of [`SpecialFiles`]. A
+/// backend that can't do something reports NFS4ERR_NOTSUPP.
+pub fn io_error_status(e: &io::Error) -> NfsStat4 {
+    match e.kind() {
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::ResourceBusy => NfsStat4::Nfs4errDelay,
+        io::ErrorKind::PermissionDenied => NfsStat4::Nfs4errAccess,
+        io::ErrorKind::StorageFull => NfsStat4::Nfs4errNospc,
+        io::ErrorKind::QuotaExceeded => NfsStat4::Nfs4errDquot,
+        io::ErrorKind::AlreadyExists => NfsStat4::Nfs4errExist,
+        io::ErrorKind::Unsupported => NfsStat4::Nfs4errNotsupp,
+        _ => NfsStat4::Nfs4errIo,
+    }
+}
+
+// ALLOW ACEs for the owner, group and everyone else that grant what the
+// read, write and execute bits of the mode grant them
+fn acl_from_mode(mode: u32) -> Vec<Nfsace4> {
+    [
+        ("OWNER@", 6, 0),
+        ("GROUP@", 3, ACE4_IDENTIFIER_GROUP),
+        ("EVERYONE@", 0, 0),
+    ]
+    .into_iter()
+    .map(|(who, shift, flag)| {
+        let bits = (mode >> shift) & 0o7;
+        let mut access_mask = 0;
+        if bits & 0o4 != 0 {
+            access_mask |= ACE4_READ_DATA;
+        }
+        if bits & 0o2 != 0 {
+            access_mask |= ACE4_WRITE_DATA | ACE4_APPEND_DATA;
+        }
+        if bits & 0o1 != 0 {
+            access_mask |= ACE4_EXECUTE;
+        }
+        Nfsace4 {
+            acetype: ACE4_ACCESS_ALLOWED_ACE_TYPE,
+            flag,
+            access_mask,
+            who: who.to_string(),
+        }
+    })
+    .collect()
+}
 
-    pub fn attr_numlinks(&self) -> u32 {
-        // numlinks:
-        // Number of hard links to this object.
-        1
+// answer a request, a requester that gave up waiting (e.g. after its
+// request timeout) must not take the actor down with it
+fn respond<T>(respond_to: oneshot::Sender<T>, value: T) {
+    if respond_to.send(value).is_err() {
+        debug!("Requester is gone, dropping response");
     }
 }
 
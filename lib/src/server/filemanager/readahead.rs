@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tracing::{debug, error};
+use vfs::VfsPath;
+
+use super::NfsFh4;
+
+#[derive(Debug, Default)]
+struct FileReadahead {
+    // where the last READ ended, the next one is sequential if it starts there
+    next_offset: u64,
+    // bumped whenever the content changes, prefetches started before are
+    // thrown away
+    generation: u64,
+    // the prefetched window
+    offset: u64,
+    data: Bytes,
+    // the window ends at the end of the file
+    eof: bool,
+}
+
+/// Prefetches the window following a sequential READ, so the next READ of
+/// a slow backend is served from memory
+#[derive(Debug)]
+pub struct Readahead {
+    window: u64,
+    files: Mutex<HashMap<NfsFh4, FileReadahead>>,
+}
+
+impl Readahead {
+    pub fn new(window: u64) -> Self {
+        Readahead {
+            window,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The prefetched data for `count` bytes at `offset`, if the window
+    /// covers them
+    pub fn get(&self, id: &NfsFh4, offset: u64, count: u32) -> Option<Bytes> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(id)?;
+        let end = file.offset + file.data.len() as u64;
+        if offset < file.offset || offset > end || (offset + count as u64 > end && !file.eof) {
+            return None;
+        }
+        let start = (offset - file.offset) as usize;
+        let stop = file.data.len().min(start + count as usize);
+        debug!("Readahead hit at {} for {:?}", offset, id);
+        Some(file.data.slice(start..stop))
+    }
+
+    /// Note a READ of `len` bytes at `offset`, a sequential one starts
+    /// prefetching the following window in the background
+    pub fn record(self: &Arc<Self>, id: NfsFh4, file: &VfsPath, offset: u64, len: u64) {
+        let next_offset = offset + len;
+        let generation = {
+            let mut files = self.files.lock().unwrap();
+            let entry = files.entry(id).or_default();
+            let sequential = offset == 0 || offset == entry.next_offset;
+            entry.next_offset = next_offset;
+            let covered = next_offset >= entry.offset
+                && (next_offset + self.window <= entry.offset + entry.data.len() as u64
+                    || entry.eof);
+            if !sequential || covered || len == 0 {
+                return;
+            }
+            entry.generation
+        };
+
+        let readahead = self.clone();
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut data = Vec::with_capacity(readahead.window as usize);
+            let read = file.open_file().and_then(|mut rfile| {
+                rfile.seek(SeekFrom::Start(next_offset))?;
+                rfile.take(readahead.window).read_to_end(&mut data)?;
+                Ok(())
+            });
+            if let Err(e) = read {
+                error!("Couldn't prefetch {:?}: {:?}", file.as_str(), e);
+                return;
+            }
+            let mut files = readahead.files.lock().unwrap();
+            if let Some(entry) = files.get_mut(&id) {
+                if entry.generation == generation {
+                    entry.eof = (data.len() as u64) < readahead.window;
+                    entry.offset = next_offset;
+                    entry.data = Bytes::from(data);
+                }
+            }
+        });
+    }
+
+    /// Forget what was prefetched for a file whose content changed
+    pub fn invalidate(&self, id: &NfsFh4) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(entry) = files.get_mut(id) {
+            entry.generation += 1;
+            entry.data = Bytes::new();
+            entry.eof = false;
+        }
+    }
+
+    /// Forget a file altogether, e.g. once it's closed
+    pub fn remove(&self, id: &NfsFh4) {
+        self.files.lock().unwrap().remove(id);
+    }
+}
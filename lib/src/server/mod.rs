@@ -1,16 +1,24 @@
+pub mod access;
+pub mod authorizer;
 pub mod clientmanager;
+pub mod clock;
+pub mod exports;
 pub mod filemanager;
 pub mod nfs40;
 pub mod operation;
 pub mod request;
 pub mod response;
+pub mod security;
+pub mod special;
 
 use async_trait::async_trait;
 
 use request::NfsRequest;
-use tracing::debug;
+use tracing::{debug, error, info_span, Instrument};
 
-use bold_proto::rpc_proto::{CallBody, MsgType, ReplyBody, RpcCallMsg, RpcReplyMsg};
+use bold_proto::rpc_proto::{
+    AcceptBody, AcceptedReply, CallBody, MsgType, OpaqueAuth, ReplyBody, RpcCallMsg, RpcReplyMsg,
+};
 
 #[async_trait]
 pub trait NfsProtoImpl: Sync {
@@ -20,17 +28,20 @@ pub trait NfsProtoImpl: Sync {
 
     fn hash(&self) -> u64;
 
-    async fn null<'a>(
-        &self,
-        _: CallBody,
-        mut request: NfsRequest<'a>,
-    ) -> (NfsRequest<'a>, ReplyBody);
+    async fn null(&self, _: CallBody, mut request: NfsRequest) -> (NfsRequest, ReplyBody);
 
-    async fn compound<'a>(
-        &self,
-        msg: CallBody,
-        mut request: NfsRequest<'a>,
-    ) -> (NfsRequest<'a>, ReplyBody);
+    async fn compound(&self, msg: CallBody, mut request: NfsRequest) -> (NfsRequest, ReplyBody);
+}
+
+/// Reply to a call whose arguments couldn't be decoded
+pub fn garbage_args_reply(xid: u32) -> Box<RpcReplyMsg> {
+    Box::new(RpcReplyMsg {
+        xid,
+        body: MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+            verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
+            reply_data: AcceptBody::GarbageArgs,
+        })),
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -49,23 +60,35 @@ where
     pub async fn call(
         &self,
         rpc_call_message: RpcCallMsg,
-        request: NfsRequest<'_>,
+        request: NfsRequest,
     ) -> Box<RpcReplyMsg> {
         debug!("{:?}", rpc_call_message);
 
         match rpc_call_message.body {
             MsgType::Call(call_body) => {
                 // TODO: check nfs protocol version
-                let (request, body) = match call_body.proc {
-                    0 => self.server.null(call_body, request).await,
-                    1 => self.server.compound(call_body, request).await,
-                    _ => {
-                        todo!("Invalid procedure")
+                let (request, body) = async {
+                    match call_body.proc {
+                        0 => self.server.null(call_body, request).await,
+                        1 => self.server.compound(call_body, request).await,
+                        proc => {
+                            error!("Invalid procedure {}", proc);
+                            (
+                                request,
+                                ReplyBody::MsgAccepted(AcceptedReply {
+                                    verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
+                                    reply_data: AcceptBody::ProcUnavail,
+                                }),
+                            )
+                        }
                     }
-                };
+                }
+                // everything logged while serving the call carries its xid
+                .instrument(info_span!("rpc", xid = rpc_call_message.xid))
+                .await;
 
                 // end request
-                request.close().await;
+                request.close();
                 let rpc_reply_message = RpcReplyMsg {
                     xid: rpc_call_message.xid,
                     body: MsgType::Reply(body),
@@ -74,8 +97,255 @@ where
                 Box::new(rpc_reply_message)
             }
             _ => {
-                todo!("Invalid message type")
+                // only calls are served, a reply can't be answered
+                error!("Invalid message type");
+                request.close();
+                garbage_args_reply(rpc_call_message.xid)
             }
         }
     }
 }
+
+/// Serves one RPC call together with the [`NfsRequest`] that carries the
+/// connection's state, so tower middleware like timeouts or concurrency
+/// limits can be layered around the NFS protocol.
+#[cfg(feature = "tower")]
+impl<Proto> tower::Service<(RpcCallMsg, NfsRequest)> for NFSService<Proto>
+where
+    Proto: NfsProtoImpl + Clone + Send + 'static,
+{
+    type Response = Box<RpcReplyMsg>;
+    type Error = std::convert::Infallible;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, (rpc_call_message, request): (RpcCallMsg, NfsRequest)) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.call(rpc_call_message, request).await) })
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use bold_proto::{
+        nfs4_proto::NfsStat4,
+        rpc_proto::{AcceptBody, AcceptedReply, MsgType, ReplyBody},
+        GarbageArgs, XDRProtoCodec,
+    };
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use tracing_test::traced_test;
+
+    use super::{
+        garbage_args_reply, nfs40::NFS40Server, request::NfsRequest, NFSService, NfsProtoImpl,
+    };
+    use crate::{
+        server::{
+            clientmanager::ClientManagerHandle,
+            filemanager::{FileManagerConfig, FileManagerHandle},
+        },
+        test_utils::{create_dummyfs, create_nfs40_server},
+    };
+    use std::time::Duration;
+
+    fn compound_frame(argarray: &[u32]) -> BytesMut {
+        // xid, call, rpcvers, prog, vers, proc, AUTH_NULL cred and verf
+        let mut message = vec![42, 0, 2, 100003, 4, 1, 0, 0, 0, 0];
+        // empty tag, minor version
+        message.extend([0, 0]);
+        message.extend(argarray);
+        let message: Vec<u8> = message.iter().flat_map(|w| w.to_be_bytes()).collect();
+
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&((message.len() as u32) | (1 << 31)).to_be_bytes());
+        frame.extend_from_slice(&message);
+        frame
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_truncated_op_argument() {
+        // one PUTFH whose 26 byte filehandle is cut off after 4 bytes
+        let mut frame = compound_frame(&[1, 22, 26, 0x80000000]);
+        let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+
+        let request = create_nfs40_server(None).await;
+        let service = NFSService::new(NFS40Server::new());
+        let reply = service.call(msg, request).await;
+        assert_eq!(reply.xid, 42);
+        match reply.body {
+            MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            })) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errBadxdr);
+                assert!(res.resarray.is_empty());
+            }
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_undecodable_attrs() {
+        let service = NFSService::new(NFS40Server::new());
+        // PUTROOTFH, VERIFY of a size with only 4 bytes of values, then
+        // VERIFY of the type, which isn't decoded
+        for verify in [[37, 1, 1 << 4, 4, 13], [37, 1, 1 << 1, 4, 2]] {
+            let mut argarray = vec![2, 24];
+            argarray.extend(verify);
+            let mut frame = compound_frame(&argarray);
+            let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+            let request = create_nfs40_server(None).await;
+            let reply = service.call(msg, request).await;
+            match reply.body {
+                MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                    reply_data: AcceptBody::Success(res),
+                    ..
+                })) => assert_eq!(res.status, NfsStat4::Nfs4errBadxdr),
+                _ => panic!("Unexpected reply"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_op_logs_carry_xid() {
+        // PUTROOTFH, READLINK of the root directory fails as second op
+        let mut frame = compound_frame(&[2, 24, 27]);
+        let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+
+        let request = create_nfs40_server(None).await;
+        let service = NFSService::new(NFS40Server::new());
+        let reply = service.call(msg, request).await;
+        assert_eq!(reply.xid, 42);
+        logs_assert(|lines: &[&str]| {
+            lines
+                .iter()
+                .find(|line| line.contains("Not a symlink"))
+                .filter(|line| line.contains("rpc{xid=42}:op{index=1 op=OpReadlink}"))
+                .map(|_| ())
+                .ok_or("error log without xid and op index".to_string())
+        });
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_close_after_failed_compound() {
+        let config = FileManagerConfig {
+            filehandle_cache_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let fmanager = FileManagerHandle::with_config(create_dummyfs(), None, config);
+        let root = fmanager.get_root_filehandle().await.unwrap();
+        let mut request = NfsRequest::new(
+            "127.0.0.1:12345".to_owned(),
+            ClientManagerHandle::new(),
+            fmanager.clone(),
+            [0; 8],
+        );
+        request.cache_filehandle(root.clone());
+
+        // GETATTR without a current filehandle fails
+        let mut frame = compound_frame(&[1, 9, 1, 1]);
+        let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+        let service = NFSService::new(NFS40Server::new());
+        let reply = service.call(msg, request).await;
+        match reply.body {
+            MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            })) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errNofilehandle);
+            }
+            _ => panic!("Unexpected reply"),
+        }
+
+        // the filehandle cache and the file manager are untouched
+        let cache = fmanager.filehandle_cache().unwrap();
+        assert!(cache.get(&root.id).is_some());
+        assert_eq!(fmanager.get_root_filehandle().await.unwrap().id, root.id);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_invalid_procedure() {
+        let mut frame = compound_frame(&[0]);
+        // procedure 2 is not defined for NFSv4
+        frame[24..28].copy_from_slice(&2u32.to_be_bytes());
+        let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+
+        let request = create_nfs40_server(None).await;
+        let reply = NFSService::new(NFS40Server::new()).call(msg, request).await;
+        assert_eq!(reply.xid, 42);
+        assert!(matches!(
+            reply.body,
+            MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::ProcUnavail,
+                ..
+            }))
+        ));
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test]
+    #[traced_test]
+    async fn test_tower_service() {
+        use tower::ServiceExt;
+
+        // PUTROOTFH, GETFH
+        let mut frame = compound_frame(&[2, 24, 10]);
+        let msg = XDRProtoCodec::new().decode(&mut frame).unwrap().unwrap();
+        let request = create_nfs40_server(None).await;
+        let reply = NFSService::new(NFS40Server::new())
+            .oneshot((msg, request))
+            .await
+            .unwrap();
+        assert_eq!(reply.xid, 42);
+        match reply.body {
+            MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            })) => {
+                assert_eq!(res.status, NfsStat4::Nfs4Ok);
+                assert_eq!(res.resarray.len(), 2);
+            }
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    #[test]
+    fn test_garbage_args_reply_xid() {
+        // xid 42, call, rpcvers, prog, vers, proc and a credential of the
+        // unknown flavor 9
+        let message: Vec<u8> = [42, 0, 2, 100003, 4, 1, 9, 0, 0, 0]
+            .iter()
+            .flat_map(|w: &u32| w.to_be_bytes())
+            .collect();
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&((message.len() as u32) | (1 << 31)).to_be_bytes());
+        frame.extend_from_slice(&message);
+
+        let err = XDRProtoCodec::new().decode(&mut frame).unwrap_err();
+        let garbage = GarbageArgs::from_io_error(&err).unwrap();
+        assert_eq!(garbage.xid, Some(42));
+
+        let reply = garbage_args_reply(garbage.xid.unwrap());
+        assert_eq!(reply.xid, 42);
+        assert!(matches!(
+            reply.body,
+            MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::GarbageArgs,
+                ..
+            }))
+        ));
+    }
+}
@@ -1,34 +1,100 @@
 use async_trait::async_trait;
 
-use super::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
-use bold_proto::{nfs4_proto::*, rpc_proto::*};
+use super::{
+    authorizer::{opnum, target_paths},
+    operation::NfsOperation,
+    request::{auth_sys_uid, principal, NfsRequest},
+    response::{op_error, NfsOpResponse},
+};
+use bold_proto::{nfs4_proto::*, rpc_proto::*, utils::xdr_size, MAX_RECORD_SIZE};
 
 mod op_access;
 mod op_close;
 mod op_commit;
 mod op_create;
 mod op_getattr;
+mod op_link;
 mod op_lookup;
 mod op_open;
+mod op_open_downgrade;
 mod op_openconfirm;
 mod op_putfh;
 mod op_read;
 mod op_readdir;
+mod op_release_lockowner;
 mod op_remove;
+mod op_rename;
 mod op_renew;
+mod op_secinfo;
 mod op_set_clientid;
 mod op_set_clientid_confirm;
 mod op_setattr;
+mod op_verify;
 mod op_write;
 
 use super::NfsProtoImpl;
-use tracing::error;
+use tracing::{error, info_span, Instrument};
+
+// room left in a reply record for the RPC header, verifier and tag
+const REPLY_HEADER_RESERVE: usize = 4096;
+
+/// End of the byte range `count` bytes from `offset` that READ, WRITE and
+/// COMMIT operate on, NFS4ERR_INVAL if it lies beyond 2^64 - 1
+fn range_end(offset: u64, count: u64) -> Result<u64, NfsStat4> {
+    offset.checked_add(count).ok_or_else(|| {
+        error!("Range of {} bytes at offset {} overflows", count, offset);
+        NfsStat4::Nfs4errInval
+    })
+}
+
+/// The mode a client asks for in the createattrs of OPEN or CREATE, the
+/// server's default applies if there is none
+fn create_mode(createattrs: &Fattr4) -> Option<u32> {
+    createattrs.attr_vals.iter().find_map(|attr| match attr {
+        FileAttrValue::Mode(mode) => Some(*mode),
+        _ => None,
+    })
+}
+
+/// The result of an operation that failed without producing one itself
+fn failed(op: NfsOpNum4, status: NfsStat4) -> NfsResOp4 {
+    NfsResOp4::OpFailed(OpFailed4 {
+        op: op as u32,
+        status,
+    })
+}
+
+/// The reply to a COMPOUND with the results of the operations that ran,
+/// `tag` is echoed from the arguments
+fn compound_reply(
+    request: NfsRequest,
+    tag: String,
+    status: NfsStat4,
+    resarray: Vec<NfsResOp4>,
+) -> (NfsRequest, ReplyBody) {
+    (
+        request,
+        ReplyBody::MsgAccepted(AcceptedReply {
+            verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
+            reply_data: AcceptBody::Success(Compound4res {
+                status,
+                tag,
+                resarray,
+            }),
+        }),
+    )
+}
+
+/// The reply to a COMPOUND that failed before any of its operations ran
+fn error_reply(request: NfsRequest, status: NfsStat4) -> (NfsRequest, ReplyBody) {
+    compound_reply(request, String::new(), status, Vec::new())
+}
 
 #[derive(Debug, Clone)]
 pub struct NFS40Server;
 
 impl NFS40Server {
-    async fn put_root_filehandle<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn put_root_filehandle(&self, mut request: NfsRequest) -> NfsOpResponse {
         match request.file_manager().get_root_filehandle().await {
             Ok(filehandle) => {
                 let _ = request.set_filehandle_id(filehandle.id).await;
@@ -42,16 +108,57 @@ impl NFS40Server {
             }
             Err(e) => {
                 error!("Err {:?}", e);
-                NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errServerfault,
-                }
+                op_error(request, NfsStat4::Nfs4errServerfault)
             }
         }
     }
 
-    fn get_current_filehandle<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    fn save_filehandle(&self, mut request: NfsRequest) -> NfsOpResponse {
+        if request.current_filehandle().is_none() {
+            return self.no_filehandle(request);
+        }
+        request.save_filehandle();
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::Opsavefh(SaveFh4res {
+                status: NfsStat4::Nfs4Ok,
+            })),
+            status: NfsStat4::Nfs4Ok,
+        }
+    }
+
+    async fn restore_filehandle(&self, mut request: NfsRequest) -> NfsOpResponse {
+        let saved = match request.saved_filehandle() {
+            Some(saved) => saved.id,
+            // https://datatracker.ietf.org/doc/html/rfc7530#section-16.27.4
+            None => {
+                error!("No saved filehandle");
+                return op_error(request, NfsStat4::Nfs4errRestorefh);
+            }
+        };
+        // the saved object may have changed (or moved) in the meantime
+        match request.set_filehandle_id(saved).await {
+            Ok(_) => NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Oprestorefh(RestoreFh4res {
+                    status: NfsStat4::Nfs4Ok,
+                })),
+                status: NfsStat4::Nfs4Ok,
+            },
+            Err(status) => op_error(request, status),
+        }
+    }
+
+    fn get_current_filehandle(&self, request: NfsRequest) -> NfsOpResponse {
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-8.4.1
+        // a referral has no filehandle of its own, this is how a client
+        // walking the tree learns that it has to fetch fs_locations
+        if request
+            .current_filehandle()
+            .is_some_and(|fh| fh.attr_fs_locations.is_some())
+        {
+            return op_error(request, NfsStat4::Nfs4errMoved);
+        }
         let fh = request.current_filehandle_id();
         match fh {
             Some(filehandle_id) => NfsOpResponse {
@@ -62,23 +169,150 @@ impl NFS40Server {
                 status: NfsStat4::Nfs4Ok,
             },
             // current filehandle not set for client
-            None => {
-                error!("Filehandle not set");
-                NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errServerfault,
-                }
+            None => self.no_filehandle(request),
+        }
+    }
+
+    async fn read_link(&self, request: NfsRequest) -> NfsOpResponse {
+        let filehandle = match request.current_filehandle() {
+            Some(filehandle) => filehandle,
+            None => return self.no_filehandle(request),
+        };
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-16.25.5
+        // READLINK on anything but a symlink is NFS4ERR_INVAL
+        if filehandle.attr_type != NfsFtype4::Nf4lnk {
+            error!("Not a symlink");
+            return op_error(request, NfsStat4::Nfs4errInval);
+        }
+        match request.file_manager().read_link(filehandle) {
+            Ok(link) => NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Opreadlink(ReadLink4res::Resok4(
+                    ReadLink4resok { link },
+                ))),
+                status: NfsStat4::Nfs4Ok,
+            },
+            Err(e) => op_error(request, e.nfs_error),
+        }
+    }
+
+    // run a single operation of a compound
+    async fn execute(&self, op: NfsOpNum4, arg: NfsArgOp, request: NfsRequest) -> NfsOpResponse {
+        match arg {
+            // these should never be called
+            NfsArgOp::OpUndef0 | NfsArgOp::OpUndef1 | NfsArgOp::OpUndef2 => {
+                error!("Illegal operation {:?}", op);
+                op_error(request, NfsStat4::Nfs4errOpIllegal)
+            }
+            // these are actual operations
+            NfsArgOp::Opgetfh(_) => self.get_current_filehandle(request),
+            NfsArgOp::Opsetclientid(args) => args.execute(request).await,
+            NfsArgOp::OpAccess(args) => args.execute(request).await,
+            NfsArgOp::Opclose(args) => args.execute(request).await,
+            NfsArgOp::Opgetattr(args) => args.execute(request).await,
+            NfsArgOp::Oplookup(args) => args.execute(request).await,
+            NfsArgOp::Opopen(args) => args.execute(request).await,
+            NfsArgOp::OpopenConfirm(args) => args.execute(request).await,
+            NfsArgOp::Opputfh(args) => args.execute(request).await,
+            NfsArgOp::Opputrootfh(_) => self.put_root_filehandle(request).await,
+            NfsArgOp::Opread(args) => args.execute(request).await,
+            NfsArgOp::Opreaddir(args) => args.execute(request).await,
+            NfsArgOp::Oprenew(args) => args.execute(request).await,
+            NfsArgOp::OpsetclientidConfirm(args) => args.execute(request).await,
+            NfsArgOp::Opsetattr(args) => args.execute(request).await,
+            NfsArgOp::Opremove(args) => args.execute(request).await,
+            NfsArgOp::Opwrite(args) => args.execute(request).await,
+
+            NfsArgOp::Opcommit(args) => args.execute(request).await,
+            NfsArgOp::Opcreate(args) => args.execute(request).await,
+
+            NfsArgOp::Opdelegpurge(_) => self.operation_not_supported(op, request),
+            NfsArgOp::Opdelegreturn(_) => self.operation_not_supported(op, request),
+
+            NfsArgOp::Oplink(args) => args.execute(request).await,
+            NfsArgOp::Oplock(_) => self.operation_not_supported(op, request),
+            NfsArgOp::Oplockt(_) => self.operation_not_supported(op, request),
+            NfsArgOp::Oplocku(_) => self.operation_not_supported(op, request),
+
+            NfsArgOp::Oplookupp(_) => self.operation_not_supported(op, request),
+            NfsArgOp::Opnverify(args) => args.execute(request).await,
+
+            NfsArgOp::Opopenattr(_) => self.operation_not_supported(op, request),
+
+            NfsArgOp::OpopenDowngrade(args) => args.execute(request).await,
+
+            NfsArgOp::Opputpubfh(_) => self.operation_not_supported(op, request),
+
+            NfsArgOp::Opreadlink(_) => self.read_link(request).await,
+
+            NfsArgOp::Oprename(args) => args.execute(request).await,
+
+            NfsArgOp::Oprestorefh(_) => self.restore_filehandle(request).await,
+            NfsArgOp::Opsavefh(_) => self.save_filehandle(request),
+            NfsArgOp::OpSecinfo(args) => args.execute(request).await,
+
+            NfsArgOp::Opverify(args) => args.execute(request).await,
+
+            NfsArgOp::OpreleaseLockOwner(args) => args.execute(request).await,
+        }
+    }
+
+    fn no_filehandle(&self, request: NfsRequest) -> NfsOpResponse {
+        error!("Filehandle not set");
+        op_error(request, NfsStat4::Nfs4errNofilehandle)
+    }
+
+    /// Returns the index of the first operation that consumes the current
+    /// filehandle before any operation of the PUTFH family has set one.
+    fn first_op_without_filehandle(argarray: &[NfsArgOp]) -> Option<usize> {
+        for (idx, arg) in argarray.iter().enumerate() {
+            match arg {
+                // these set the current filehandle
+                NfsArgOp::Opputfh(_)
+                | NfsArgOp::Opputpubfh(_)
+                | NfsArgOp::Opputrootfh(_)
+                | NfsArgOp::Oprestorefh(_) => return None,
+                // these don't operate on the current filehandle
+                NfsArgOp::OpUndef0
+                | NfsArgOp::OpUndef1
+                | NfsArgOp::OpUndef2
+                | NfsArgOp::Opdelegpurge(_)
+                | NfsArgOp::Oprenew(_)
+                | NfsArgOp::Opsetclientid(_)
+                | NfsArgOp::OpsetclientidConfirm(_)
+                | NfsArgOp::OpreleaseLockOwner(_) => {}
+                _ => return Some(idx),
             }
         }
+        None
     }
 
-    fn operation_not_supported<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
-        NfsOpResponse {
-            request,
-            result: None,
-            status: NfsStat4::Nfs4errNotsupp,
+    // ask the embedder's authorizer, if any, whether the operation may run
+    fn authorize(arg: &NfsArgOp, request: &NfsRequest) -> Result<(), NfsStat4> {
+        let authorizer = match request.authorizer() {
+            Some(authorizer) => authorizer,
+            None => return Ok(()),
+        };
+        let current = request
+            .current_filehandle()
+            .map(|fh| fh.path.as_str())
+            .unwrap_or("");
+        let saved = request
+            .saved_filehandle()
+            .map(|fh| fh.path.as_str())
+            .unwrap_or("");
+        let op = opnum(arg);
+        for path in target_paths(arg, current, saved) {
+            authorizer
+                .authorize(request.principal().map(String::as_str), op, &path)
+                .inspect_err(|status| error!("{:?} on {:?} denied: {:?}", op, path, status))?;
         }
+        Ok(())
+    }
+
+    fn operation_not_supported(&self, op: NfsOpNum4, request: NfsRequest) -> NfsOpResponse {
+        error!("Operation {:?} not supported", op);
+        op_error(request, NfsStat4::Nfs4errNotsupp)
     }
 }
 
@@ -92,111 +326,74 @@ impl NfsProtoImpl for NFS40Server {
         0
     }
 
-    async fn null<'a>(&self, _: CallBody, request: NfsRequest<'a>) -> (NfsRequest<'a>, ReplyBody) {
-        (
-            request,
-            ReplyBody::MsgAccepted(AcceptedReply {
-                verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
-                reply_data: AcceptBody::Success(Compound4res {
-                    status: NfsStat4::Nfs4Ok,
-                    tag: "".to_string(),
-                    resarray: Vec::new(),
-                }),
-            }),
-        )
+    async fn null(&self, _: CallBody, request: NfsRequest) -> (NfsRequest, ReplyBody) {
+        compound_reply(request, String::new(), NfsStat4::Nfs4Ok, Vec::new())
     }
 
-    async fn compound<'a>(
-        &self,
-        msg: CallBody,
-        mut request: NfsRequest<'a>,
-    ) -> (NfsRequest<'a>, ReplyBody) {
+    async fn compound(&self, msg: CallBody, mut request: NfsRequest) -> (NfsRequest, ReplyBody) {
         let mut last_status = NfsStat4::Nfs4Ok;
+        request.set_principal(principal(&msg.cred));
+        request.set_uid(auth_sys_uid(&msg.cred));
+        let tag = msg
+            .args
+            .as_ref()
+            .map(|args| args.tag.clone())
+            .unwrap_or_default();
         let res = match msg.args {
             Some(args) => {
                 let mut resarray = Vec::with_capacity(args.argarray.len());
+                let mut reply_size = 0;
+                let no_filehandle_idx = Self::first_op_without_filehandle(&args.argarray);
                 // The server will process the COMPOUND procedure by evaluating each of
                 // the operations within the COMPOUND procedure in order.
-                for arg in args.argarray {
-                    let response = match arg {
-                        // these should never be called
-                        NfsArgOp::OpUndef0 | NfsArgOp::OpUndef1 | NfsArgOp::OpUndef2 => {
-                            self.operation_not_supported(request)
-                        }
-                        // these are actual operations
-                        NfsArgOp::Opgetfh(_) => self.get_current_filehandle(request),
-                        NfsArgOp::Opsetclientid(args) => args.execute(request).await,
-                        NfsArgOp::OpAccess(args) => args.execute(request).await,
-                        NfsArgOp::Opclose(args) => args.execute(request).await,
-                        NfsArgOp::Opgetattr(args) => args.execute(request).await,
-                        NfsArgOp::Oplookup(args) => args.execute(request).await,
-                        NfsArgOp::Opopen(args) => args.execute(request).await,
-                        NfsArgOp::OpopenConfirm(args) => args.execute(request).await,
-                        NfsArgOp::Opputfh(args) => args.execute(request).await,
-                        NfsArgOp::Opputrootfh(_) => self.put_root_filehandle(request).await,
-                        NfsArgOp::Opread(args) => args.execute(request).await,
-                        NfsArgOp::Opreaddir(args) => args.execute(request).await,
-                        NfsArgOp::Oprenew(args) => args.execute(request).await,
-                        NfsArgOp::OpsetclientidConfirm(args) => args.execute(request).await,
-                        NfsArgOp::Opsetattr(args) => args.execute(request).await,
-                        NfsArgOp::Opremove(args) => args.execute(request).await,
-                        NfsArgOp::Opwrite(args) => args.execute(request).await,
-
-                        NfsArgOp::Opcommit(args) => args.execute(request).await,
-                        NfsArgOp::Opcreate(args) => args.execute(request).await,
-
-                        NfsArgOp::Opdelegpurge(_) => self.operation_not_supported(request),
-                        NfsArgOp::Opdelegreturn(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Oplink(_) => self.operation_not_supported(request),
-                        NfsArgOp::Oplock(_) => self.operation_not_supported(request),
-                        NfsArgOp::Oplockt(_) => self.operation_not_supported(request),
-                        NfsArgOp::Oplocku(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Oplookupp(_) => self.operation_not_supported(request),
-                        NfsArgOp::Opnverify(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Opopenattr(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::OpopenDowngrade(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Opputpubfh(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Opreadlink(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Oprename(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Oprestorefh(_) => self.operation_not_supported(request),
-                        NfsArgOp::Opsavefh(_) => self.operation_not_supported(request),
-                        NfsArgOp::OpSecinfo(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::Opverify(_) => self.operation_not_supported(request),
-
-                        NfsArgOp::OpreleaseLockOwner(_) => self.operation_not_supported(request),
-                    };
+                for (idx, arg) in args.argarray.into_iter().enumerate() {
+                    let op = opnum(&arg);
+                    // logs of the operation tell where in the compound it is
+                    let span = info_span!("op", index = idx, ?op);
+                    if no_filehandle_idx == Some(idx) {
+                        let response = span.in_scope(|| self.no_filehandle(request));
+                        last_status = response.status;
+                        resarray.push(failed(op, last_status.clone()));
+                        request = response.request;
+                        break;
+                    }
+                    if let Err(status) = span.in_scope(|| Self::authorize(&arg, &request)) {
+                        last_status = status;
+                        resarray.push(failed(op, last_status.clone()));
+                        break;
+                    }
+                    let response = self
+                        .execute(op, arg, request)
+                        .instrument(span.clone())
+                        .await;
                     // match the result of the operation, pass on success, return on error
                     let res = response.result;
                     last_status = response.status;
                     if let Some(res) = res {
+                        // stop before the reply grows beyond what fits into
+                        // a single record
+                        reply_size += xdr_size(&res);
+                        if reply_size > MAX_RECORD_SIZE - REPLY_HEADER_RESERVE {
+                            span.in_scope(|| error!("Reply exceeds {} bytes", MAX_RECORD_SIZE));
+                            last_status = NfsStat4::Nfs4errResource;
+                            resarray.push(failed(op, last_status.clone()));
+                            request = response.request;
+                            break;
+                        }
                         resarray.push(res);
                     } else {
+                        // the reply carries the status of the failed
+                        // operation after the results of those before it
+                        if last_status != NfsStat4::Nfs4Ok {
+                            resarray.push(failed(op, last_status.clone()));
+                        }
                         request = response.request;
                         break;
                     }
                     match last_status {
                         NfsStat4::Nfs4Ok => {}
                         _ => {
-                            return (
-                                response.request,
-                                ReplyBody::MsgAccepted(AcceptedReply {
-                                    verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
-                                    reply_data: AcceptBody::Success(Compound4res {
-                                        status: last_status,
-                                        tag: "".to_string(),
-                                        resarray,
-                                    }),
-                                }),
-                            );
+                            return compound_reply(response.request, tag, last_status, resarray);
                         }
                     }
                     // pass on the request to the next operation
@@ -204,23 +401,667 @@ impl NfsProtoImpl for NFS40Server {
                 }
                 resarray
             }
-            None => Vec::new(),
+            None => {
+                if msg.malformed_args {
+                    error!("Couldn't decode compound arguments");
+                    return error_reply(request, NfsStat4::Nfs4errBadxdr);
+                }
+                Vec::new()
+            }
         };
 
-        (
-            request,
+        compound_reply(request, tag, last_status, res)
+    }
+
+    fn minor_version(&self) -> u32 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use std::sync::Arc;
+
+    use crate::{
+        server::{
+            authorizer::{opnum, Authorizer},
+            clientmanager::ClientManagerHandle,
+            filemanager::FileManagerHandle,
+            nfs40::{error_reply, NFS40Server},
+            request::NfsRequest,
+            response::op_error,
+            NfsProtoImpl,
+        },
+        test_utils::{create_client, create_fake_fs, create_nfs40_server},
+    };
+    use bold_proto::{nfs4_proto::*, rpc_proto::*, utils::xdr_size, MAX_RECORD_SIZE};
+    use tracing_test::traced_test;
+    use vfs::{MemoryFS, VfsPath};
+
+    fn compound_call(argarray: Vec<NfsArgOp>) -> CallBody {
+        CallBody {
+            rpcvers: 2,
+            prog: 100003,
+            vers: 4,
+            proc: 1,
+            cred: OpaqueAuth::AuthNull(Vec::new()),
+            verf: OpaqueAuth::AuthNull(Vec::new()),
+            args: Some(Compound4args {
+                tag: "".to_string(),
+                minor_version: 0,
+                argarray,
+            }),
+            malformed_args: false,
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_error_reply() {
+        let request = create_nfs40_server(None).await;
+        let (request, reply) = error_reply(request, NfsStat4::Nfs4errBadxdr);
+        let expected = ReplyBody::MsgAccepted(AcceptedReply {
+            verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
+            reply_data: AcceptBody::Success(Compound4res {
+                status: NfsStat4::Nfs4errBadxdr,
+                tag: "".to_string(),
+                resarray: Vec::new(),
+            }),
+        });
+        let (mut bytes, mut expected_bytes) = (Vec::new(), Vec::new());
+        serde_xdr::to_writer(&mut bytes, &reply).unwrap();
+        serde_xdr::to_writer(&mut expected_bytes, &expected).unwrap();
+        assert_eq!(bytes, expected_bytes);
+
+        let response = op_error(request, NfsStat4::Nfs4errStale);
+        assert!(response.result.is_none());
+        assert_eq!(response.status, NfsStat4::Nfs4errStale);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_tag() {
+        let request = create_nfs40_server(None).await;
+        let server = NFS40Server::new();
+        let mut call = compound_call(vec![NfsArgOp::Opputrootfh(()), NfsArgOp::Opgetfh(())]);
+        call.args.as_mut().unwrap().tag = "mount /export".to_string();
+
+        let (_, reply) = server.compound(call, request).await;
+        match reply {
             ReplyBody::MsgAccepted(AcceptedReply {
-                verf: OpaqueAuth::AuthNull(Vec::<u8>::new()),
-                reply_data: AcceptBody::Success(Compound4res {
-                    status: last_status,
-                    tag: "".to_string(),
-                    resarray: res,
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => {
+                assert_eq!(res.status, NfsStat4::Nfs4Ok);
+                assert_eq!(res.tag, "mount /export");
+                assert_eq!(res.resarray.len(), 2);
+            }
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_tag_on_error() {
+        // failing compounds carry the tag as well
+        let request = create_nfs40_server(Some(create_fake_fs())).await;
+        let server = NFS40Server::new();
+        let mut call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "missing".to_string(),
+            }),
+            NfsArgOp::Opgetfh(()),
+        ]);
+        call.args.as_mut().unwrap().tag = "lookup missing".to_string();
+
+        let (_, reply) = server.compound(call, request).await;
+        match reply {
+            ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errNoent);
+                assert_eq!(res.tag, "lookup missing");
+                assert_eq!(res.resarray.len(), 2);
+            }
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_without_filehandle() {
+        let request = create_nfs40_server(None).await;
+        let server = NFS40Server::new();
+        let call = compound_call(vec![NfsArgOp::Opgetattr(Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+        })]);
+
+        let (_, reply) = server.compound(call, request).await;
+        match reply {
+            ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errNofilehandle);
+                assert_eq!(
+                    res.resarray,
+                    vec![NfsResOp4::OpFailed(OpFailed4 {
+                        op: NfsOpNum4::OpGetattr as u32,
+                        status: NfsStat4::Nfs4errNofilehandle,
+                    })]
+                );
+            }
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_unsupported_ops() {
+        let server = NFS40Server::new();
+        let mut request = create_nfs40_server(None).await;
+        let ops = [
+            (NfsArgOp::OpUndef0, NfsStat4::Nfs4errOpIllegal),
+            (NfsArgOp::OpUndef2, NfsStat4::Nfs4errOpIllegal),
+            (NfsArgOp::Oplookupp(()), NfsStat4::Nfs4errNotsupp),
+            (NfsArgOp::Opputpubfh(()), NfsStat4::Nfs4errNotsupp),
+        ];
+        for (op, status) in ops {
+            let call = compound_call(vec![NfsArgOp::Opputrootfh(()), op]);
+            let (next, reply) = server.compound(call, request).await;
+            match reply {
+                ReplyBody::MsgAccepted(AcceptedReply {
+                    reply_data: AcceptBody::Success(res),
+                    ..
+                }) => {
+                    assert_eq!(res.status, status);
+                    assert_eq!(res.resarray.len(), 2);
+                }
+                _ => panic!("Unexpected reply"),
+            }
+            request = next;
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_partial_results() {
+        let request = create_nfs40_server(Some(create_fake_fs())).await;
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "missing".to_string(),
+            }),
+            NfsArgOp::Opgetfh(()),
+        ]);
+
+        let (_, reply) = NFS40Server::new().compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errNoent);
+        // the results up to and including the failed LOOKUP, nothing after
+        assert_eq!(
+            resarray,
+            vec![
+                NfsResOp4::Opputrootfh(PutRootFh4res {
+                    status: NfsStat4::Nfs4Ok
+                }),
+                NfsResOp4::Oplookup(Lookup4res {
+                    status: NfsStat4::Nfs4errNoent
                 }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_compound_reply_too_large() {
+        let root: VfsPath = MemoryFS::new().into();
+        root.join("big")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(&vec![1; 5 * 1024 * 1024])
+            .unwrap();
+        let request = create_nfs40_server(Some(root)).await;
+        let read = Read4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            count: 5 * 1024 * 1024,
+        };
+        // two reads of 5 MiB each don't fit into a single reply
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "big".to_string(),
             }),
-        )
+            NfsArgOp::Opread(read.clone()),
+            NfsArgOp::Opread(read),
+        ]);
+
+        let (_, reply) = NFS40Server::new().compound(call, request).await;
+        match reply {
+            ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errResource);
+                // the second READ is answered with NFS4ERR_RESOURCE
+                assert_eq!(res.resarray.len(), 4);
+                assert_eq!(
+                    res.resarray[3],
+                    NfsResOp4::OpFailed(OpFailed4 {
+                        op: NfsOpNum4::OpRead as u32,
+                        status: NfsStat4::Nfs4errResource,
+                    })
+                );
+                assert!(xdr_size(&res) < MAX_RECORD_SIZE);
+            }
+            _ => panic!("Unexpected reply"),
+        }
     }
 
-    fn minor_version(&self) -> u32 {
-        0
+    fn compound_status(reply: ReplyBody) -> (NfsStat4, Vec<NfsResOp4>) {
+        match reply {
+            ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => (res.status, res.resarray),
+            _ => panic!("Unexpected reply"),
+        }
+    }
+
+    fn auth_sys(uid: u32) -> OpaqueAuth {
+        OpaqueAuth::AuthUnix(AuthUnix {
+            stamp: 0,
+            machinename: "laptop".to_string(),
+            uid,
+            gid: uid,
+            gids: vec![],
+        })
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setclientid_principal() {
+        let request = create_nfs40_server(None).await;
+        let server = NFS40Server::new();
+        let client_id = "Linux NFSv4.0 LAPTOP/127.0.0.1".to_string();
+
+        // uid 1000 establishes and confirms the client id
+        let mut call = compound_call(vec![NfsArgOp::Opsetclientid(create_client(
+            [1; 8],
+            client_id.clone(),
+        ))]);
+        call.cred = auth_sys(1000);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let resok = match &resarray[0] {
+            NfsResOp4::Opsetclientid(SetClientId4res::Resok4(resok)) => resok.clone(),
+            _ => panic!("Unexpected response"),
+        };
+        let mut call = compound_call(vec![NfsArgOp::OpsetclientidConfirm(
+            SetClientIdConfirm4args {
+                clientid: resok.clientid,
+                setclientid_confirm: resok.setclientid_confirm,
+            },
+        )]);
+        call.cred = auth_sys(1000);
+        let (request, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4Ok);
+
+        // another principal can't take it over
+        let mut call = compound_call(vec![NfsArgOp::Opsetclientid(create_client(
+            [2; 8],
+            client_id.clone(),
+        ))]);
+        call.cred = auth_sys(1001);
+        let (request, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4errClidInuse);
+
+        // neither can a client without credentials
+        let call = compound_call(vec![NfsArgOp::Opsetclientid(create_client(
+            [2; 8],
+            client_id.clone(),
+        ))]);
+        let (request, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4errClidInuse);
+
+        // the owning principal still can
+        let mut call = compound_call(vec![NfsArgOp::Opsetclientid(create_client(
+            [2; 8],
+            client_id.clone(),
+        ))]);
+        call.cred = auth_sys(1000);
+        let (_, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4Ok);
+    }
+
+    // denies removing, moving and linking anything below a directory
+    #[derive(Debug)]
+    struct ProtectDir(&'static str);
+
+    impl Authorizer for ProtectDir {
+        fn authorize(
+            &self,
+            _principal: Option<&str>,
+            op: NfsOpNum4,
+            path: &str,
+        ) -> Result<(), NfsStat4> {
+            let changes = matches!(
+                op,
+                NfsOpNum4::OpRemove | NfsOpNum4::OpRename | NfsOpNum4::OpLink
+            );
+            if changes && path.starts_with(self.0) {
+                return Err(NfsStat4::Nfs4errAccess);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_authorizer() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        request.set_authorizer(Some(Arc::new(ProtectDir("/dir1/"))));
+        let server = NFS40Server::new();
+
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "dir1".to_string(),
+            }),
+            NfsArgOp::Opremove(Remove4args {
+                target: "file2.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errAccess);
+        // PUTROOTFH and LOOKUP went through, REMOVE was denied
+        assert_eq!(resarray.len(), 3);
+        assert!(request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .is_ok());
+
+        // anything else is allowed
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Opremove(Remove4args {
+                target: "file1.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        assert_ne!(compound_status(reply).0, NfsStat4::Nfs4errAccess);
+        assert!(request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_authorizer_rename() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        request.set_authorizer(Some(Arc::new(ProtectDir("/dir1/"))));
+        let server = NFS40Server::new();
+
+        // moving something out of the directory, the source is protected
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "dir1".to_string(),
+            }),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oprename(Rename4args {
+                oldname: "file2.txt".to_string(),
+                newname: "moved.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errAccess);
+        assert_eq!(resarray.len(), 5);
+
+        // moving something into it, the target is protected
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "dir1".to_string(),
+            }),
+            NfsArgOp::Oprename(Rename4args {
+                oldname: "file1.txt".to_string(),
+                newname: "file1.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errAccess);
+        assert_eq!(resarray.len(), 4);
+        for path in ["/dir1/file2.txt", "/file1.txt"] {
+            assert!(request
+                .file_manager()
+                .get_filehandle_for_path(path.to_string())
+                .await
+                .is_ok());
+        }
+
+        // renames elsewhere are allowed
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Oprename(Rename4args {
+                oldname: "file1.txt".to_string(),
+                newname: "moved.txt".to_string(),
+            }),
+        ]);
+        let (_, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4Ok);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_authorizer_link() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        request.set_authorizer(Some(Arc::new(ProtectDir("/dir1/"))));
+        let server = NFS40Server::new();
+
+        // a new name in the directory is protected
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "file1.txt".to_string(),
+            }),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "dir1".to_string(),
+            }),
+            NfsArgOp::Oplink(Link4args {
+                newname: "link.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errAccess);
+        assert_eq!(resarray.len(), 6);
+
+        // and so is a file in it getting a name outside
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "dir1".to_string(),
+            }),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "file2.txt".to_string(),
+            }),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplink(Link4args {
+                newname: "link.txt".to_string(),
+            }),
+        ]);
+        let (request, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errAccess);
+        assert_eq!(resarray.len(), 6);
+
+        // anything else gets past the authorizer, MemoryFS has no links
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "file1.txt".to_string(),
+            }),
+            NfsArgOp::Opsavefh(()),
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Oplink(Link4args {
+                newname: "link.txt".to_string(),
+            }),
+        ]);
+        let (_, reply) = server.compound(call, request).await;
+        assert_eq!(compound_status(reply).0, NfsStat4::Nfs4errNotsupp);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_ops_without_filehandle() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let server = NFS40Server::new();
+        let stateid = Stateid4 {
+            seqid: 0,
+            other: [0; 12],
+        };
+        let no_attrs = Fattr4 {
+            attrmask: Attrlist4::<FileAttr>::new(None),
+            attr_vals: Attrlist4::<FileAttrValue>::new(None),
+        };
+
+        // run on their own, without the compound checking for a filehandle
+        let ops = vec![
+            NfsArgOp::Oplookup(Lookup4args {
+                objname: "file1.txt".to_string(),
+            }),
+            NfsArgOp::Opread(Read4args {
+                stateid: stateid.clone(),
+                offset: 0,
+                count: 10,
+            }),
+            NfsArgOp::Opwrite(Write4args {
+                stateid: stateid.clone(),
+                offset: 0,
+                stable: StableHow4::Unstable4,
+                data: b"data".to_vec(),
+            }),
+            NfsArgOp::Opcommit(Commit4args {
+                offset: 0,
+                count: 0,
+            }),
+            NfsArgOp::Opreaddir(Readdir4args {
+                cookie: 0,
+                cookieverf: [0; 8],
+                dircount: 1024,
+                maxcount: 4096,
+                attr_request: Attrlist4::<FileAttr>::new(None),
+            }),
+            NfsArgOp::Opcreate(Create4args {
+                objtype: Createtype4::Nf4dir,
+                objname: "dir".to_string(),
+                createattrs: no_attrs.clone(),
+            }),
+            NfsArgOp::Opremove(Remove4args {
+                target: "file1.txt".to_string(),
+            }),
+            NfsArgOp::Opgetattr(Getattr4args {
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            }),
+            NfsArgOp::Opsetattr(SetAttr4args {
+                stateid,
+                obj_attributes: no_attrs,
+            }),
+        ];
+        for arg in ops {
+            let op = opnum(&arg);
+            let response = server.execute(op, arg, request).await;
+            assert_eq!(
+                response.status,
+                NfsStat4::Nfs4errNofilehandle,
+                "{:?} without a filehandle",
+                op
+            );
+            request = response.request;
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_putfh_forged() {
+        let request = create_nfs40_server(Some(create_fake_fs())).await;
+        let server = NFS40Server::new();
+
+        // a forged filehandle fails at PUTFH, not at the GETATTR using it
+        let call = compound_call(vec![
+            NfsArgOp::Opputfh(PutFh4args { object: [7; 26] }),
+            NfsArgOp::Opgetattr(Getattr4args {
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            }),
+        ]);
+        let (_, reply) = server.compound(call, request).await;
+        let (status, resarray) = compound_status(reply);
+        assert_eq!(status, NfsStat4::Nfs4errBadhandle);
+        assert_eq!(
+            resarray,
+            vec![NfsResOp4::Opputfh(PutFh4res {
+                status: NfsStat4::Nfs4errBadhandle
+            })]
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_request_builder() {
+        let request = NfsRequest::builder(
+            ClientManagerHandle::new(),
+            FileManagerHandle::new(create_fake_fs(), None),
+        )
+        .client_addr("[::ffff:10.0.0.1]:700".parse().unwrap())
+        .principal("1000@laptop")
+        .build();
+        assert_eq!(request.client_addr(), "10.0.0.1:700");
+        assert_eq!(request.principal().unwrap(), "1000@laptop");
+
+        let server = NFS40Server::new();
+        let call = compound_call(vec![
+            NfsArgOp::Opputrootfh(()),
+            NfsArgOp::Opgetattr(Getattr4args {
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            }),
+        ]);
+        let (_, reply) = server.compound(call, request).await;
+        match reply {
+            ReplyBody::MsgAccepted(AcceptedReply {
+                reply_data: AcceptBody::Success(res),
+                ..
+            }) => {
+                assert_eq!(res.status, NfsStat4::Nfs4Ok);
+                match &res.resarray[1] {
+                    NfsResOp4::Opgetattr(res) => {
+                        let attrs = res.obj_attributes.as_ref().unwrap();
+                        assert_eq!(attrs.attr_vals[0], FileAttrValue::Type(NfsFtype4::Nf4dir));
+                    }
+                    _ => panic!("Unexpected result"),
+                }
+            }
+            _ => panic!("Unexpected reply"),
+        }
     }
 }
@@ -1,30 +1,65 @@
 use async_trait::async_trait;
-use tracing::debug;
+use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{
-    Access4args, Access4res, Access4resok, NfsResOp4, NfsStat4, ACCESS4_DELETE, ACCESS4_EXECUTE,
-    ACCESS4_EXTEND, ACCESS4_LOOKUP, ACCESS4_MODIFY, ACCESS4_READ,
+    Access4args, Access4res, Access4resok, NfsFtype4, NfsResOp4, NfsStat4, ACCESS4_DELETE,
+    ACCESS4_EXECUTE, ACCESS4_EXTEND, ACCESS4_LOOKUP, ACCESS4_MODIFY, ACCESS4_READ,
 };
 
+const ACCESS4_ALL: u32 = ACCESS4_READ
+    | ACCESS4_LOOKUP
+    | ACCESS4_MODIFY
+    | ACCESS4_EXTEND
+    | ACCESS4_DELETE
+    | ACCESS4_EXECUTE;
+
+/// The access bits that have a meaning for an object of the given type,
+/// see https://datatracker.ietf.org/doc/html/rfc7530#section-16.1
+fn supported_access(ftype: &NfsFtype4) -> u32 {
+    match ftype {
+        // LOOKUP and DELETE only apply to directory entries, EXECUTE has no
+        // meaning for a directory
+        NfsFtype4::Nf4dir | NfsFtype4::Nf4attrdir => {
+            ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_DELETE
+        }
+        NfsFtype4::Nf4reg | NfsFtype4::Nf4namedattr => {
+            ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_EXECUTE
+        }
+        // the only thing to do with a symlink is to read it with READLINK
+        NfsFtype4::Nf4lnk => ACCESS4_READ,
+        // special files are read and written, never executed
+        NfsFtype4::Nf4blk | NfsFtype4::Nf4chr | NfsFtype4::Nf4sock | NfsFtype4::Nf4fifo => {
+            ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND
+        }
+        NfsFtype4::Nf4Undef => ACCESS4_ALL,
+    }
+}
+
 #[async_trait]
 impl NfsOperation for Access4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 3: ACCESS - Check Access Rights {:?}, with request {:?}",
             self, request
         );
+        let supported = match request.current_filehandle() {
+            Some(filehandle) => supported_access(&filehandle.attr_type),
+            None => {
+                error!("None filehandle");
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
+            }
+        };
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::OpAccess(Access4res::Resok4(Access4resok {
-                supported: ACCESS4_READ
-                    | ACCESS4_LOOKUP
-                    | ACCESS4_MODIFY
-                    | ACCESS4_EXTEND
-                    | ACCESS4_DELETE
-                    | ACCESS4_EXECUTE,
-                access: self.access,
+                supported,
+                access: self.access & supported,
             }))),
             status: NfsStat4::Nfs4Ok,
         }
@@ -41,10 +76,58 @@ mod integration_tests {
             },
             operation::NfsOperation,
         },
-        test_utils::create_nfs40_server,
+        test_utils::{create_fake_fs, create_nfs40_server},
     };
+    use bold_proto::nfs4_proto::NfsFtype4;
     use tracing_test::traced_test;
 
+    async fn check_access_for_type(ftype: NfsFtype4) -> (u32, u32) {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let mut filehandle = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        // the memory fs has no special files, pretend this is one
+        filehandle.attr_type = ftype;
+        request.set_filehandle(filehandle);
+
+        let args = Access4args {
+            access: ACCESS4_READ
+                | ACCESS4_LOOKUP
+                | ACCESS4_MODIFY
+                | ACCESS4_EXTEND
+                | ACCESS4_DELETE
+                | ACCESS4_EXECUTE,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::OpAccess(Access4res::Resok4(res))) => (res.supported, res.access),
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    async fn check_access_for_path(path: &str) -> (u32, u32) {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let filehandle = request
+            .file_manager()
+            .get_filehandle_for_path(path.to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(filehandle);
+
+        let args = Access4args {
+            access: ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_EXECUTE,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::OpAccess(Access4res::Resok4(res))) => (res.supported, res.access),
+            _ => panic!("Unexpected response"),
+        }
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_check_access() {
@@ -57,23 +140,43 @@ mod integration_tests {
                 | ACCESS4_DELETE,
         };
         let response = args.execute(request).await;
-        assert_eq!(response.status, NfsStat4::Nfs4Ok);
-        if let Some(NfsResOp4::OpAccess(Access4res::Resok4(res))) = response.result {
-            assert_eq!(
-                res.supported,
-                ACCESS4_READ
-                    | ACCESS4_LOOKUP
-                    | ACCESS4_MODIFY
-                    | ACCESS4_EXTEND
-                    | ACCESS4_DELETE
-                    | ACCESS4_EXECUTE
-            );
-            assert_eq!(
-                res.access,
-                ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_DELETE
-            );
-        } else {
-            panic!("Unexpected response: {:?}", response);
-        }
+        assert_eq!(response.status, NfsStat4::Nfs4errNofilehandle);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_check_access_dir_and_file() {
+        let (supported, access) = check_access_for_path("/dir1").await;
+        assert_eq!(
+            supported,
+            ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_DELETE
+        );
+        assert_eq!(access, ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY);
+
+        let (supported, access) = check_access_for_path("/file1.txt").await;
+        assert_eq!(
+            supported,
+            ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_EXECUTE
+        );
+        assert_eq!(access, ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXECUTE);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_check_access_special_files() {
+        let (supported, access) = check_access_for_type(NfsFtype4::Nf4lnk).await;
+        assert_eq!(supported, ACCESS4_READ);
+        assert_eq!(access, ACCESS4_READ);
+
+        let (supported, access) = check_access_for_type(NfsFtype4::Nf4fifo).await;
+        assert_eq!(supported, ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND);
+        assert_eq!(access, ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND);
+
+        let (supported, _) = check_access_for_type(NfsFtype4::Nf4reg).await;
+        assert_eq!(
+            supported,
+            ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_EXECUTE
+        );
     }
 }
@@ -1,28 +1,129 @@
 use async_trait::async_trait;
-use tracing::debug;
+use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
-use bold_proto::nfs4_proto::{Close4args, Close4res, NfsResOp4, NfsStat4, Stateid4};
+use bold_proto::nfs4_proto::{Close4args, Close4res, NfsResOp4, NfsStat4};
 
 #[async_trait]
 impl NfsOperation for Close4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 4: CLOSE - Close File {:?}, with request {:?}",
             self, request
         );
 
         let current_filehandle = request.current_filehandle().unwrap();
-        request.drop_filehandle_from_cache(current_filehandle.id);
+        let id = current_filehandle.id;
+        // cached writes go to the backend first, if that fails the file
+        // stays open so the client learns about it
+        if let Err(e) = request.file_manager().flush_write_cache(id).await {
+            error!("Couldn't write cached data: {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+        request.drop_filehandle_from_cache(id);
+        if let Some(readahead) = request.file_manager().readahead() {
+            readahead.remove(&id);
+        }
+
+        let stateid = match request
+            .file_manager()
+            .close_file(self.open_stateid.clone())
+            .await
+        {
+            Ok(stateid) => stateid,
+            Err(e) => {
+                error!("Couldn't close file: {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        };
 
         NfsOpResponse {
             request,
-            result: Some(NfsResOp4::Opclose(Close4res::OpenStateid(Stateid4 {
-                seqid: self.seqid,
-                other: self.open_stateid.other,
-            }))),
+            result: Some(NfsResOp4::Opclose(Close4res::OpenStateid(stateid))),
             status: NfsStat4::Nfs4Ok,
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            nfs40::{
+                Close4args, Close4res, CreateHow4, NfsResOp4, NfsStat4, Open4args, Open4res,
+                OpenClaim4, OpenConfirm4args, OpenFlag4, OpenOwner4, Read4args,
+                OPEN4_SHARE_ACCESS_BOTH,
+            },
+            operation::NfsOperation,
+        },
+        test_utils::create_nfs40_server,
+    };
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_close_stateid() {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        let args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_BOTH,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8])),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        let open_stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+        let args = OpenConfirm4args {
+            open_stateid: open_stateid.clone(),
+            seqid: 1,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // CLOSE replies with the stateid of the open, its seqid bumped
+        let mut confirmed = open_stateid.clone();
+        confirmed.seqid += 1;
+        let args = Close4args {
+            seqid: 2,
+            open_stateid: confirmed.clone(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let closed = match response.result {
+            Some(NfsResOp4::Opclose(Close4res::OpenStateid(stateid))) => stateid,
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(closed.seqid, confirmed.seqid + 1);
+        assert_eq!(closed.other, open_stateid.other);
+
+        // neither the stateid passed to CLOSE nor the one it returned are
+        // good for anything after that
+        let args = Read4args {
+            stateid: confirmed,
+            offset: 0,
+            count: 4,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errBadStateid);
+        let args = Close4args {
+            seqid: 3,
+            open_stateid: closed,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errBadStateid);
+    }
+}
@@ -1,19 +1,18 @@
 use async_trait::async_trait;
 use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    nfs40::range_end,
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{Commit4args, Commit4res, Commit4resok, NfsResOp4, NfsStat4};
 
-fn verifier_from_boot(boot_time: &u64) -> [u8; 8] {
-    let mut verifier = [0; 8];
-    verifier.copy_from_slice(boot_time.to_be_bytes().as_ref());
-    verifier
-}
-
 #[async_trait]
 impl NfsOperation for Commit4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 5: COMMIT - Commit Cached Data {:?}, with request {:?}",
             self, request
@@ -23,37 +22,205 @@ impl NfsOperation for Commit4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-16.3.5
+        if let Err(status) = range_end(self.offset, self.count as u64) {
+            return op_error(request, status);
+        }
+        // state isn't reclaimed after a restart, so there's no grace period
+        // that could refuse a COMMIT with NFS4ERR_GRACE. Without unstable
+        // writes since the last one, a COMMIT has nothing to do.
+
         // unlock write cache & write file
+        let _lock = request.file_manager().lock_file(filehandle.id).await;
 
-        let write_cache = &request
+        let write_cache = match request
             .file_manager()
             .get_write_cache_handle(filehandle.clone())
             .await
-            .unwrap();
+        {
+            Ok(write_cache) => write_cache,
+            Err(e) => {
+                error!("Couldn't get write cache {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        };
         // // TODO: this commits the whole cache, we should only commit the data up to the offset
-        write_cache.commit().await;
+        if let Err(e) = write_cache.commit().await {
+            return op_error(request, e.nfs_error);
+        }
 
-        request
-            .file_manager()
-            .touch_file(filehandle.id.clone())
-            .await;
+        request.file_manager().touch_file(filehandle.id).await;
 
-        request.drop_filehandle_from_cache(filehandle.id.clone());
-        let boot_time = request.boot_time;
+        request.drop_filehandle_from_cache(filehandle.id);
+        let writeverf = request.boot_verifier;
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::Opcommit(Commit4res::Resok4(Commit4resok {
-                writeverf: verifier_from_boot(&boot_time),
+                writeverf,
             }))),
             status: NfsStat4::Nfs4Ok,
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use std::io::{self, Write};
+
+    use crate::{
+        server::{
+            nfs40::{
+                Close4args, Commit4args, Commit4res, NfsResOp4, NfsStat4, Open4args, Open4res,
+                OpenClaim4, OpenFlag4, OpenOwner4, StableHow4, Stateid4, Write4args,
+            },
+            operation::NfsOperation,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server, FaultFS, FsCall},
+    };
+    use bold_proto::nfs4_proto::OPEN4_SHARE_ACCESS_BOTH;
+    use tracing_test::traced_test;
+    use vfs::VfsPath;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_commit_after_write() {
+        let root = create_fake_fs();
+        let mut request = create_nfs40_server(Some(root.clone())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        for round in 0..20_u8 {
+            let data = vec![b'a' + round; 5];
+            let args = Write4args {
+                stateid: Stateid4 {
+                    seqid: 0,
+                    other: [0; 12],
+                },
+                offset: 0,
+                stable: StableHow4::Unstable4,
+                data: data.clone(),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+            // the COMMIT right behind the WRITE has to cover it
+            let args = Commit4args {
+                offset: 0,
+                count: 0,
+            };
+            let response = args.execute(response.request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            let mut content = String::new();
+            root.join("file1.txt")
+                .unwrap()
+                .open_file()
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            assert!(content.as_bytes().starts_with(&data));
+            request = response.request;
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_commit_without_writes() {
+        // right after the start, when a server with reclaimable state would
+        // be in its grace period
+        let root = create_fake_fs();
+        let mut request = create_nfs40_server(Some(root.clone())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Commit4args {
+            offset: 0,
+            count: 0,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opcommit(Commit4res::Resok4(res))) => {
+                assert_eq!(res.writeverf, response.request.boot_verifier);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let content = root.join("file1.txt").unwrap().read_to_string().unwrap();
+        assert_eq!(content, "Hello, loooooooong world!");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_commit_backend_error() {
+        let root: VfsPath = FaultFS::default()
+            .fail(FsCall::Append, "broken", io::ErrorKind::Other)
+            .into();
+        root.join("broken")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+        let mut request = create_nfs40_server(Some(root.clone())).await;
+        let dir = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(dir);
+        let args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_BOTH,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::Open4Nocreate,
+            claim: OpenClaim4::ClaimNull("broken".to_string()),
+        };
+        let response = args.execute(request).await;
+        let stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+
+        let args = Write4args {
+            stateid: stateid.clone(),
+            offset: 0,
+            stable: StableHow4::Unstable4,
+            data: b"new!".to_vec(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // the data can't be written out, it stays cached and every COMMIT
+        // tries again
+        let mut request = response.request;
+        for _ in 0..2 {
+            let args = Commit4args {
+                offset: 0,
+                count: 0,
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4errIo);
+            request = response.request;
+        }
+        // so does CLOSE, which leaves the file open
+        let args = Close4args {
+            seqid: 1,
+            open_stateid: stateid,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errIo);
+        let content = root.join("broken").unwrap().read_to_string().unwrap();
+        assert_eq!(content, "data");
+    }
+}
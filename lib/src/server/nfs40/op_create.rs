@@ -1,16 +1,21 @@
 use async_trait::async_trait;
 use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    nfs40::create_mode,
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{
     Attrlist4, ChangeInfo4, Create4args, Create4res, Create4resok, Createtype4, FileAttr,
-    NfsResOp4, NfsStat4,
+    NfsFtype4, NfsResOp4, NfsStat4,
 };
 
 #[async_trait]
 impl NfsOperation for Create4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 6: CREATE - Create a Non-regular File Object {:?}, with request {:?}",
             self, request
@@ -21,28 +26,73 @@ impl NfsOperation for Create4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
-        if self.objname.len() == 0 {
+        if self.objname.is_empty() {
             // If the objname is of zero length, NFS4ERR_INVAL will be returned.
             // The objname is also subject to the normal UTF-8, character support,
             // and name checks.  See Section 12.7 for further discussion.
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errInval,
-            };
+            return op_error(request, NfsStat4::Nfs4errInval);
         }
 
-        let (cinfo, attrset) = match self.objtype {
-            // TODO support links
-            // LinkData(vec) => todo!(),
+        let (cinfo, attrset) = match &self.objtype {
+            Createtype4::Nf4lnk(_) | Createtype4::Nf4blk(_) | Createtype4::Nf4chr(_) => {
+                if !filehandle.file.is_dir().unwrap_or(false) {
+                    return op_error(request, NfsStat4::Nfs4errNotdir);
+                }
+                let parent_id = filehandle.id;
+                let parent_change = filehandle.attr_change;
+                let path = filehandle.file.join(&self.objname).unwrap();
+                let fmanager = request.file_manager();
+                let resp = match &self.objtype {
+                    Createtype4::Nf4lnk(linktext) => {
+                        fmanager.create_symlink(path, linktext.clone()).await
+                    }
+                    Createtype4::Nf4blk(rawdev) | Createtype4::Nf4chr(rawdev) => {
+                        // like mknod(2), only root may create device nodes
+                        if request.uid() != Some(0) {
+                            error!("Only root may create devices");
+                            return op_error(request, NfsStat4::Nfs4errPerm);
+                        }
+                        let ftype = match self.objtype {
+                            Createtype4::Nf4blk(_) => NfsFtype4::Nf4blk,
+                            _ => NfsFtype4::Nf4chr,
+                        };
+                        fmanager.create_device(path, ftype, *rawdev).await
+                    }
+                    _ => unreachable!(),
+                };
+                let filehandle = match resp {
+                    Ok(filehandle) => filehandle,
+                    Err(e) => {
+                        debug!("FileManagerError {:?}", e);
+                        return op_error(request, e.nfs_error);
+                    }
+                };
+                // the parent directory was touched while creating the link
+                let after = match request
+                    .file_manager()
+                    .get_filehandle_for_id(parent_id)
+                    .await
+                {
+                    Ok(parent) => parent.attr_change,
+                    Err(e) => {
+                        return op_error(request, e.nfs_error);
+                    }
+                };
+                request.set_filehandle(filehandle);
+
+                (
+                    ChangeInfo4 {
+                        atomic: false,
+                        before: parent_change,
+                        after,
+                    },
+                    Attrlist4::<FileAttr>::new(None),
+                )
+            }
             Createtype4::Nf4dir => {
                 let current_dir = if filehandle.file.is_file().unwrap() {
                     &filehandle.file.parent()
@@ -52,7 +102,30 @@ impl NfsOperation for Create4args {
                 let new_dir = current_dir.join(self.objname.clone()).unwrap();
                 let _ = new_dir.create_dir();
 
-                request.file_manager().touch_file(filehandle.id).await;
+                // the new entry changes the parent directory, not the
+                // current filehandle if that's a file
+                let parent = match request
+                    .file_manager()
+                    .get_filehandle_for_path(current_dir.as_str().to_string())
+                    .await
+                {
+                    Ok(parent) => parent,
+                    Err(e) => {
+                        debug!("FileManagerError {:?}", e);
+                        return op_error(request, e.nfs_error);
+                    }
+                };
+                request.file_manager().touch_file(parent.id).await;
+                let after = match request
+                    .file_manager()
+                    .get_filehandle_for_id(parent.id)
+                    .await
+                {
+                    Ok(parent) => parent.attr_change,
+                    Err(e) => {
+                        return op_error(request, e.nfs_error);
+                    }
+                };
 
                 let resp = request
                     .file_manager()
@@ -63,34 +136,54 @@ impl NfsOperation for Create4args {
                     Err(e) => {
                         debug!("FileManagerError {:?}", e);
                         request.unset_filehandle();
-                        return NfsOpResponse {
-                            request,
-                            result: None,
-                            status: e.nfs_error,
-                        };
+                        return op_error(request, e.nfs_error);
                     }
                 };
-                request.set_filehandle(filehandle.clone());
+                request.set_filehandle(filehandle);
 
                 (
                     ChangeInfo4 {
-                        atomic: true,
-                        before: filehandle.attr_change,
-                        after: filehandle.attr_change,
+                        atomic: false,
+                        before: parent.attr_change,
+                        after,
                     },
                     Attrlist4::<FileAttr>::new(None),
                 )
             }
-            _ => {
+            Createtype4::Nf4sock | Createtype4::Nf4fifo => {
+                // no backend can hold sockets or fifos
+                error!("Unsupported object type {:?}", self.objtype);
+                return op_error(request, NfsStat4::Nfs4errBadtype);
+            }
+            Createtype4::Nf4Undef
+            | Createtype4::Nf4reg
+            | Createtype4::Nf4attrdir
+            | Createtype4::Nf4namedattr => {
+                // regular files are created with OPEN, named attributes
+                // aren't supported, see
                 // https://datatracker.ietf.org/doc/html/rfc7530#section-16.4.2
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errBadtype,
-                };
+                return op_error(request, NfsStat4::Nfs4errBadtype);
             }
         };
 
+        // a mode in the createattrs replaces the server's default
+        let attrset = match create_mode(&self.createattrs) {
+            Some(mode) => {
+                let id = request.current_filehandle_id().unwrap();
+                match request.file_manager().set_mode(id, mode).await {
+                    Ok(filehandle) => {
+                        request.set_filehandle(filehandle);
+                        Attrlist4(vec![FileAttr::Mode])
+                    }
+                    Err(e) => {
+                        debug!("FileManagerError {:?}", e);
+                        return op_error(request, e.nfs_error);
+                    }
+                }
+            }
+            None => attrset,
+        };
+
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::Opcreate(Create4res::Resok4(Create4resok {
@@ -101,3 +194,386 @@ impl NfsOperation for Create4args {
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        server::{
+            filemanager::FileManagerConfig,
+            nfs40::{
+                Attrlist4, Create4args, Create4res, Createtype4, Fattr4, FileAttr, FileAttrValue,
+                NFS40Server, NfsFtype4, NfsResOp4, NfsStat4, ReadLink4res, ReadLink4resok,
+                Remove4args, Rename4args, Specdata4, StableHow4, Stateid4, Write4args,
+            },
+            operation::NfsOperation,
+            special::{LocalSpecialFiles, SpecialFiles},
+        },
+        test_utils::{
+            create_fake_fs, create_nfs40_server, create_nfs40_server_with_config, TempDir, TestFs,
+        },
+        VfsPath,
+    };
+    use tracing_test::traced_test;
+
+    // the fake file system in a local directory that can hold symlinks
+    fn symlink_config() -> (VfsPath, TempDir, FileManagerConfig) {
+        let (root, dir) = TestFs::Physical.create_fake_fs();
+        let dir = dir.unwrap();
+        let config = FileManagerConfig {
+            special_files: Some(Arc::new(LocalSpecialFiles::new(dir.path()))),
+            ..Default::default()
+        };
+        (root, dir, config)
+    }
+
+    fn create_symlink(name: &str, target: &str) -> Create4args {
+        Create4args {
+            objtype: Createtype4::Nf4lnk(target.to_string()),
+            objname: name.to_string(),
+            createattrs: Fattr4 {
+                attrmask: Attrlist4::<FileAttr>::new(None),
+                attr_vals: Attrlist4::<FileAttrValue>::new(None),
+            },
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_create_symlink() {
+        let (root, dir, config) = symlink_config();
+        let mut request = create_nfs40_server_with_config(Some(root), config).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        let response = create_symlink("link", "dir1/file.txt")
+            .execute(request)
+            .await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let request = response.request;
+        // the current filehandle is the new symlink
+        let link = request.current_filehandle().unwrap();
+        assert_eq!(link.path, "/link");
+        assert_eq!(link.attr_type, NfsFtype4::Nf4lnk);
+        assert_eq!(link.attr_size, "dir1/file.txt".len() as u64);
+        // a real one, even though its target doesn't exist
+        assert_eq!(
+            std::fs::read_link(dir.path().join("link")).unwrap(),
+            std::path::Path::new("dir1/file.txt")
+        );
+
+        let response = NFS40Server.read_link(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        assert_eq!(
+            response.result.unwrap(),
+            NfsResOp4::Opreadlink(ReadLink4res::Resok4(ReadLink4resok {
+                link: "dir1/file.txt".to_string()
+            }))
+        );
+
+        // the name is taken now
+        let mut request = response.request;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+        let response = create_symlink("link", "file1.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errExist);
+
+        // READLINK of a regular file
+        let mut request = response.request;
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(file);
+        let response = NFS40Server.read_link(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_create_symlink_not_supported() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        let response = create_symlink("link", "file1.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotsupp);
+        assert!(response
+            .request
+            .file_manager()
+            .get_filehandle_for_path("link".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_symlink_type_from_backend() {
+        let (root, dir, config) = symlink_config();
+        std::os::unix::fs::symlink("file1.txt", dir.path().join("link")).unwrap();
+
+        // a server started on the directory finds the symlink
+        let mut request = create_nfs40_server_with_config(Some(root.clone()), config.clone()).await;
+        let link = request
+            .file_manager()
+            .get_filehandle_for_path("/link".to_string())
+            .await
+            .unwrap();
+        assert_eq!(link.attr_type, NfsFtype4::Nf4lnk);
+        request.set_filehandle(link);
+        let response = NFS40Server.read_link(request).await;
+        assert_eq!(
+            response.result.unwrap(),
+            NfsResOp4::Opreadlink(ReadLink4res::Resok4(ReadLink4resok {
+                link: "file1.txt".to_string()
+            }))
+        );
+
+        // it stays a symlink when renamed
+        let mut request = response.request;
+        let root_fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root_fh.clone());
+        request.save_filehandle();
+        let args = Rename4args {
+            oldname: "link".to_string(),
+            newname: "moved".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let moved = response
+            .request
+            .file_manager()
+            .get_filehandle_for_path("/moved".to_string())
+            .await
+            .unwrap();
+        assert_eq!(moved.attr_type, NfsFtype4::Nf4lnk);
+
+        // the link is removed, not its target, and a file created in its
+        // place is a regular file
+        let mut request = response.request;
+        request.set_filehandle(root_fh);
+        let args = Remove4args {
+            target: "moved".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert!(root.join("file1.txt").unwrap().exists().unwrap());
+        root.join("moved").unwrap().create_file().unwrap();
+        let file = response
+            .request
+            .file_manager()
+            .get_filehandle_for_path("/moved".to_string())
+            .await
+            .unwrap();
+        assert_eq!(file.attr_type, NfsFtype4::Nf4reg);
+
+        // MemoryFS has no symlinks
+        let request = create_nfs40_server(Some(create_fake_fs())).await;
+        assert!(!request.file_manager().attr_symlink_support());
+    }
+
+    // a backend that only holds devices, in memory
+    #[derive(Debug, Default)]
+    struct DeviceStub {
+        devices: Mutex<HashMap<String, (NfsFtype4, Specdata4)>>,
+    }
+
+    impl SpecialFiles for DeviceStub {
+        fn special_type(&self, path: &str) -> io::Result<Option<(NfsFtype4, Specdata4)>> {
+            Ok(self.devices.lock().unwrap().get(path).copied())
+        }
+
+        fn symlink(&self, _path: &str, _target: &str) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn read_link(&self, _path: &str) -> io::Result<String> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn mknod(&self, path: &str, ftype: NfsFtype4, rawdev: Specdata4) -> io::Result<()> {
+            self.devices
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), (ftype, rawdev));
+            Ok(())
+        }
+    }
+
+    fn create_device(name: &str, rawdev: Specdata4) -> Create4args {
+        Create4args {
+            objtype: Createtype4::Nf4chr(rawdev),
+            objname: name.to_string(),
+            createattrs: Fattr4 {
+                attrmask: Attrlist4::<FileAttr>::new(None),
+                attr_vals: Attrlist4::<FileAttrValue>::new(None),
+            },
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_create_device() {
+        let rawdev = Specdata4 {
+            specdata1: 1,
+            specdata2: 3,
+        };
+        // MemoryFS can't hold devices
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        request.set_uid(Some(0));
+        let response = create_device("null", rawdev).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotsupp);
+
+        let config = FileManagerConfig {
+            special_files: Some(Arc::new(DeviceStub::default())),
+            ..Default::default()
+        };
+        let mut request = create_nfs40_server_with_config(Some(create_fake_fs()), config).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+        // only root creates devices, whatever the machine name says
+        request.set_principal(Some("0@laptop".to_string()));
+        request.set_uid(Some(1000));
+        let response = create_device("null", rawdev).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errPerm);
+
+        let mut request = response.request;
+        request.set_uid(Some(0));
+        let response = create_device("null", rawdev).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let device = response.request.current_filehandle().unwrap();
+        assert_eq!(device.path, "/null");
+        assert_eq!(device.attr_type, NfsFtype4::Nf4chr);
+        assert_eq!(device.attr_rawdev, rawdev);
+
+        let (attrs, values) = response
+            .request
+            .file_manager()
+            .get_filehandle_attrs(device.id, vec![FileAttr::Type, FileAttr::Rawdev])
+            .await
+            .unwrap();
+        assert_eq!(attrs, vec![FileAttr::Type, FileAttr::Rawdev]);
+        assert_eq!(
+            values,
+            vec![
+                FileAttrValue::Type(NfsFtype4::Nf4chr),
+                FileAttrValue::Rawdev(rawdev)
+            ]
+        );
+
+        // the backend refuses symlinks
+        let mut request = response.request;
+        request.set_filehandle(root);
+        let response = create_symlink("link", "null").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotsupp);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_create_badtype() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        for objtype in [
+            Createtype4::Nf4fifo,
+            Createtype4::Nf4sock,
+            Createtype4::Nf4reg,
+        ] {
+            let args = Create4args {
+                objtype,
+                objname: "special".to_string(),
+                createattrs: Fattr4 {
+                    attrmask: Attrlist4::<FileAttr>::new(None),
+                    attr_vals: Attrlist4::<FileAttrValue>::new(None),
+                },
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4errBadtype);
+            assert!(response.result.is_none());
+            request = response.request;
+        }
+        // nothing was created
+        assert!(request
+            .file_manager()
+            .get_filehandle_for_path("/special".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_create_parent_change() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let dir = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .unwrap();
+
+        // writing to a child only changes the child
+        request.set_filehandle(file.clone());
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"Howdy".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let mut request = response.request;
+        let written = request
+            .file_manager()
+            .get_filehandle_for_id(file.id)
+            .await
+            .unwrap();
+        assert_ne!(written.attr_change, file.attr_change);
+        let unchanged = request
+            .file_manager()
+            .get_filehandle_for_id(dir.id)
+            .await
+            .unwrap();
+        assert_eq!(unchanged.attr_change, dir.attr_change);
+
+        // creating a child changes the parent
+        request.set_filehandle(dir.clone());
+        let args = Create4args {
+            objtype: Createtype4::Nf4dir,
+            objname: "subdir".to_string(),
+            createattrs: Fattr4 {
+                attrmask: Attrlist4::<FileAttr>::new(None),
+                attr_vals: Attrlist4::<FileAttrValue>::new(None),
+            },
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let cinfo = match response.result {
+            Some(NfsResOp4::Opcreate(Create4res::Resok4(res))) => res.cinfo,
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(cinfo.before, dir.attr_change);
+        assert_ne!(cinfo.after, cinfo.before);
+        let changed = response
+            .request
+            .file_manager()
+            .get_filehandle_for_id(dir.id)
+            .await
+            .unwrap();
+        assert_eq!(changed.attr_change, cinfo.after);
+    }
+}
@@ -9,7 +9,7 @@ use bold_proto::nfs4_proto::{Fattr4, Getattr4args, Getattr4resok, NfsResOp4};
 
 #[async_trait]
 impl NfsOperation for Getattr4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 9: GETATTR - Get Attributes {:?}, with request {:?}",
             self, request
@@ -22,15 +22,15 @@ impl NfsOperation for Getattr4args {
                     request,
                     result: Some(NfsResOp4::Opgetattr(Getattr4resok {
                         obj_attributes: None,
-                        status: NfsStat4::Nfs4errStale,
+                        status: NfsStat4::Nfs4errNofilehandle,
                     })),
-                    status: NfsStat4::Nfs4errStale,
+                    status: NfsStat4::Nfs4errNofilehandle,
                 };
             }
             Some(filehandle) => {
                 let resp = request
                     .file_manager()
-                    .filehandle_attrs(&self.attr_request, &filehandle);
+                    .filehandle_attrs(&self.attr_request, filehandle);
 
                 let (answer_attrs, attrs) = match resp {
                     Some(inner) => inner,
@@ -62,6 +62,312 @@ impl NfsOperation for Getattr4args {
     }
 }
 
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            filemanager::{FileManagerConfig, FilehandleStrategy},
+            nfs40::{
+                Attrlist4, FileAttr, FileAttrValue, Getattr4args, NfsResOp4, NfsStat4,
+                ACE4_ACCESS_ALLOWED_ACE_TYPE, ACE4_APPEND_DATA, ACE4_IDENTIFIER_GROUP,
+                ACE4_READ_DATA, ACE4_WRITE_DATA, ACL4_SUPPORT_ALLOW_ACL, FH4_VOLATILE_ANY,
+                FH4_VOL_MIGRATION, FH4_VOL_RENAME,
+            },
+            operation::NfsOperation,
+        },
+        test_utils::{create_nfs40_server, create_nfs40_server_with_config, TestFs},
+    };
+    use tracing_test::traced_test;
+
+    async fn get_expire_type(strategy: FilehandleStrategy) -> u32 {
+        let mut request = create_nfs40_server_with_config(
+            None,
+            FileManagerConfig {
+                filehandle_strategy: strategy,
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request.file_manager().get_root_filehandle().await;
+        let _ = request.set_filehandle_id(fh.unwrap().id).await;
+
+        let args = Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::FhExpireType])),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => {
+                let attrs = res.obj_attributes.unwrap();
+                match attrs.attr_vals[0] {
+                    FileAttrValue::FhExpireType(expire_type) => expire_type,
+                    _ => panic!("Unexpected attribute"),
+                }
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_expire_type() {
+        assert_eq!(
+            get_expire_type(FilehandleStrategy::Volatile).await,
+            FH4_VOLATILE_ANY
+        );
+        assert_eq!(
+            get_expire_type(FilehandleStrategy::VolatileOnMigrationRename).await,
+            FH4_VOL_MIGRATION | FH4_VOL_RENAME
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_mounted_on_fileid() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server(Some(root)).await;
+            let mut fsids = Vec::new();
+            for path in ["/", "/dir1", "/dir1/file2.txt"] {
+                let fh = request
+                    .file_manager()
+                    .get_filehandle_for_path(path.to_string())
+                    .await
+                    .unwrap();
+                request.set_filehandle(fh);
+                let args = Getattr4args {
+                    attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                        FileAttr::Fsid,
+                        FileAttr::Fileid,
+                        FileAttr::MountedOnFileid,
+                    ])),
+                };
+                let response = args.execute(request).await;
+                assert_eq!(response.status, NfsStat4::Nfs4Ok);
+                let attrs = match response.result {
+                    Some(NfsResOp4::Opgetattr(res)) => res.obj_attributes.unwrap(),
+                    _ => panic!("Unexpected result"),
+                };
+                match (
+                    &attrs.attr_vals[0],
+                    &attrs.attr_vals[1],
+                    &attrs.attr_vals[2],
+                ) {
+                    (
+                        FileAttrValue::Fsid(fsid),
+                        FileAttrValue::Fileid(fileid),
+                        FileAttrValue::MountedOnFileid(mounted_on_fileid),
+                    ) => {
+                        // no file system is mounted below the export root
+                        assert_eq!(fileid, mounted_on_fileid);
+                        fsids.push(*fsid);
+                    }
+                    _ => panic!("Unexpected attributes"),
+                }
+                request = response.request;
+            }
+            // a single export, no file system boundary is crossed
+            assert!(fsids.iter().all(|fsid| *fsid == fsids[0]));
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_homogeneous() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server(Some(root)).await;
+            let mut fs_attrs = Vec::new();
+            for path in ["/file1.txt", "/dir1/file2.txt"] {
+                let fh = request
+                    .file_manager()
+                    .get_filehandle_for_path(path.to_string())
+                    .await
+                    .unwrap();
+                request.set_filehandle(fh);
+                let args = Getattr4args {
+                    attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                        FileAttr::LeaseTime,
+                        FileAttr::Homogeneous,
+                        FileAttr::Maxread,
+                        FileAttr::Maxwrite,
+                    ])),
+                };
+                let response = args.execute(request).await;
+                assert_eq!(response.status, NfsStat4::Nfs4Ok);
+                let attrs = match response.result {
+                    Some(NfsResOp4::Opgetattr(res)) => res.obj_attributes.unwrap(),
+                    _ => panic!("Unexpected result"),
+                };
+                assert_eq!(attrs.attr_vals[1], FileAttrValue::Homogeneous(true));
+                assert!(matches!(attrs.attr_vals[2], FileAttrValue::Maxread(v) if v > 0));
+                fs_attrs.push(attrs.attr_vals.0);
+                request = response.request;
+            }
+            // the per-file system attributes are the same for every file
+            assert_eq!(fs_attrs[0], fs_attrs[1]);
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_quota() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let attr_request = Attrlist4::<FileAttr>::new(Some(vec![
+                FileAttr::QuotaAvailHard,
+                FileAttr::QuotaAvailSoft,
+                FileAttr::QuotaUsed,
+            ]));
+            for (quota, expected) in [
+                (
+                    Some(100),
+                    vec![
+                        FileAttrValue::QuotaAvailHard(62),
+                        FileAttrValue::QuotaAvailSoft(62),
+                        FileAttrValue::QuotaUsed(38),
+                    ],
+                ),
+                // without a quota the attributes aren't supported
+                (None, vec![]),
+            ] {
+                let mut request = create_nfs40_server_with_config(
+                    Some(root.clone()),
+                    FileManagerConfig {
+                        quota,
+                        ..Default::default()
+                    },
+                )
+                .await;
+                let fh = request.file_manager().get_root_filehandle().await.unwrap();
+                request.set_filehandle(fh);
+                let args = Getattr4args {
+                    attr_request: attr_request.clone(),
+                };
+                let response = args.execute(request).await;
+                assert_eq!(response.status, NfsStat4::Nfs4Ok);
+                match response.result {
+                    Some(NfsResOp4::Opgetattr(res)) => {
+                        // file1.txt and dir1/file2.txt take 25 and 13 bytes
+                        assert_eq!(res.obj_attributes.unwrap().attr_vals.0, expected);
+                    }
+                    _ => panic!("Unexpected result"),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_files() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server_with_config(
+                Some(root.clone()),
+                FileManagerConfig {
+                    files_total: Some(10),
+                    ..Default::default()
+                },
+            )
+            .await;
+            let fh = request.file_manager().get_root_filehandle().await.unwrap();
+            request.set_filehandle(fh);
+
+            // the root, file1.txt, dir1 and dir1/file2.txt take four, every
+            // new file another one
+            for free in [6, 5] {
+                let args = Getattr4args {
+                    attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                        FileAttr::FilesAvail,
+                        FileAttr::FilesFree,
+                        FileAttr::FilesTotal,
+                    ])),
+                };
+                let response = args.execute(request).await;
+                assert_eq!(response.status, NfsStat4::Nfs4Ok);
+                match response.result {
+                    Some(NfsResOp4::Opgetattr(res)) => {
+                        assert_eq!(
+                            res.obj_attributes.unwrap().attr_vals.0,
+                            vec![
+                                FileAttrValue::FilesAvail(free),
+                                FileAttrValue::FilesFree(free),
+                                FileAttrValue::FilesTotal(10),
+                            ]
+                        );
+                    }
+                    _ => panic!("Unexpected result"),
+                }
+                request = response.request;
+                root.join("file3.txt").unwrap().create_file().unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_get_attr_acl() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server_with_config(
+                Some(root),
+                FileManagerConfig {
+                    file_mode: Some(0o644),
+                    ..Default::default()
+                },
+            )
+            .await;
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path("/file1.txt".to_string())
+                .await
+                .unwrap();
+            request.set_filehandle(fh);
+            let args = Getattr4args {
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                    FileAttr::Acl,
+                    FileAttr::AclSupport,
+                ])),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            let attrs = match response.result {
+                Some(NfsResOp4::Opgetattr(res)) => res.obj_attributes.unwrap(),
+                _ => panic!("Unexpected result"),
+            };
+            assert_eq!(attrs.attrmask.0, vec![FileAttr::Acl, FileAttr::AclSupport]);
+            let acl = match &attrs.attr_vals[0] {
+                FileAttrValue::Acl(acl) => acl,
+                _ => panic!("Unexpected attribute"),
+            };
+            // only ALLOW ACEs, as advertised
+            assert_eq!(
+                attrs.attr_vals[1],
+                FileAttrValue::AclSupport(ACL4_SUPPORT_ALLOW_ACL)
+            );
+            assert!(acl
+                .iter()
+                .all(|ace| ace.acetype == ACE4_ACCESS_ALLOWED_ACE_TYPE));
+            let aces: Vec<_> = acl
+                .iter()
+                .map(|ace| (ace.who.as_str(), ace.flag, ace.access_mask))
+                .collect();
+            assert_eq!(
+                aces,
+                vec![
+                    (
+                        "OWNER@",
+                        0,
+                        ACE4_READ_DATA | ACE4_WRITE_DATA | ACE4_APPEND_DATA
+                    ),
+                    ("GROUP@", ACE4_IDENTIFIER_GROUP, ACE4_READ_DATA),
+                    ("EVERYONE@", 0, ACE4_READ_DATA),
+                ]
+            );
+        }
+    }
+}
+
 // #[cfg(test)]
 // mod integration_tests {
 //     use crate::{
@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{
+    nfs40::{ChangeInfo4, NfsStat4},
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+
+use bold_proto::nfs4_proto::{Link4args, Link4res, Link4resok, NfsResOp4};
+
+#[async_trait]
+impl NfsOperation for Link4args {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
+        // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.9.5
+        debug!(
+            "Operation 11: LINK - Create Link to a File {:?}, with request {:?}",
+            self, request
+        );
+        // the saved filehandle is the file to link to, the current one the
+        // directory the new name goes into
+        let (source, target) = match (request.saved_filehandle(), request.current_filehandle()) {
+            (Some(source), Some(target)) => (source.clone(), target.clone()),
+            _ => {
+                error!("None filehandle");
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
+            }
+        };
+
+        if !target.file.is_dir().unwrap_or(false) {
+            error!("Not a directory");
+            return op_error(request, NfsStat4::Nfs4errNotdir);
+        }
+
+        let path = match target.file.join(&self.newname) {
+            Ok(path) if !self.newname.is_empty() => path,
+            _ => {
+                error!("Invalid name {:?}", self.newname);
+                return op_error(request, NfsStat4::Nfs4errInval);
+            }
+        };
+
+        if let Err(e) = request
+            .file_manager()
+            .link_file(source.file.clone(), path)
+            .await
+        {
+            debug!("FileManagerError {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+
+        // the link count of the file and the directory changed
+        request.drop_filehandle_from_cache(source.id);
+        request.drop_filehandle_from_cache(target.id);
+
+        let after = match request.set_filehandle_id(target.id).await {
+            Ok(fh) => fh.attr_change,
+            Err(status) => {
+                return op_error(request, status);
+            }
+        };
+
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::Oplink(Link4res::Resok4(Link4resok {
+                cinfo: ChangeInfo4 {
+                    atomic: false,
+                    before: target.attr_change,
+                    after,
+                },
+            }))),
+            status: NfsStat4::Nfs4Ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use std::sync::Arc;
+
+    use crate::{
+        server::{
+            filemanager::FileManagerConfig,
+            nfs40::{FileAttr, FileAttrValue, Link4args, NfsStat4},
+            operation::NfsOperation,
+            special::LocalSpecialFiles,
+        },
+        test_utils::{
+            create_fake_fs, create_nfs40_server, create_nfs40_server_with_config, TestFs,
+        },
+        VfsPath,
+    };
+    use tracing_test::traced_test;
+
+    fn link(newname: &str) -> Link4args {
+        Link4args {
+            newname: newname.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_link_max_link() {
+        let (root, dir) = TestFs::Physical.create_fake_fs();
+        let dir = dir.unwrap();
+        let config = FileManagerConfig {
+            special_files: Some(Arc::new(LocalSpecialFiles::new(dir.path()))),
+            max_link: Some(2),
+            ..Default::default()
+        };
+        let mut request = create_nfs40_server_with_config(Some(root.clone()), config).await;
+        let root_fh = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(file.attr_numlinks, 1);
+
+        // file1.txt gets its second name
+        request.set_filehandle(file.clone());
+        request.save_filehandle();
+        request.set_filehandle(root_fh.clone());
+        let response = link("second.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let mut request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().id, root_fh.id);
+        assert_eq!(
+            root.join("second.txt").unwrap().read_to_string().unwrap(),
+            root.join("file1.txt").unwrap().read_to_string().unwrap()
+        );
+        let (_, values) = request
+            .file_manager()
+            .get_filehandle_attrs(file.id, vec![FileAttr::Numlinks, FileAttr::Maxlink])
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![FileAttrValue::Numlinks(2), FileAttrValue::Maxlink(2)]
+        );
+
+        // a third one is past maxlink
+        request.set_filehandle(root_fh);
+        let response = link("third.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errMlink);
+        assert!(response.result.is_none());
+        assert!(!root.join("third.txt").unwrap().exists().unwrap());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_link_errors() {
+        let (root, dir) = TestFs::Physical.create_fake_fs();
+        let dir = dir.unwrap();
+        let config = FileManagerConfig {
+            special_files: Some(Arc::new(LocalSpecialFiles::new(dir.path()))),
+            ..Default::default()
+        };
+        let mut request = create_nfs40_server_with_config(Some(root), config).await;
+        let root_fh = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        let dir1 = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+
+        // no saved filehandle
+        request.set_filehandle(root_fh.clone());
+        let response = link("second.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNofilehandle);
+
+        // the target isn't a directory
+        let mut request = response.request;
+        request.set_filehandle(file.clone());
+        request.save_filehandle();
+        let response = link("second.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotdir);
+
+        // the name is taken
+        let mut request = response.request;
+        request.set_filehandle(root_fh.clone());
+        let response = link("file1.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errExist);
+
+        // directories have no hard links
+        let mut request = response.request;
+        request.set_filehandle(dir1);
+        request.save_filehandle();
+        request.set_filehandle(root_fh);
+        let response = link("dir2").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errIsdir);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_link_not_supported() {
+        let root: VfsPath = create_fake_fs();
+        let mut request = create_nfs40_server(Some(root.clone())).await;
+        let root_fh = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        assert!(!request.file_manager().attr_link_support());
+
+        request.set_filehandle(file);
+        request.save_filehandle();
+        request.set_filehandle(root_fh);
+        let response = link("second.txt").execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotsupp);
+        assert!(!root.join("second.txt").unwrap().exists().unwrap());
+    }
+}
@@ -5,14 +5,14 @@ use crate::server::{
     nfs40::{Lookup4res, NfsResOp4},
     operation::NfsOperation,
     request::NfsRequest,
-    response::NfsOpResponse,
+    response::{op_error, NfsOpResponse},
 };
 
-use bold_proto::nfs4_proto::{Lookup4args, NfsStat4};
+use bold_proto::nfs4_proto::{Lookup4args, NfsFtype4, NfsStat4};
 
 #[async_trait]
 impl NfsOperation for Lookup4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 15: LOOKUP - Look Up Filename {:?}, with request {:?}",
             self, request
@@ -22,14 +22,38 @@ impl NfsOperation for Lookup4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-8.4
+        // the file system below a referral is absent here, the client has
+        // to fetch fs_locations and look it up on another server
+        if filehandle.attr_fs_locations.is_some() {
+            debug!("Referral at {:?}", filehandle.path);
+            return NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Oplookup(Lookup4res {
+                    status: NfsStat4::Nfs4errMoved,
+                })),
+                status: NfsStat4::Nfs4errMoved,
+            };
+        }
+
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-16.15.5
+        // symlinks are resolved by the client, a path through one has to
+        // be continued with what READLINK returns
+        if filehandle.attr_type == NfsFtype4::Nf4lnk {
+            debug!("Symlink at {:?}", filehandle.path);
+            return NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Oplookup(Lookup4res {
+                    status: NfsStat4::Nfs4errSymlink,
+                })),
+                status: NfsStat4::Nfs4errSymlink,
+            };
+        }
+
         let mut path = filehandle.path.clone();
         if path == "/" {
             path.push_str(self.objname.as_str());
@@ -74,12 +98,22 @@ impl NfsOperation for Lookup4args {
 mod integration_tests {
     use crate::{
         server::{
-            nfs40::{Lookup4args, NfsStat4, PutFh4args},
+            filemanager::{FileManagerConfig, FileMetadata},
+            nfs40::{
+                Attrlist4, FileAttr, FileAttrValue, FsLocation4, FsLocations4, Getattr4args,
+                Lookup4args, Lookup4res, NfsFtype4, NfsResOp4, NfsStat4, PutFh4args,
+            },
             operation::NfsOperation,
+            special::LocalSpecialFiles,
+        },
+        test_utils::{
+            create_fake_fs, create_nfs40_server, create_nfs40_server_with_config, FaultFS, FsCall,
+            TestFs,
         },
-        test_utils::{create_fake_fs, create_nfs40_server},
     };
+    use std::{collections::HashMap, sync::Arc, time::Duration};
     use tracing_test::traced_test;
+    use vfs::{AltrootFS, MemoryFS, VfsPath};
 
     #[tokio::test]
     #[traced_test]
@@ -126,4 +160,223 @@ mod integration_tests {
         let lookup2_response = args.execute(putfh1_request.request).await;
         assert_eq!(lookup2_response.status, NfsStat4::Nfs4errNoent);
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_lookup_altroot() {
+        // a subtree of the backend, rooted explicitly or just by its path
+        let subtree = create_fake_fs().join("dir1").unwrap();
+        for root in [VfsPath::new(AltrootFS::new(subtree.clone())), subtree] {
+            let request = create_nfs40_server(Some(root)).await;
+            let root_fh = request.file_manager().get_root_filehandle().await.unwrap();
+            assert_eq!(root_fh.path, "/");
+
+            let response = PutFh4args { object: root_fh.id }.execute(request).await;
+            let args = Getattr4args {
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                    FileAttr::Type,
+                    FileAttr::Fileid,
+                    FileAttr::MountedOnFileid,
+                ])),
+            };
+            let response = args.execute(response.request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            match response.result {
+                Some(NfsResOp4::Opgetattr(res)) => {
+                    let attrs = res.obj_attributes.unwrap().attr_vals;
+                    assert_eq!(attrs[0], FileAttrValue::Type(NfsFtype4::Nf4dir));
+                    assert_eq!(attrs[1], FileAttrValue::Fileid(root_fh.attr_fileid));
+                    // the export root is the root of its file system
+                    assert_eq!(
+                        attrs[2],
+                        FileAttrValue::MountedOnFileid(root_fh.attr_fileid)
+                    );
+                }
+                _ => panic!("Unexpected result"),
+            }
+
+            let args = Lookup4args {
+                objname: "file2.txt".to_string(),
+            };
+            let response = args.execute(response.request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            let fh = response.request.current_filehandle().unwrap();
+            assert_eq!(fh.path, "/file2.txt");
+            assert_eq!(fh.file.read_to_string().unwrap(), "Hello, file2!");
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_lookup_max_path_depth() {
+        let root: VfsPath = MemoryFS::new().into();
+        root.join("a/b/c").unwrap().create_dir_all().unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                max_path_depth: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+
+        for name in ["a", "b"] {
+            let args = Lookup4args {
+                objname: name.to_string(),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            request = response.request;
+        }
+        assert_eq!(request.current_filehandle().unwrap().path, "/a/b");
+
+        // "/a/b/c" is three components deep
+        let args = Lookup4args {
+            objname: "c".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNametoolong);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_lookup_through_symlink() {
+        let (root, dir) = TestFs::Physical.create_fake_fs();
+        let dir = dir.unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                special_files: Some(Arc::new(LocalSpecialFiles::new(dir.path()))),
+                ..Default::default()
+            },
+        )
+        .await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request
+            .file_manager()
+            .create_symlink(root.file.join("link").unwrap(), "dir1".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(root);
+
+        // the link itself is found
+        let args = Lookup4args {
+            objname: "link".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // but the client has to resolve it to go further
+        let args = Lookup4args {
+            objname: "file2.txt".to_string(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errSymlink);
+        assert_eq!(
+            response.result,
+            Some(NfsResOp4::Oplookup(Lookup4res {
+                status: NfsStat4::Nfs4errSymlink,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_lookup_referral() {
+        let locations = FsLocations4 {
+            fs_root: vec!["dir1".to_string()],
+            locations: vec![FsLocation4 {
+                server: vec!["nfs2.example.com".to_string()],
+                rootpath: vec!["export".to_string(), "dir1".to_string()],
+            }],
+        };
+        let mut request = create_nfs40_server_with_config(
+            Some(create_fake_fs()),
+            FileManagerConfig {
+                metadata: HashMap::from([(
+                    "/dir1".to_string(),
+                    FileMetadata {
+                        fs_locations: Some(locations.clone()),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+
+        // the referral itself is found in this file system
+        let args = Lookup4args {
+            objname: "dir1".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // its locations are available
+        let args = Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::FsLocations])),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => {
+                assert_eq!(
+                    res.obj_attributes.unwrap().attr_vals.0,
+                    vec![FileAttrValue::FsLocations(locations)]
+                );
+            }
+            _ => panic!("Unexpected result"),
+        }
+
+        // but not what is below it
+        let args = Lookup4args {
+            objname: "file2.txt".to_string(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errMoved);
+
+        // an ordinary directory has no locations
+        let mut request = response.request;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+        let args = Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::FsLocations])),
+        };
+        let response = args.execute(request).await;
+        match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => {
+                assert!(res.obj_attributes.unwrap().attr_vals.is_empty());
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[traced_test]
+    async fn test_lookup_timeout() {
+        let root: VfsPath = FaultFS::default()
+            .stall(FsCall::Lookup, "slow", Duration::from_millis(200))
+            .into();
+        root.join("slow").unwrap().create_file().unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                request_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+
+        let args = Lookup4args {
+            objname: "slow".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errDelay);
+    }
 }
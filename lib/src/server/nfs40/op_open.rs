@@ -3,60 +3,89 @@ use tracing::{debug, error};
 
 use crate::server::{
     filemanager::Filehandle,
-    nfs40::{ChangeInfo4, Open4res, Open4resok, OpenDelegation4, OPEN4_RESULT_CONFIRM},
+    nfs40::{
+        create_mode, ChangeInfo4, Open4res, Open4resok, OpenDelegation4, OPEN4_RESULT_CONFIRM,
+    },
     operation::NfsOperation,
     request::NfsRequest,
-    response::NfsOpResponse,
+    response::{op_error, NfsOpResponse},
 };
 
 use bold_proto::nfs4_proto::{
-    Attrlist4, CreateHow4, FileAttr, NfsResOp4, NfsStat4, Open4args, OpenClaim4, OpenFlag4,
-    Stateid4,
+    Attrlist4, CreateHow4, FileAttr, FileAttrValue, NfsResOp4, NfsStat4, Open4args, OpenClaim4,
+    OpenFlag4, Stateid4, OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_ACCESS_WANT_DELEG_MASK,
+    OPEN4_SHARE_DENY_BOTH,
 };
 
-async fn open_for_reading<'a>(file: &String, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
-    let filehandle = request.current_filehandle();
-    let path = &filehandle.unwrap().path;
+/// Split share_access into the access bits, ignoring the delegation want
+/// bits. Returns NFS4ERR_INVAL if no access is requested or undefined bits
+/// are set, as does an undefined share_deny.
+fn share_access_mode(share_access: u32, share_deny: u32) -> Result<u32, NfsStat4> {
+    if share_access & !(OPEN4_SHARE_ACCESS_BOTH | OPEN4_SHARE_ACCESS_WANT_DELEG_MASK) != 0
+        || share_deny & !OPEN4_SHARE_DENY_BOTH != 0
+    {
+        return Err(NfsStat4::Nfs4errInval);
+    }
+    match share_access & OPEN4_SHARE_ACCESS_BOTH {
+        0 => Err(NfsStat4::Nfs4errInval),
+        access => Ok(access),
+    }
+}
 
-    let fh_path = {
-        if path == "/" {
-            format!("{}{}", path, file)
-        } else {
-            format!("{}/{}", path, file)
-        }
+async fn open_for_reading(
+    args: &Open4args,
+    share_access: u32,
+    file: &String,
+    mut request: NfsRequest,
+) -> NfsOpResponse {
+    let parent = request.current_filehandle().unwrap();
+    let path = &parent.path;
+    // opening an existing file leaves the directory untouched
+    let cinfo = ChangeInfo4 {
+        atomic: true,
+        before: parent.attr_change,
+        after: parent.attr_change,
     };
 
-    debug!("open_for_reading {:?}", fh_path);
+    debug!("open_for_reading {:?}/{:?}", path, file);
+    let newfile = match parent.file.join(file) {
+        Ok(newfile) => newfile,
+        Err(e) => {
+            error!("Err {:?}", e);
+            return op_error(request, NfsStat4::Nfs4errInval);
+        }
+    };
+    // without OPEN4_CREATE a missing file is NFS4ERR_NOENT, an existing one
+    // gets a share reservation like a created one
     let filehandle = match request
         .file_manager()
-        .get_filehandle_for_path(fh_path)
+        .open_file(
+            newfile,
+            args.owner.clientid,
+            args.owner.owner.clone(),
+            share_access,
+            args.share_deny,
+        )
         .await
     {
         Ok(filehandle) => filehandle,
         Err(e) => {
             error!("Err {:?}", e);
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: e.nfs_error,
-            };
+            return op_error(request, e.nfs_error);
         }
     };
 
-    request.set_filehandle(filehandle);
+    request.set_filehandle(filehandle.clone());
+    let lock = &filehandle.locks[0];
 
     NfsOpResponse {
         request,
         result: Some(NfsResOp4::Opopen(Open4res::Resok4(Open4resok {
             stateid: Stateid4 {
-                seqid: 0,
-                other: [0; 12],
-            },
-            cinfo: ChangeInfo4 {
-                atomic: false,
-                before: 0,
-                after: 0,
+                seqid: lock.seqid,
+                other: lock.stateid,
             },
+            cinfo,
             // OPEN4_RESULT_CONFIRM indicates that the client MUST execute an
             // OPEN_CONFIRM operation before using the open file.
             rflags: OPEN4_RESULT_CONFIRM,
@@ -67,14 +96,38 @@ async fn open_for_reading<'a>(file: &String, mut request: NfsRequest<'a>) -> Nfs
     }
 }
 
-async fn open_for_writing<'a>(
+// truncate an opened file, the refreshed filehandle keeps the open's lock
+async fn truncate(
+    request: &mut NfsRequest,
+    filehandle: Filehandle,
+) -> Result<Filehandle, NfsStat4> {
+    let _lock = request.file_manager().lock_file(filehandle.id).await;
+    request
+        .file_manager()
+        .set_attr(&filehandle, &Attrlist4(vec![FileAttrValue::Size(0)]))?;
+    request.file_manager().touch_file(filehandle.id).await;
+    let mut truncated = request
+        .file_manager()
+        .get_filehandle_for_id(filehandle.id)
+        .await
+        .map_err(|e| {
+            error!("Err {:?}", e);
+            e.nfs_error
+        })?;
+    truncated.locks = filehandle.locks;
+    Ok(truncated)
+}
+
+async fn open_for_writing(
     args: &Open4args,
+    share_access: u32,
     filehandle: &Filehandle,
     file: &String,
     how: &CreateHow4,
-    mut request: NfsRequest<'a>,
-) -> NfsOpResponse<'a> {
+    mut request: NfsRequest,
+) -> NfsOpResponse {
     let path = &filehandle.path;
+    let parent_change = filehandle.attr_change;
 
     let fh_path = {
         if path == "/" {
@@ -87,6 +140,9 @@ async fn open_for_writing<'a>(
     debug!("open_for_writing {:?}", fh_path);
 
     let newfile_op = filehandle.file.join(file);
+    let existed = newfile_op
+        .as_ref()
+        .is_ok_and(|newfile| newfile.is_file().unwrap_or(false));
 
     let filehandle = match how {
         CreateHow4::UNCHECKED4(_fattr) => {
@@ -96,7 +152,7 @@ async fn open_for_writing<'a>(
                     newfile_op.unwrap(),
                     args.owner.clientid,
                     args.owner.owner.clone(),
-                    args.share_access,
+                    share_access,
                     args.share_deny,
                     None,
                 )
@@ -105,11 +161,7 @@ async fn open_for_writing<'a>(
                 Ok(filehandle) => filehandle,
                 Err(e) => {
                     error!("Err {:?}", e);
-                    return NfsOpResponse {
-                        request,
-                        result: None,
-                        status: NfsStat4::Nfs4errServerfault,
-                    };
+                    return op_error(request, e.nfs_error);
                 }
             }
         }
@@ -120,7 +172,7 @@ async fn open_for_writing<'a>(
                     newfile_op.unwrap(),
                     args.owner.clientid,
                     args.owner.owner.clone(),
-                    args.share_access,
+                    share_access,
                     args.share_deny,
                     Some(*verifier),
                 )
@@ -129,22 +181,68 @@ async fn open_for_writing<'a>(
                 Ok(filehandle) => filehandle,
                 Err(e) => {
                     error!("Err {:?}", e);
-                    return NfsOpResponse {
-                        request,
-                        result: None,
-                        status: NfsStat4::Nfs4errServerfault,
-                    };
+                    return op_error(request, e.nfs_error);
                 }
             }
         }
         _ => {
             error!("Unsupported CreateHow4 {:?}", how);
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errNotsupp,
-            };
+            return op_error(request, NfsStat4::Nfs4errNotsupp);
+        }
+    };
+
+    // a mode in the createattrs replaces the server's default, an existing
+    // file is only truncated if the createattrs ask for a size of zero
+    let mut attrset = Vec::new();
+    let filehandle = match how {
+        CreateHow4::UNCHECKED4(fattr) if existed => {
+            if fattr.attr_vals.contains(&FileAttrValue::Size(0)) {
+                match truncate(&mut request, filehandle).await {
+                    Ok(filehandle) => {
+                        attrset.push(FileAttr::Size);
+                        filehandle
+                    }
+                    Err(status) => {
+                        return op_error(request, status);
+                    }
+                }
+            } else {
+                filehandle
+            }
         }
+        CreateHow4::UNCHECKED4(fattr) => match create_mode(fattr) {
+            Some(mode) => match request.file_manager().set_mode(filehandle.id, mode).await {
+                Ok(filehandle) => {
+                    attrset.push(FileAttr::Mode);
+                    filehandle
+                }
+                Err(e) => {
+                    error!("Err {:?}", e);
+                    return op_error(request, e.nfs_error);
+                }
+            },
+            None => filehandle,
+        },
+        _ => filehandle,
+    };
+
+    // the create changed the directory, report its change attribute from
+    // before and after
+    let after = match request
+        .file_manager()
+        .get_filehandle_for_path(path.clone())
+        .await
+    {
+        Ok(parent) => parent.attr_change,
+        Err(e) => {
+            error!("Err {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+    };
+    let cinfo = ChangeInfo4 {
+        atomic: false,
+        before: parent_change,
+        after,
     };
 
     request.set_filehandle(filehandle.clone());
@@ -158,15 +256,11 @@ async fn open_for_writing<'a>(
                 seqid: lock.seqid,
                 other: lock.stateid,
             },
-            cinfo: ChangeInfo4 {
-                atomic: false,
-                before: 0,
-                after: 0,
-            },
+            cinfo,
             // OPEN4_RESULT_CONFIRM indicates that the client MUST execute an
             // OPEN_CONFIRM operation before using the open file.
             rflags: OPEN4_RESULT_CONFIRM,
-            attrset: Attrlist4::<FileAttr>::new(None),
+            attrset: Attrlist4(attrset),
             delegation: OpenDelegation4::None,
         }))),
         status: NfsStat4::Nfs4Ok,
@@ -175,7 +269,7 @@ async fn open_for_writing<'a>(
 
 #[async_trait]
 impl NfsOperation for Open4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.16.5
         debug!(
             "Operation 18: OPEN - Open a Regular File {:?}, with request {:?}",
@@ -187,11 +281,7 @@ impl NfsOperation for Open4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
@@ -199,11 +289,7 @@ impl NfsOperation for Open4args {
         // NFS4ERR_NOTDIR will be returned.
         if !filehandle.file.is_dir().unwrap() {
             error!("Not a directory");
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errNotdir,
-            };
+            return op_error(request, NfsStat4::Nfs4errNotdir);
         }
 
         let file = match &self.claim {
@@ -214,11 +300,7 @@ impl NfsOperation for Open4args {
             // claim type.
             _ => {
                 error!("Unsupported OpenClaim4 {:?}", self.claim);
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errNotsupp,
-                };
+                return op_error(request, NfsStat4::Nfs4errNotsupp);
             }
         };
 
@@ -227,22 +309,643 @@ impl NfsOperation for Open4args {
         // and name checks.  See Section 12.7 for further discussion.
         if file.is_empty() {
             error!("Empty file name");
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errInval,
-            };
+            return op_error(request, NfsStat4::Nfs4errInval);
         }
 
+        // want bits only express delegation preferences, we never grant
+        // delegations so they don't change the reservation taken
+        let share_access = match share_access_mode(self.share_access, self.share_deny) {
+            Ok(share_access) => share_access,
+            Err(status) => {
+                error!("Invalid share_access {:#x}", self.share_access);
+                return op_error(request, status);
+            }
+        };
+
         match &self.openhow {
             OpenFlag4::Open4Nocreate => {
-                // Open a file for reading
-                open_for_reading(file, request).await
+                // Open an existing file
+                open_for_reading(self, share_access, file, request).await
             }
             OpenFlag4::How(how) => {
                 // Open a file for writing
-                open_for_writing(self, &filehandle.clone(), file, how, request).await
+                open_for_writing(self, share_access, &filehandle.clone(), file, how, request).await
             }
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use std::io::Read;
+
+    use crate::{
+        server::{
+            filemanager::FileManagerConfig,
+            nfs40::{
+                Attrlist4, Close4args, CreateHow4, Fattr4, FileAttr, FileAttrValue, LockOwner4,
+                NfsResOp4, NfsStat4, Open4args, Open4res, OpenClaim4, OpenFlag4, OpenOwner4,
+                Read4args, Read4res, ReleaseLockowner4args, StableHow4, Stateid4, Write4args,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server, create_nfs40_server_with_config},
+    };
+    use bold_proto::nfs4_proto::{
+        OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_ACCESS_WRITE,
+    };
+    use tracing_test::traced_test;
+
+    async fn create_file(
+        request: NfsRequest,
+        name: &str,
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        create_file_with_access(request, name, OPEN4_SHARE_ACCESS_WRITE).await
+    }
+
+    async fn create_file_with_access(
+        request: NfsRequest,
+        name: &str,
+        share_access: u32,
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        create_file_with_owner(request, name, share_access, b"owner").await
+    }
+
+    async fn create_file_with_owner(
+        request: NfsRequest,
+        name: &str,
+        share_access: u32,
+        owner: &[u8],
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        let openhow = OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8]));
+        open_file(request, name, share_access, owner, openhow).await
+    }
+
+    // OPEN of `name` in the root without OPEN4_CREATE
+    async fn open_existing(
+        request: NfsRequest,
+        name: &str,
+        share_access: u32,
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        open_file(
+            request,
+            name,
+            share_access,
+            b"owner",
+            OpenFlag4::Open4Nocreate,
+        )
+        .await
+    }
+
+    async fn open_file(
+        mut request: NfsRequest,
+        name: &str,
+        share_access: u32,
+        owner: &[u8],
+        openhow: OpenFlag4,
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        let args = Open4args {
+            seqid: 0,
+            share_access,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: owner.to_vec(),
+            },
+            openhow,
+            claim: OpenClaim4::ClaimNull(name.to_string()),
+        };
+        let response = args.execute(request).await;
+        let stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => Some(res.stateid),
+            _ => None,
+        };
+        (response.request, response.status, stateid)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_limit() {
+        let request = create_nfs40_server_with_config(
+            None,
+            FileManagerConfig {
+                max_opens: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (request, status, stateid) = create_file(request, "file1").await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+
+        let (request, status, _) = create_file(request, "file2").await;
+        assert_eq!(status, NfsStat4::Nfs4errResource);
+
+        // closing the first file frees the slot
+        let mut request = request;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let args = Close4args {
+            seqid: 1,
+            open_stateid: stateid.unwrap(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        let (request, status, _) = create_file(response.request, "file2").await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+
+        // opens of existing files without create count as well
+        let (request, status, _) = open_existing(request, "file1", OPEN4_SHARE_ACCESS_READ).await;
+        assert_eq!(status, NfsStat4::Nfs4errResource);
+        let (_, status, _) = open_existing(request, "file2", OPEN4_SHARE_ACCESS_READ).await;
+        assert_eq!(status, NfsStat4::Nfs4errResource);
+    }
+
+    async fn close_file(
+        mut request: NfsRequest,
+        path: &str,
+        stateid: Stateid4,
+    ) -> (NfsRequest, NfsStat4) {
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path(path.to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let args = Close4args {
+            seqid: stateid.seqid,
+            open_stateid: stateid,
+        };
+        let response = args.execute(request).await;
+        (response.request, response.status)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_owner_limit() {
+        let request = create_nfs40_server_with_config(
+            None,
+            FileManagerConfig {
+                max_owners_per_client: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        let access = OPEN4_SHARE_ACCESS_WRITE;
+
+        // the first owner may open as many files as it likes
+        let (request, status, stateid1) =
+            create_file_with_owner(request, "file1", access, b"a").await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (request, status, stateid2) =
+            create_file_with_owner(request, "file2", access, b"a").await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+
+        // a second owner exceeds the limit
+        let (request, status, _) = create_file_with_owner(request, "file3", access, b"b").await;
+        assert_eq!(status, NfsStat4::Nfs4errResource);
+
+        // the slot is freed once the first owner closed all its files
+        let (request, status) = close_file(request, "/file1", stateid1.unwrap()).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (request, status, _) = create_file_with_owner(request, "file3", access, b"b").await;
+        assert_eq!(status, NfsStat4::Nfs4errResource);
+        let (request, status) = close_file(request, "/file2", stateid2.unwrap()).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+
+        // releasing an owner without locks succeeds
+        let args = ReleaseLockowner4args {
+            lock_owner: LockOwner4 {
+                clientid: 1,
+                owner: b"a".to_vec(),
+            },
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        let (_, status, _) = create_file_with_owner(response.request, "file3", access, b"b").await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+    }
+
+    async fn read_write(
+        request: NfsRequest,
+        stateid: &Stateid4,
+    ) -> (NfsRequest, NfsStat4, NfsStat4) {
+        let args = Write4args {
+            stateid: stateid.clone(),
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"data".to_vec(),
+        };
+        let write = args.execute(request).await;
+        let args = Read4args {
+            stateid: stateid.clone(),
+            offset: 0,
+            count: 4,
+        };
+        let read = args.execute(write.request).await;
+        (read.request, write.status, read.status)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_share_access_io() {
+        let request = create_nfs40_server(None).await;
+
+        let (request, status, stateid) =
+            create_file_with_access(request, "both", OPEN4_SHARE_ACCESS_BOTH).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (request, write, read) = read_write(request, &stateid.unwrap()).await;
+        assert_eq!((write, read), (NfsStat4::Nfs4Ok, NfsStat4::Nfs4Ok));
+
+        let (request, status, stateid) =
+            create_file_with_access(request, "read", OPEN4_SHARE_ACCESS_READ).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (request, write, read) = read_write(request, &stateid.unwrap()).await;
+        assert_eq!((write, read), (NfsStat4::Nfs4errOpenmode, NfsStat4::Nfs4Ok));
+
+        let (request, status, stateid) =
+            create_file_with_access(request, "write", OPEN4_SHARE_ACCESS_WRITE).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let stateid = stateid.unwrap();
+        let (mut request, write, read) = read_write(request, &stateid).await;
+        assert_eq!((write, read), (NfsStat4::Nfs4Ok, NfsStat4::Nfs4errOpenmode));
+
+        // the stateid of one file can't be used for another
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/both".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let (_, write, _) = read_write(request, &stateid).await;
+        assert_eq!(write, NfsStat4::Nfs4errBadStateid);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_nocreate_openmode() {
+        let request = create_nfs40_server(Some(create_fake_fs())).await;
+
+        // an existing file opened for reading only can't be written to
+        let (request, status, stateid) =
+            open_existing(request, "file1.txt", OPEN4_SHARE_ACCESS_READ).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let stateid = stateid.unwrap();
+        assert_ne!(stateid.other, [0; 12]);
+        let (request, write, read) = read_write(request, &stateid).await;
+        assert_eq!((write, read), (NfsStat4::Nfs4errOpenmode, NfsStat4::Nfs4Ok));
+
+        // and its stateid is released by CLOSE
+        let (request, status) = close_file(request, "/file1.txt", stateid.clone()).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (_, _, read) = read_write(request, &stateid).await;
+        assert_eq!(read, NfsStat4::Nfs4errBadStateid);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_share_access_want_bits() {
+        // OPEN4_SHARE_ACCESS_WANT_NO_DELEG (0x0400) must not be read as access bits
+        let request = create_nfs40_server(None).await;
+        let (request, status, _) =
+            create_file_with_access(request, "file1", OPEN4_SHARE_ACCESS_WRITE | 0x0400).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let fh = request.current_filehandle().unwrap();
+        assert_eq!(fh.locks[0].share_access, Some(OPEN4_SHARE_ACCESS_WRITE));
+
+        // no access at all, only a delegation want
+        let (request, status, _) = create_file_with_access(request, "file2", 0x0100).await;
+        assert_eq!(status, NfsStat4::Nfs4errInval);
+
+        // undefined bits outside the want mask
+        let (_, status, _) = create_file_with_access(request, "file3", 0x10002).await;
+        assert_eq!(status, NfsStat4::Nfs4errInval);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_create_write_read() {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        let args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_BOTH,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::UNCHECKED4(Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::Mode]),
+                attr_vals: Attrlist4(vec![FileAttrValue::Mode(0o644)]),
+            })),
+            claim: OpenClaim4::ClaimNull("new.txt".to_string()),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+        assert_ne!(stateid.other, [0; 12]);
+
+        // the stateid is good for I/O right away
+        let args = Write4args {
+            stateid: stateid.clone(),
+            offset: 0,
+            stable: StableHow4::Unstable4,
+            data: b"Howdy".to_vec(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let args = Read4args {
+            stateid,
+            offset: 0,
+            count: 16,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opread(Read4res::Resok4(res))) => {
+                assert_eq!(res.data, b"Howdy".to_vec());
+                assert!(res.eof);
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_cinfo() {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+
+        let mut args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_WRITE,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8])),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let cinfo = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.cinfo,
+            _ => panic!("Unexpected result"),
+        };
+        // the create changed the root directory
+        assert_eq!(cinfo.before, root.attr_change);
+        assert_ne!(cinfo.before, cinfo.after);
+
+        let mut request = response.request;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+        args.openhow = OpenFlag4::Open4Nocreate;
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let cinfo = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.cinfo,
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(cinfo.before, root.attr_change);
+        assert_eq!(cinfo.before, cinfo.after);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_nocreate_missing() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let dir = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(dir.clone());
+
+        let args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_READ,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::Open4Nocreate,
+            claim: OpenClaim4::ClaimNull("missing.txt".to_string()),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNoent);
+        assert!(response.result.is_none());
+        // nothing was created and the directory stays current
+        let request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().path, "/dir1");
+        assert!(request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/missing.txt".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_default_mode() {
+        let mut request = create_nfs40_server_with_config(
+            None,
+            FileManagerConfig {
+                file_mode: Some(0o640),
+                ..Default::default()
+            },
+        )
+        .await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+
+        let mut args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_WRITE,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::UNCHECKED4(Fattr4 {
+                attrmask: Attrlist4(vec![]),
+                attr_vals: Attrlist4(vec![]),
+            })),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => assert!(res.attrset.is_empty()),
+            _ => panic!("Unexpected result"),
+        }
+        let mut request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().attr_mode, 0o640);
+
+        // a mode in the createattrs wins over the default, and sticks
+        request.set_filehandle(root);
+        args.openhow = OpenFlag4::How(CreateHow4::UNCHECKED4(Fattr4 {
+            attrmask: Attrlist4(vec![FileAttr::Mode]),
+            attr_vals: Attrlist4(vec![FileAttrValue::Mode(0o600)]),
+        }));
+        args.claim = OpenClaim4::ClaimNull("file2".to_string());
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => {
+                assert_eq!(res.attrset, Attrlist4(vec![FileAttr::Mode]))
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().attr_mode, 0o600);
+        let filehandle = request
+            .file_manager()
+            .get_filehandle_for_path("/file2".to_string())
+            .await
+            .unwrap();
+        assert_eq!(filehandle.attr_mode, 0o600);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_existing_truncate() {
+        let fs = create_fake_fs();
+        let mut request = create_nfs40_server(Some(fs.clone())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root.clone());
+
+        // without a size the existing file is opened as it is
+        let mut args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_WRITE,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::UNCHECKED4(Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::Mode]),
+                attr_vals: Attrlist4(vec![FileAttrValue::Mode(0o600)]),
+            })),
+            claim: OpenClaim4::ClaimNull("file1.txt".to_string()),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => assert!(res.attrset.is_empty()),
+            _ => panic!("Unexpected result"),
+        }
+        let mut request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().attr_size, 25);
+
+        // a size of zero truncates it
+        request.set_filehandle(root);
+        args.owner.owner = b"owner2".to_vec();
+        args.openhow = OpenFlag4::How(CreateHow4::UNCHECKED4(Fattr4 {
+            attrmask: Attrlist4(vec![FileAttr::Size]),
+            attr_vals: Attrlist4(vec![FileAttrValue::Size(0)]),
+        }));
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => {
+                assert_eq!(res.attrset, Attrlist4(vec![FileAttr::Size]));
+                assert_ne!(res.stateid.other, [0; 12]);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().attr_size, 0);
+        let filehandle = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(filehandle.attr_size, 0);
+        assert_eq!(fs.join("file1.txt").unwrap().metadata().unwrap().len, 0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_exclusive_verifier() {
+        let fs = create_fake_fs();
+        let request = create_nfs40_server(Some(fs.clone())).await;
+        let exclusive = |verifier| OpenFlag4::How(CreateHow4::EXCLUSIVE4(verifier));
+        let contents = |name: &str| {
+            let mut buf = String::new();
+            fs.join(name)
+                .unwrap()
+                .open_file()
+                .unwrap()
+                .read_to_string(&mut buf)
+                .unwrap();
+            buf
+        };
+        let before = contents("file1.txt");
+
+        // a file the OPEN didn't create is left alone
+        let (request, status, _) = open_file(
+            request,
+            "file1.txt",
+            OPEN4_SHARE_ACCESS_WRITE,
+            b"owner",
+            exclusive([1; 8]),
+        )
+        .await;
+        assert_eq!(status, NfsStat4::Nfs4errExist);
+        assert_eq!(contents("file1.txt"), before);
+
+        // a retransmission opens the file it created
+        let (request, status, stateid) = open_file(
+            request,
+            "new",
+            OPEN4_SHARE_ACCESS_BOTH,
+            b"owner",
+            exclusive([2; 8]),
+        )
+        .await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let (request, write, _) = read_write(request, &stateid.unwrap()).await;
+        assert_eq!(write, NfsStat4::Nfs4Ok);
+        let (request, status, _) = open_file(
+            request,
+            "new",
+            OPEN4_SHARE_ACCESS_READ,
+            b"owner2",
+            exclusive([2; 8]),
+        )
+        .await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        assert_eq!(contents("new"), "data");
+
+        // but another verifier doesn't
+        let (_, status, _) = open_file(
+            request,
+            "new",
+            OPEN4_SHARE_ACCESS_READ,
+            b"owner3",
+            exclusive([3; 8]),
+        )
+        .await;
+        assert_eq!(status, NfsStat4::Nfs4errExist);
+        assert_eq!(contents("new"), "data");
+    }
+}
@@ -0,0 +1,209 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+
+use bold_proto::nfs4_proto::{
+    NfsResOp4, NfsStat4, OpenDowngrade4args, OpenDowngrade4res, OpenDowngrade4resok,
+};
+
+#[async_trait]
+impl NfsOperation for OpenDowngrade4args {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
+        // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.19.5
+        debug!(
+            "Operation 21: OPEN_DOWNGRADE - Reduce Open File Access {:?}, with request {:?}",
+            self, request
+        );
+        let open_stateid = match request
+            .file_manager()
+            .downgrade_open(
+                self.open_stateid.clone(),
+                self.seqid,
+                self.share_access,
+                self.share_deny,
+            )
+            .await
+        {
+            Ok(stateid) => stateid,
+            Err(e) => {
+                error!("Couldn't downgrade open: {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        };
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::OpopenDowngrade(OpenDowngrade4res::Resok4(
+                OpenDowngrade4resok { open_stateid },
+            ))),
+            status: NfsStat4::Nfs4Ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            nfs40::{
+                CreateHow4, NfsResOp4, NfsStat4, Open4args, Open4res, OpenClaim4, OpenConfirm4args,
+                OpenConfirm4res, OpenDowngrade4args, OpenDowngrade4res, OpenFlag4, OpenOwner4,
+                Read4args, StableHow4, Stateid4, Write4args, OPEN4_SHARE_ACCESS_BOTH,
+                OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_ACCESS_WRITE,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::create_nfs40_server,
+    };
+    use tracing_test::traced_test;
+
+    // open a new file for reading and writing and confirm the open with
+    // seqid 1
+    async fn open_both() -> (NfsRequest, Stateid4) {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        let args = Open4args {
+            seqid: 0,
+            share_access: OPEN4_SHARE_ACCESS_BOTH,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8])),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        let open_stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+        let args = OpenConfirm4args {
+            open_stateid,
+            seqid: 1,
+        };
+        let response = args.execute(response.request).await;
+        match response.result {
+            Some(NfsResOp4::OpopenConfirm(OpenConfirm4res::Resok4(res))) => {
+                (response.request, res.open_stateid)
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    fn downgrade(open_stateid: Stateid4, seqid: u32) -> OpenDowngrade4args {
+        OpenDowngrade4args {
+            open_stateid,
+            seqid,
+            share_access: OPEN4_SHARE_ACCESS_READ,
+            share_deny: 0,
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_downgrade() {
+        let (request, confirmed) = open_both().await;
+
+        let args = downgrade(confirmed.clone(), 2);
+        let first = args.execute(request).await;
+        assert_eq!(first.status, NfsStat4::Nfs4Ok);
+        let downgraded = match &first.result {
+            Some(NfsResOp4::OpopenDowngrade(OpenDowngrade4res::Resok4(res))) => {
+                res.open_stateid.clone()
+            }
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(downgraded.seqid, confirmed.seqid + 1);
+        assert_eq!(downgraded.other, confirmed.other);
+
+        // the retransmission gets the same reply, the stateid isn't bumped
+        // once more
+        let second = args.execute(first.request).await;
+        assert_eq!(second.status, NfsStat4::Nfs4Ok);
+        assert_eq!(second.result, first.result);
+
+        // the open now only reads
+        let args = Write4args {
+            stateid: downgraded.clone(),
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"data".to_vec(),
+        };
+        let response = args.execute(second.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errOpenmode);
+        let args = Read4args {
+            stateid: downgraded.clone(),
+            offset: 0,
+            count: 4,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // access the open doesn't have can't be added with a downgrade
+        let args = OpenDowngrade4args {
+            open_stateid: downgraded,
+            seqid: 3,
+            share_access: OPEN4_SHARE_ACCESS_WRITE,
+            share_deny: 0,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_downgrade_bad_seqid() {
+        let (request, confirmed) = open_both().await;
+
+        // the open owner is at seqid 1, a gap is out of order
+        let response = downgrade(confirmed.clone(), 3).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errBadSeqid);
+        // so is an old seqid with another stateid than the one it came with
+        let response = downgrade(confirmed.clone(), 0)
+            .execute(response.request)
+            .await;
+        assert_eq!(response.status, NfsStat4::Nfs4errBadSeqid);
+
+        // the open is unchanged, the next seqid still works
+        let response = downgrade(confirmed, 2).execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_open_downgrade_stale_stateid() {
+        let (request, confirmed) = open_both().await;
+        let write = Write4args {
+            stateid: confirmed.clone(),
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"data".to_vec(),
+        };
+        let response = write.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        let response = downgrade(confirmed.clone(), 2)
+            .execute(response.request)
+            .await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // the stateid from before the downgrade doesn't write anymore
+        let response = write.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errOpenmode);
+        // access the open still has is refused for the outdated seqid
+        let args = Read4args {
+            stateid: confirmed,
+            offset: 0,
+            count: 4,
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errOldStateid);
+    }
+}
@@ -1,33 +1,186 @@
 use async_trait::async_trait;
-use tracing::debug;
+use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{
-    NfsResOp4, NfsStat4, OpenConfirm4args, OpenConfirm4res, OpenConfirm4resok, Stateid4,
+    NfsResOp4, NfsStat4, OpenConfirm4args, OpenConfirm4res, OpenConfirm4resok,
 };
 
 #[async_trait]
 impl NfsOperation for OpenConfirm4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 20: OPEN_CONFIRM - Confirm Open {:?}, with request {:?}",
             self, request
         );
-        // we expect filehandle to have one lock (for the shared reservation)
-        let lock = request.current_filehandle().unwrap().locks[0].clone();
-        // TODO check if the stateid is correct
+        let open_stateid = match request
+            .file_manager()
+            .confirm_open(self.open_stateid.clone(), self.seqid)
+            .await
+        {
+            Ok(stateid) => stateid,
+            Err(e) => {
+                error!("Couldn't confirm open: {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        };
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::OpopenConfirm(OpenConfirm4res::Resok4(
-                OpenConfirm4resok {
-                    open_stateid: Stateid4 {
-                        seqid: lock.seqid,
-                        other: lock.stateid,
-                    },
-                },
+                OpenConfirm4resok { open_stateid },
             ))),
             status: NfsStat4::Nfs4Ok,
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            nfs40::{
+                Close4args, Close4res, CreateHow4, NfsResOp4, NfsStat4, Open4args, Open4res,
+                OpenClaim4, OpenConfirm4args, OpenConfirm4res, OpenFlag4, OpenOwner4, Stateid4,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::create_nfs40_server,
+    };
+    use tracing_test::traced_test;
+
+    async fn confirm(
+        request: NfsRequest,
+        open_stateid: Stateid4,
+        seqid: u32,
+    ) -> (NfsRequest, NfsStat4, Option<Stateid4>) {
+        let args = OpenConfirm4args {
+            open_stateid,
+            seqid,
+        };
+        let response = args.execute(request).await;
+        let stateid = match response.result {
+            Some(NfsResOp4::OpopenConfirm(OpenConfirm4res::Resok4(res))) => Some(res.open_stateid),
+            _ => None,
+        };
+        (response.request, response.status, stateid)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_stateid_seqid() {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        let args = Open4args {
+            seqid: 0,
+            share_access: 2,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8])),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        let open_stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(open_stateid.seqid, 1);
+
+        // the current seqid is bumped
+        let (request, status, stateid) = confirm(response.request, open_stateid.clone(), 1).await;
+        assert_eq!(status, NfsStat4::Nfs4Ok);
+        let confirmed = stateid.unwrap();
+        assert_eq!(confirmed.seqid, 2);
+        assert_eq!(confirmed.other, open_stateid.other);
+
+        // the seqid from before the confirm is outdated
+        let (request, status, _) = confirm(request, open_stateid.clone(), 2).await;
+        assert_eq!(status, NfsStat4::Nfs4errOldStateid);
+
+        // a seqid that was never handed out
+        let future = Stateid4 {
+            seqid: 7,
+            other: open_stateid.other,
+        };
+        let (request, status, _) = confirm(request, future, 3).await;
+        assert_eq!(status, NfsStat4::Nfs4errBadStateid);
+
+        // unknown stateid
+        let unknown = Stateid4 {
+            seqid: 1,
+            other: [9; 12],
+        };
+        let (request, status, _) = confirm(request, unknown, 4).await;
+        assert_eq!(status, NfsStat4::Nfs4errBadStateid);
+
+        let args = Close4args {
+            seqid: 2,
+            open_stateid: confirmed,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opclose(Close4res::OpenStateid(stateid))) => {
+                assert_eq!(stateid.seqid, 3)
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_confirm_replay() {
+        let mut request = create_nfs40_server(None).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+        let args = Open4args {
+            seqid: 0,
+            share_access: 2,
+            share_deny: 0,
+            owner: OpenOwner4 {
+                clientid: 1,
+                owner: b"owner".to_vec(),
+            },
+            openhow: OpenFlag4::How(CreateHow4::EXCLUSIVE4([0; 8])),
+            claim: OpenClaim4::ClaimNull("file1".to_string()),
+        };
+        let response = args.execute(request).await;
+        let open_stateid = match response.result {
+            Some(NfsResOp4::Opopen(Open4res::Resok4(res))) => res.stateid,
+            _ => panic!("Unexpected result"),
+        };
+
+        let args = OpenConfirm4args {
+            open_stateid,
+            seqid: 1,
+        };
+        let first = args.execute(response.request).await;
+        assert_eq!(first.status, NfsStat4::Nfs4Ok);
+        // the retransmission gets the same reply, the seqid isn't bumped
+        // once more
+        let second = args.execute(first.request).await;
+        assert_eq!(second.status, NfsStat4::Nfs4Ok);
+        assert_eq!(second.result, first.result);
+        let confirmed = match second.result {
+            Some(NfsResOp4::OpopenConfirm(OpenConfirm4res::Resok4(res))) => res.open_stateid,
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(confirmed.seqid, 2);
+
+        let args = Close4args {
+            seqid: 2,
+            open_stateid: confirmed,
+        };
+        let response = args.execute(second.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+    }
+}
@@ -6,27 +6,24 @@ use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, PutFh4args, PutFh4res};
 
 #[async_trait]
 impl NfsOperation for PutFh4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 22: PUTFH - Set Current Filehandle {:?}, with request {:?}",
             self, request
         );
 
-        match request.get_filehandle_from_cache(self.object.clone()) {
-            Some(fh) => {
-                request.set_filehandle(fh);
-                return NfsOpResponse {
-                    request,
-                    result: Some(NfsResOp4::Opputfh(PutFh4res {
-                        status: NfsStat4::Nfs4Ok,
-                    })),
+        if let Some(fh) = request.get_filehandle_from_cache(self.object) {
+            request.set_filehandle(fh);
+            return NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Opputfh(PutFh4res {
                     status: NfsStat4::Nfs4Ok,
-                };
-            }
-            None => {}
+                })),
+                status: NfsStat4::Nfs4Ok,
+            };
         }
 
-        match request.set_filehandle_id(self.object.clone()).await {
+        match request.set_filehandle_id(self.object).await {
             Ok(fh) => {
                 request.cache_filehandle(fh);
                 return NfsOpResponse {
@@ -52,11 +49,14 @@ impl NfsOperation for PutFh4args {
 mod integration_tests {
     use crate::{
         server::{
+            filemanager::{FileManagerConfig, FilehandleStrategy, MAX_RETIRED_FILEHANDLES},
             nfs40::{NfsResOp4, NfsStat4, PutFh4args, PutFh4res},
             operation::NfsOperation,
+            request::NfsRequest,
         },
-        test_utils::create_nfs40_server,
+        test_utils::{create_fake_fs, create_nfs40_server, create_nfs40_server_with_config},
     };
+    use std::{io::Write, time::Duration};
     use tracing_test::traced_test;
 
     #[tokio::test]
@@ -77,4 +77,189 @@ mod integration_tests {
             }))
         );
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_put_unknown_filehandle() {
+        let fh = request_root_id().await;
+        // a filehandle issued by an earlier server instance
+        let mut expired = fh;
+        expired[1..9].copy_from_slice(&0_u64.to_be_bytes());
+        // a filehandle of this instance that was never handed out
+        let mut removed = fh;
+        removed[9..25].copy_from_slice(&u128::MAX.to_be_bytes());
+
+        for (strategy, id, status) in [
+            (
+                FilehandleStrategy::Volatile,
+                expired,
+                NfsStat4::Nfs4errFhexpired,
+            ),
+            (
+                FilehandleStrategy::Volatile,
+                removed,
+                NfsStat4::Nfs4errStale,
+            ),
+            (
+                FilehandleStrategy::VolatileOnMigrationRename,
+                expired,
+                NfsStat4::Nfs4errStale,
+            ),
+            (
+                FilehandleStrategy::Volatile,
+                [7; 26],
+                NfsStat4::Nfs4errBadhandle,
+            ),
+            (
+                FilehandleStrategy::VolatileOnMigrationRename,
+                [7; 26],
+                NfsStat4::Nfs4errBadhandle,
+            ),
+        ] {
+            let request = create_nfs40_server_with_config(
+                None,
+                FileManagerConfig {
+                    filehandle_strategy: strategy,
+                    ..Default::default()
+                },
+            )
+            .await;
+            let args = PutFh4args { object: id };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, status);
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_put_filehandle_shared_cache() {
+        let config = FileManagerConfig {
+            filehandle_cache_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let request = create_nfs40_server_with_config(None, config).await;
+        let id = request
+            .file_manager()
+            .get_root_filehandle()
+            .await
+            .unwrap()
+            .id;
+        // a second connection to the same server
+        let mut other = NfsRequest::builder(request.client_manager(), request.file_manager())
+            .client_addr("127.0.0.1:12346".parse().unwrap())
+            .build();
+        assert!(other.get_filehandle_from_cache(id).is_none());
+
+        // resolving the filehandle on the first connection caches it
+        let response = PutFh4args { object: id }.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // and the second one finds it there
+        assert_eq!(other.get_filehandle_from_cache(id).unwrap().id, id);
+        let response = PutFh4args { object: id }.execute(other).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        assert_eq!(response.request.current_filehandle_id(), Some(id));
+
+        // without a ttl nothing is cached
+        let request = create_nfs40_server(None).await;
+        let id = request
+            .file_manager()
+            .get_root_filehandle()
+            .await
+            .unwrap()
+            .id;
+        let response = PutFh4args { object: id }.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let mut request = response.request;
+        assert!(request.get_filehandle_from_cache(id).is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_put_refreshed_filehandle() {
+        for refresh in [true, false] {
+            let config = FileManagerConfig {
+                refresh_stale_filehandles: refresh,
+                ..Default::default()
+            };
+            let request = create_nfs40_server_with_config(Some(create_fake_fs()), config).await;
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path("/file1.txt".to_string())
+                .await
+                .unwrap();
+            request
+                .file_manager()
+                .remove_file(fh.file.clone())
+                .await
+                .unwrap();
+
+            // nothing to refresh the filehandle with
+            let response = PutFh4args { object: fh.id }.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4errStale);
+
+            // the file is back, with other content
+            let mut file = fh.file.create_file().unwrap();
+            file.write_all(b"Recreated").unwrap();
+            drop(file);
+
+            let response = PutFh4args { object: fh.id }.execute(response.request).await;
+            if !refresh {
+                assert_eq!(response.status, NfsStat4::Nfs4errStale);
+                continue;
+            }
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            let current = response.request.current_filehandle().unwrap();
+            assert_eq!(current.id, fh.id);
+            assert_eq!(current.path, "/file1.txt");
+            assert_eq!(current.file.read_to_string().unwrap(), "Recreated");
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_retired_filehandles_bounded() {
+        let config = FileManagerConfig {
+            refresh_stale_filehandles: true,
+            ..Default::default()
+        };
+        let root = create_fake_fs();
+        let request = create_nfs40_server_with_config(Some(root.clone()), config).await;
+
+        // one more removed file than the server remembers
+        let mut removed = Vec::new();
+        for i in 0..=MAX_RETIRED_FILEHANDLES {
+            let file = root.join(format!("file{}.tmp", i)).unwrap();
+            file.create_file().unwrap();
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path(file.as_str().to_string())
+                .await
+                .unwrap();
+            request.file_manager().remove_file(file).await.unwrap();
+            removed.push(fh);
+        }
+        for fh in [removed.first().unwrap(), removed.last().unwrap()] {
+            fh.file.create_file().unwrap();
+        }
+
+        // the oldest was forgotten, the newest is still refreshed
+        let first = removed.first().unwrap().id;
+        let response = PutFh4args { object: first }.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errStale);
+        let last = removed.last().unwrap().id;
+        let response = PutFh4args { object: last }.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        assert_eq!(response.request.current_filehandle().unwrap().id, last);
+    }
+
+    async fn request_root_id() -> [u8; 26] {
+        let request = create_nfs40_server(None).await;
+        request
+            .file_manager()
+            .get_root_filehandle()
+            .await
+            .unwrap()
+            .id
+    }
 }
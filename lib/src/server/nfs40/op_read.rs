@@ -1,14 +1,23 @@
-use std::io::SeekFrom;
+use std::io::{Read, SeekFrom};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
-use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, Read4args, Read4res, Read4resok};
+use crate::server::{
+    filemanager::vfs_error_status,
+    nfs40::range_end,
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+use bold_proto::nfs4_proto::{
+    NfsResOp4, NfsStat4, Read4args, Read4res, Read4resok, OPEN4_SHARE_ACCESS_READ,
+};
 
 #[async_trait]
 impl NfsOperation for Read4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 25: READ - Read from File {:?}, with request {:?}",
             self, request
@@ -18,26 +27,366 @@ impl NfsOperation for Read4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
-        let mut buffer: Vec<u8> = vec![0; self.count as usize];
-        let mut rfile = filehandle.file.open_file().unwrap();
-        rfile.seek(SeekFrom::Start(self.offset)).unwrap();
-        let _ = rfile.read_exact(&mut buffer);
+        if let Err(status) = range_end(self.offset, self.count as u64) {
+            return op_error(request, status);
+        }
+
+        if let Err(e) = request
+            .file_manager()
+            .check_stateid_access(self.stateid.clone(), filehandle.id, OPEN4_SHARE_ACCESS_READ)
+            .await
+        {
+            error!("Stateid not valid for this file {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+
+        // unstable writes still sitting in the write cache have to be read
+        // back, so they go to the backend first
+        match request
+            .file_manager()
+            .flush_write_cache(filehandle.id)
+            .await
+        {
+            Ok(true) => {
+                let id = filehandle.id;
+                request.drop_filehandle_from_cache(id);
+                if let Err(status) = request.set_filehandle_id(id).await {
+                    return op_error(request, status);
+                }
+            }
+            Ok(false) => (),
+            Err(e) => {
+                error!("Couldn't flush write cache {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        }
+        let filehandle = request.current_filehandle().unwrap();
+
+        let readahead = request.file_manager().readahead().cloned();
+        let prefetched = readahead
+            .as_ref()
+            .and_then(|readahead| readahead.get(&filehandle.id, self.offset, self.count));
+        let data = match prefetched {
+            Some(data) => data,
+            None => {
+                // a failing backend is reported to the client instead of
+                // taking down the connection
+                let mut buffer: Vec<u8> = Vec::with_capacity(self.count as usize);
+                let read = filehandle.file.open_file().and_then(|mut rfile| {
+                    rfile.seek(SeekFrom::Start(self.offset))?;
+                    rfile.take(self.count as u64).read_to_end(&mut buffer)?;
+                    Ok(())
+                });
+                if let Err(e) = read {
+                    error!("Couldn't read {:?}: {:?}", filehandle.path, e);
+                    return op_error(request, vfs_error_status(&e));
+                }
+                // hands the buffer over without copying it
+                Bytes::from(buffer)
+            }
+        };
+        if let Some(readahead) = readahead {
+            readahead.record(
+                filehandle.id,
+                &filehandle.file,
+                self.offset,
+                data.len() as u64,
+            );
+        }
+        let eof = data.len() < self.count as usize
+            || self.offset + data.len() as u64 >= filehandle.attr_size;
 
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::Opread(Read4res::Resok4(Read4resok {
-                eof: true,
-                data: buffer,
+                eof,
+                data,
             }))),
             status: NfsStat4::Nfs4Ok,
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use std::{io, sync::atomic::Ordering, time::Duration};
+
+    use crate::{
+        server::{
+            filemanager::FileManagerConfig,
+            nfs40::{
+                Attrlist4, Fattr4, FileAttr, FileAttrValue, NfsResOp4, NfsStat4, Read4args,
+                Read4res, SetAttr4args, StableHow4, Stateid4, Write4args, OPEN4_SHARE_ACCESS_READ,
+                OPEN4_SHARE_DENY_WRITE,
+            },
+            operation::NfsOperation,
+        },
+        test_utils::{
+            create_fake_fs, create_nfs40_server, create_nfs40_server_with_config, FaultFS, FsCall,
+        },
+    };
+    use tracing_test::traced_test;
+    use vfs::VfsPath;
+
+    fn read_args(offset: u64, count: u32) -> Read4args {
+        Read4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset,
+            count,
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_range_overflow() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let response = read_args(u64::MAX - 1, 5).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let response = read_args(7, 5).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opread(Read4res::Resok4(res))) => {
+                assert_eq!(res.data, &b"loooo"[..]);
+                assert!(!res.eof);
+            }
+            _ => panic!("Unexpected result"),
+        }
+
+        // reading past the end returns what's left
+        let response = read_args(19, 100).execute(response.request).await;
+        match response.result {
+            Some(NfsResOp4::Opread(Read4res::Resok4(res))) => {
+                assert_eq!(res.data, &b"world!"[..]);
+                assert!(res.eof);
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_backend_error() {
+        let root: VfsPath = FaultFS::default()
+            .fail(FsCall::Open, "broken", io::ErrorKind::Other)
+            .into();
+        root.join("broken")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/broken".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let response = read_args(0, 4).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errIo);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_backend_busy() {
+        let root: VfsPath = FaultFS::default()
+            .fail(FsCall::Open, "busy", io::ErrorKind::WouldBlock)
+            .into();
+        root.join("busy")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/busy".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        // a transient condition, the client is told to retry
+        let response = read_args(0, 4).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errDelay);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_ahead() {
+        let fs = FaultFS::default();
+        let opens = fs.opens.clone();
+        let root: VfsPath = fs.into();
+        let content: Vec<u8> = (0..24).collect();
+        root.join("data")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(&content)
+            .unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                readahead: Some(32),
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/data".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        opens.store(0, Ordering::SeqCst);
+        let response = read_args(0, 8).execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        // the first READ opens the file, the prefetch of the rest follows
+        for _ in 0..100 {
+            if opens.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+
+        // the next READ doesn't touch the backend
+        let response = read_args(8, 8).execute(response.request).await;
+        match response.result {
+            Some(NfsResOp4::Opread(Read4res::Resok4(res))) => {
+                assert_eq!(res.data, &content[8..16]);
+                assert!(!res.eof);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let response = read_args(16, 100).execute(response.request).await;
+        match response.result {
+            Some(NfsResOp4::Opread(Read4res::Resok4(res))) => {
+                assert_eq!(res.data, &content[16..]);
+                assert!(res.eof);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_special_stateids() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        let content = fh.file.read_to_string().unwrap();
+        // another client reads the file and denies writes
+        request
+            .file_manager()
+            .create_file(
+                fh.file.clone(),
+                1,
+                b"reader".to_vec(),
+                OPEN4_SHARE_ACCESS_READ,
+                OPEN4_SHARE_DENY_WRITE,
+                None,
+            )
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let anonymous = Stateid4 {
+            seqid: 0,
+            other: [0; 12],
+        };
+        let bypass = Stateid4 {
+            seqid: u32::MAX,
+            other: [0xff; 12],
+        };
+        for stateid in [anonymous.clone(), bypass.clone()] {
+            let args = Read4args {
+                stateid,
+                offset: 0,
+                count: 5,
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            request = response.request;
+        }
+
+        // the deny mode holds for writes with either special stateid
+        for stateid in [anonymous.clone(), bypass.clone()] {
+            let args = Write4args {
+                stateid: stateid.clone(),
+                offset: 0,
+                stable: StableHow4::FileSync4,
+                data: b"Howdy".to_vec(),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4errLocked);
+            let args = SetAttr4args {
+                stateid,
+                obj_attributes: Fattr4 {
+                    attrmask: Attrlist4(vec![FileAttr::Size]),
+                    attr_vals: Attrlist4(vec![FileAttrValue::Size(0)]),
+                },
+            };
+            let response = args.execute(response.request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4errLocked);
+            request = response.request;
+        }
+        assert_eq!(
+            request
+                .current_filehandle()
+                .unwrap()
+                .file
+                .read_to_string()
+                .unwrap(),
+            content
+        );
+
+        // special stateids only come with their own seqid
+        let args = Read4args {
+            stateid: Stateid4 {
+                seqid: 1,
+                other: [0; 12],
+            },
+            offset: 0,
+            count: 5,
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errBadStateid);
+    }
+}
@@ -1,15 +1,38 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
 use async_trait::async_trait;
 use tracing::{debug, error};
+use vfs::VfsPath;
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{
-    DirList4, Entry4, Fattr4, NfsResOp4, NfsStat4, ReadDir4res, ReadDir4resok, Readdir4args,
+    Attrlist4, DirList4, Entry4, Fattr4, NfsResOp4, NfsStat4, ReadDir4res, ReadDir4resok,
+    Readdir4args,
 };
 
+// cookie of the first directory entry, 0 to 2 are reserved
+const FIRST_ENTRY_COOKIE: u64 = 3;
+
+/// The cookie verifier of a directory listing. It is derived from the entry
+/// names, so it changes whenever entries are added or removed, including the
+/// transitions to and from an empty directory, which has a verifier of its own.
+fn cookie_verifier(dir: &[VfsPath]) -> [u8; 8] {
+    let mut hasher = DefaultHasher::new();
+    dir.len().hash(&mut hasher);
+    for entry in dir {
+        entry.filename().hash(&mut hasher);
+    }
+    hasher.finish().to_be_bytes()
+}
+
 #[async_trait]
 impl NfsOperation for Readdir4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 26: READDIR - Read Directory {:?}, with request {:?}",
             self, request
@@ -19,132 +42,125 @@ impl NfsOperation for Readdir4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
-        let dir = dir_fh.file.read_dir().unwrap();
+        // children nested deeper than the server hands out filehandles for
+        // can't be looked up, they are left out of the listing
+        let depth = dir_fh.path.split('/').filter(|c| !c.is_empty()).count();
+        let too_deep = request
+            .file_manager()
+            .max_path_depth()
+            .is_some_and(|max_path_depth| depth >= max_path_depth);
+        let dir: Vec<VfsPath> = if too_deep {
+            debug!("Children of {:?} exceed the maximum depth", dir_fh.path);
+            Vec::new()
+        } else {
+            dir_fh.file.read_dir().unwrap().collect()
+        };
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-16.24.4
+        // The cookie values of 0, 1, and 2 are reserved, entries are handed out
+        // starting with cookie 3. A client resuming from a reserved value reads
+        // from the start of the directory.
+        let cookie = if self.cookie < FIRST_ENTRY_COOKIE {
+            0
+        } else {
+            self.cookie
+        };
 
-        let mut fnames = Vec::new();
-        let mut filehandles = Vec::new();
-        let dircount: usize = self.dircount as usize;
-        let maxcount: usize = self.maxcount as usize;
+        // a dircount of 0 leaves the size of the names unbounded, unless the
+        // server caps it
+        let (max_dircount, max_maxcount) = request.file_manager().readdir_limits();
+        let dircount = match (self.dircount, max_dircount) {
+            (0, Some(max)) => max,
+            (dircount, Some(max)) => dircount.min(max),
+            (dircount, None) => dircount,
+        } as usize;
+        let maxcount = max_maxcount.map_or(self.maxcount, |max| self.maxcount.min(max)) as usize;
         let mut maxcount_actual: usize = 128;
         let mut dircount_actual = 0;
-        // get a list of filenames and filehandles
-        for (i, entry) in dir.enumerate() {
-            let name = entry.filename();
-            fnames.push(name.clone());
-            // if the cookie value is progressed, we add only subsequent filehandles
-            // https://datatracker.ietf.org/doc/html/rfc7530#section-16.24.4
-            // To enable some client environments, the cookie values of 0, 1, and 2 are to be considered reserved.
-            if (i + 2) >= self.cookie as usize {
-                // this is a poor man's estimation of the XRD outputs bytes, must be improved
-                // we need to know the definitive size of the output of the XDR message here, but how?
-                dircount_actual = dircount_actual + 8 + name.len() + 5;
-                maxcount_actual += 200;
-                if dircount == 0 || (dircount > dircount_actual && maxcount > maxcount_actual) {
-                    let filehandle = request
-                        .file_manager()
-                        .get_filehandle_for_path(entry.as_str().to_string())
-                        .await;
-                    match filehandle {
-                        Err(_e) => {
-                            error!("None filehandle");
-                            return NfsOpResponse {
-                                request,
-                                result: None,
-                                status: NfsStat4::Nfs4errFhexpired,
-                            };
-                        }
-                        Ok(filehandle) => {
-                            // https://datatracker.ietf.org/doc/html/rfc7530#section-16.24.4
-                            // To enable some client environments, the cookie values of 0, 1, and 2 are to be considered reserved.
-                            filehandles.push((i + 3, filehandle));
-                        }
-                    }
-                }
+        // select the entries of this page, filehandles are only resolved for these
+        let mut page = Vec::new();
+        let mut eof = true;
+        // if the cookie value is progressed, we add only subsequent entries. The
+        // cookie of the last entry reads nothing, the listing is at its end.
+        let start = if cookie == 0 {
+            0
+        } else {
+            usize::try_from(cookie - FIRST_ENTRY_COOKIE + 1).unwrap_or(usize::MAX)
+        };
+        for (i, entry) in dir.iter().enumerate().skip(start) {
+            let entry_cookie = i as u64 + FIRST_ENTRY_COOKIE;
+            // this is a poor man's estimation of the XRD outputs bytes, must be improved
+            // we need to know the definitive size of the output of the XDR message here, but how?
+            dircount_actual = dircount_actual + 8 + entry.filename().len() + 5;
+            maxcount_actual += 200;
+            if (dircount == 0 || dircount > dircount_actual) && maxcount > maxcount_actual {
+                page.push((entry_cookie, entry));
+            } else {
+                eof = false;
+                break;
             }
         }
 
-        // get a seed of this directory, concat all files names
-        let seed: String = fnames
-            .iter()
-            .flat_map(|s| s.as_str().chars().collect::<Vec<_>>())
-            .collect();
-        // take only every nth char to create a cookie verifier
-        let mut cookieverf = seed
-            .as_bytes()
-            .iter()
-            .step_by(seed.len() / 8 + 1)
-            .copied()
-            .collect::<Vec<_>>();
-        if self.cookie != 0 && cookieverf != self.cookieverf {
+        let cookieverf = cookie_verifier(&dir);
+        if cookie != 0 && cookieverf != self.cookieverf {
             error!("Nfs4errNotSame");
-            return NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errNotSame,
-            };
+            return op_error(request, NfsStat4::Nfs4errNotSame);
         }
 
-        // if this directory is empty, we can't create a cookie verifier based on the dir contents
-        // setting it to a default value
-        if cookieverf.is_empty() {
-            cookieverf = [0u8; 8].to_vec();
-        } else if cookieverf.len() < 8 {
-            let mut diff = 8 - cookieverf.len();
-            while diff > 0 {
-                cookieverf.push(0);
-                diff -= 1;
-            }
+        // no entry of the unchanged directory ever had a cookie past the last
+        // one, the client made it up
+        if cookie != 0 && cookie >= dir.len() as u64 + FIRST_ENTRY_COOKIE {
+            error!("Nfs4errBadCookie");
+            return op_error(request, NfsStat4::Nfs4errBadCookie);
         }
 
-        let mut tnextentry = None;
-        let mut added_entries = 0;
-        for (cookie, fh) in filehandles.into_iter().rev() {
-            let resp = request
-                .file_manager()
-                .filehandle_attrs(&self.attr_request, &fh);
-            let (answer_attrs, attrs) = match resp {
-                Some(inner) => inner,
-                None => {
-                    return NfsOpResponse {
-                        request,
-                        result: None,
-                        status: NfsStat4::Nfs4errServerfault,
-                    };
+        if page.is_empty() && !eof {
+            error!("Nfs4errToosmall");
+            return op_error(request, NfsStat4::Nfs4errToosmall);
+        }
+
+        // the filehandles and attributes of the whole page are resolved in a
+        // single request to the file manager
+        let paths = page
+            .iter()
+            .map(|(_, entry)| entry.as_str().to_string())
+            .collect();
+        let resolved = match request
+            .file_manager()
+            .get_filehandle_attrs_many(paths, self.attr_request.to_vec())
+            .await
+        {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                return op_error(request, e.nfs_error);
+            }
+        };
+        let mut entries = Vec::with_capacity(page.len());
+        for ((entry_cookie, entry), res) in page.into_iter().zip(resolved) {
+            match res {
+                Err(status) => {
+                    error!("Couldn't resolve entry {:?}: {:?}", entry.as_str(), status);
+                    return op_error(request, status);
                 }
-            };
+                Ok(res) => entries.push((entry_cookie, res)),
+            }
+        }
 
+        let mut tnextentry = None;
+        for (cookie, (fh, answer_attrs, attrs)) in entries.into_iter().rev() {
             let entry = Entry4 {
                 name: fh.file.filename(),
-                cookie: cookie as u64,
+                cookie,
                 attrs: Fattr4 {
-                    attrmask: answer_attrs,
-                    attr_vals: attrs,
-                },
-                nextentry: if tnextentry.is_some() {
-                    Some(Box::new(tnextentry.unwrap()))
-                } else {
-                    None
+                    attrmask: Attrlist4(answer_attrs),
+                    attr_vals: Attrlist4(attrs),
                 },
+                nextentry: tnextentry.map(Box::new),
             };
-            added_entries += 1;
             tnextentry = Some(entry);
         }
-        let eof = {
-            if tnextentry.is_some()
-                && (tnextentry.clone().unwrap().cookie + added_entries) >= fnames.len() as u64
-            {
-                true
-            } else {
-                tnextentry.is_none()
-            }
-        };
 
         NfsOpResponse {
             request,
@@ -154,7 +170,7 @@ impl NfsOperation for Readdir4args {
                     entries: tnextentry.clone(),
                     eof,
                 },
-                cookieverf: cookieverf.as_slice().try_into().unwrap(),
+                cookieverf,
             }))),
             status: NfsStat4::Nfs4Ok,
         }
@@ -164,155 +180,617 @@ impl NfsOperation for Readdir4args {
 #[cfg(test)]
 mod integration_tests {
 
+    use std::collections::HashSet;
+
     use bold_proto::nfs4_proto::Attrlist4;
     use tracing_test::traced_test;
+    use vfs::{MemoryFS, VfsPath};
 
     use crate::{
         server::{
+            filemanager::FileManagerConfig,
             nfs40::{
                 DirList4, FileAttr, FileAttrValue, NfsFtype4, NfsResOp4, NfsStat4, PutFh4args,
                 ReadDir4res, ReadDir4resok, Readdir4args,
             },
             operation::NfsOperation,
         },
-        test_utils::{create_fake_fs, create_nfs40_server},
+        test_utils::{
+            create_nfs40_server, create_nfs40_server_with_config, create_tree_fs, FaultFS, TestFs,
+            TreeSpec,
+        },
     };
 
     #[tokio::test]
     #[traced_test]
     async fn test_read_directory() {
-        // dummy fs, empty
-        let request = create_nfs40_server(None).await;
-        let fh = request.file_manager().get_root_filehandle().await;
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            // dummy fs, empty
+            let (empty, _empty_dir) = backend.root();
+            let request = create_nfs40_server(Some(empty)).await;
+            let fh = request.file_manager().get_root_filehandle().await;
 
-        let putfh_args = PutFh4args {
-            object: fh.unwrap().id,
-        };
-        let putfh_request = putfh_args.execute(request).await;
+            let putfh_args = PutFh4args {
+                object: fh.unwrap().id,
+            };
+            let putfh_request = putfh_args.execute(request).await;
+
+            let readdir_args = Readdir4args {
+                cookie: 0,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                    FileAttr::Type,
+                    FileAttr::Change,
+                    FileAttr::Size,
+                    FileAttr::Fsid,
+                    FileAttr::RdattrError,
+                    FileAttr::Filehandle,
+                    FileAttr::Fileid,
+                    FileAttr::Mode,
+                    FileAttr::Numlinks,
+                    FileAttr::Owner,
+                    FileAttr::OwnerGroup,
+                    FileAttr::Rawdev,
+                    FileAttr::SpaceUsed,
+                    FileAttr::TimeAccess,
+                    FileAttr::TimeMetadata,
+                    FileAttr::TimeModify,
+                    FileAttr::MountedOnFileid,
+                ])),
+            };
+
+            let readdir_response = readdir_args.execute(putfh_request.request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            assert_eq!(
+                readdir_response.result,
+                Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(ReadDir4resok {
+                    cookieverf: super::cookie_verifier(&[]),
+                    reply: DirList4 {
+                        entries: None,
+                        eof: true
+                    }
+                })))
+            );
 
+            // a more filled directory, still eof = true
+
+            let request = create_nfs40_server(Some(root)).await;
+            let fh = request.file_manager().get_root_filehandle().await;
+
+            let putfh_args = PutFh4args {
+                object: fh.unwrap().id,
+            };
+            let putfh_request = putfh_args.execute(request).await;
+
+            let readdir_args = Readdir4args {
+                cookie: 0,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![
+                    FileAttr::Type,
+                    FileAttr::Change,
+                    FileAttr::Size,
+                    FileAttr::Fsid,
+                    FileAttr::RdattrError,
+                    FileAttr::Filehandle,
+                    FileAttr::Fileid,
+                    FileAttr::Mode,
+                    FileAttr::Numlinks,
+                    FileAttr::Owner,
+                    FileAttr::OwnerGroup,
+                    FileAttr::Rawdev,
+                    FileAttr::SpaceUsed,
+                    FileAttr::TimeAccess,
+                    FileAttr::TimeMetadata,
+                    FileAttr::TimeModify,
+                    FileAttr::MountedOnFileid,
+                ])),
+            };
+
+            let readdir_response = readdir_args.execute(putfh_request.request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            let result = readdir_response.result.unwrap();
+            match result {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    assert_eq!(res.cookieverf.len(), 8);
+                    let entries = res.reply.entries.unwrap();
+                    assert_eq!(entries.cookie, 3);
+                    if entries.name == "file1.txt" {
+                        assert_eq!(entries.attrs.attrmask.len(), 16);
+                        assert_eq!(entries.attrs.attr_vals.len(), 16);
+                        assert_eq!(
+                            entries.attrs.attr_vals[0],
+                            FileAttrValue::Type(NfsFtype4::Nf4reg)
+                        );
+                    } else if entries.name == "dir1" {
+                        assert_eq!(entries.attrs.attrmask.len(), 16);
+                        assert_eq!(entries.attrs.attr_vals.len(), 16);
+                        assert_eq!(
+                            entries.attrs.attr_vals[0],
+                            FileAttrValue::Type(NfsFtype4::Nf4dir)
+                        );
+                    } else {
+                        panic!("Unexpected entry");
+                    }
+                    let next = entries.nextentry.unwrap();
+                    assert_eq!(next.cookie, 4);
+                    if next.name == "file1.txt" {
+                        assert_eq!(next.attrs.attrmask.len(), 16);
+                        assert_eq!(next.attrs.attr_vals.len(), 16);
+                        assert_eq!(
+                            next.attrs.attr_vals[0],
+                            FileAttrValue::Type(NfsFtype4::Nf4reg)
+                        );
+                    } else if next.name == "dir1" {
+                        assert_eq!(next.attrs.attrmask.len(), 16);
+                        assert_eq!(next.attrs.attr_vals.len(), 16);
+                        assert_eq!(
+                            next.attrs.attr_vals[0],
+                            FileAttrValue::Type(NfsFtype4::Nf4dir)
+                        );
+                    } else {
+                        panic!("Unexpected entry");
+                    }
+                    assert_eq!(next.nextentry, None);
+                    assert!(res.reply.eof);
+                }
+                _ => panic!("Expected Resok4"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_reserved_cookie() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let request = create_nfs40_server(Some(root)).await;
+            let fh = request.file_manager().get_root_filehandle().await;
+
+            let putfh_args = PutFh4args {
+                object: fh.unwrap().id,
+            };
+            let putfh_request = putfh_args.execute(request).await;
+
+            // resuming with a reserved cookie starts at the first entry
+            let readdir_args = Readdir4args {
+                cookie: 1,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            };
+
+            let readdir_response = readdir_args.execute(putfh_request.request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            match readdir_response.result.unwrap() {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    let entries = res.reply.entries.unwrap();
+                    assert_eq!(entries.cookie, 3);
+                    assert_eq!(entries.nextentry.unwrap().cookie, 4);
+                    assert!(res.reply.eof);
+                }
+                _ => panic!("Expected Resok4"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_page() {
+        let fs = FaultFS::default();
+        let lookups = fs.lookups.clone();
+        let root: VfsPath = fs.into();
+        for i in 0..100 {
+            root.join(format!("file{}", i))
+                .unwrap()
+                .create_file()
+                .unwrap();
+        }
+
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+        lookups.lock().unwrap().clear();
+
+        // room for 5 entries
         let readdir_args = Readdir4args {
             cookie: 0,
             cookieverf: [0u8; 8],
-            dircount: 262122,
-            maxcount: 1048488,
-            attr_request: Attrlist4::<FileAttr>::new(Some(vec![
-                FileAttr::Type,
-                FileAttr::Change,
-                FileAttr::Size,
-                FileAttr::Fsid,
-                FileAttr::RdattrError,
-                FileAttr::Filehandle,
-                FileAttr::Fileid,
-                FileAttr::Mode,
-                FileAttr::Numlinks,
-                FileAttr::Owner,
-                FileAttr::OwnerGroup,
-                FileAttr::Rawdev,
-                FileAttr::SpaceUsed,
-                FileAttr::TimeAccess,
-                FileAttr::TimeMetadata,
-                FileAttr::TimeModify,
-                FileAttr::MountedOnFileid,
-            ])),
+            dircount: 0,
+            maxcount: 1200,
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
         };
 
-        let readdir_response = readdir_args.execute(putfh_request.request).await;
+        let readdir_response = readdir_args.execute(request).await;
         assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
-        assert_eq!(
-            readdir_response.result,
-            Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(ReadDir4resok {
-                cookieverf: [0, 0, 0, 0, 0, 0, 0, 0],
-                reply: DirList4 {
-                    entries: None,
-                    eof: true
+        assert_eq!(lookups.lock().unwrap().len(), 5);
+        match readdir_response.result.unwrap() {
+            NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                let mut entry = res.reply.entries;
+                let mut count = 0;
+                while let Some(e) = entry {
+                    count += 1;
+                    entry = e.nextentry.map(|e| *e);
                 }
-            })))
-        );
+                assert_eq!(count, 5);
+                assert!(!res.reply.eof);
+            }
+            _ => panic!("Expected Resok4"),
+        }
+    }
 
-        // a more filled directory, still eof = true
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_large_tree() {
+        let root = create_tree_fs(&TreeSpec {
+            depth: 3,
+            breadth: 2,
+            files: 1000,
+            file_size: 64,
+        });
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/dir0".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
 
-        let request = create_nfs40_server(Some(create_fake_fs())).await;
-        let fh = request.file_manager().get_root_filehandle().await;
+        // page through the deepest level
+        let mut args = Readdir4args {
+            cookie: 0,
+            cookieverf: [0u8; 8],
+            dircount: 0,
+            maxcount: 8192,
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Size])),
+        };
+        let mut names = HashSet::new();
+        let mut pages = 0;
+        loop {
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            request = response.request;
+            pages += 1;
+            let res = match response.result {
+                Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(res))) => res,
+                _ => panic!("Expected Resok4"),
+            };
+            let mut entry = res.reply.entries;
+            while let Some(e) = entry {
+                assert!(e.cookie > args.cookie);
+                assert_eq!(e.attrs.attr_vals[0], FileAttrValue::Size(64));
+                assert!(names.insert(e.name.clone()));
+                args.cookie = e.cookie;
+                entry = e.nextentry.map(|e| *e);
+            }
+            args.cookieverf = res.cookieverf;
+            if res.reply.eof {
+                break;
+            }
+        }
+        assert_eq!(names.len(), 1000);
+        assert!(pages > 1);
+    }
 
-        let putfh_args = PutFh4args {
-            object: fh.unwrap().id,
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_max_path_depth() {
+        let root: VfsPath = MemoryFS::new().into();
+        root.join("a/b/c").unwrap().create_dir_all().unwrap();
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                max_path_depth: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let args = Readdir4args {
+            cookie: 0,
+            cookieverf: [0u8; 8],
+            dircount: 0,
+            maxcount: 8192,
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
         };
-        let putfh_request = putfh_args.execute(request).await;
 
-        let readdir_args = Readdir4args {
+        // "/a/b" is within the limit
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/a".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(res))) => {
+                let entry = res.reply.entries.unwrap();
+                assert_eq!(entry.name, "b");
+                assert!(entry.nextentry.is_none());
+            }
+            _ => panic!("Expected Resok4"),
+        }
+
+        // "/a/b/c" is three components deep, it is left out instead of
+        // failing the listing
+        let mut request = response.request;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/a/b".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(res))) => {
+                assert!(res.reply.entries.is_none());
+                assert!(res.reply.eof);
+            }
+            _ => panic!("Expected Resok4"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_capped() {
+        let root = create_tree_fs(&TreeSpec {
+            depth: 1,
+            breadth: 0,
+            files: 200,
+            file_size: 8,
+        });
+        let mut request = create_nfs40_server_with_config(
+            Some(root),
+            FileManagerConfig {
+                readdir_max_dircount: Some(4096),
+                readdir_max_maxcount: Some(8192),
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+
+        // the client asks for everything at once
+        let mut args = Readdir4args {
+            cookie: 0,
+            cookieverf: [0u8; 8],
+            dircount: u32::MAX,
+            maxcount: u32::MAX,
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Size])),
+        };
+        let mut names = HashSet::new();
+        let mut pages = 0;
+        loop {
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            request = response.request;
+            pages += 1;
+            let res = match response.result {
+                Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(res))) => res,
+                _ => panic!("Expected Resok4"),
+            };
+            let mut entry = res.reply.entries;
+            let mut page_len = 0;
+            while let Some(e) = entry {
+                assert!(names.insert(e.name.clone()));
+                args.cookie = e.cookie;
+                page_len += 1;
+                entry = e.nextentry.map(|e| *e);
+            }
+            // well below the 200 entries asked for
+            assert!(page_len < 50);
+            args.cookieverf = res.cookieverf;
+            if res.reply.eof {
+                break;
+            }
+        }
+        assert_eq!(names.len(), 200);
+        assert!(pages > 4);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_batched_attrs() {
+        let root = create_tree_fs(&TreeSpec {
+            depth: 1,
+            breadth: 0,
+            files: 20,
+            file_size: 8,
+        });
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(fh);
+
+        let args = Readdir4args {
             cookie: 0,
             cookieverf: [0u8; 8],
-            dircount: 262122,
+            dircount: 0,
             maxcount: 1048488,
-            attr_request: Attrlist4::<FileAttr>::new(Some(vec![
-                FileAttr::Type,
-                FileAttr::Change,
-                FileAttr::Size,
-                FileAttr::Fsid,
-                FileAttr::RdattrError,
-                FileAttr::Filehandle,
-                FileAttr::Fileid,
-                FileAttr::Mode,
-                FileAttr::Numlinks,
-                FileAttr::Owner,
-                FileAttr::OwnerGroup,
-                FileAttr::Rawdev,
-                FileAttr::SpaceUsed,
-                FileAttr::TimeAccess,
-                FileAttr::TimeMetadata,
-                FileAttr::TimeModify,
-                FileAttr::MountedOnFileid,
-            ])),
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type, FileAttr::Size])),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let mut entry = match response.result {
+            Some(NfsResOp4::Opreaddir(ReadDir4res::Resok4(res))) => res.reply.entries,
+            _ => panic!("Expected Resok4"),
         };
+        let mut count = 0;
+        while let Some(e) = entry {
+            assert_eq!(e.attrs.attr_vals[1], FileAttrValue::Size(8));
+            count += 1;
+            entry = e.nextentry.map(|e| *e);
+        }
+        assert_eq!(count, 20);
 
-        let readdir_response = readdir_args.execute(putfh_request.request).await;
-        assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
-        let result = readdir_response.result.unwrap();
-        match result {
-            NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
-                assert_eq!(res.cookieverf.len(), 8);
-                let entries = res.reply.entries.unwrap();
-                assert_eq!(entries.cookie, 3);
-                if entries.name == "file1.txt" {
-                    assert_eq!(entries.attrs.attrmask.len(), 14);
-                    assert_eq!(entries.attrs.attr_vals.len(), 14);
-                    assert_eq!(
-                        entries.attrs.attr_vals[0],
-                        FileAttrValue::Type(NfsFtype4::Nf4reg)
-                    );
-                } else if entries.name == "dir1" {
-                    assert_eq!(entries.attrs.attrmask.len(), 14);
-                    assert_eq!(entries.attrs.attr_vals.len(), 14);
-                    assert_eq!(
-                        entries.attrs.attr_vals[0],
-                        FileAttrValue::Type(NfsFtype4::Nf4dir)
-                    );
-                } else {
-                    panic!("Unexpected entry");
+        // all entries were resolved in one request to the file manager
+        logs_assert(|lines: &[&str]| {
+            let batched: Vec<_> = lines
+                .iter()
+                .filter(|line| line.contains("Batched attributes request"))
+                .collect();
+            match batched.as_slice() {
+                [line] if line.contains("for 20 entries") => Ok(()),
+                _ => Err(format!("expected one batched request, got {:?}", batched)),
+            }
+        });
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_emptied() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server(Some(root)).await;
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path("/dir1".to_string())
+                .await
+                .unwrap();
+            request.set_filehandle(fh);
+
+            let readdir_args = Readdir4args {
+                cookie: 0,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            };
+            let readdir_response = readdir_args.execute(request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            let (cookie, cookieverf) = match readdir_response.result.unwrap() {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    let entries = res.reply.entries.unwrap();
+                    assert_eq!(entries.name, "file2.txt");
+                    (entries.cookie, res.cookieverf)
+                }
+                _ => panic!("Expected Resok4"),
+            };
+            assert_ne!(cookieverf, super::cookie_verifier(&[]));
+
+            // resuming an unchanged directory with its verifier is fine
+            let readdir_args = Readdir4args {
+                cookie,
+                cookieverf,
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            };
+            let readdir_response = readdir_args.execute(readdir_response.request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+
+            // once the directory is emptied, the old verifier is stale
+            let request = readdir_response.request;
+            request
+                .current_filehandle()
+                .unwrap()
+                .file
+                .join("file2.txt")
+                .unwrap()
+                .remove_file()
+                .unwrap();
+            let readdir_response = readdir_args.execute(request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4errNotSame);
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_past_end() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server(Some(root)).await;
+            let fh = request.file_manager().get_root_filehandle().await.unwrap();
+            request.set_filehandle(fh);
+
+            let mut readdir_args = Readdir4args {
+                cookie: 0,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            };
+            let readdir_response = readdir_args.execute(request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            let (last_cookie, cookieverf) = match readdir_response.result.unwrap() {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    let mut entry = res.reply.entries;
+                    let mut last_cookie = 0;
+                    while let Some(e) = entry {
+                        last_cookie = e.cookie;
+                        entry = e.nextentry.map(|e| *e);
+                    }
+                    assert!(res.reply.eof);
+                    (last_cookie, res.cookieverf)
                 }
-                let next = entries.nextentry.unwrap();
-                assert_eq!(next.cookie, 4);
-                if next.name == "file1.txt" {
-                    assert_eq!(next.attrs.attrmask.len(), 14);
-                    assert_eq!(next.attrs.attr_vals.len(), 14);
-                    assert_eq!(
-                        next.attrs.attr_vals[0],
-                        FileAttrValue::Type(NfsFtype4::Nf4reg)
-                    );
-                } else if next.name == "dir1" {
-                    assert_eq!(next.attrs.attrmask.len(), 14);
-                    assert_eq!(next.attrs.attr_vals.len(), 14);
-                    assert_eq!(
-                        next.attrs.attr_vals[0],
-                        FileAttrValue::Type(NfsFtype4::Nf4dir)
-                    );
-                } else {
-                    panic!("Unexpected entry");
+                _ => panic!("Expected Resok4"),
+            };
+
+            // after the last cookie of the unchanged directory there's nothing
+            // left to read
+            readdir_args.cookie = last_cookie;
+            readdir_args.cookieverf = cookieverf;
+            let readdir_response = readdir_args.execute(readdir_response.request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            match readdir_response.result.unwrap() {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    assert!(res.reply.entries.is_none());
+                    assert!(res.reply.eof);
+                    assert_eq!(res.cookieverf, cookieverf);
                 }
-                assert_eq!(next.nextentry, None);
-                assert!(res.reply.eof);
+                _ => panic!("Expected Resok4"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_read_directory_bad_cookie() {
+        for backend in TestFs::ALL {
+            let (root, _dir) = backend.create_fake_fs();
+            let mut request = create_nfs40_server(Some(root)).await;
+            let fh = request.file_manager().get_root_filehandle().await.unwrap();
+            request.set_filehandle(fh);
+
+            let mut readdir_args = Readdir4args {
+                cookie: 0,
+                cookieverf: [0u8; 8],
+                dircount: 262122,
+                maxcount: 1048488,
+                attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Type])),
+            };
+            let readdir_response = readdir_args.execute(request).await;
+            assert_eq!(readdir_response.status, NfsStat4::Nfs4Ok);
+            let (last_cookie, cookieverf) = match readdir_response.result.unwrap() {
+                NfsResOp4::Opreaddir(ReadDir4res::Resok4(res)) => {
+                    let mut entry = res.reply.entries;
+                    let mut last_cookie = 0;
+                    while let Some(e) = entry {
+                        last_cookie = e.cookie;
+                        entry = e.nextentry.map(|e| *e);
+                    }
+                    assert!(res.reply.eof);
+                    (last_cookie, res.cookieverf)
+                }
+                _ => panic!("Expected Resok4"),
+            };
+
+            // cookies no entry ever had are made up
+            readdir_args.cookieverf = cookieverf;
+            let mut request = readdir_response.request;
+            for cookie in [last_cookie + 1, u64::MAX] {
+                readdir_args.cookie = cookie;
+                let readdir_response = readdir_args.execute(request).await;
+                assert_eq!(readdir_response.status, NfsStat4::Nfs4errBadCookie);
+                assert!(readdir_response.result.is_none());
+                request = readdir_response.request;
             }
-            _ => panic!("Expected Resok4"),
         }
     }
 }
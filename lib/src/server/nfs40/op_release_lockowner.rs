@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+
+use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, ReleaseLockowner4args, ReleaseLockowner4res};
+
+#[async_trait]
+impl NfsOperation for ReleaseLockowner4args {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
+        // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.37
+        debug!(
+            "Operation 39: RELEASE_LOCKOWNER - Release Lock-Owner State {:?}, with request {:?}",
+            self, request
+        );
+        let res = request
+            .file_manager()
+            .release_lockowner(self.lock_owner.clientid, self.lock_owner.owner.clone())
+            .await;
+        let status = match res {
+            Ok(_) => NfsStat4::Nfs4Ok,
+            Err(e) => {
+                error!("Release lock owner err {:?}", e);
+                e.nfs_error
+            }
+        };
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::OpreleaseLockOwner(ReleaseLockowner4res {
+                status: status.clone(),
+            })),
+            status,
+        }
+    }
+}
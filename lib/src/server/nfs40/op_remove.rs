@@ -5,14 +5,14 @@ use crate::server::{
     nfs40::{ChangeInfo4, NfsStat4},
     operation::NfsOperation,
     request::NfsRequest,
-    response::NfsOpResponse,
+    response::{op_error, NfsOpResponse},
 };
 
 use bold_proto::nfs4_proto::{NfsResOp4, Remove4args, Remove4res};
 
 #[async_trait]
 impl NfsOperation for Remove4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 28: REMOVE - Remove File System Object {:?}, with request {:?}",
             self, request
@@ -24,14 +24,14 @@ impl NfsOperation for Remove4args {
                 return NfsOpResponse {
                     request,
                     result: Some(NfsResOp4::Opremove(Remove4res {
-                        status: NfsStat4::Nfs4errStale,
+                        status: NfsStat4::Nfs4errNofilehandle,
                         cinfo: ChangeInfo4 {
                             atomic: false,
                             before: 0,
                             after: 0,
                         },
                     })),
-                    status: NfsStat4::Nfs4errStale,
+                    status: NfsStat4::Nfs4errNofilehandle,
                 };
             }
             Some(filehandle) => {
@@ -50,9 +50,57 @@ impl NfsOperation for Remove4args {
                         })),
                         status: NfsStat4::Nfs4errStale,
                     },
-                    Err(_) => todo!(),
+                    Err(e) => {
+                        debug!("FileManagerError {:?}", e);
+                        op_error(request, e.nfs_error)
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{nfs40::Remove4args, operation::NfsOperation},
+        test_utils::{create_fake_fs, create_nfs40_server},
+    };
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_remove_drops_metadata() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        let default_mode = file.attr_mode;
+        request
+            .file_manager()
+            .set_mode(file.id, 0o600)
+            .await
+            .unwrap();
+
+        request.set_filehandle(root.clone());
+        let args = Remove4args {
+            target: "file1.txt".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert!(!root.file.join("file1.txt").unwrap().exists().unwrap());
+
+        // a file created in its place doesn't inherit the mode
+        root.file.join("file1.txt").unwrap().create_file().unwrap();
+        let recreated = response
+            .request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        assert_ne!(recreated.id, file.id);
+        assert_eq!(recreated.attr_mode, default_mode);
+    }
+}
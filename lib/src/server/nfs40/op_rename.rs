@@ -0,0 +1,269 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{
+    nfs40::{ChangeInfo4, NfsStat4},
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+
+use bold_proto::nfs4_proto::{NfsResOp4, Rename4args, Rename4res, Rename4resok};
+
+#[async_trait]
+impl NfsOperation for Rename4args {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
+        // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.26.5
+        debug!(
+            "Operation 29: RENAME - Rename Directory Entry {:?}, with request {:?}",
+            self, request
+        );
+        // the saved filehandle is the source directory, the current one the
+        // target directory
+        let (source, target) = match (request.saved_filehandle(), request.current_filehandle()) {
+            (Some(source), Some(target)) => (source.clone(), target.clone()),
+            _ => {
+                error!("None filehandle");
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
+            }
+        };
+
+        if !source.file.is_dir().unwrap_or(false) || !target.file.is_dir().unwrap_or(false) {
+            error!("Not a directory");
+            return op_error(request, NfsStat4::Nfs4errNotdir);
+        }
+
+        let (from, to) = match (
+            source.file.join(&self.oldname),
+            target.file.join(&self.newname),
+        ) {
+            (Ok(from), Ok(to)) if !self.oldname.is_empty() && !self.newname.is_empty() => {
+                (from, to)
+            }
+            _ => {
+                error!("Invalid name {:?} or {:?}", self.oldname, self.newname);
+                return op_error(request, NfsStat4::Nfs4errInval);
+            }
+        };
+        let old_path = from.as_str().to_string();
+
+        if let Err(e) = request.file_manager().rename_file(from, to).await {
+            debug!("FileManagerError {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+
+        // filehandles this client cached for the old path are gone, and so
+        // are the ones of both directories
+        request.drop_path_from_cache(&old_path);
+        request.drop_filehandle_from_cache(source.id);
+        request.drop_filehandle_from_cache(target.id);
+
+        let source_after = match request
+            .file_manager()
+            .get_filehandle_for_id(source.id)
+            .await
+        {
+            Ok(fh) => fh.attr_change,
+            Err(e) => {
+                return op_error(request, e.nfs_error);
+            }
+        };
+        let target_after = match request.set_filehandle_id(target.id).await {
+            Ok(fh) => fh.attr_change,
+            Err(status) => {
+                return op_error(request, status);
+            }
+        };
+
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::Oprename(Rename4res::Resok4(Rename4resok {
+                source_cinfo: ChangeInfo4 {
+                    atomic: false,
+                    before: source.attr_change,
+                    after: source_after,
+                },
+                target_cinfo: ChangeInfo4 {
+                    atomic: false,
+                    before: target.attr_change,
+                    after: target_after,
+                },
+            }))),
+            status: NfsStat4::Nfs4Ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            nfs40::{
+                Attrlist4, FileAttr, FileAttrValue, Getattr4args, NfsResOp4, NfsStat4, PutFh4args,
+                Rename4args, Rename4res,
+            },
+            operation::NfsOperation,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server},
+    };
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_rename_keeps_filehandle() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        let dir = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .unwrap();
+        // the client has the file's filehandle cached
+        request.cache_filehandle(file.clone());
+
+        // move /dir1/file2.txt to /moved.txt
+        request.set_filehandle(dir.clone());
+        request.save_filehandle();
+        request.set_filehandle(root.clone());
+        let args = Rename4args {
+            oldname: "file2.txt".to_string(),
+            newname: "moved.txt".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Oprename(Rename4res::Resok4(res))) => {
+                assert_eq!(res.source_cinfo.before, dir.attr_change);
+                assert_eq!(res.target_cinfo.before, root.attr_change);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let request = response.request;
+        let moved = request
+            .file_manager()
+            .get_filehandle_for_path("/moved.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(moved.id, file.id);
+
+        // the pre-rename filehandle now refers to the moved file
+        let response = PutFh4args { object: file.id }.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        assert_eq!(
+            response.request.current_filehandle().unwrap().path,
+            "/moved.txt"
+        );
+        let args = Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Size])),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => {
+                let attrs = res.obj_attributes.unwrap();
+                assert_eq!(attrs.attr_vals[0], FileAttrValue::Size(13));
+            }
+            _ => panic!("Unexpected result"),
+        }
+        let request = response.request;
+        assert!(request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_rename_dir_moves_children() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .unwrap();
+
+        request.set_filehandle(root);
+        request.save_filehandle();
+        let args = Rename4args {
+            oldname: "dir1".to_string(),
+            newname: "dir2".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let request = response.request;
+        let moved = request
+            .file_manager()
+            .get_filehandle_for_id(file.id)
+            .await
+            .unwrap();
+        assert_eq!(moved.path, "/dir2/file2.txt");
+        assert_eq!(moved.file.read_to_string().unwrap(), "Hello, file2!");
+
+        // missing sources aren't there to move
+        let args = Rename4args {
+            oldname: "dir1".to_string(),
+            newname: "dir3".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNoent);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_rename_moves_metadata() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        let file = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .unwrap();
+        let default_mode = file.attr_mode;
+        request
+            .file_manager()
+            .set_mode(file.id, 0o600)
+            .await
+            .unwrap();
+
+        request.set_filehandle(root.clone());
+        request.save_filehandle();
+        let args = Rename4args {
+            oldname: "dir1".to_string(),
+            newname: "dir2".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // refreshed attributes still carry the mode at the new path
+        let request = response.request;
+        request.file_manager().touch_file(file.id).await;
+        let moved = request
+            .file_manager()
+            .get_filehandle_for_id(file.id)
+            .await
+            .unwrap();
+        assert_eq!(moved.path, "/dir2/file2.txt");
+        assert_eq!(moved.attr_mode, 0o600);
+
+        // and none is left behind at the old one
+        root.file.join("dir1").unwrap().create_dir().unwrap();
+        root.file
+            .join("dir1/file2.txt")
+            .unwrap()
+            .create_file()
+            .unwrap();
+        let recreated = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1/file2.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(recreated.attr_mode, default_mode);
+    }
+}
@@ -7,7 +7,7 @@ use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, Renew4args, Renew4res};
 
 #[async_trait]
 impl NfsOperation for Renew4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 30: RENEW - Renew a Lease {:?}, with request {:?}",
             self, request
@@ -0,0 +1,185 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+
+use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, SecInfo4args, SecInfo4res};
+
+#[async_trait]
+impl NfsOperation for SecInfo4args {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
+        // Description: https://datatracker.ietf.org/doc/html/rfc7530#section-16.31
+        debug!(
+            "Operation 33: SECINFO - Obtain Available Security {:?}, with request {:?}",
+            self, request
+        );
+        let filehandle = match request.current_filehandle() {
+            Some(filehandle) => filehandle,
+            None => {
+                error!("None filehandle");
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
+            }
+        };
+
+        if !filehandle.file.is_dir().unwrap_or(false) {
+            error!("Not a directory");
+            return op_error(request, NfsStat4::Nfs4errNotdir);
+        }
+
+        // https://datatracker.ietf.org/doc/html/rfc7530#section-16.31.4
+        if self.name.is_empty() {
+            error!("Empty name");
+            return op_error(request, NfsStat4::Nfs4errInval);
+        }
+
+        // "." probes the current directory itself, anything else has to
+        // exist below it
+        if self.name != "." {
+            let path = if filehandle.path == "/" {
+                format!("/{}", self.name)
+            } else {
+                format!("{}/{}", filehandle.path, self.name)
+            };
+            if let Err(e) = request.file_manager().get_filehandle_for_path(path).await {
+                debug!("FileManagerError {:?}", e);
+                return op_error(request, e.nfs_error);
+            }
+        }
+
+        // there is a single export, the flavors are the same everywhere and
+        // in the order the server prefers them
+        let flavors = request.file_manager().secinfo().clone();
+        NfsOpResponse {
+            request,
+            result: Some(NfsResOp4::OpSecinfo(SecInfo4res::Resok4(flavors))),
+            status: NfsStat4::Nfs4Ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            filemanager::FileManagerConfig,
+            nfs40::{NfsResOp4, NfsStat4, SeCinfo4, SecInfo4args, SecInfo4res},
+            operation::NfsOperation,
+            security::SecurityFlavor,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server, create_nfs40_server_with_config},
+    };
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_secinfo_root() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        let args = SecInfo4args {
+            name: ".".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let result = response.result.unwrap();
+        assert_eq!(
+            result,
+            NfsResOp4::OpSecinfo(SecInfo4res::Resok4(vec![
+                SeCinfo4::AuthSys,
+                SeCinfo4::AuthNone
+            ]))
+        );
+        // opcode, NFS4_OK, two flavors: AUTH_SYS and AUTH_NONE
+        let mut bytes = Vec::new();
+        serde_xdr::to_writer(&mut bytes, &result).unwrap();
+        let words: Vec<u32> = bytes
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, vec![33, 0, 2, 1, 0]);
+
+        // the current filehandle is left untouched
+        let request = response.request;
+        assert_eq!(request.current_filehandle().unwrap().path, "/");
+
+        let args = SecInfo4args {
+            name: "dir1".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        let args = SecInfo4args {
+            name: "missing".to_string(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNoent);
+
+        // a name is required
+        let args = SecInfo4args {
+            name: "".to_string(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_secinfo_flavor_order() {
+        let mut request = create_nfs40_server_with_config(
+            Some(create_fake_fs()),
+            FileManagerConfig {
+                security_flavors: vec![
+                    SecurityFlavor::Sys,
+                    SecurityFlavor::Krb5,
+                    SecurityFlavor::Krb5p,
+                    SecurityFlavor::Sys,
+                    SecurityFlavor::Krb5i,
+                ],
+                ..Default::default()
+            },
+        )
+        .await;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request.set_filehandle(root);
+
+        let args = SecInfo4args {
+            name: "dir1".to_string(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let result = response.result.unwrap();
+        let expected = [
+            SecurityFlavor::Krb5p,
+            SecurityFlavor::Krb5i,
+            SecurityFlavor::Krb5,
+            SecurityFlavor::Sys,
+        ]
+        .map(SecurityFlavor::secinfo);
+        assert_eq!(
+            result,
+            NfsResOp4::OpSecinfo(SecInfo4res::Resok4(expected.to_vec()))
+        );
+
+        // opcode, NFS4_OK, four flavors: RPCSEC_GSS with the krb5 oid, qop 0
+        // and privacy, integrity and none as service, then AUTH_SYS
+        let mut bytes = Vec::new();
+        serde_xdr::to_writer(&mut bytes, &result).unwrap();
+        let words: Vec<u32> = bytes
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        let krb5 = |service| vec![6, 9, 0x2a864886, 0xf7120102, 0x02000000, 0, service];
+        let mut expected = vec![33, 0, 4];
+        for service in [3, 2, 1] {
+            expected.extend(krb5(service));
+        }
+        expected.push(1);
+        assert_eq!(words, expected);
+    }
+}
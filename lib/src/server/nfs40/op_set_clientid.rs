@@ -2,8 +2,10 @@ use async_trait::async_trait;
 use tracing::debug;
 
 use crate::server::{
-    clientmanager::ClientCallback, operation::NfsOperation, request::NfsRequest,
-    response::NfsOpResponse,
+    clientmanager::ClientCallback,
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
 };
 
 use bold_proto::nfs4_proto::{
@@ -18,7 +20,7 @@ impl NfsOperation for SetClientId4args {
     /// share reservation, and delegation state on the server.
     ///
     /// Please read: [RFC 7530](https://datatracker.ietf.org/doc/html/rfc7530#section-16.33)
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 35: SETCLIENTID - Negotiate Client ID {:?}, with request {:?}",
             self, request
@@ -32,7 +34,12 @@ impl NfsOperation for SetClientId4args {
 
         let res = request
             .client_manager()
-            .upsert_client(self.client.verifier, self.client.id.clone(), callback, None)
+            .upsert_client(
+                self.client.verifier,
+                self.client.id.clone(),
+                callback,
+                request.principal().cloned(),
+            )
             .await;
         match res {
             Ok(client) => NfsOpResponse {
@@ -45,11 +52,7 @@ impl NfsOperation for SetClientId4args {
                 ))),
                 status: NfsStat4::Nfs4Ok,
             },
-            Err(_e) => NfsOpResponse {
-                request,
-                result: None,
-                status: NfsStat4::Nfs4errServerfault,
-            },
+            Err(e) => op_error(request, e.nfs_error),
         }
     }
 }
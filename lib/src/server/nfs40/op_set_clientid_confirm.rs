@@ -1,7 +1,11 @@
 use async_trait::async_trait;
 use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
 use bold_proto::nfs4_proto::{
     NfsResOp4, NfsStat4, SetClientIdConfirm4args, SetClientIdConfirm4res,
@@ -9,7 +13,7 @@ use bold_proto::nfs4_proto::{
 
 #[async_trait]
 impl NfsOperation for SetClientIdConfirm4args {
-    async fn execute<'a>(&self, request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 36: SETCLIENTID_CONFIRM - Confirm Client ID {:?}, with request {:?}",
             self, request
@@ -17,24 +21,34 @@ impl NfsOperation for SetClientIdConfirm4args {
 
         let res = request
             .client_manager()
-            .confirm_client(self.clientid, self.setclientid_confirm, None)
+            .confirm_client(
+                self.clientid,
+                self.setclientid_confirm,
+                request.principal().cloned(),
+            )
             .await;
         match res {
-            Ok(_) => NfsOpResponse {
-                request,
-                result: Some(NfsResOp4::OpsetclientidConfirm(SetClientIdConfirm4res {
-                    status: NfsStat4::Nfs4Ok,
-                })),
-                status: NfsStat4::Nfs4Ok,
-            },
-            Err(e) => {
-                error!("Err {:?}", e);
+            Ok(confirmed) => {
+                // the client rebooted, the state of its earlier instance is
+                // released
+                if let Some(replaced) = confirmed.replaced {
+                    debug!("Client {} replaced by {}", replaced, self.clientid);
+                    if let Err(e) = request.file_manager().release_client(replaced).await {
+                        return op_error(request, e.nfs_error);
+                    }
+                }
                 NfsOpResponse {
                     request,
-                    result: None,
-                    status: e.nfs_error,
+                    result: Some(NfsResOp4::OpsetclientidConfirm(SetClientIdConfirm4res {
+                        status: NfsStat4::Nfs4Ok,
+                    })),
+                    status: NfsStat4::Nfs4Ok,
                 }
             }
+            Err(e) => {
+                error!("Err {:?}", e);
+                op_error(request, e.nfs_error)
+            }
         }
     }
 }
@@ -43,8 +57,12 @@ impl NfsOperation for SetClientIdConfirm4args {
 mod integration_tests {
     use crate::{
         server::{
-            nfs40::{NfsResOp4, NfsStat4, SetClientId4res, SetClientIdConfirm4args},
+            nfs40::{
+                NfsResOp4, NfsStat4, SetClientId4res, SetClientIdConfirm4args,
+                OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_DENY_WRITE,
+            },
             operation::NfsOperation,
+            response::NfsOpResponse,
         },
         test_utils::{create_client, create_nfs40_server},
     };
@@ -74,19 +92,17 @@ mod integration_tests {
         // setup clients
         let res_client1 = client1.execute(request).await;
         let (client1_id, client1_confirm) = match res_client1.result.unwrap() {
-            NfsResOp4::Opsetclientid(res) => match res {
-                SetClientId4res::Resok4(resok) => (resok.clientid, resok.setclientid_confirm),
-                _ => panic!("Unexpected response"),
-            },
+            NfsResOp4::Opsetclientid(SetClientId4res::Resok4(resok)) => {
+                (resok.clientid, resok.setclientid_confirm)
+            }
             _ => panic!("Unexpected response"),
         };
 
         let res_client2 = client2.execute(res_client1.request).await;
         let (client2_id, client2_confirm) = match res_client2.result.unwrap() {
-            NfsResOp4::Opsetclientid(res) => match res {
-                SetClientId4res::Resok4(resok) => (resok.clientid, resok.setclientid_confirm),
-                _ => panic!("Unexpected response"),
-            },
+            NfsResOp4::Opsetclientid(SetClientId4res::Resok4(resok)) => {
+                (resok.clientid, resok.setclientid_confirm)
+            }
             _ => panic!("Unexpected response"),
         };
 
@@ -112,4 +128,67 @@ mod integration_tests {
         let res_confirm_client3 = conf_client3.execute(res_confirm_client2.request).await;
         assert_eq!(res_confirm_client3.status, NfsStat4::Nfs4errStaleClientid);
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_confirm_rebooted_client() {
+        let request = create_nfs40_server(None).await;
+        let id = "Linux NFSv4.0 LAPTOP/127.0.0.1".to_string();
+
+        // set up a client and confirm it
+        let confirm = |response: NfsOpResponse| {
+            let args = match response.result.unwrap() {
+                NfsResOp4::Opsetclientid(SetClientId4res::Resok4(resok)) => {
+                    create_client_confirm(resok.setclientid_confirm, resok.clientid)
+                }
+                _ => panic!("Unexpected response"),
+            };
+            (args, response.request)
+        };
+        let response = create_client([1; 8], id.clone()).execute(request).await;
+        let (args, request) = confirm(response);
+        let old_clientid = args.clientid;
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // the client opens a file
+        let request = response.request;
+        let root = request.file_manager().get_root_filehandle().await.unwrap();
+        request
+            .file_manager()
+            .create_file(
+                root.file.join("file").unwrap(),
+                old_clientid,
+                b"owner".to_vec(),
+                OPEN4_SHARE_ACCESS_BOTH,
+                OPEN4_SHARE_DENY_WRITE,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(request.file_manager().dump_locks().await.unwrap().len(), 1);
+
+        // it reboots and comes back with a new verifier
+        let response = create_client([2; 8], id).execute(request).await;
+        let (args, request) = confirm(response);
+        assert_ne!(args.clientid, old_clientid);
+        // nothing is released before the new instance is confirmed
+        assert_eq!(request.file_manager().dump_locks().await.unwrap().len(), 1);
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // the open of the earlier instance is gone, so is its clientid
+        let request = response.request;
+        assert!(request
+            .file_manager()
+            .dump_locks()
+            .await
+            .unwrap()
+            .is_empty());
+        let renewed = request.client_manager().renew_leases(old_clientid).await;
+        assert_eq!(
+            renewed.unwrap_err().nfs_error,
+            NfsStat4::Nfs4errStaleClientid
+        );
+    }
 }
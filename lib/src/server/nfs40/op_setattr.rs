@@ -5,11 +5,27 @@ use crate::server::{
     nfs40::NfsStat4, operation::NfsOperation, request::NfsRequest, response::NfsOpResponse,
 };
 
-use bold_proto::nfs4_proto::{Attrlist4, FileAttr, NfsResOp4, SetAttr4args, SetAttr4res};
+use bold_proto::nfs4_proto::{
+    Attrlist4, FileAttr, FileAttrValue, NfsResOp4, SetAttr4args, SetAttr4res,
+    OPEN4_SHARE_ACCESS_WRITE,
+};
+
+// unlike most operations, SETATTR reports the attributes it set along with
+// an error
+fn setattr_error(request: NfsRequest, status: NfsStat4) -> NfsOpResponse {
+    NfsOpResponse {
+        request,
+        result: Some(NfsResOp4::Opsetattr(SetAttr4res {
+            status: status.clone(),
+            attrsset: Attrlist4::<FileAttr>::new(None),
+        })),
+        status,
+    }
+}
 
 #[async_trait]
 impl NfsOperation for SetAttr4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 34: SETATTR - Set Attributes {:?}, with request {:?}",
             self, request
@@ -18,36 +34,71 @@ impl NfsOperation for SetAttr4args {
         match filehandle {
             None => {
                 error!("None filehandle");
-                NfsOpResponse {
-                    request,
-                    result: Some(NfsResOp4::Opsetattr(SetAttr4res {
-                        status: NfsStat4::Nfs4errStale,
-                        attrsset: Attrlist4::<FileAttr>::new(None),
-                    })),
-                    status: NfsStat4::Nfs4errStale,
-                }
+                setattr_error(request, NfsStat4::Nfs4errNofilehandle)
             }
             Some(filehandle) => {
+                // changing the size writes to the file, the stateid has to
+                // allow that like for a WRITE
+                let sets_size = self
+                    .obj_attributes
+                    .attr_vals
+                    .iter()
+                    .any(|attr| matches!(attr, FileAttrValue::Size(_)));
+                if sets_size {
+                    if let Err(e) = request
+                        .file_manager()
+                        .check_stateid_access(
+                            self.stateid.clone(),
+                            filehandle.id,
+                            OPEN4_SHARE_ACCESS_WRITE,
+                        )
+                        .await
+                    {
+                        error!("Stateid not valid for this file {:?}", e);
+                        return setattr_error(request, e.nfs_error);
+                    }
+                }
                 let attrsset = if !self.obj_attributes.attrmask.is_empty() {
-                    let attrsset = request
+                    let _lock = request.file_manager().lock_file(filehandle.id).await;
+                    let mut attrsset = match request
                         .file_manager()
-                        .set_attr(&filehandle, &self.obj_attributes.attr_vals);
+                        .set_attr(filehandle, &self.obj_attributes.attr_vals)
+                    {
+                        Ok(attrsset) => attrsset,
+                        Err(status) => {
+                            error!("Couldn't set attributes {:?}", status);
+                            return setattr_error(request, status);
+                        }
+                    };
+                    // attributes kept with the file manager rather than the backend
+                    for attr in self.obj_attributes.attr_vals.iter() {
+                        let fmanager = request.file_manager();
+                        let (set, fileattr) = match attr {
+                            FileAttrValue::Archive(archive) => (
+                                fmanager.set_archive(filehandle.id, *archive).await,
+                                FileAttr::Archive,
+                            ),
+                            FileAttrValue::TimeBackup(time) => (
+                                fmanager.set_time_backup(filehandle.id, *time).await,
+                                FileAttr::TimeBackup,
+                            ),
+                            _ => continue,
+                        };
+                        if let Err(e) = set {
+                            debug!("FileManagerError {:?}", e);
+                            return setattr_error(request, e.nfs_error);
+                        }
+                        attrsset.push(fileattr);
+                    }
 
-                    request
-                        .file_manager()
-                        .touch_file(filehandle.id.clone())
-                        .await;
+                    request.file_manager().touch_file(filehandle.id).await;
 
-                    match request.set_filehandle_id(filehandle.id.clone()).await {
+                    match request.set_filehandle_id(filehandle.id).await {
                         Ok(fh) => {
                             request.cache_filehandle(fh);
                         }
                         Err(e) => {
-                            return NfsOpResponse {
-                                request,
-                                result: None,
-                                status: e,
-                            };
+                            return setattr_error(request, e);
                         }
                     }
 
@@ -68,3 +119,214 @@ impl NfsOperation for SetAttr4args {
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use std::io::{self, Write};
+
+    use crate::{
+        server::{
+            filemanager::MAX_FILE_SIZE,
+            nfs40::{
+                Attrlist4, Fattr4, FileAttr, FileAttrValue, Getattr4args, NfsResOp4, NfsStat4,
+                Nfstime4, SetAttr4args, Stateid4,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server, FaultFS, FsCall},
+    };
+    use tracing_test::traced_test;
+    use vfs::VfsPath;
+
+    async fn set_size(mut request: NfsRequest, path: &str, size: u64) -> NfsStat4 {
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path(path.to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let args = SetAttr4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            obj_attributes: Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::Size]),
+                attr_vals: Attrlist4(vec![FileAttrValue::Size(size)]),
+            },
+        };
+        args.execute(request).await.status
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setattr_archive() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        assert!(!fh.attr_archive);
+        request.set_filehandle(fh);
+
+        let args = SetAttr4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            obj_attributes: Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::Archive]),
+                attr_vals: Attrlist4(vec![FileAttrValue::Archive(true)]),
+            },
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opsetattr(res)) => {
+                assert_eq!(res.attrsset, Attrlist4(vec![FileAttr::Archive]))
+            }
+            _ => panic!("Unexpected result"),
+        }
+
+        let args = Getattr4args {
+            attr_request: Attrlist4(vec![FileAttr::Archive]),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let attrs = match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => res.obj_attributes.unwrap(),
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(
+            attrs.attr_vals,
+            Attrlist4(vec![FileAttrValue::Archive(true)])
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setattr_time_backup() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let backup = Nfstime4 {
+            seconds: 1_700_000_000,
+            nseconds: 500,
+        };
+
+        let args = SetAttr4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            obj_attributes: Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::TimeBackup]),
+                attr_vals: Attrlist4(vec![FileAttrValue::TimeBackup(backup)]),
+            },
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opsetattr(res)) => {
+                assert_eq!(res.attrsset, Attrlist4(vec![FileAttr::TimeBackup]))
+            }
+            _ => panic!("Unexpected result"),
+        }
+
+        let args = Getattr4args {
+            attr_request: Attrlist4(vec![FileAttr::TimeBackup, FileAttr::SupportedAttrs]),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let attrs = match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => res.obj_attributes.unwrap(),
+            _ => panic!("Unexpected result"),
+        };
+        assert_eq!(attrs.attr_vals[0], FileAttrValue::TimeBackup(backup));
+        match &attrs.attr_vals[1] {
+            FileAttrValue::SupportedAttrs(supported) => {
+                assert!(supported.contains(&FileAttr::TimeBackup))
+            }
+            _ => panic!("Unexpected attribute"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setattr_size_of_dir() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = SetAttr4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            obj_attributes: Fattr4 {
+                attrmask: Attrlist4(vec![FileAttr::Size, FileAttr::Archive]),
+                attr_vals: Attrlist4(vec![FileAttrValue::Size(0), FileAttrValue::Archive(true)]),
+            },
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errIsdir);
+        match response.result {
+            Some(NfsResOp4::Opsetattr(res)) => {
+                assert_eq!(res.status, NfsStat4::Nfs4errIsdir);
+                assert!(res.attrsset.is_empty());
+            }
+            _ => panic!("Unexpected result"),
+        }
+        // none of the attributes was set, the directory is still there
+        let dir = response
+            .request
+            .file_manager()
+            .get_filehandle_for_path("/dir1".to_string())
+            .await
+            .unwrap();
+        assert!(!dir.attr_archive);
+        assert!(dir.file.is_dir().unwrap());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setattr_size_too_big() {
+        let fs = create_fake_fs();
+        let request = create_nfs40_server(Some(fs.clone())).await;
+
+        let status = set_size(request, "/file1.txt", MAX_FILE_SIZE + 1).await;
+        assert_eq!(status, NfsStat4::Nfs4errFbig);
+        // the file is left as it was
+        let file = fs.join("file1.txt").unwrap();
+        assert_eq!(file.metadata().unwrap().len, 25);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_setattr_size_backend_error() {
+        let root: VfsPath = FaultFS::default()
+            .fail(FsCall::Open, "broken", io::ErrorKind::Other)
+            .into();
+        root.join("broken")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+        let request = create_nfs40_server(Some(root.clone())).await;
+
+        let status = set_size(request, "/broken", 2).await;
+        assert_eq!(status, NfsStat4::Nfs4errIo);
+        assert_eq!(root.join("broken").unwrap().metadata().unwrap().len, 4);
+    }
+}
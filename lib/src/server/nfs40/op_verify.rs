@@ -0,0 +1,179 @@
+use async_trait::async_trait;
+use tracing::{debug, error};
+
+use crate::server::{
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
+
+use bold_proto::nfs4_proto::{
+    Fattr4, FileAttr, NfsResOp4, NfsStat4, Nverify4args, Nverify4res, Verify4args, Verify4res,
+};
+
+// https://datatracker.ietf.org/doc/html/rfc7530#section-16.35.4
+// Whether the attributes of the current object are the ones the client
+// sent, value by value. rdattr_error can't be compared and attributes the
+// server doesn't support fail the comparison as a whole.
+fn attrs_match(request: &NfsRequest, attrs: &Fattr4) -> Result<bool, NfsStat4> {
+    let filehandle = match request.current_filehandle() {
+        Some(filehandle) => filehandle,
+        None => {
+            error!("None filehandle");
+            return Err(NfsStat4::Nfs4errNofilehandle);
+        }
+    };
+    if attrs.attrmask.contains(&FileAttr::RdattrError) {
+        error!("rdattr_error can't be verified");
+        return Err(NfsStat4::Nfs4errInval);
+    }
+    let (answer_attrs, values) = request
+        .file_manager()
+        .filehandle_attrs(&attrs.attrmask, filehandle)
+        .ok_or(NfsStat4::Nfs4errServerfault)?;
+    if answer_attrs.len() != attrs.attrmask.len() {
+        debug!("Unsupported attributes in {:?}", attrs.attrmask);
+        return Err(NfsStat4::Nfs4errAttrnotsupp);
+    }
+    Ok(values.0 == attrs.attr_vals.0)
+}
+
+#[async_trait]
+impl NfsOperation for Verify4args {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
+        debug!(
+            "Operation 37: VERIFY - Verify Same Attributes {:?}, with request {:?}",
+            self, request
+        );
+        match attrs_match(&request, &self.obj_attributes) {
+            Ok(true) => NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Opverify(Verify4res {
+                    status: NfsStat4::Nfs4Ok,
+                })),
+                status: NfsStat4::Nfs4Ok,
+            },
+            Ok(false) => op_error(request, NfsStat4::Nfs4errNotSame),
+            Err(status) => op_error(request, status),
+        }
+    }
+}
+
+#[async_trait]
+impl NfsOperation for Nverify4args {
+    async fn execute(&self, request: NfsRequest) -> NfsOpResponse {
+        debug!(
+            "Operation 17: NVERIFY - Verify Difference in Attributes {:?}, with request {:?}",
+            self, request
+        );
+        match attrs_match(&request, &self.obj_attributes) {
+            Ok(false) => NfsOpResponse {
+                request,
+                result: Some(NfsResOp4::Opnverify(Nverify4res {
+                    status: NfsStat4::Nfs4Ok,
+                })),
+                status: NfsStat4::Nfs4Ok,
+            },
+            Ok(true) => op_error(request, NfsStat4::Nfs4errSame),
+            Err(status) => op_error(request, status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            nfs40::{
+                Attrlist4, Fattr4, FileAttr, FileAttrValue, Getattr4args, NfsResOp4, NfsStat4,
+                Nverify4args, StableHow4, Stateid4, Verify4args, Write4args,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::{create_fake_fs, create_nfs40_server},
+    };
+    use tracing_test::traced_test;
+
+    async fn get_change(request: NfsRequest) -> (NfsRequest, u64) {
+        let args = Getattr4args {
+            attr_request: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Change])),
+        };
+        let response = args.execute(request).await;
+        match response.result {
+            Some(NfsResOp4::Opgetattr(res)) => match res.obj_attributes.unwrap().attr_vals[0] {
+                FileAttrValue::Change(change) => (response.request, change),
+                _ => panic!("Unexpected attribute"),
+            },
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    fn change_attrs(change: u64) -> Fattr4 {
+        Fattr4 {
+            attrmask: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Change])),
+            attr_vals: Attrlist4::<FileAttrValue>::new(Some(vec![FileAttrValue::Change(change)])),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_verify_change() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+        let (request, change) = get_change(request).await;
+
+        // the object didn't change since GETATTR
+        let args = Verify4args {
+            obj_attributes: change_attrs(change),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        let args = Nverify4args {
+            obj_attributes: change_attrs(change),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errSame);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"changed".to_vec(),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // now it did
+        let args = Verify4args {
+            obj_attributes: change_attrs(change),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errNotSame);
+        let args = Nverify4args {
+            obj_attributes: change_attrs(change),
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+        // rdattr_error isn't an attribute of the object
+        let args = Verify4args {
+            obj_attributes: Fattr4 {
+                attrmask: Attrlist4::<FileAttr>::new(Some(vec![FileAttr::RdattrError])),
+                attr_vals: Attrlist4::<FileAttrValue>::new(Some(vec![FileAttrValue::RdattrError(
+                    NfsStat4::Nfs4Ok,
+                )])),
+            },
+        };
+        let response = args.execute(response.request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+    }
+}
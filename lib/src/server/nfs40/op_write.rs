@@ -3,19 +3,21 @@ use std::io::{Seek, SeekFrom, Write};
 use async_trait::async_trait;
 use tracing::{debug, error};
 
-use crate::server::{operation::NfsOperation, request::NfsRequest, response::NfsOpResponse};
+use crate::server::{
+    filemanager::vfs_error_status,
+    nfs40::range_end,
+    operation::NfsOperation,
+    request::NfsRequest,
+    response::{op_error, NfsOpResponse},
+};
 
-use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4, StableHow4, Write4args, Write4res, Write4resok};
-
-fn verifier_from_boot(boot_time: &u64) -> [u8; 8] {
-    let mut verifier = [0; 8];
-    verifier.copy_from_slice(boot_time.to_be_bytes().as_ref());
-    verifier
-}
+use bold_proto::nfs4_proto::{
+    NfsResOp4, NfsStat4, StableHow4, Write4args, Write4res, Write4resok, OPEN4_SHARE_ACCESS_WRITE,
+};
 
 #[async_trait]
 impl NfsOperation for Write4args {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a> {
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse {
         debug!(
             "Operation 38: WRITE - Write to File {:?}, with request {:?}",
             self, request
@@ -26,56 +28,502 @@ impl NfsOperation for Write4args {
             Some(filehandle) => filehandle,
             None => {
                 error!("None filehandle");
-                return NfsOpResponse {
-                    request,
-                    result: None,
-                    status: NfsStat4::Nfs4errFhexpired,
-                };
+                return op_error(request, NfsStat4::Nfs4errNofilehandle);
             }
         };
 
+        if let Err(status) = range_end(self.offset, self.data.len() as u64) {
+            return op_error(request, status);
+        }
+
+        // writes of the same open apply in the order they arrived, pipelined
+        // ones could overtake each other at any of the awaits below
+        let _turn = request
+            .file_manager()
+            .order_write(&self.stateid, filehandle.id)
+            .await;
+
+        if let Err(e) = request
+            .file_manager()
+            .check_stateid_access(
+                self.stateid.clone(),
+                filehandle.id,
+                OPEN4_SHARE_ACCESS_WRITE,
+            )
+            .await
+        {
+            error!("Stateid not valid for this file {:?}", e);
+            return op_error(request, e.nfs_error);
+        }
+
+        let _lock = request.file_manager().lock_file(filehandle.id).await;
         let mut stable = StableHow4::Unstable4;
         let mut count: u32 = self.data.len() as u32;
-        if self.stable == StableHow4::Unstable4 {
+        let write_through = request.file_manager().write_through();
+        if self.stable == StableHow4::Unstable4 && !write_through {
             // write to cache
             let write_cache = match &filehandle.write_cache {
                 Some(write_cache) => write_cache,
                 None => {
-                    let write_cache = request
+                    let write_cache = match request
                         .file_manager()
                         .get_write_cache_handle(filehandle.clone())
                         .await
-                        .unwrap();
-                    request.drop_filehandle_from_cache(filehandle.id.clone());
+                    {
+                        Ok(write_cache) => write_cache,
+                        Err(e) => {
+                            error!("Couldn't get write cache {:?}", e);
+                            return op_error(request, e.nfs_error);
+                        }
+                    };
+                    request.drop_filehandle_from_cache(filehandle.id);
                     &write_cache.clone()
                 }
             };
 
-            write_cache
+            if let Err(e) = write_cache
                 .write_bytes(self.offset, self.data.clone())
-                .await;
+                .await
+            {
+                return op_error(request, e.nfs_error);
+            }
         } else {
-            // write to file
-            let mut file = filehandle.file.append_file().unwrap();
-            let _ = file.seek(SeekFrom::Start(self.offset as u64));
-            count = file.write(&self.data).unwrap() as u32;
-            stable = StableHow4::FileSync4;
+            // writes cached before this one must not overwrite it when they
+            // are flushed later, so they go to the backend first
+            let id = filehandle.id;
+            let filehandle = match request.file_manager().flush_locked_write_cache(id).await {
+                Ok(false) => filehandle.clone(),
+                Ok(true) => {
+                    request.drop_filehandle_from_cache(id);
+                    match request.file_manager().get_filehandle_for_id(id).await {
+                        Ok(filehandle) => filehandle,
+                        Err(e) => {
+                            return op_error(request, e.nfs_error);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't flush cached writes {:?}", e);
+                    return op_error(request, e.nfs_error);
+                }
+            };
+            // write to file, a failing backend is reported to the client
+            let written = filehandle.file.append_file().and_then(|mut file| {
+                file.seek(SeekFrom::Start(self.offset))?;
+                let count = file.write(&self.data)?;
+                file.flush()?;
+                Ok(count)
+            });
+            count = match written {
+                Ok(count) => count as u32,
+                Err(e) => {
+                    error!("Couldn't write {:?}: {:?}", filehandle.path, e);
+                    return op_error(request, vfs_error_status(&e));
+                }
+            };
+            // in write-through mode every write is committed to the backend
+            stable = if write_through {
+                StableHow4::FileSync4
+            } else {
+                self.stable.clone()
+            };
 
             if count > 0 {
-                file.flush().unwrap();
-                request.file_manager().touch_file(filehandle.id).await;
+                if stable == StableHow4::FileSync4 {
+                    // data and all metadata
+                    request.file_manager().touch_file(filehandle.id).await;
+                } else {
+                    // DataSync4 only needs the data and what's required to
+                    // read it back, i.e. size and mtime
+                    let mut filehandle = filehandle.clone();
+                    filehandle.update_data_attrs(request.file_manager().now());
+                    request.file_manager().update_filehandle(filehandle).await;
+                }
+                // operations later in the compound see the new attributes
+                request.drop_filehandle_from_cache(filehandle.id);
+                let _ = request.set_filehandle_id(filehandle.id).await;
             }
         }
 
-        let boot_time = request.boot_time;
+        let writeverf = request.boot_verifier;
         NfsOpResponse {
             request,
             result: Some(NfsResOp4::Opwrite(Write4res::Resok4(Write4resok {
-                count: count,
+                count,
                 committed: stable,
-                writeverf: verifier_from_boot(&boot_time),
+                writeverf,
             }))),
             status: NfsStat4::Nfs4Ok,
         }
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{
+        server::{
+            clientmanager::ClientManagerHandle,
+            filemanager::{FileManagerConfig, FileManagerHandle},
+            nfs40::{
+                Attrlist4, Fattr4, FileAttr, FileAttrValue, NfsResOp4, NfsStat4, SetAttr4args,
+                StableHow4, Stateid4, Write4args, Write4res,
+            },
+            operation::NfsOperation,
+            request::NfsRequest,
+        },
+        test_utils::{
+            create_fake_fs, create_nfs40_server, create_nfs40_server_with_config, FaultFS, FsCall,
+        },
+    };
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+    use tracing_test::traced_test;
+    use vfs::VfsPath;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_committed_level() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+
+        for stable in [
+            StableHow4::Unstable4,
+            StableHow4::DataSync4,
+            StableHow4::FileSync4,
+        ] {
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path("file1.txt".to_string())
+                .await
+                .unwrap();
+            let _ = request.set_filehandle_id(fh.id).await;
+
+            let args = Write4args {
+                stateid: Stateid4 {
+                    seqid: 0,
+                    other: [0; 12],
+                },
+                offset: 0,
+                stable: stable.clone(),
+                data: b"Hello".to_vec(),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+            match response.result {
+                Some(NfsResOp4::Opwrite(Write4res::Resok4(res))) => {
+                    assert_eq!(res.count, 5);
+                    assert_eq!(res.committed, stable);
+                }
+                _ => panic!("Unexpected result"),
+            }
+            request = response.request;
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_range_overflow() {
+        let mut request = create_nfs40_server(Some(create_fake_fs())).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: u64::MAX - 1,
+            stable: StableHow4::FileSync4,
+            data: b"Hello".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errInval);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_boot_verifier() {
+        let verifier = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut request = NfsRequest::new(
+            "127.0.0.1:12345".to_owned(),
+            ClientManagerHandle::with_config(verifier, None, Default::default()),
+            FileManagerHandle::new(create_fake_fs(), None),
+            verifier,
+        );
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"Hello".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opwrite(Write4res::Resok4(res))) => {
+                assert_eq!(res.writeverf, verifier);
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_through() {
+        let root = create_fake_fs();
+        let mut request = create_nfs40_server_with_config(
+            Some(root.clone()),
+            FileManagerConfig {
+                write_through: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::Unstable4,
+            data: b"Howdy".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4Ok);
+        match response.result {
+            Some(NfsResOp4::Opwrite(Write4res::Resok4(res))) => {
+                assert_eq!(res.count, 5);
+                assert_eq!(res.committed, StableHow4::FileSync4);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        // no COMMIT needed, the data is already on the file system
+        let content = root.join("file1.txt").unwrap().read_to_string().unwrap();
+        assert_eq!(content, "Howdy, loooooooong world!");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_cache_flush() {
+        let by_interval = FileManagerConfig {
+            write_cache_flush_interval: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let by_size = FileManagerConfig {
+            write_cache_flush_size: Some(4),
+            ..Default::default()
+        };
+        for (config, flushed_at_once) in [(by_interval, false), (by_size, true)] {
+            let root = create_fake_fs();
+            let mut request = create_nfs40_server_with_config(Some(root.clone()), config).await;
+            let fh = request
+                .file_manager()
+                .get_filehandle_for_path("file1.txt".to_string())
+                .await
+                .unwrap();
+            request.set_filehandle(fh);
+
+            let args = Write4args {
+                stateid: Stateid4 {
+                    seqid: 0,
+                    other: [0; 12],
+                },
+                offset: 0,
+                stable: StableHow4::Unstable4,
+                data: b"Howdy".to_vec(),
+            };
+            let response = args.execute(request).await;
+            assert_eq!(response.status, NfsStat4::Nfs4Ok);
+
+            // the data reaches the file system without a COMMIT
+            let file = root.join("file1.txt").unwrap();
+            let start = Instant::now();
+            while file.read_to_string().unwrap() != "Howdy, loooooooong world!" {
+                assert!(start.elapsed() < Duration::from_secs(5), "never flushed");
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let elapsed = start.elapsed();
+            if flushed_at_once {
+                assert!(elapsed < Duration::from_millis(300));
+            } else {
+                assert!(elapsed >= Duration::from_millis(250));
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_quota_exceeded() {
+        // the quota is used up, files can't grow
+        let root: VfsPath = FaultFS::default()
+            .fail(FsCall::Append, "", io::ErrorKind::QuotaExceeded)
+            .into();
+        root.join("file1.txt").unwrap().create_file().unwrap();
+        let mut request = create_nfs40_server(Some(root)).await;
+        let fh = request
+            .file_manager()
+            .get_filehandle_for_path("/file1.txt".to_string())
+            .await
+            .unwrap();
+        request.set_filehandle(fh);
+
+        let args = Write4args {
+            stateid: Stateid4 {
+                seqid: 0,
+                other: [0; 12],
+            },
+            offset: 0,
+            stable: StableHow4::FileSync4,
+            data: b"Howdy".to_vec(),
+        };
+        let response = args.execute(request).await;
+        assert_eq!(response.status, NfsStat4::Nfs4errDquot);
+        assert!(response.result.is_none());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_write_order_per_stateid() {
+        let stateid = Stateid4 {
+            seqid: 0,
+            other: [0; 12],
+        };
+        for (first, second) in [
+            (StableHow4::Unstable4, StableHow4::FileSync4),
+            (StableHow4::FileSync4, StableHow4::Unstable4),
+            (StableHow4::Unstable4, StableHow4::Unstable4),
+            (StableHow4::FileSync4, StableHow4::DataSync4),
+        ] {
+            let root = create_fake_fs();
+            let fmanager = FileManagerHandle::new(root.clone(), None);
+            let fh = fmanager
+                .get_filehandle_for_path("file1.txt".to_string())
+                .await
+                .unwrap();
+            let new_request = || {
+                let mut request = NfsRequest::new(
+                    "127.0.0.1:12345".to_owned(),
+                    ClientManagerHandle::new(),
+                    fmanager.clone(),
+                    [0; 8],
+                );
+                request.set_filehandle(fh.clone());
+                request
+            };
+
+            // the second write overlaps the first one, both are in flight
+            // at the same time
+            let first = Write4args {
+                stateid: stateid.clone(),
+                offset: 0,
+                stable: first,
+                data: b"AAAAAA".to_vec(),
+            };
+            let second = Write4args {
+                stateid: stateid.clone(),
+                offset: 2,
+                stable: second,
+                data: b"BB".to_vec(),
+            };
+            let (first, second) =
+                tokio::join!(first.execute(new_request()), second.execute(new_request()));
+            assert_eq!(first.status, NfsStat4::Nfs4Ok);
+            assert_eq!(second.status, NfsStat4::Nfs4Ok);
+
+            // the later write wins, also once the cached data is committed
+            fmanager.flush_write_cache(fh.id).await.unwrap();
+            let content = root.join("file1.txt").unwrap().read_to_string().unwrap();
+            assert!(content.starts_with("AABBAA"), "{:?}", content);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[traced_test]
+    async fn test_write_concurrent_truncate() {
+        let root = create_fake_fs();
+        let fmanager = FileManagerHandle::new(root.clone(), None);
+        let stateid = Stateid4 {
+            seqid: 0,
+            other: [0; 12],
+        };
+        for _ in 0..10 {
+            let fh = fmanager
+                .get_filehandle_for_path("file1.txt".to_string())
+                .await
+                .unwrap();
+            let mut writer = NfsRequest::new(
+                "127.0.0.1:12345".to_owned(),
+                ClientManagerHandle::new(),
+                fmanager.clone(),
+                [0; 8],
+            );
+            writer.set_filehandle(fh.clone());
+            let mut truncater = NfsRequest::new(
+                "127.0.0.1:12346".to_owned(),
+                ClientManagerHandle::new(),
+                fmanager.clone(),
+                [0; 8],
+            );
+            truncater.set_filehandle(fh);
+
+            let write = Write4args {
+                stateid: stateid.clone(),
+                offset: 0,
+                stable: StableHow4::FileSync4,
+                data: b"Howdy".to_vec(),
+            };
+            let setattr = SetAttr4args {
+                stateid: stateid.clone(),
+                obj_attributes: Fattr4 {
+                    attrmask: Attrlist4(vec![FileAttr::Size]),
+                    attr_vals: Attrlist4(vec![FileAttrValue::Size(10)]),
+                },
+            };
+            let write = tokio::spawn(async move { write.execute(writer).await.status });
+            let setattr = tokio::spawn(async move { setattr.execute(truncater).await.status });
+            assert_eq!(write.await.unwrap(), NfsStat4::Nfs4Ok);
+            assert_eq!(setattr.await.unwrap(), NfsStat4::Nfs4Ok);
+
+            // either order ends with the new data cut to the new size, and
+            // the attributes match the content
+            let content = root.join("file1.txt").unwrap().read_to_string().unwrap();
+            assert_eq!(content, "Howdy, loo");
+            let fh = fmanager
+                .get_filehandle_for_path("file1.txt".to_string())
+                .await
+                .unwrap();
+            assert_eq!(fh.attr_size, 10);
+
+            // restore the original content for the next round
+            root.join("file1.txt")
+                .unwrap()
+                .create_file()
+                .unwrap()
+                .write_all(b"Hello, loooooooong world!")
+                .unwrap();
+            fmanager.touch_file(fh.id).await;
+        }
+    }
+}
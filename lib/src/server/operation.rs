@@ -4,5 +4,5 @@ use super::{request::NfsRequest, response::NfsOpResponse};
 
 #[async_trait]
 pub trait NfsOperation: Sync {
-    async fn execute<'a>(&self, mut request: NfsRequest<'a>) -> NfsOpResponse<'a>;
+    async fn execute(&self, mut request: NfsRequest) -> NfsOpResponse;
 }
@@ -1,71 +1,219 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use bold_proto::nfs4_proto::{NfsFh4, NfsStat4};
-use tracing::error;
+use bold_proto::{
+    nfs4_proto::{NfsFh4, NfsStat4},
+    rpc_proto::OpaqueAuth,
+};
+use tracing::{debug, error};
 
 use super::{
+    authorizer::Authorizer,
     clientmanager::ClientManagerHandle,
     filemanager::{FileManagerHandle, Filehandle},
 };
 
+/// Verifier identifying a server instance, handed out as write verifier
+/// and embedded in clientids. It changes whenever the server restarts.
+pub fn boot_verifier(boot_time: u64) -> [u8; 8] {
+    boot_time.to_be_bytes()
+}
+
+/// Canonical form of a client address, used as key for client state.
+///
+/// IPv4-mapped IPv6 addresses are reduced to IPv4, so dual-stack clients
+/// are keyed the same way on both stacks. IPv6 scope ids and flow labels
+/// are dropped, a client is identified by its address and port alone.
+pub fn client_addr_key(addr: &SocketAddr) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        },
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port()).to_string()
+}
+
+/// Principal a client authenticated as, derived from the RPC credential.
+///
+/// AUTH_SYS credentials map to `uid@machinename`. AUTH_NULL and flavors the
+/// server doesn't understand carry no principal.
+pub fn principal(cred: &OpaqueAuth) -> Option<String> {
+    match cred {
+        OpaqueAuth::AuthUnix(auth) => Some(format!("{}@{}", auth.uid, auth.machinename)),
+        _ => None,
+    }
+}
+
+/// The uid of an AUTH_SYS credential, other flavors don't carry one
+pub fn auth_sys_uid(cred: &OpaqueAuth) -> Option<u32> {
+    match cred {
+        OpaqueAuth::AuthUnix(auth) => Some(auth.uid),
+        _ => None,
+    }
+}
+
+/// Builds an [`NfsRequest`] outside of the connection pipeline, e.g. to run
+/// operations from tests or tools against the manager handles.
+#[derive(Debug)]
+pub struct NfsRequestBuilder {
+    client_addr: String,
+    principal: Option<String>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    cmanager: ClientManagerHandle,
+    fmanager: FileManagerHandle,
+    boot_verifier: [u8; 8],
+}
+
+impl NfsRequestBuilder {
+    pub fn new(cmanager: ClientManagerHandle, fmanager: FileManagerHandle) -> Self {
+        NfsRequestBuilder {
+            client_addr: "127.0.0.1:0".to_string(),
+            principal: None,
+            authorizer: None,
+            cmanager,
+            fmanager,
+            boot_verifier: [0; 8],
+        }
+    }
+
+    /// Address the request appears to come from, see client_addr_key
+    pub fn client_addr(&mut self, client_addr: SocketAddr) -> &mut Self {
+        self.client_addr = client_addr_key(&client_addr);
+        self
+    }
+
+    pub fn principal(&mut self, principal: impl Into<String>) -> &mut Self {
+        self.principal = Some(principal.into());
+        self
+    }
+
+    pub fn authorizer(&mut self, authorizer: Arc<dyn Authorizer>) -> &mut Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    pub fn boot_verifier(&mut self, boot_verifier: [u8; 8]) -> &mut Self {
+        self.boot_verifier = boot_verifier;
+        self
+    }
+
+    pub fn build(&mut self) -> NfsRequest {
+        let mut request = NfsRequest::new(
+            self.client_addr.clone(),
+            self.cmanager.clone(),
+            self.fmanager.clone(),
+            self.boot_verifier,
+        );
+        request.set_principal(self.principal.clone());
+        request.set_authorizer(self.authorizer.clone());
+        request
+    }
+}
+
 #[derive(Debug)]
-pub struct NfsRequest<'a> {
+pub struct NfsRequest {
     client_addr: String,
+    // principal of the RPC credential, see principal()
+    principal: Option<String>,
+    // uid of an AUTH_SYS credential, see auth_sys_uid()
+    uid: Option<u32>,
+    // custom policy consulted before each operation
+    authorizer: Option<Arc<dyn Authorizer>>,
     filehandle: Option<Filehandle>,
+    // filehandle put aside by SAVEFH
+    saved_filehandle: Option<Filehandle>,
     // shared state for client manager between connections
     cmanager: ClientManagerHandle,
     // local filehandle manager
     fmanager: FileManagerHandle,
-    // time the server was booted
-    pub boot_time: u64,
+    // identifies this server instance, see boot_verifier
+    pub boot_verifier: [u8; 8],
     // time the request was received
     pub request_time: u64,
-    // locally cached filehandles for this client
-    pub filehandle_cache: Option<&'a mut HashMap<NfsFh4, (SystemTime, Filehandle)>>,
-    cache_ttl: u64,
 }
 
-impl<'a> NfsRequest<'a> {
+impl NfsRequest {
     pub fn new(
         client_addr: String,
         cmanager: ClientManagerHandle,
         fmanager: FileManagerHandle,
-        boot_time: u64,
-        // cache ttl + filehandle
-        filehandle_cache: Option<&'a mut HashMap<NfsFh4, (SystemTime, Filehandle)>>,
+        boot_verifier: [u8; 8],
     ) -> Self {
         let request_time = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
 
         NfsRequest {
             client_addr,
+            principal: None,
+            uid: None,
+            authorizer: None,
             filehandle: None,
+            saved_filehandle: None,
             cmanager,
             fmanager,
-            boot_time,
+            boot_verifier,
             request_time,
-            filehandle_cache,
-            // set filehandle cache ttl to 10 seconds
-            cache_ttl: 10,
         }
     }
 
+    // This method will help users to discover the builder
+    pub fn builder(
+        cmanager: ClientManagerHandle,
+        fmanager: FileManagerHandle,
+    ) -> NfsRequestBuilder {
+        NfsRequestBuilder::new(cmanager, fmanager)
+    }
+
     pub fn client_addr(&self) -> &String {
         &self.client_addr
     }
 
+    pub fn principal(&self) -> Option<&String> {
+        self.principal.as_ref()
+    }
+
+    pub fn set_principal(&mut self, principal: Option<String>) {
+        self.principal = principal;
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn set_uid(&mut self, uid: Option<u32>) {
+        self.uid = uid;
+    }
+
+    pub fn authorizer(&self) -> Option<&Arc<dyn Authorizer>> {
+        self.authorizer.as_ref()
+    }
+
+    pub fn set_authorizer(&mut self, authorizer: Option<Arc<dyn Authorizer>>) {
+        self.authorizer = authorizer;
+    }
+
     pub fn current_filehandle_id(&self) -> Option<NfsFh4> {
-        match self.filehandle {
-            Some(ref fh) => Some(fh.id.clone()),
-            None => None,
-        }
+        self.filehandle.as_ref().map(|fh| fh.id)
     }
 
     pub fn current_filehandle(&self) -> Option<&Filehandle> {
         // TODO handle None
-        match self.filehandle {
-            Some(ref fh) => Some(fh),
-            None => None,
-        }
+        self.filehandle.as_ref()
+    }
+
+    pub fn saved_filehandle(&self) -> Option<&Filehandle> {
+        self.saved_filehandle.as_ref()
+    }
+
+    pub fn save_filehandle(&mut self) {
+        self.saved_filehandle = self.filehandle.clone();
+    }
+
+    pub fn restore_filehandle(&mut self) {
+        self.filehandle = self.saved_filehandle.clone();
     }
 
     pub fn client_manager(&self) -> ClientManagerHandle {
@@ -80,51 +228,32 @@ impl<'a> NfsRequest<'a> {
         self.filehandle = Some(filehandle);
     }
 
+    // filehandles are cached in the file manager, so every connection
+    // benefits from the lookups of the others
     pub fn cache_filehandle(&mut self, filehandle: Filehandle) {
-        let cache = self.filehandle_cache.as_mut();
-        match cache {
-            None => return,
-            Some(cache) => {
-                let now: SystemTime = SystemTime::now();
-                cache.insert(filehandle.id.clone(), (now, filehandle));
-            }
+        if let Some(cache) = self.fmanager.filehandle_cache() {
+            cache.insert(filehandle);
         }
     }
 
     pub fn drop_filehandle_from_cache(&mut self, filehandle_id: NfsFh4) {
-        let cache = self.filehandle_cache.as_mut();
-        match cache {
-            None => return,
-            Some(cache) => {
-                cache.remove(&filehandle_id);
-            }
+        if let Some(cache) = self.fmanager.filehandle_cache() {
+            cache.remove(&filehandle_id);
         }
     }
 
-    pub fn get_filehandle_from_cache(&mut self, filehandle_id: NfsFh4) -> Option<Filehandle> {
-        // if no cache set, return None
-        let cache = self.filehandle_cache.as_ref();
-        match cache {
-            None => None,
-            Some(cache) => {
-                match cache.get(&filehandle_id) {
-                    Some(fh) => {
-                        let now: SystemTime = SystemTime::now();
-                        let (time, filehandle) = fh;
-                        // if cache is expired since 10 seconds, remove it
-                        if now.duration_since(*time).unwrap().as_secs() > self.cache_ttl {
-                            self.drop_filehandle_from_cache(filehandle.id.clone());
-                            None
-                        } else {
-                            Some(filehandle.clone())
-                        }
-                    }
-                    None => None,
-                }
-            }
+    /// Drop the cached filehandles of `path` and everything below it, e.g.
+    /// once it was renamed
+    pub fn drop_path_from_cache(&mut self, path: &str) {
+        if let Some(cache) = self.fmanager.filehandle_cache() {
+            cache.remove_path(path);
         }
     }
 
+    pub fn get_filehandle_from_cache(&mut self, filehandle_id: NfsFh4) -> Option<Filehandle> {
+        self.fmanager.filehandle_cache()?.get(&filehandle_id)
+    }
+
     pub async fn set_filehandle_id(
         &mut self,
         filehandle_id: NfsFh4,
@@ -137,7 +266,7 @@ impl<'a> NfsRequest<'a> {
             }
             Err(e) => {
                 error!("couldn't set filehandle: {:?}", e);
-                Err(NfsStat4::Nfs4errStale)
+                Err(e.nfs_error)
             }
         }
     }
@@ -146,12 +275,55 @@ impl<'a> NfsRequest<'a> {
         self.filehandle = None;
     }
 
-    // this is called when the request is done
-    pub async fn close(&self) {
-        // if let Some(fh) = self.filehandle.as_ref() {
-        //     self.cmanager
-        //         .set_current_filehandle(self.client_addr.clone(), fh.id.clone())
-        //         .await;
-        // }
+    /// Ends the request once its reply has been built.
+    ///
+    /// This releases what the request holds for its own duration, the
+    /// current filehandle. It never changes shared state, the cached
+    /// filehandles and the state held by the client and file managers stay
+    /// as they are, no matter whether the compound succeeded.
+    pub fn close(mut self) {
+        if let Some(fh) = self.filehandle.take() {
+            debug!("Releasing current filehandle {:?}", fh.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bold_proto::rpc_proto::{AuthUnix, OpaqueAuth};
+
+    use super::{auth_sys_uid, client_addr_key, principal};
+
+    fn key(addr: &str) -> String {
+        client_addr_key(&addr.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_client_addr_key() {
+        assert_eq!(key("127.0.0.1:700"), "127.0.0.1:700");
+        // dual-stack sockets see IPv4 clients as mapped addresses
+        assert_eq!(key("[::ffff:127.0.0.1]:700"), "127.0.0.1:700");
+        // link-local addresses with and without scope are the same client
+        assert_eq!(key("[fe80::1%2]:700"), "[fe80::1]:700");
+        assert_eq!(key("[fe80::1]:700"), "[fe80::1]:700");
+        // non-canonical notation
+        assert_eq!(key("[2001:db8:0:0::1]:700"), "[2001:db8::1]:700");
+    }
+
+    #[test]
+    fn test_principal() {
+        assert_eq!(principal(&OpaqueAuth::AuthNull(Vec::new())), None);
+        let cred = OpaqueAuth::AuthUnix(AuthUnix {
+            stamp: 0,
+            machinename: "laptop".to_string(),
+            uid: 1000,
+            gid: 1000,
+            gids: vec![],
+        });
+        assert_eq!(principal(&cred), Some("1000@laptop".to_string()));
+        assert_eq!(auth_sys_uid(&cred), Some(1000));
+        assert_eq!(auth_sys_uid(&OpaqueAuth::AuthNull(Vec::new())), None);
     }
 }
@@ -3,10 +3,20 @@ use bold_proto::nfs4_proto::{NfsResOp4, NfsStat4};
 use super::request::NfsRequest;
 
 #[derive(Debug)]
-pub struct NfsOpResponse<'a> {
-    pub request: NfsRequest<'a>,
+pub struct NfsOpResponse {
+    pub request: NfsRequest,
     // result of this operation
     pub result: Option<NfsResOp4>,
     // status of this operation, err or ok
     pub status: NfsStat4,
 }
+
+/// The response of an operation that failed with `status` and has no result
+/// to report besides it
+pub fn op_error(request: NfsRequest, status: NfsStat4) -> NfsOpResponse {
+    NfsOpResponse {
+        request,
+        result: None,
+        status,
+    }
+}
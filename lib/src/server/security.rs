@@ -0,0 +1,53 @@
+use bold_proto::nfs4_proto::{RpcGssSvc, RpcSecGssInfo, SeCinfo4, SecInfo4resok};
+
+// the Kerberos V5 GSS-API mechanism, 1.2.840.113554.1.2.2 in DER
+const KRB5_OID: [u8; 9] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02];
+
+/// A security flavor the export is announced with in SECINFO.
+///
+/// The variants are declared strongest first, which is the order the
+/// server prefers them in. Only AUTH_SYS and AUTH_NONE are understood by
+/// the server itself, the Kerberos flavors have to be handled in front of
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SecurityFlavor {
+    /// RPCSEC_GSS with Kerberos V5 and privacy
+    Krb5p,
+    /// RPCSEC_GSS with Kerberos V5 and integrity
+    Krb5i,
+    /// RPCSEC_GSS with Kerberos V5, authentication only
+    Krb5,
+    /// AUTH_SYS
+    Sys,
+    /// AUTH_NONE
+    None,
+}
+
+impl SecurityFlavor {
+    pub fn secinfo(self) -> SeCinfo4 {
+        let service = match self {
+            SecurityFlavor::Sys => return SeCinfo4::AuthSys,
+            SecurityFlavor::None => return SeCinfo4::AuthNone,
+            SecurityFlavor::Krb5 => RpcGssSvc::RpcGssSvcNone,
+            SecurityFlavor::Krb5i => RpcGssSvc::RpcGssSvcIntegrity,
+            SecurityFlavor::Krb5p => RpcGssSvc::RpcGssSvcPrivacy,
+        };
+        SeCinfo4::FlavorInfo(RpcSecGssInfo {
+            oid: KRB5_OID.to_vec(),
+            qop: 0,
+            service,
+        })
+    }
+}
+
+/// The SECINFO reply for `flavors`, in the server's order of preference
+/// and without duplicates. AUTH_SYS and AUTH_NONE if none are configured.
+pub fn secinfo(flavors: &[SecurityFlavor]) -> SecInfo4resok {
+    let mut flavors = flavors.to_vec();
+    if flavors.is_empty() {
+        flavors = vec![SecurityFlavor::Sys, SecurityFlavor::None];
+    }
+    flavors.sort();
+    flavors.dedup();
+    flavors.into_iter().map(SecurityFlavor::secinfo).collect()
+}
@@ -0,0 +1,134 @@
+use std::{
+    ffi::CString,
+    fmt::Debug,
+    fs, io,
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use bold_proto::nfs4_proto::{NfsFtype4, Specdata4};
+
+/// Symlinks, device nodes and hard links of a backend.
+///
+/// The file systems of the vfs crate only know regular files and
+/// directories. A backend that can hold more implements this for the
+/// export, `path` is always relative to the export root, like "/dir/link".
+/// What a backend can't do it refuses with [`io::ErrorKind::Unsupported`],
+/// which clients see as NFS4ERR_NOTSUPP.
+pub trait SpecialFiles: Debug + Send + Sync {
+    /// The type and device numbers of `path` if it's neither a regular file
+    /// nor a directory, without following a symlink. `None` for everything
+    /// else, including paths that don't exist.
+    fn special_type(&self, path: &str) -> io::Result<Option<(NfsFtype4, Specdata4)>>;
+
+    /// Create a symlink at `path` pointing to `target`.
+    fn symlink(&self, path: &str, target: &str) -> io::Result<()>;
+
+    /// The target of the symlink at `path`.
+    fn read_link(&self, path: &str) -> io::Result<String>;
+
+    /// Create a block or character device at `path`.
+    fn mknod(&self, _path: &str, _ftype: NfsFtype4, _rawdev: Specdata4) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Create `path` as another name of the file at `existing`.
+    fn hard_link(&self, _existing: &str, _path: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Number of names the file at `path` has.
+    fn link_count(&self, _path: &str) -> io::Result<u32> {
+        Ok(1)
+    }
+}
+
+/// [`SpecialFiles`] of an export that is a directory on the local disk,
+/// typically served with [`PhysicalFS`](vfs::PhysicalFS).
+#[derive(Debug, Clone)]
+pub struct LocalSpecialFiles {
+    root: PathBuf,
+}
+
+impl LocalSpecialFiles {
+    /// `root` is the directory that is exported
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        LocalSpecialFiles {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn local_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+}
+
+impl SpecialFiles for LocalSpecialFiles {
+    fn special_type(&self, path: &str) -> io::Result<Option<(NfsFtype4, Specdata4)>> {
+        let metadata = match fs::symlink_metadata(self.local_path(path)) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let file_type = metadata.file_type();
+        let ftype = if file_type.is_symlink() {
+            NfsFtype4::Nf4lnk
+        } else if file_type.is_block_device() {
+            NfsFtype4::Nf4blk
+        } else if file_type.is_char_device() {
+            NfsFtype4::Nf4chr
+        } else if file_type.is_fifo() {
+            NfsFtype4::Nf4fifo
+        } else if file_type.is_socket() {
+            NfsFtype4::Nf4sock
+        } else {
+            return Ok(None);
+        };
+        let rdev = metadata.rdev();
+        let rawdev = Specdata4 {
+            specdata1: libc::major(rdev as libc::dev_t) as u32,
+            specdata2: libc::minor(rdev as libc::dev_t) as u32,
+        };
+        Ok(Some((ftype, rawdev)))
+    }
+
+    fn symlink(&self, path: &str, target: &str) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, self.local_path(path))
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        let target = fs::read_link(self.local_path(path))?;
+        target
+            .into_os_string()
+            .into_string()
+            .map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+
+    fn mknod(&self, path: &str, ftype: NfsFtype4, rawdev: Specdata4) -> io::Result<()> {
+        let kind = match ftype {
+            NfsFtype4::Nf4blk => libc::S_IFBLK,
+            NfsFtype4::Nf4chr => libc::S_IFCHR,
+            _ => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        let path = CString::new(self.local_path(path).into_os_string().into_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let dev = libc::makedev(rawdev.specdata1, rawdev.specdata2);
+        // SAFETY: path is a NUL terminated string that outlives the call
+        if unsafe { libc::mknod(path.as_ptr(), kind | 0o644, dev) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn hard_link(&self, existing: &str, path: &str) -> io::Result<()> {
+        fs::hard_link(self.local_path(existing), self.local_path(path))
+    }
+
+    fn link_count(&self, path: &str) -> io::Result<u32> {
+        let metadata = fs::symlink_metadata(self.local_path(path))?;
+        Ok(metadata.nlink().try_into().unwrap_or(u32::MAX))
+    }
+}
@@ -0,0 +1,158 @@
+use bold_proto::{
+    nfs4_proto::{
+        Attrlist4, Compound4res, DirList4, Entry4, Fattr4, FileAttr, FileAttrValue, NfsFtype4,
+        NfsResOp4, NfsStat4, PutFh4res, Read4res, Read4resok, ReadDir4res, ReadDir4resok,
+    },
+    rpc_proto::{AcceptBody, AcceptedReply, MsgType, OpaqueAuth, ReplyBody, RpcReplyMsg},
+    XDRProtoCodec,
+};
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// XDR words of a COMPOUND call: PUTROOTFH, LOOKUP "dir1", GETATTR and a
+/// 64 KiB READ, as a client issues it while walking to and reading a file.
+fn compound_call() -> Vec<u8> {
+    // xid, call, rpcvers, prog, vers, proc, AUTH_NULL cred and verf
+    let mut words: Vec<u32> = vec![42, 0, 2, 100003, 4, 1, 0, 0, 0, 0];
+    // empty tag, minor version, four operations
+    words.extend([0, 0, 4]);
+    // PUTROOTFH
+    words.push(24);
+    // LOOKUP "dir1"
+    words.extend([15, 4, u32::from_be_bytes(*b"dir1")]);
+    // GETATTR type, size, fileid and mode
+    words.extend([9, 2, (1 << 1) | (1 << 4) | (1 << 20), 1 << 1]);
+    // READ with the anonymous stateid, offset 0, count 64 KiB
+    words.extend([25, 0, 0, 0, 0, 0, 0, 65536]);
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+/// Wraps a message into record marking fragments of at most `fragment_size`
+/// bytes.
+fn frame(message: &[u8], fragment_size: usize) -> BytesMut {
+    let mut frame = BytesMut::new();
+    let mut chunks = message.chunks(fragment_size).peekable();
+    while let Some(chunk) = chunks.next() {
+        let mut header = chunk.len() as u32;
+        if chunks.peek().is_none() {
+            header |= 1 << 31;
+        }
+        frame.extend_from_slice(&header.to_be_bytes());
+        frame.extend_from_slice(chunk);
+    }
+    frame
+}
+
+fn reply(resarray: Vec<NfsResOp4>) -> Box<RpcReplyMsg> {
+    Box::new(RpcReplyMsg {
+        xid: 42,
+        body: MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+            verf: OpaqueAuth::AuthNull(Vec::new()),
+            reply_data: AcceptBody::Success(Compound4res {
+                status: NfsStat4::Nfs4Ok,
+                tag: String::new(),
+                resarray,
+            }),
+        })),
+    })
+}
+
+fn read_reply(size: usize) -> Box<RpcReplyMsg> {
+    reply(vec![
+        NfsResOp4::Opputfh(PutFh4res {
+            status: NfsStat4::Nfs4Ok,
+        }),
+        NfsResOp4::Opread(Read4res::Resok4(Read4resok {
+            eof: false,
+            data: Bytes::from(vec![0xAB; size]),
+        })),
+    ])
+}
+
+fn readdir_reply(entries: u64) -> Box<RpcReplyMsg> {
+    let mut list = None;
+    for cookie in (1..=entries).rev() {
+        list = Some(Entry4 {
+            cookie,
+            name: format!("file{}", cookie),
+            attrs: Fattr4 {
+                attrmask: Attrlist4::<FileAttr>::new(Some(vec![
+                    FileAttr::Type,
+                    FileAttr::Size,
+                    FileAttr::Fileid,
+                    FileAttr::Mode,
+                ])),
+                attr_vals: Attrlist4::<FileAttrValue>::new(Some(vec![
+                    FileAttrValue::Type(NfsFtype4::Nf4reg),
+                    FileAttrValue::Size(4096),
+                    FileAttrValue::Fileid(cookie),
+                    FileAttrValue::Mode(0o644),
+                ])),
+            },
+            nextentry: list.map(Box::new),
+        });
+    }
+    reply(vec![
+        NfsResOp4::Opputfh(PutFh4res {
+            status: NfsStat4::Nfs4Ok,
+        }),
+        NfsResOp4::Opreaddir(ReadDir4res::Resok4(ReadDir4resok {
+            cookieverf: [0; 8],
+            reply: DirList4 {
+                entries: list,
+                eof: true,
+            },
+        })),
+    ])
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let message = compound_call();
+    let mut group = c.benchmark_group("decode_compound");
+    group.throughput(Throughput::Bytes(message.len() as u64));
+    for (name, fragment_size) in [("single_fragment", message.len()), ("fragments_16", 16)] {
+        let frame = frame(&message, fragment_size);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || frame.clone(),
+                |mut src| {
+                    XDRProtoCodec::new()
+                        .decode(black_box(&mut src))
+                        .unwrap()
+                        .unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_reply");
+    let replies = [
+        ("read_4k", read_reply(4096)),
+        ("read_1m", read_reply(1024 * 1024)),
+        ("read_4m", read_reply(4 * 1024 * 1024)),
+        ("readdir_16", readdir_reply(16)),
+        ("readdir_256", readdir_reply(256)),
+    ];
+    for (name, message) in replies {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                // the encoder consumes the reply
+                || (message.clone(), BytesMut::new()),
+                |(message, mut dst)| {
+                    XDRProtoCodec::new().encode(message, &mut dst).unwrap();
+                    dst
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode);
+criterion_main!(benches);
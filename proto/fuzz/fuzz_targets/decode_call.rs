@@ -0,0 +1,18 @@
+#![no_main]
+
+use bold_proto::{from_bytes, XDRProtoCodec};
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+// malformed calls have to be refused with an error, decoding must never
+// panic
+fuzz_target!(|data: &[u8]| {
+    // the bytes as the body of a record
+    let _ = from_bytes(data.to_vec());
+    // and as a stream of record marking fragments, decoded until the codec
+    // runs out of data or gives up
+    let mut codec = XDRProtoCodec::new();
+    let mut src = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut src) {}
+});
@@ -2,7 +2,7 @@ pub mod nfs4_proto;
 pub mod rpc_proto;
 pub mod utils;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use serde_xdr::{from_reader, to_writer, CompatDeserializationError};
 use std::io::Cursor;
 use tokio_util::codec::{Decoder, Encoder};
@@ -11,9 +11,42 @@ use tokio_util::codec::{Decoder, Encoder};
 use self::rpc_proto::{RpcCallMsg, RpcReplyMsg};
 
 #[derive(Debug)]
-pub struct XDRProtoCodec {}
+pub struct XDRProtoCodec {
+    // the fragments of the record read so far, a record can arrive over
+    // several calls to decode
+    message_data: Vec<u8>,
+    fragments: usize,
+}
+
+/// Largest RPC message the codec reads or writes
+pub const MAX_RECORD_SIZE: usize = 8 * 1024 * 1024;
+
+/// Most fragments a record read by the codec may consist of
+pub const MAX_FRAGMENTS: usize = 1024;
+
+/// A record that doesn't hold a decodable RPC call. The xid is kept if the
+/// record is long enough to carry one, so the GARBAGE_ARGS reply can be
+/// matched to the call.
+#[derive(Debug)]
+pub struct GarbageArgs {
+    pub xid: Option<u32>,
+    source: anyhow::Error,
+}
+
+impl GarbageArgs {
+    /// The garbage call behind a decoder error, if that's what failed
+    pub fn from_io_error(e: &std::io::Error) -> Option<&GarbageArgs> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::fmt::Display for GarbageArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Garbage RPC call (xid {:?}): {}", self.xid, self.source)
+    }
+}
 
-const MAX: usize = 8 * 1024 * 1024;
+impl std::error::Error for GarbageArgs {}
 
 impl Default for XDRProtoCodec {
     fn default() -> Self {
@@ -23,7 +56,10 @@ impl Default for XDRProtoCodec {
 
 impl XDRProtoCodec {
     pub fn new() -> XDRProtoCodec {
-        XDRProtoCodec {}
+        XDRProtoCodec {
+            message_data: Vec::new(),
+            fragments: 0,
+        }
     }
 }
 
@@ -32,7 +68,6 @@ impl Decoder for XDRProtoCodec {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut message_data = Vec::new();
         let mut is_last = false;
         while !is_last {
             if src.len() < 4 {
@@ -50,12 +85,27 @@ impl Decoder for XDRProtoCodec {
 
             // Check that the length is not too large to avoid a denial of
             // service attack where the server runs out of memory.
-            if length > MAX {
+            if length > MAX_RECORD_SIZE {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
                     format!("Frame of length {} is too large.", length),
                 ));
             }
+            // The same holds for the whole record, a client must not get
+            // around the limit by splitting it into many small fragments.
+            let record_length = self.message_data.len() + length;
+            if record_length > MAX_RECORD_SIZE || self.fragments >= MAX_FRAGMENTS {
+                let fragments = self.fragments + 1;
+                self.message_data = Vec::new();
+                self.fragments = 0;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Record of length {} in {} fragments is too large.",
+                        record_length, fragments
+                    ),
+                ));
+            }
 
             if src.len() < 4 + length {
                 // The full string has not yet arrived.
@@ -65,7 +115,8 @@ impl Decoder for XDRProtoCodec {
             let fragment = src[4..4 + length].to_vec();
             src.advance(4 + length);
 
-            message_data.extend_from_slice(&fragment[..]);
+            self.message_data.extend_from_slice(&fragment[..]);
+            self.fragments += 1;
             // TODO remove due to performance reasons
             // trace!(
             //     length = length,
@@ -74,8 +125,16 @@ impl Decoder for XDRProtoCodec {
             // );
         }
 
+        let message_data = std::mem::take(&mut self.message_data);
+        self.fragments = 0;
+        // the xid leads the message, keep it even if the rest is garbage
+        let xid = message_data
+            .get(..4)
+            .map(|xid| u32::from_be_bytes(xid.try_into().unwrap()));
         RpcCallMsg::from_bytes(message_data)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            .map_err(|source| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, GarbageArgs { xid, source })
+            })
             .map(Some)
     }
 }
@@ -84,16 +143,32 @@ impl Encoder<Box<RpcReplyMsg>> for XDRProtoCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, message: Box<RpcReplyMsg>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let buffer_message = message
-            .to_bytes()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        let buffer_header = u32::to_be_bytes(buffer_message.len() as u32 + (1 << 31));
-        // Reserve space in the buffer.
-        dst.reserve(4 + buffer_message.len());
-
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&buffer_header);
-        dst.extend_from_slice(&buffer_message);
+        // the message is serialized straight into the output buffer behind
+        // a placeholder for the record mark, so large READ payloads are
+        // copied only once
+        let start = dst.len();
+        dst.put_u32(0);
+        let mut writer = dst.writer();
+        let result = to_writer(&mut writer, &*message);
+        let dst = writer.into_inner();
+        if let Err(e) = result {
+            dst.truncate(start);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                anyhow::anyhow!("Error serializing message: {:?}", e),
+            ));
+        }
+        let length = dst.len() - start - 4;
+        // a larger message doesn't fit into a single record fragment
+        if length > MAX_RECORD_SIZE {
+            dst.truncate(start);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Reply of length {} is too large.", length),
+            ));
+        }
+        let buffer_header = u32::to_be_bytes(length as u32 + (1 << 31));
+        dst[start..start + 4].copy_from_slice(&buffer_header);
         Ok(())
     }
 }
@@ -117,3 +192,94 @@ pub fn to_bytes(message: &RpcReplyMsg) -> Result<Vec<u8>, anyhow::Error> {
         Err(e) => Err(anyhow::anyhow!("Error serializing message: {:?}", e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{
+        nfs4_proto::{
+            Compound4res, NfsResOp4, NfsStat4, OpFailed4, PutRootFh4res, Read4res, Read4resok,
+        },
+        rpc_proto::{AcceptBody, AcceptedReply, MsgType, OpaqueAuth, ReplyBody, RpcReplyMsg},
+        XDRProtoCodec,
+    };
+
+    #[test]
+    fn test_encode_read_reply() {
+        // an odd length, the data is padded to a multiple of 4
+        let data: Bytes = (0..100_001).map(|i| (i % 251) as u8).collect();
+        let message = Box::new(RpcReplyMsg {
+            xid: 42,
+            body: MsgType::Reply(ReplyBody::MsgAccepted(AcceptedReply {
+                verf: OpaqueAuth::AuthNull(Vec::new()),
+                reply_data: AcceptBody::Success(Compound4res {
+                    status: NfsStat4::Nfs4Ok,
+                    tag: String::new(),
+                    resarray: vec![NfsResOp4::Opread(Read4res::Resok4(Read4resok {
+                        eof: true,
+                        data: data.clone(),
+                    }))],
+                }),
+            })),
+        });
+        let expected = message.to_bytes().unwrap();
+
+        // replies are appended to what is already buffered
+        let mut dst = BytesMut::from(&b"prev"[..]);
+        XDRProtoCodec::new().encode(message, &mut dst).unwrap();
+        assert_eq!(&dst[..4], b"prev");
+        let header = u32::from_be_bytes(dst[4..8].try_into().unwrap());
+        assert_eq!(header, (1 << 31) | expected.len() as u32);
+        assert_eq!(&dst[8..], &expected[..]);
+
+        // opaque length, the data itself and the padding close the record
+        let payload = &dst[dst.len() - data.len() - 3..];
+        assert_eq!(&payload[..data.len()], &data[..]);
+        assert_eq!(&payload[data.len()..], &[0, 0, 0]);
+        let length = &dst[dst.len() - data.len() - 7..dst.len() - data.len() - 3];
+        assert_eq!(length, &(data.len() as u32).to_be_bytes());
+    }
+
+    #[test]
+    fn test_encode_failed_op() {
+        let res = Compound4res {
+            status: NfsStat4::Nfs4errNofilehandle,
+            tag: String::new(),
+            resarray: vec![
+                NfsResOp4::Opputrootfh(PutRootFh4res {
+                    status: NfsStat4::Nfs4Ok,
+                }),
+                NfsResOp4::OpFailed(OpFailed4 {
+                    op: 9,
+                    status: NfsStat4::Nfs4errNofilehandle,
+                }),
+            ],
+        };
+        let mut bytes = Vec::new();
+        serde_xdr::to_writer(&mut bytes, &res).unwrap();
+        let words: Vec<u32> = bytes
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        // status, empty tag, two results: PUTROOTFH ok and GETATTR failed
+        assert_eq!(words, vec![10020, 0, 2, 24, 0, 9, 10020]);
+    }
+
+    #[test]
+    fn test_fuzz_seeds_decode() {
+        // the seed corpus of the fuzz target holds well-formed calls
+        let seeds = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/seeds/decode_call");
+        let mut count = 0;
+        for seed in std::fs::read_dir(seeds).unwrap() {
+            let seed = seed.unwrap().path();
+            let mut src = BytesMut::from(&std::fs::read(&seed).unwrap()[..]);
+            let msg = XDRProtoCodec::new().decode(&mut src);
+            assert!(matches!(msg, Ok(Some(_))), "{:?}: {:?}", seed, msg);
+            assert!(src.is_empty());
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+}
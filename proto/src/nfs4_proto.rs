@@ -2,6 +2,7 @@ extern crate serde_bytes;
 extern crate serde_xdr;
 use super::utils::write_argarray;
 
+use bytes::Bytes;
 use num_derive::{FromPrimitive, ToPrimitive};
 
 use serde_derive::{Deserialize, Serialize};
@@ -133,14 +134,14 @@ pub type NfsFh4 = [u8; 26];
 pub type NfsLease4 = u32;
 type Offset4 = u64;
 type Qop4 = u32;
-type SecOid4 = Vec<u64>;
+type SecOid4 = Vec<u8>;
 type Seqid4 = u32;
 // type opaque  String<>;
 type Utf8strCis = String;
 type Utf8strCs = String;
 type Utf8strMixed = String;
 type Component4 = Utf8strCs;
-type Linktext4 = Vec<u64>;
+type Linktext4 = String;
 // type AsciiRequired4 = String;
 type Pathname4 = Vec<Component4>;
 // type NfsLockid4 = u64;
@@ -184,14 +185,14 @@ pub struct Fsid4 {
  */
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FsLocation4 {
-    server: Vec<Utf8strCis>,
-    rootpath: Pathname4,
+    pub server: Vec<Utf8strCis>,
+    pub rootpath: Pathname4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FsLocations4 {
-    fs_root: Pathname4,
-    locations: Vec<FsLocation4>,
+    pub fs_root: Pathname4,
+    pub locations: Vec<FsLocation4>,
 }
 
 /*
@@ -212,7 +213,7 @@ type Acetype4 = u32;
 /*
  * Acetype4 values; others can be added as needed.
  */
-// const ACE4_ACCESS_ALLOWED_ACE_TYPE: u32 = 0x00000000;
+pub const ACE4_ACCESS_ALLOWED_ACE_TYPE: u32 = 0x00000000;
 // const ACE4_ACCESS_DENIED_ACE_TYPE: u32 = 0x00000001;
 // const ACE4_SYSTEM_AUDIT_ACE_TYPE: u32 = 0x00000002;
 // const ACE4_SYSTEM_ALARM_ACE_TYPE: u32 = 0x00000003;
@@ -231,7 +232,7 @@ type Aceflag4 = u32;
 // const ACE4_INHERIT_ONLY_ACE: u32 = 0x00000008;
 // const ACE4_SUCCESSFUL_ACCESS_ACE_FLAG: u32 = 0x00000010;
 // const ACE4_FAILED_ACCESS_ACE_FLAG: u32 = 0x00000020;
-// const ACE4_IDENTIFIER_GROUP: u32 = 0x00000040;
+pub const ACE4_IDENTIFIER_GROUP: u32 = 0x00000040;
 
 /*
  * ACE mask
@@ -241,15 +242,15 @@ type Acemask4 = u32;
 /*
  * ACE mask values
  */
-// const ACE4_READ_DATA: u32 = 0x00000001;
+pub const ACE4_READ_DATA: u32 = 0x00000001;
 // const ACE4_LIST_DIRECTORY: u32 = 0x00000001;
-// const ACE4_WRITE_DATA: u32 = 0x00000002;
+pub const ACE4_WRITE_DATA: u32 = 0x00000002;
 // const ACE4_ADD_FILE: u32 = 0x00000002;
-// const ACE4_APPEND_DATA: u32 = 0x00000004;
+pub const ACE4_APPEND_DATA: u32 = 0x00000004;
 // const ACE4_ADD_SUBDIRECTORY: u32 = 0x00000004;
 // const ACE4_READ_NAMED_ATTRS: u32 = 0x00000008;
 // const ACE4_WRITE_NAMED_ATTRS: u32 = 0x00000010;
-// const ACE4_EXECUTE: u32 = 0x00000020;
+pub const ACE4_EXECUTE: u32 = 0x00000020;
 // const ACE4_DELETE_CHILD: u32 = 0x00000040;
 // const ACE4_READ_ATTRIBUTES: u32 = 0x00000080;
 // const ACE4_WRITE_ATTRIBUTES: u32 = 0x00000100;
@@ -321,10 +322,10 @@ pub const MODE4_XOTH: u32 = 0x001; /* execute permission: other */
  * Special data/attribute associated with
  * file types NF4BLK and NF4CHR.
  */
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Specdata4 {
-    specdata1: u32, /* major device number */
-    specdata2: u32, /* minor device number */
+    pub specdata1: u32, /* major device number */
+    pub specdata2: u32, /* minor device number */
 }
 
 /*
@@ -465,9 +466,9 @@ pub struct OpenOwner4 {
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct LockOwner4 {
-    clientid: Clientid4,
+    pub clientid: Clientid4,
     #[serde(with = "serde_bytes")]
-    owner: Vec<u8>,
+    pub owner: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -518,8 +519,8 @@ pub enum Close4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Commit4args {
     /* CURRENT_FH: file */
-    offset: Offset4,
-    count: Count4,
+    pub offset: Offset4,
+    pub count: Count4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -540,7 +541,7 @@ pub enum Createtype4 {
     Nf4Undef = 0,          /* undefined */
     Nf4reg = 1,            /* Regular File */
     Nf4dir = 2,            /* Directory */
-    Nf4blk = 3,            /* Special File - block device */
+    Nf4blk(Specdata4) = 3, /* Special File - block device */
     Nf4chr(Specdata4) = 4, /* Special File - character device */
     Nf4lnk(Linktext4) = 5, /* Symbolic Link */
     Nf4sock = 6,           /* Special File - socket */
@@ -667,36 +668,36 @@ pub enum FileAttrValue {
     UniqueHandles(bool) = 9,
     LeaseTime(NfsLease4) = 10,
     RdattrError(NfsStat4) = 11,
-    Acl = 12,
+    Acl(Vec<Nfsace4>) = 12,
     AclSupport(u32) = 13,
-    Archive = 14,
+    Archive(bool) = 14,
     Cansettime = 15,
     CaseInsensitive = 16,
     CasePreserving = 17,
     ChownRestricted = 18,
     Filehandle(NfsFh4) = 19,
     Fileid(u64) = 20,
-    FilesAvail = 21,
-    FilesFree = 22,
-    FilesTotal = 23,
-    FsLocations = 24,
+    FilesAvail(u64) = 21,
+    FilesFree(u64) = 22,
+    FilesTotal(u64) = 23,
+    FsLocations(FsLocations4) = 24,
     Hidden = 25,
-    Homogeneous = 26,
-    Maxfilesize = 27,
-    Maxlink = 28,
+    Homogeneous(bool) = 26,
+    Maxfilesize(u64) = 27,
+    Maxlink(u32) = 28,
     Maxname = 29,
-    Maxread = 30,
-    Maxwrite = 31,
+    Maxread(u64) = 30,
+    Maxwrite(u64) = 31,
     Mimetype(String) = 32,
     Mode(u32) = 33,
     NoTrunc = 34,
     Numlinks(u32) = 35,
     Owner(String) = 36,
     OwnerGroup(String) = 37,
-    QuotaAvailHard = 38,
-    QuotaAvailSoft = 39,
-    QuotaUsed = 40,
-    Rawdev = 41,
+    QuotaAvailHard(u64) = 38,
+    QuotaAvailSoft(u64) = 39,
+    QuotaUsed(u64) = 40,
+    Rawdev(Specdata4) = 41,
     SpaceAvail = 42,
     SpaceFree = 43,
     SpaceTotal = 44,
@@ -704,7 +705,7 @@ pub enum FileAttrValue {
     System = 46,
     TimeAccess(Nfstime4) = 47,
     TimeAccessSet = 48,
-    TimeBackup = 49,
+    TimeBackup(Nfstime4) = 49,
     TimeCreate = 50,
     TimeDelta = 51,
     TimeMetadata(Nfstime4) = 52,
@@ -747,12 +748,12 @@ pub enum GetFh4res {
 pub struct Link4args {
     /* SAVED_FH: source object */
     /* CURRENT_FH: target directory */
-    newname: Component4,
+    pub newname: Component4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Link4resok {
-    cinfo: ChangeInfo4,
+    pub cinfo: ChangeInfo4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -873,22 +874,24 @@ pub struct LookupP4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Nverify4args {
     /* CURRENT_FH: object */
-    obj_attributes: Fattr4,
+    pub obj_attributes: Fattr4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Nverify4res {
-    status: NfsStat4,
+    pub status: NfsStat4,
 }
 
-// const OPEN4_SHARE_ACCESS_READ: u32 = 0x00000001;
-// const OPEN4_SHARE_ACCESS_WRITE: u32 = 0x00000002;
-// const OPEN4_SHARE_ACCESS_BOTH: u32 = 0x00000003;
+pub const OPEN4_SHARE_ACCESS_READ: u32 = 0x00000001;
+pub const OPEN4_SHARE_ACCESS_WRITE: u32 = 0x00000002;
+pub const OPEN4_SHARE_ACCESS_BOTH: u32 = 0x00000003;
+/* delegation want bits, defined by NFSv4.1 (RFC 5661) but sent by some 4.0 clients */
+pub const OPEN4_SHARE_ACCESS_WANT_DELEG_MASK: u32 = 0x0000FF00;
 
-// const OPEN4_SHARE_DENY_NONE: u32 = 0x00000000;
-// const OPEN4_SHARE_DENY_READ: u32 = 0x00000001;
-// const OPEN4_SHARE_DENY_WRITE: u32 = 0x00000002;
-// const OPEN4_SHARE_DENY_BOTH: u32 = 0x00000003;
+pub const OPEN4_SHARE_DENY_NONE: u32 = 0x00000000;
+pub const OPEN4_SHARE_DENY_READ: u32 = 0x00000001;
+pub const OPEN4_SHARE_DENY_WRITE: u32 = 0x00000002;
+pub const OPEN4_SHARE_DENY_BOTH: u32 = 0x00000003;
 /*
  * Various definitions for OPEN
  */
@@ -1112,8 +1115,8 @@ pub struct OpenAttr4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct OpenConfirm4args {
     /* CURRENT_FH: opened file */
-    open_stateid: Stateid4,
-    seqid: Seqid4,
+    pub open_stateid: Stateid4,
+    pub seqid: Seqid4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1130,15 +1133,15 @@ pub enum OpenConfirm4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct OpenDowngrade4args {
     /* CURRENT_FH: opened file */
-    open_stateid: Stateid4,
-    seqid: Seqid4,
-    share_access: u32,
-    share_deny: u32,
+    pub open_stateid: Stateid4,
+    pub seqid: Seqid4,
+    pub share_access: u32,
+    pub share_deny: u32,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct OpenDowngrade4resok {
-    open_stateid: Stateid4,
+    pub open_stateid: Stateid4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1182,8 +1185,7 @@ pub struct Read4args {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Read4resok {
     pub eof: bool,
-    #[serde(with = "serde_bytes")]
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1232,7 +1234,7 @@ pub enum ReadDir4res {
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct ReadLink4resok {
-    link: Linktext4,
+    pub link: Linktext4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1256,15 +1258,15 @@ pub struct Remove4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Rename4args {
     /* SAVED_FH: source directory */
-    oldname: Component4,
+    pub oldname: Component4,
     /* CURRENT_FH: target directory */
-    newname: Component4,
+    pub newname: Component4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Rename4resok {
-    source_cinfo: ChangeInfo4,
-    target_cinfo: ChangeInfo4,
+    pub source_cinfo: ChangeInfo4,
+    pub target_cinfo: ChangeInfo4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1286,19 +1288,19 @@ pub struct Renew4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct RestoreFh4res {
     /* CURRENT_FH: value of saved fh */
-    status: NfsStat4,
+    pub status: NfsStat4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct SaveFh4res {
     /* SAVED_FH: value of current fh */
-    status: NfsStat4,
+    pub status: NfsStat4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct SecInfo4args {
     /* CURRENT_FH: directory */
-    name: Component4,
+    pub name: Component4,
 }
 
 /*
@@ -1307,6 +1309,7 @@ pub struct SecInfo4args {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 
 pub enum RpcGssSvc {
+    RpcGssSvcUndef = 0,
     RpcGssSvcNone = 1,
     RpcGssSvcIntegrity = 2,
     RpcGssSvcPrivacy = 3,
@@ -1314,18 +1317,25 @@ pub enum RpcGssSvc {
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct RpcSecGssInfo {
-    oid: SecOid4,
-    qop: Qop4,
-    service: RpcGssSvc,
+    #[serde(with = "serde_bytes")]
+    pub oid: SecOid4,
+    pub qop: Qop4,
+    pub service: RpcGssSvc,
 }
 
 /* RPCSEC_GSS has a value of '6'.  See RFC 2203 */
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+// encoded by flavor number, see utils
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
 pub enum SeCinfo4 {
-    FlavorInfo(RpcSecGssInfo),
+    AuthNone = 0,
+    AuthSys = 1,
+    AuthShort = 2,
+    AuthDh = 3,
+    FlavorInfo(RpcSecGssInfo) = 6,
 }
 
-type SecInfo4resok = Vec<SeCinfo4>;
+pub type SecInfo4resok = Vec<SeCinfo4>;
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 
@@ -1383,12 +1393,12 @@ pub struct SetClientIdConfirm4res {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Verify4args {
     /* CURRENT_FH: object */
-    obj_attributes: Fattr4,
+    pub obj_attributes: Fattr4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Verify4res {
-    status: NfsStat4,
+    pub status: NfsStat4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1425,12 +1435,12 @@ pub enum Write4res {
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct ReleaseLockowner4args {
-    lock_owner: LockOwner4,
+    pub lock_owner: LockOwner4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct ReleaseLockowner4res {
-    status: NfsStat4,
+    pub status: NfsStat4,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -1438,11 +1448,20 @@ pub struct Illegal4res {
     status: NfsStat4,
 }
 
+/// The result of an operation that failed without a result of its own.
+/// It's encoded like any failed operation, as the opcode followed by the
+/// status.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OpFailed4 {
+    pub op: u32,
+    pub status: NfsStat4,
+}
+
 /*
  * Operation arrays
  */
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
 #[repr(u32)]
 pub enum NfsOpNum4 {
     OpAccess = 3,
@@ -1602,12 +1621,12 @@ pub enum NfsResOp4 {
     Opputrootfh(PutRootFh4res) = 24,
     Opread(Read4res) = 25,
     Opreaddir(ReadDir4res) = 26,
-    Opreadlink(()) = 27,
+    Opreadlink(ReadLink4res) = 27,
     Opremove(Remove4res) = 28,
     Oprename(Rename4res) = 29,
     Oprenew(Renew4res) = 30,
-    Oprestorefh(()) = 31,
-    Opsavefh(()) = 32,
+    Oprestorefh(RestoreFh4res) = 31,
+    Opsavefh(SaveFh4res) = 32,
 
     OpSecinfo(SecInfo4res) = 33,
     Opsetattr(SetAttr4res) = 34,
@@ -1616,6 +1635,7 @@ pub enum NfsResOp4 {
     Opverify(Verify4res) = 37,
     Opwrite(Write4res) = 38,
     OpreleaseLockOwner(ReleaseLockowner4res) = 39,
+    OpFailed(OpFailed4) = 40,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -39,6 +39,9 @@ pub struct CallBody {
     pub cred: OpaqueAuth,
     pub verf: OpaqueAuth,
     pub args: Option<Compound4args>,
+    // the RPC header was decoded but the compound arguments were not
+    #[serde(skip)]
+    pub malformed_args: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -122,7 +125,7 @@ pub struct RpcCompoundCallMsg {
     pub body: MsgType,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RpcReplyMsg {
     pub xid: u32,
     pub body: MsgType,
@@ -5,7 +5,7 @@ use std::{
 
 use num_traits::{FromPrimitive, ToPrimitive};
 use serde::{
-    de::{self, SeqAccess, Visitor},
+    de::{self, EnumAccess, SeqAccess, VariantAccess, Visitor},
     ser::{SerializeSeq, SerializeStruct},
     Deserialize, Serialize, Serializer,
 };
@@ -14,10 +14,32 @@ use tracing::{debug, error};
 use crate::nfs4_proto::Compound4args;
 
 use super::{
-    nfs4_proto::{Attrlist4, Fattr4, FileAttr, FileAttrValue, Getattr4resok, NfsResOp4, NfsStat4},
+    nfs4_proto::{
+        Attrlist4, Fattr4, FileAttr, FileAttrValue, Getattr4resok, NfsResOp4, NfsStat4, Nfstime4,
+        SeCinfo4,
+    },
     rpc_proto::CallBody,
 };
 
+/// Number of bytes `value` takes up when encoded as XDR
+pub fn xdr_size<T: Serialize>(value: &T) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    let _ = serde_xdr::to_writer(&mut counter, value);
+    counter.0
+}
+
 pub fn write_argarray<T, S>(v: &T, serializer: S) -> Result<S::Ok, S::Error>
 where
     T: AsRef<[NfsResOp4]>,
@@ -27,7 +49,15 @@ where
     if values.is_empty() {
         serializer.serialize_none()
     } else {
-        values.serialize(serializer)
+        let mut seq = serializer.serialize_seq(Some(values.len()))?;
+        for value in values {
+            match value {
+                // the opcode takes the place of the union discriminant
+                NfsResOp4::OpFailed(failed) => seq.serialize_element(failed)?,
+                _ => seq.serialize_element(value)?,
+            }
+        }
+        seq.end()
     }
 }
 
@@ -40,6 +70,76 @@ impl Serialize for NfsStat4 {
     }
 }
 
+// serde numbers variants by position, the flavors aren't contiguous
+impl Serialize for SeCinfo4 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            SeCinfo4::AuthNone => serializer.serialize_unit_variant("SeCinfo4", 0, "AuthNone"),
+            SeCinfo4::AuthSys => serializer.serialize_unit_variant("SeCinfo4", 1, "AuthSys"),
+            SeCinfo4::AuthShort => serializer.serialize_unit_variant("SeCinfo4", 2, "AuthShort"),
+            SeCinfo4::AuthDh => serializer.serialize_unit_variant("SeCinfo4", 3, "AuthDh"),
+            SeCinfo4::FlavorInfo(info) => {
+                serializer.serialize_newtype_variant("SeCinfo4", 6, "FlavorInfo", info)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SeCinfo4 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct SeCinfo4Visitor;
+
+        impl<'de> Visitor<'de> for SeCinfo4Visitor {
+            type Value = SeCinfo4;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("enum SeCinfo4")
+            }
+
+            fn visit_enum<A>(self, data: A) -> Result<SeCinfo4, A::Error>
+            where
+                A: EnumAccess<'de>,
+            {
+                let (flavor, variant): (u32, _) = data.variant()?;
+                let secinfo = match flavor {
+                    0 => SeCinfo4::AuthNone,
+                    1 => SeCinfo4::AuthSys,
+                    2 => SeCinfo4::AuthShort,
+                    3 => SeCinfo4::AuthDh,
+                    6 => return Ok(SeCinfo4::FlavorInfo(variant.newtype_variant()?)),
+                    _ => {
+                        return Err(de::Error::invalid_value(
+                            de::Unexpected::Unsigned(flavor.into()),
+                            &self,
+                        ))
+                    }
+                };
+                variant.unit_variant()?;
+                Ok(secinfo)
+            }
+        }
+
+        // indexed by flavor number, the decoder looks the number on the
+        // wire up in here; 4 and 5 are unassigned
+        const VARIANTS: &[&str] = &[
+            "AuthNone",
+            "AuthSys",
+            "AuthShort",
+            "AuthDh",
+            "",
+            "",
+            "FlavorInfo",
+        ];
+        deserializer.deserialize_enum("SeCinfo4", VARIANTS, SeCinfo4Visitor)
+    }
+}
+
 impl Serialize for Getattr4resok {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -106,12 +206,14 @@ impl<'de> Deserialize<'de> for CallBody {
                         cred,
                         verf,
                         args: None,
+                        malformed_args: false,
                     })
                 } else {
                     // Procedure 1: COMPOUND - Compound Operations
-                    let args: Compound4args = seq
-                        .next_element()?
-                        .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                    // The frame and RPC header are intact at this point, so a
+                    // failure here is reported as NFS4ERR_BADXDR by the server
+                    // rather than failing the whole message.
+                    let args: Option<Compound4args> = seq.next_element().ok().flatten();
                     Ok(CallBody {
                         rpcvers,
                         prog,
@@ -119,7 +221,8 @@ impl<'de> Deserialize<'de> for CallBody {
                         proc,
                         cred,
                         verf,
-                        args: Some(args),
+                        malformed_args: args.is_none(),
+                        args,
                     })
                 }
             }
@@ -139,75 +242,59 @@ struct FattrRaw {
 }
 impl FattrRaw {
     fn to_fileattrs(&self) -> Attrlist4<FileAttr> {
-        let mut attrmask = Attrlist4::<FileAttr>::new(None);
-        for (idx, segment) in self.attrmask.iter().enumerate() {
-            for n in 0..32 {
-                let bit = (segment >> n) & 1;
-                if bit == 1 {
-                    let attr: Option<FileAttr> = FromPrimitive::from_u32((idx * 32 + n) as u32);
-                    if let Some(attr) = attr {
-                        attrmask.push(attr);
-                    }
-                }
-            }
-        }
-        attrmask
+        Attrlist4::from_u32(self.attrmask.clone())
     }
 
-    fn attrvalues_from_bytes(&self, fileattrs: &[FileAttr]) -> Attrlist4<FileAttrValue> {
+    // the values of `fileattrs` in attr_vals, fails if attr_vals is too
+    // short or holds an attribute that can't be decoded
+    fn attrvalues_from_bytes<E: de::Error>(
+        &self,
+        fileattrs: &[FileAttr],
+    ) -> Result<Attrlist4<FileAttrValue>, E> {
         let mut attr_vals = Attrlist4::<FileAttrValue>::new(None);
-        let mut offset = 0;
-        for (idx, attr) in fileattrs.iter().enumerate() {
+        let mut rest = &self.attr_vals[..];
+        let mut take = |attr: &FileAttr, len: usize| -> Result<&[u8], E> {
+            if rest.len() < len {
+                return Err(de::Error::invalid_length(
+                    rest.len(),
+                    &format!("{} bytes for {:?}", len, attr).as_str(),
+                ));
+            }
+            let (value, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(value)
+        };
+        for attr in fileattrs.iter() {
             match attr {
-                FileAttr::Type => {
-                    todo!();
-                }
                 FileAttr::Change => {
-                    todo!();
+                    let ele = u64::from_be_bytes(take(attr, 8)?.try_into().unwrap());
+                    attr_vals.push(FileAttrValue::Change(ele));
                 }
                 FileAttr::Size => {
-                    let ele =
-                        u64::from_be_bytes(self.attr_vals[offset..offset + 8].try_into().unwrap());
+                    let ele = u64::from_be_bytes(take(attr, 8)?.try_into().unwrap());
                     attr_vals.push(FileAttrValue::Size(ele));
-                    offset += idx + 4;
-                }
-                FileAttr::TimeAccess => {
-                    todo!();
-                }
-                FileAttr::TimeModify => {
-                    todo!();
-                }
-                FileAttr::TimeMetadata => {
-                    todo!();
-                }
-                FileAttr::MountedOnFileid => {
-                    todo!();
                 }
-                FileAttr::Owner => {
-                    todo!();
+                FileAttr::Archive => {
+                    let ele = u32::from_be_bytes(take(attr, 4)?.try_into().unwrap());
+                    attr_vals.push(FileAttrValue::Archive(ele != 0));
                 }
-                FileAttr::OwnerGroup => {
-                    todo!();
-                }
-                FileAttr::SpaceUsed => {
-                    todo!();
-                }
-                FileAttr::Numlinks => {
-                    todo!();
+                FileAttr::TimeBackup => {
+                    let value = take(attr, 12)?;
+                    let seconds = i64::from_be_bytes(value[..8].try_into().unwrap());
+                    let nseconds = u32::from_be_bytes(value[8..].try_into().unwrap());
+                    attr_vals.push(FileAttrValue::TimeBackup(Nfstime4 { seconds, nseconds }));
                 }
                 FileAttr::Mode => {
-                    let ele =
-                        u32::from_be_bytes(self.attr_vals[offset..offset + 4].try_into().unwrap());
+                    let ele = u32::from_be_bytes(take(attr, 4)?.try_into().unwrap());
                     attr_vals.push(FileAttrValue::Mode(ele));
-                    offset += idx + 4;
                 }
                 _ => {
                     error!("Cannot deserialize {:?}", attr);
-                    todo!()
+                    return Err(de::Error::custom(format!("cannot deserialize {:?}", attr)));
                 }
             }
         }
-        attr_vals
+        Ok(attr_vals)
     }
 }
 
@@ -218,7 +305,7 @@ impl<'de> Deserialize<'de> for Fattr4 {
     {
         let fattr_raw = <FattrRaw as serde::Deserialize>::deserialize(deserializer)?;
         let attrmask = fattr_raw.to_fileattrs();
-        let attr_vals = fattr_raw.attrvalues_from_bytes(&attrmask);
+        let attr_vals = fattr_raw.attrvalues_from_bytes(&attrmask)?;
 
         Ok(Fattr4 {
             attrmask,
@@ -262,15 +349,20 @@ impl Attrlist4<FileAttr> {
             .iter()
             .map(|attr| ToPrimitive::to_u32(attr).unwrap())
             .collect::<Vec<u32>>();
+        // the words are filled in ascending order, whatever order the
+        // attributes were requested in
+        idxs.sort_unstable();
+        idxs.dedup();
 
-        idxs.reverse();
         let mut segment = 0_u32;
-        while let Some(idx) = idxs.pop() {
-            if (idx.div_ceil(31) as i16) - 1 > attrs.len() as i16 {
+        for idx in idxs {
+            // bit idx % 32 of word idx / 32, skipped words are encoded as
+            // empty ones
+            while idx as usize / 32 > attrs.len() {
                 attrs.push(segment);
                 segment = 0_u32;
             }
-            segment += 2_u32.pow(idx % 32);
+            segment |= 1 << (idx % 32);
         }
         attrs.push(segment);
 
@@ -345,6 +437,36 @@ impl Attrlist4<FileAttrValue> {
                 FileAttrValue::UniqueHandles(v) => {
                     buffer.extend_from_slice((*v as u32).to_be_bytes().as_ref());
                 }
+                FileAttrValue::FilesAvail(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::FilesFree(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::FilesTotal(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::FsLocations(v) => {
+                    serde_xdr::to_writer(&mut buffer, v).unwrap();
+                }
+                FileAttrValue::Archive(v) => {
+                    buffer.extend_from_slice((*v as u32).to_be_bytes().as_ref());
+                }
+                FileAttrValue::Homogeneous(v) => {
+                    buffer.extend_from_slice((*v as u32).to_be_bytes().as_ref());
+                }
+                FileAttrValue::Maxfilesize(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::Maxlink(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::Maxread(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::Maxwrite(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
                 FileAttrValue::RdattrError(v) => {
                     buffer
                         .extend_from_slice(ToPrimitive::to_u32(v).unwrap().to_be_bytes().as_ref());
@@ -352,6 +474,9 @@ impl Attrlist4<FileAttrValue> {
                 FileAttrValue::Fileid(v) => {
                     buffer.extend_from_slice(v.to_be_bytes().as_ref());
                 }
+                FileAttrValue::Acl(v) => {
+                    serde_xdr::to_writer(&mut buffer, v).unwrap();
+                }
                 FileAttrValue::AclSupport(v) => {
                     buffer.extend_from_slice(v.to_be_bytes().as_ref());
                 }
@@ -366,6 +491,10 @@ impl Attrlist4<FileAttrValue> {
                     buffer.extend_from_slice(v.seconds.to_be_bytes().as_ref());
                     buffer.extend_from_slice(v.nseconds.to_be_bytes().as_ref());
                 }
+                FileAttrValue::TimeBackup(v) => {
+                    buffer.extend_from_slice(v.seconds.to_be_bytes().as_ref());
+                    buffer.extend_from_slice(v.nseconds.to_be_bytes().as_ref());
+                }
                 FileAttrValue::TimeMetadata(v) => {
                     buffer.extend_from_slice(v.seconds.to_be_bytes().as_ref());
                     buffer.extend_from_slice(v.nseconds.to_be_bytes().as_ref());
@@ -381,12 +510,25 @@ impl Attrlist4<FileAttrValue> {
                     buffer.extend_from_slice((v.len() as u32).to_be_bytes().as_ref());
                     buffer.extend_from_slice(v.as_bytes());
                 }
+                FileAttrValue::QuotaAvailHard(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::QuotaAvailSoft(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
+                FileAttrValue::QuotaUsed(v) => {
+                    buffer.extend_from_slice(v.to_be_bytes().as_ref());
+                }
                 FileAttrValue::SpaceUsed(v) => {
                     buffer.extend_from_slice(v.to_be_bytes().as_ref());
                 }
                 FileAttrValue::Numlinks(v) => {
                     buffer.extend_from_slice(v.to_be_bytes().as_ref());
                 }
+                FileAttrValue::Rawdev(v) => {
+                    buffer.extend_from_slice(v.specdata1.to_be_bytes().as_ref());
+                    buffer.extend_from_slice(v.specdata2.to_be_bytes().as_ref());
+                }
                 _ => {}
             }
         }
@@ -428,3 +570,131 @@ impl Serialize for Attrlist4<FileAttrValue> {
         serializer.serialize_bytes(&attr_values)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::nfs4_proto::{
+        Attrlist4, Fattr4, FileAttr, FileAttrValue, Nfstime4, RpcGssSvc, RpcSecGssInfo, SeCinfo4,
+    };
+
+    fn words(attrs: &Attrlist4<FileAttr>) -> Vec<u32> {
+        let bytes = serde_xdr::to_bytes(attrs).unwrap();
+        bytes
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_bitmap_second_word() {
+        let attrs = Attrlist4::<FileAttr>::new(Some(vec![
+            FileAttr::MountedOnFileid,
+            FileAttr::Type,
+            FileAttr::TimeModify,
+            FileAttr::Type,
+        ]));
+        // length, then word 0 with attr 1 and word 1 with attrs 53 and 55
+        assert_eq!(words(&attrs), vec![2, 1 << 1, (1 << 21) | (1 << 23)]);
+
+        let bytes = serde_xdr::to_bytes(&attrs).unwrap();
+        let decoded: Attrlist4<FileAttr> = serde_xdr::from_bytes(bytes).unwrap();
+        assert_eq!(
+            decoded.0,
+            vec![
+                FileAttr::Type,
+                FileAttr::TimeModify,
+                FileAttr::MountedOnFileid
+            ]
+        );
+
+        // only attrs of the second word still encode an empty first word
+        let attrs = Attrlist4::<FileAttr>::new(Some(vec![FileAttr::MountedOnFileid]));
+        assert_eq!(words(&attrs), vec![2, 0, 1 << 23]);
+    }
+
+    #[test]
+    fn test_bitmap_word_boundary() {
+        // the last bit of word 0 and the first bit of word 1
+        let attrs = Attrlist4::<FileAttr>::new(Some(vec![FileAttr::Mimetype, FileAttr::Maxwrite]));
+        assert_eq!(words(&attrs), vec![2, 1 << 31, 1]);
+
+        let bytes = serde_xdr::to_bytes(&attrs).unwrap();
+        let decoded: Attrlist4<FileAttr> = serde_xdr::from_bytes(bytes).unwrap();
+        assert_eq!(decoded.0, vec![FileAttr::Maxwrite, FileAttr::Mimetype]);
+
+        // bit 63 is the last bit of word 1, there is no attribute 63 so it
+        // is dropped while the known attributes around it are kept
+        let decoded = Attrlist4::<FileAttr>::from_u32(vec![1 << 31, 1 | (1 << 31)]);
+        assert_eq!(decoded.0, vec![FileAttr::Maxwrite, FileAttr::Mimetype]);
+    }
+
+    #[test]
+    fn test_fattr_time_backup() {
+        let time = Nfstime4 {
+            seconds: 1_700_000_000,
+            nseconds: 42,
+        };
+        let fattr = Fattr4 {
+            attrmask: Attrlist4(vec![FileAttr::TimeBackup]),
+            attr_vals: Attrlist4(vec![FileAttrValue::TimeBackup(time)]),
+        };
+        let bytes = serde_xdr::to_bytes(&fattr).unwrap();
+        let decoded: Fattr4 = serde_xdr::from_bytes(bytes).unwrap();
+        assert_eq!(decoded.attr_vals.0, vec![FileAttrValue::TimeBackup(time)]);
+    }
+
+    #[test]
+    fn test_fattr_change_size_mode() {
+        let fattr = Fattr4 {
+            attrmask: Attrlist4(vec![FileAttr::Change, FileAttr::Size, FileAttr::Mode]),
+            attr_vals: Attrlist4(vec![
+                FileAttrValue::Change(0x0102_0304_0506_0708),
+                FileAttrValue::Size(13),
+                FileAttrValue::Mode(0o644),
+            ]),
+        };
+        let bytes = serde_xdr::to_bytes(&fattr).unwrap();
+        let decoded: Fattr4 = serde_xdr::from_bytes(bytes).unwrap();
+        assert_eq!(decoded, fattr);
+    }
+
+    #[test]
+    fn test_fattr_undecodable() {
+        // the mask asks for a size, but attr_vals only holds 4 bytes
+        let words: [u32; 4] = [1, 1 << 4, 4, 13];
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert!(serde_xdr::from_bytes::<_, Fattr4>(bytes).is_err());
+
+        // type isn't decoded
+        let words: [u32; 4] = [1, 1 << 1, 4, 2];
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert!(serde_xdr::from_bytes::<_, Fattr4>(bytes).is_err());
+    }
+
+    #[test]
+    fn test_secinfo_flavor_numbers() {
+        let flavors = vec![
+            SeCinfo4::FlavorInfo(RpcSecGssInfo {
+                oid: vec![1, 2, 3],
+                qop: 0,
+                service: RpcGssSvc::RpcGssSvcIntegrity,
+            }),
+            SeCinfo4::AuthSys,
+            SeCinfo4::AuthNone,
+        ];
+        let bytes = serde_xdr::to_bytes(&flavors).unwrap();
+        // the flavors go on the wire as their RPC flavor numbers
+        assert_eq!(&bytes[4..8], &6u32.to_be_bytes());
+        assert_eq!(
+            &bytes[bytes.len() - 8..bytes.len() - 4],
+            &1u32.to_be_bytes()
+        );
+        assert_eq!(&bytes[bytes.len() - 4..], &0u32.to_be_bytes());
+
+        let decoded: Vec<SeCinfo4> = serde_xdr::from_bytes(bytes).unwrap();
+        assert_eq!(decoded, flavors);
+
+        // 4 and 5 aren't flavors
+        assert!(serde_xdr::from_bytes::<_, SeCinfo4>(4u32.to_be_bytes()).is_err());
+    }
+}